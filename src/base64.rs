@@ -0,0 +1,53 @@
+// Standard base64 (RFC 4648, with padding), for Basic credentials and the
+// like, and the URL-safe variant JWTs and PKCE use.
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(crate) fn encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let group = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * index) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// "-" and "_" for "+" and "/", and no padding.
+pub(crate) fn encode_url(data: &[u8]) -> String {
+    encode(data).trim_end_matches('=').replace('+', "-").replace('/', "_")
+}
+
+// Either alphabet, padded or not. None if it isn't base64 at all.
+pub(crate) fn decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    let mut decoded = Vec::with_capacity(text.len() * 3 / 4);
+    let mut group = 0u32;
+    for (index, byte) in text.bytes().enumerate() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
+        };
+        group = group << 6 | value as u32;
+        if index % 4 == 3 {
+            decoded.extend_from_slice(&group.to_be_bytes()[1..]);
+            group = 0;
+        }
+    }
+    match text.len() % 4 {
+        0 => {}
+        2 => decoded.push((group >> 4) as u8),
+        3 => decoded.extend_from_slice(&((group >> 2) as u16).to_be_bytes()),
+        _ => return None,
+    }
+    Some(decoded)
+}
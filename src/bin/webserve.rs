@@ -0,0 +1,178 @@
+// Serves a directory over HTTP, like `python -m http.server`:
+//
+//     webserve ./public --port 8080 --gzip --spa
+//
+// or whatever mounts and redirects a config file declares:
+//
+//     webserve --config site.toml
+use std::{env, process};
+use web_server::{
+    middleware::{AccessLog, Compression},
+    parser::{self, Parsing, Verdict},
+    server::Server,
+    static_files::StaticDir,
+};
+
+const USAGE: &str = "Usage: webserve [DIR] [--ip IP] [--port PORT] [--workers N] [--gzip] [--spa] [--hidden]
+                [--markdown] [--quiet] [--strict] [--handover SOCKET] [--check-parser]
+       webserve --config FILE [--gzip]
+
+Serves the files in DIR (default: the current directory), or the mounts and
+redirects declared in a config file.
+
+Options:
+  --ip IP        Address to listen on (default: 127.0.0.1)
+  --port PORT    Port to listen on (default: 8080)
+  --workers N    Number of worker threads (default: 4)
+  --gzip         Compress responses for clients that accept gzip
+  --spa          Serve index.html for paths that don't match a file
+  --hidden       Serve dotfiles too (.env, .git and the like)
+  --markdown     Render .md files as HTML pages
+  --quiet        Don't log each request
+  --strict       Refuse requests that don't parse to the letter of RFC 9112
+  --handover SOCKET
+                 Take over the listener of a webserve already running with the
+                 same SOCKET, which then drains and exits (Linux only)
+  --config FILE  Serve what FILE declares; its address, workers and logging
+                 replace the options above, apart from --gzip. Reloaded on
+                 SIGHUP
+  --check-parser Run the parser against its request smuggling corpus, in
+                 strict mode with --strict, and exit
+  -h, --help     Show this message
+
+WEB_SERVER_* environment variables override these options.";
+
+struct Options {
+    dir: String,
+    ip: String,
+    port: u32,
+    workers: usize,
+    gzip: bool,
+    spa: bool,
+    hidden: bool,
+    markdown: bool,
+    quiet: bool,
+    strict: bool,
+    check_parser: bool,
+    handover: Option<String>,
+    config: Option<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        dir: ".".to_string(),
+        ip: "127.0.0.1".to_string(),
+        port: 8080,
+        workers: 4,
+        gzip: false,
+        spa: false,
+        hidden: false,
+        markdown: false,
+        quiet: false,
+        strict: false,
+        check_parser: false,
+        handover: None,
+        config: None,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{name} needs a value"));
+        match arg.as_str() {
+            "--ip" => options.ip = value("--ip")?,
+            "--port" => options.port = value("--port")?.parse().map_err(|_| "Invalid --port".to_string())?,
+            "--workers" => {
+                options.workers = match value("--workers")?.parse() {
+                    Ok(workers) if workers > 0 => workers,
+                    _ => return Err("Invalid --workers".to_string()),
+                }
+            }
+            "--gzip" => options.gzip = true,
+            "--spa" => options.spa = true,
+            "--hidden" => options.hidden = true,
+            "--markdown" => options.markdown = true,
+            "--quiet" => options.quiet = true,
+            "--strict" => options.strict = true,
+            "--check-parser" => options.check_parser = true,
+            "--handover" => options.handover = Some(value("--handover")?),
+            "--config" => options.config = Some(value("--config")?),
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                process::exit(0);
+            }
+            flag if flag.starts_with('-') => return Err(format!("Unknown option {flag}")),
+            dir => options.dir = dir.to_string(),
+        }
+    }
+    Ok(options)
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|error| {
+        eprintln!("{error}\n\n{USAGE}");
+        process::exit(2);
+    });
+
+    if options.check_parser {
+        check_parser(if options.strict { Parsing::Strict } else { Parsing::Lenient });
+    }
+
+    if let Some(path) = &options.config {
+        serve_config(path, options.gzip);
+    }
+
+    let mut builder = Server::builder(&options.ip, options.port)
+        .workers(options.workers)
+        .strict_parsing(options.strict);
+    if let Some(path) = &options.handover {
+        builder = builder.handover_socket(path);
+    }
+    let mut server = builder.with_env().build();
+    if !options.quiet {
+        server.add_middleware(AccessLog::new());
+    }
+    if options.gzip {
+        server.add_middleware(Compression::new());
+    }
+    let dir = StaticDir::new(&options.dir)
+        .spa(options.spa)
+        .serve_hidden(options.hidden)
+        .markdown(options.markdown);
+    server.add_static_dir("/", dir);
+    server.handle_signals();
+
+    println!("Serving {} on http://{}:{}", options.dir, options.ip, options.port);
+    server.run();
+}
+
+fn serve_config(path: &str, gzip: bool) -> ! {
+    let mut server = Server::from_config(path).unwrap_or_else(|error| {
+        eprintln!("Error in {path}: {error}");
+        process::exit(2);
+    });
+    if gzip {
+        server.add_middleware(Compression::new());
+    }
+    server.handle_signals();
+
+    for address in server.local_addrs() {
+        println!("Serving {path} on http://{address}");
+    }
+    server.run();
+    process::exit(0);
+}
+
+fn check_parser(parsing: Parsing) -> ! {
+    let outcomes = parser::check_corpus(parsing);
+    let failed = outcomes.iter().filter(|outcome| !outcome.passed()).count();
+    for outcome in &outcomes {
+        let verdict = if outcome.got == Verdict::Accept { "accept" } else { "reject" };
+        let status = if outcome.passed() { "ok  " } else { "FAIL" };
+        match outcome.reason {
+            Some(reason) => println!("{status} {verdict} {}: {reason}", outcome.name),
+            None => println!("{status} {verdict} {}", outcome.name),
+        }
+    }
+    println!("\n{} of {} cases as expected", outcomes.len() - failed, outcomes.len());
+    process::exit(if failed == 0 { 0 } else { 1 });
+}
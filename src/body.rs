@@ -0,0 +1,121 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+static SPILLED: AtomicU64 = AtomicU64::new(0);
+
+// A request body read straight off the connection as the handler asks for
+// it, so large uploads never have to fit in memory. Stops at Content-Length;
+// the route's size limit was checked before the handler ran.
+pub struct BodyReader {
+    // What arrived along with the request head.
+    buffered: Vec<u8>,
+    position: usize,
+    stream: TcpStream,
+    // Still to come from the stream.
+    remaining: u64,
+    length: u64,
+}
+
+impl BodyReader {
+    pub(crate) fn new(buffered: Vec<u8>, stream: TcpStream, length: u64) -> BodyReader {
+        let remaining = length - buffered.len() as u64;
+        BodyReader { buffered, position: 0, stream, remaining, length }
+    }
+
+    // The whole body's length, from Content-Length.
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.position < self.buffered.len() {
+            let count = buffer.len().min(self.buffered.len() - self.position);
+            buffer[..count].copy_from_slice(&self.buffered[self.position..self.position + count]);
+            self.position += count;
+            return Ok(count);
+        }
+        if self.remaining == 0 || buffer.is_empty() {
+            return Ok(0);
+        }
+        let wanted = buffer.len().min(self.remaining.try_into().unwrap_or(usize::MAX));
+        match self.stream.read(&mut buffer[..wanted])? {
+            0 => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Client closed the connection mid-body")),
+            read => {
+                self.remaining -= read as u64;
+                Ok(read)
+            }
+        }
+    }
+}
+
+// A request body too big to keep in memory, written to a temporary file
+// before the handler runs. `open` as often as needed for random access; the
+// file is deleted once the request is done with it.
+pub struct BodyFile {
+    path: PathBuf,
+    length: u64,
+}
+
+impl BodyFile {
+    // Copies `length` bytes of body, starting with `buffered`, into a new
+    // file under `dir`.
+    pub(crate) fn spill(dir: &Path, buffered: &[u8], mut stream: &TcpStream, length: u64) -> io::Result<BodyFile> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+        let name = format!("web_server-{}-{nanos}-{}.body", std::process::id(), SPILLED.fetch_add(1, Ordering::Relaxed));
+        let path = dir.join(name);
+
+        // create_new, so nothing already at the path (like a planted
+        // symlink) gets written through.
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&path)?;
+        // From here on, dropping the BodyFile cleans up after a failed copy.
+        let body = BodyFile { path, length };
+
+        file.write_all(buffered)?;
+        let rest = length - buffered.len() as u64;
+        let copied = io::copy(&mut Read::take(&mut stream, rest), &mut file)?;
+        if copied < rest {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Client closed the connection mid-body"));
+        }
+        Ok(body)
+    }
+
+    pub fn open(&self) -> io::Result<File> {
+        File::open(&self.path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+}
+
+impl Drop for BodyFile {
+    fn drop(&mut self) {
+        if let Err(error) = fs::remove_file(&self.path) {
+            eprintln!("Error removing {}: {error}", self.path.display());
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use crate::{
+    http::{Request, StatusCode},
+    stream::ResponseWriter,
+};
+
+// Fans messages out to everyone subscribed to a topic, from anywhere in the
+// app, for pushing live updates to browsers:
+//
+//     let hub = Broadcast::new();
+//     server.register_broadcast(&hub);
+//     server.add_stream("/events", hub.sse_handler(|request| {
+//         request.param("room").map(|room| vec![room.to_string()]).unwrap_or_default()
+//     }));
+//     ...
+//     hub.publish("lobby", "{\"joined\":\"ann\"}");
+//
+// Each subscriber has its own queue, so publishing never waits on a slow
+// client. One that lets `queue_size` messages pile up is evicted instead:
+// its subscription ends, and for SSE the connection closes, leaving the
+// browser to reconnect and catch up. Anything else holding a connection
+// open, a WebSocket say, can `subscribe` and forward what it receives.
+#[derive(Clone)]
+pub struct Broadcast {
+    queue_size: usize,
+    heartbeat: Duration,
+    state: Arc<HubState>,
+}
+
+#[derive(Default)]
+struct HubState {
+    topics: RwLock<HashMap<String, Vec<Arc<Subscriber>>>>,
+    next_id: AtomicU64,
+    evicted: AtomicU64,
+    closed: AtomicBool,
+}
+
+struct Subscriber {
+    id: u64,
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<Message>,
+    // Evicted, or the hub closed: nothing more will arrive.
+    closed: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub topic: Arc<str>,
+    pub data: Arc<str>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Received {
+    Message(Message),
+    // Nothing arrived in time.
+    Timeout,
+    // Evicted for falling behind, or the hub was closed.
+    Closed,
+}
+
+impl Broadcast {
+    // Queues up to 64 messages per subscriber, and has SSE connections send a
+    // heartbeat after 15 seconds of quiet.
+    pub fn new() -> Broadcast {
+        Broadcast { queue_size: 64, heartbeat: Duration::from_secs(15), state: Arc::new(HubState::default()) }
+    }
+
+    pub fn queue_size(mut self, messages: usize) -> Broadcast {
+        self.queue_size = messages.max(1);
+        self
+    }
+
+    // How long an SSE connection may go without a message before it's sent
+    // a comment, which keeps proxies from timing it out and notices
+    // clients that have gone.
+    pub fn heartbeat(mut self, interval: Duration) -> Broadcast {
+        self.heartbeat = interval;
+        self
+    }
+
+    pub fn subscribe(&self, topics: &[&str]) -> Subscription {
+        // Checking `closed` under the lock means `close` can't miss it.
+        let mut all = self.state.topics.write().unwrap();
+        let subscriber = Arc::new(Subscriber {
+            id: self.state.next_id.fetch_add(1, Ordering::Relaxed),
+            queue: Mutex::new(Queue { closed: self.is_closed(), ..Queue::default() }),
+            ready: Condvar::new(),
+        });
+        for topic in topics {
+            all.entry(topic.to_string()).or_default().push(Arc::clone(&subscriber));
+        }
+        Subscription {
+            state: Arc::clone(&self.state),
+            subscriber,
+            topics: topics.iter().map(|topic| topic.to_string()).collect(),
+        }
+    }
+
+    // Queues `data` for everyone subscribed to `topic`, and returns how many
+    // that was. Subscribers with full queues are evicted rather than sent it.
+    pub fn publish(&self, topic: &str, data: &str) -> usize {
+        let message = Message { topic: Arc::from(topic), data: Arc::from(data) };
+        let mut delivered = 0;
+        let mut evicted = vec![];
+        if let Some(subscribers) = self.state.topics.read().unwrap().get(topic) {
+            for subscriber in subscribers {
+                let mut queue = subscriber.queue.lock().unwrap();
+                if queue.closed {
+                    continue;
+                }
+                if queue.messages.len() >= self.queue_size {
+                    queue.closed = true;
+                    evicted.push(subscriber.id);
+                } else {
+                    queue.messages.push_back(message.clone());
+                    delivered += 1;
+                }
+                subscriber.ready.notify_all();
+            }
+        }
+        if !evicted.is_empty() {
+            eprintln!("Evicted {} slow subscriber(s) from {topic}", evicted.len());
+            self.state.evicted.fetch_add(evicted.len() as u64, Ordering::Relaxed);
+            self.state.remove(&evicted);
+        }
+        delivered
+    }
+
+    pub fn subscribers(&self, topic: &str) -> usize {
+        self.state.topics.read().unwrap().get(topic).map_or(0, Vec::len)
+    }
+
+    // How many subscribers have been evicted for falling behind.
+    pub fn evicted(&self) -> u64 {
+        self.state.evicted.load(Ordering::Relaxed)
+    }
+
+    // Ends every subscription, now and to come. The server does this when
+    // it starts shutting down, for hubs given to `register_broadcast`.
+    pub fn close(&self) {
+        self.state.closed.store(true, Ordering::SeqCst);
+        let topics = std::mem::take(&mut *self.state.topics.write().unwrap());
+        for subscriber in topics.values().flatten() {
+            subscriber.queue.lock().unwrap().closed = true;
+            subscriber.ready.notify_all();
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.closed.load(Ordering::SeqCst)
+    }
+
+    // A handler for `Server::add_stream` sending each message on the topics
+    // `topics` picks for the request as a server-sent event, named after its
+    // topic, until the client goes or the subscription ends.
+    pub fn sse_handler<F>(&self, topics: F) -> impl Fn(&Request, &mut ResponseWriter) -> io::Result<()> + Send + Sync + 'static
+    where
+        F: Fn(&Request) -> Vec<String> + Send + Sync + 'static,
+    {
+        let hub = self.clone();
+        move |request, writer| {
+            let topics = topics(request);
+            if topics.is_empty() {
+                writer.start(StatusCode::NotFound, &[])?;
+                return Ok(());
+            }
+            let topics: Vec<&str> = topics.iter().map(String::as_str).collect();
+            let subscription = hub.subscribe(&topics);
+            writer.start(StatusCode::Ok, &[("Content-Type", "text/event-stream"), ("Cache-Control", "no-cache")])?;
+            loop {
+                match subscription.recv(hub.heartbeat) {
+                    Received::Message(message) => writer.write(sse_event(&message).as_bytes())?,
+                    Received::Timeout => writer.write(b": heartbeat\n\n")?,
+                    Received::Closed => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl Default for Broadcast {
+    fn default() -> Broadcast {
+        Broadcast::new()
+    }
+}
+
+impl HubState {
+    fn remove(&self, ids: &[u64]) {
+        let mut topics = self.topics.write().unwrap();
+        topics.retain(|_, subscribers| {
+            subscribers.retain(|subscriber| !ids.contains(&subscriber.id));
+            !subscribers.is_empty()
+        });
+    }
+}
+
+// One subscriber's end of a Broadcast. Dropping it unsubscribes.
+pub struct Subscription {
+    state: Arc<HubState>,
+    subscriber: Arc<Subscriber>,
+    topics: Vec<String>,
+}
+
+impl Subscription {
+    // The next message, waiting up to `timeout` for one. Messages already
+    // queued are still handed out after eviction, before Closed.
+    pub fn recv(&self, timeout: Duration) -> Received {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.subscriber.queue.lock().unwrap();
+        loop {
+            if let Some(message) = queue.messages.pop_front() {
+                return Received::Message(message);
+            }
+            if queue.closed {
+                return Received::Closed;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Received::Timeout;
+            }
+            queue = self.subscriber.ready.wait_timeout(queue, deadline - now).unwrap().0;
+        }
+    }
+
+    // A message if one is queued, without waiting.
+    pub fn try_recv(&self) -> Received {
+        self.recv(Duration::ZERO)
+    }
+
+    pub fn topics(&self) -> &[String] {
+        &self.topics
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.state.remove(&[self.subscriber.id]);
+    }
+}
+
+// Data spanning several lines goes out as several data fields, which the
+// browser joins back up with newlines.
+fn sse_event(message: &Message) -> String {
+    let mut event = format!("event: {}\n", message.topic.replace(['\r', '\n'], ""));
+    for line in message.data.split('\n') {
+        event.push_str("data: ");
+        event.push_str(line.trim_end_matches('\r'));
+        event.push('\n');
+    }
+    event.push('\n');
+    event
+}
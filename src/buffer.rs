@@ -0,0 +1,64 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+// A free list of byte buffers so hot paths can reuse allocations instead of
+// asking the allocator for a fresh Vec on every request.
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    max_pooled: usize,
+    max_capacity: usize,
+}
+
+impl BufferPool {
+    pub const fn new(max_pooled: usize, max_capacity: usize) -> BufferPool {
+        BufferPool {
+            buffers: Mutex::new(Vec::new()),
+            max_pooled,
+            max_capacity,
+        }
+    }
+
+    pub fn take(&self) -> PooledBuffer<'_> {
+        let buffer = self.buffers.lock().unwrap().pop().unwrap_or_default();
+        PooledBuffer { pool: self, buffer }
+    }
+
+    fn give_back(&self, mut buffer: Vec<u8>) {
+        // Buffers that grew for one huge response aren't worth keeping around.
+        if buffer.capacity() > self.max_capacity {
+            return;
+        }
+        buffer.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_pooled {
+            buffers.push(buffer);
+        }
+    }
+}
+
+pub struct PooledBuffer<'pool> {
+    pool: &'pool BufferPool,
+    buffer: Vec<u8>,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.give_back(std::mem::take(&mut self.buffer));
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    io::Write,
+    thread,
+    time::Duration,
+};
+use crate::{
+    http::{Request, Response, StatusCode},
+    log,
+    middleware::{Middleware, Next},
+    random,
+    server::Server,
+};
+
+// Makes the server misbehave on purpose, for testing how its clients cope:
+// slow answers, errors, connections cut after the work is done, bodies cut
+// off partway. Each fault hits the given percentage of requests:
+//
+//     server.add_middleware(Chaos::new()
+//         .latency(20.0, Duration::from_millis(100), Duration::from_secs(2))
+//         .errors(5.0, &[StatusCode::InternalServerError, StatusCode::ServiceUnavailable])
+//         .drops(1.0)
+//         .truncation(1.0)
+//         .route("/api"));
+//
+// Errors are answered without calling the handler; drops and truncation
+// happen once it has run, so whatever it did has been done. Only for test
+// environments, obviously.
+pub struct Chaos {
+    latency: Option<(f64, Duration, Duration)>,
+    errors: Option<(f64, Vec<StatusCode>)>,
+    drops: f64,
+    truncation: f64,
+    routes: Vec<String>,
+}
+
+impl Chaos {
+    // Injects nothing until told what to.
+    pub fn new() -> Chaos {
+        Chaos { latency: None, errors: None, drops: 0.0, truncation: 0.0, routes: vec![] }
+    }
+
+    // Delays `percent` of requests by between `min` and `max`.
+    pub fn latency(mut self, percent: f64, min: Duration, max: Duration) -> Chaos {
+        self.latency = Some((percent, min, max.max(min)));
+        self
+    }
+
+    // Answers `percent` of requests with one of `statuses` instead.
+    pub fn errors(mut self, percent: f64, statuses: &[StatusCode]) -> Chaos {
+        let statuses = match statuses {
+            [] => vec![StatusCode::InternalServerError],
+            statuses => statuses.to_vec(),
+        };
+        self.errors = Some((percent, statuses));
+        self
+    }
+
+    // Closes the connection instead of answering `percent` of requests.
+    pub fn drops(mut self, percent: f64) -> Chaos {
+        self.drops = percent;
+        self
+    }
+
+    // Sends only part of the body for `percent` of requests, then closes
+    // the connection, with the full length still in Content-Length.
+    pub fn truncation(mut self, percent: f64) -> Chaos {
+        self.truncation = percent;
+        self
+    }
+
+    // Only misbehaves under `prefix`; everywhere, if never called.
+    pub fn route(mut self, prefix: &str) -> Chaos {
+        self.routes.push(prefix.to_string());
+        self
+    }
+
+    fn applies(&self, path: &str) -> bool {
+        let path = path.split('?').next().unwrap_or_default();
+        self.routes.is_empty() || self.routes.iter().any(|prefix| path.starts_with(prefix.as_str()))
+    }
+
+    fn roll(percent: f64) -> bool {
+        percent > 0.0 && random::unit() * 100.0 < percent
+    }
+
+    fn injected(fault: &str, request: &Request) {
+        if log::enabled(log::Level::Debug) {
+            println!("Chaos: {fault} for {:?} {}", request.method, request.path);
+        }
+    }
+
+    // Writes the head and the first half of the body, then hangs up.
+    fn truncate(request: &Request, mut response: Response) -> Response {
+        let Some(connection) = &request.connection else {
+            return response;
+        };
+        let mut head = vec![];
+        Server::write_head(&response, &mut head);
+        let _ = connection.with_stream(|stream| {
+            stream.write_all(&head)?;
+            stream.write_all(&response.body[..response.body.len() / 2])?;
+            stream.flush()
+        });
+        connection.hang_up();
+        response.streamed = true;
+        response
+    }
+}
+
+impl Default for Chaos {
+    fn default() -> Chaos {
+        Chaos::new()
+    }
+}
+
+impl Middleware for Chaos {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        if !self.applies(&request.path) {
+            return next(request);
+        }
+        if let Some((percent, min, max)) = self.latency {
+            if Chaos::roll(percent) {
+                let delay = min + (max - min).mul_f64(random::unit());
+                Chaos::injected(&format!("{}ms delay", delay.as_millis()), request);
+                thread::sleep(delay);
+            }
+        }
+        if let Some((percent, statuses)) = &self.errors {
+            if Chaos::roll(*percent) {
+                let status = statuses[(random::unit() * statuses.len() as f64) as usize % statuses.len()];
+                Chaos::injected(&format!("{} instead", status.code()), request);
+                return Response::new(status, &status.to_string());
+            }
+        }
+
+        let response = next(request);
+        if Chaos::roll(self.drops) {
+            Chaos::injected("dropped connection", request);
+            if let Some(connection) = &request.connection {
+                connection.hang_up();
+            }
+            let mut response = response;
+            response.streamed = true;
+            return response;
+        }
+        // Streamed and file bodies have gone, or go, out another way.
+        if !response.streamed && response.file.is_none() && response.body.len() > 1 && Chaos::roll(self.truncation) {
+            Chaos::injected("truncated body", request);
+            return Chaos::truncate(request, response);
+        }
+        response
+    }
+}
@@ -0,0 +1,404 @@
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+use crate::http::HttpMethod;
+
+const MAX_HEAD: usize = 64 * 1024;
+
+// A small blocking HTTP/1.1 client for the odd outbound call (a webhook, an
+// upstream API, a health check), one connection per request:
+//
+//     let response = client::get("http://127.0.0.1:9000/health")?;
+//     let response = ClientRequest::new(HttpMethod::POST, "http://api.internal/jobs")
+//         .header("Content-Type", "application/json")
+//         .body(r#"{"run":true}"#)
+//         .timeout(Duration::from_secs(5))
+//         .send()?;
+//
+// Only plain http:// for now; https:// URLs fail with `ClientError::Tls`
+// until the crate grows TLS support.
+pub fn get(url: &str) -> Result<ClientResponse, ClientError> {
+    ClientRequest::new(HttpMethod::GET, url).send()
+}
+
+#[derive(Clone)]
+pub struct ClientRequest {
+    method: HttpMethod,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    connect_timeout: Duration,
+    timeout: Duration,
+    max_response_size: u64,
+    // The first header that couldn't be sent as given, which `send` fails on.
+    invalid_header: Option<String>,
+}
+
+impl ClientRequest {
+    // 10 seconds to connect, 30 for each read or write after that, and
+    // responses of up to 16 MiB.
+    pub fn new(method: HttpMethod, url: &str) -> ClientRequest {
+        ClientRequest {
+            method,
+            url: url.to_string(),
+            headers: vec![],
+            body: vec![],
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            max_response_size: 16 * 1024 * 1024,
+            invalid_header: None,
+        }
+    }
+
+    // A CR or LF would end the header early and let the rest pass as
+    // headers of its own, so names or values with one make `send` fail
+    // with `ClientError::InvalidHeader`.
+    pub fn header(mut self, name: &str, value: &str) -> ClientRequest {
+        let splits = |text: &str| text.contains(['\r', '\n']);
+        if name.is_empty() || name.contains(':') || splits(name) || splits(value) {
+            self.invalid_header.get_or_insert_with(|| name.to_string());
+            return self;
+        }
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> ClientRequest {
+        self.body = body.into();
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> ClientRequest {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    // How long any one read or write may stall, not the whole exchange.
+    pub fn timeout(mut self, timeout: Duration) -> ClientRequest {
+        self.timeout = timeout;
+        self
+    }
+
+    // Bodies bigger than this fail with `ClientError::TooLarge` rather than
+    // filling memory.
+    pub fn max_response_size(mut self, bytes: u64) -> ClientRequest {
+        self.max_response_size = bytes;
+        self
+    }
+
+    pub fn send(&self) -> Result<ClientResponse, ClientError> {
+        if let Some(name) = &self.invalid_header {
+            return Err(ClientError::InvalidHeader(name.clone()));
+        }
+        let target = Target::parse(&self.url)?;
+        let stream = self.connect(&target)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        self.write_request(&stream, &target)?;
+        self.read_response(BufReader::new(stream))
+    }
+
+    fn connect(&self, target: &Target) -> Result<TcpStream, ClientError> {
+        let mut last_error = None;
+        for address in (target.host.as_str(), target.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, self.connect_timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(error) => last_error = Some(error),
+            }
+        }
+        Err(match last_error {
+            Some(error) => ClientError::Io(error),
+            None => ClientError::Url(format!("{} doesn't resolve to any address", target.host)),
+        })
+    }
+
+    fn write_request(&self, mut stream: &TcpStream, target: &Target) -> io::Result<()> {
+        let mut head = format!("{:?} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", self.method, target.path, target.authority);
+        if !self.has_header("User-Agent") {
+            head.push_str("User-Agent: web_server\r\n");
+        }
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        let sends_body = !self.body.is_empty() || matches!(self.method, HttpMethod::POST | HttpMethod::PUT | HttpMethod::PATCH);
+        if sends_body && !self.has_header("Content-Length") {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+
+    fn read_response(&self, mut reader: BufReader<TcpStream>) -> Result<ClientResponse, ClientError> {
+        let (status, reason, headers) = loop {
+            let mut budget = MAX_HEAD;
+            let status_line = read_line(&mut reader, &mut budget)?;
+            let mut parts = status_line.splitn(3, ' ');
+            let version = parts.next().unwrap_or_default();
+            let status: u16 = parts.next()
+                .and_then(|status| status.parse().ok())
+                .filter(|_| version.starts_with("HTTP/1."))
+                .ok_or(ClientError::Malformed("Bad status line"))?;
+            let reason = parts.next().unwrap_or_default().to_string();
+            let headers = read_headers(&mut reader, &mut budget)?;
+            // Interim 1xx responses (100 Continue, 103 Early Hints) come
+            // before the real one.
+            if !(100..200).contains(&status) {
+                break (status, reason, headers);
+            }
+        };
+        let response = ClientResponse { status, reason, headers, body: vec![] };
+
+        // Answers to HEAD say how long the body would be, but don't send it.
+        let body = if status == 204 || status == 304 || self.method == HttpMethod::HEAD {
+            vec![]
+        } else if response.header("Transfer-Encoding").is_some_and(|coding| coding.to_ascii_lowercase().contains("chunked")) {
+            self.read_chunked(&mut reader)?
+        } else if let Some(length) = response.header("Content-Length") {
+            let length: u64 = length.trim().parse().map_err(|_| ClientError::Malformed("Bad Content-Length"))?;
+            if length > self.max_response_size {
+                return Err(ClientError::TooLarge);
+            }
+            let mut body = Vec::with_capacity(length.min(MAX_HEAD as u64) as usize);
+            reader.take(length).read_to_end(&mut body)?;
+            if (body.len() as u64) < length {
+                return Err(ClientError::Malformed("Connection closed mid-body"));
+            }
+            body
+        } else {
+            // Neither: the body runs until the server closes the connection.
+            let mut body = vec![];
+            reader.take(self.max_response_size.saturating_add(1)).read_to_end(&mut body)?;
+            if body.len() as u64 > self.max_response_size {
+                return Err(ClientError::TooLarge);
+            }
+            body
+        };
+        Ok(ClientResponse { body, ..response })
+    }
+
+    fn read_chunked(&self, reader: &mut BufReader<TcpStream>) -> Result<Vec<u8>, ClientError> {
+        let mut body = vec![];
+        loop {
+            let mut budget = MAX_HEAD;
+            let line = read_line(reader, &mut budget)?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = u64::from_str_radix(size, 16).map_err(|_| ClientError::Malformed("Bad chunk size"))?;
+            if size == 0 {
+                // Trailers, which nothing here uses.
+                read_headers(reader, &mut budget)?;
+                return Ok(body);
+            }
+            if (body.len() as u64).checked_add(size).is_none_or(|total| total > self.max_response_size) {
+                return Err(ClientError::TooLarge);
+            }
+            let start = body.len();
+            reader.by_ref().take(size).read_to_end(&mut body)?;
+            if ((body.len() - start) as u64) < size {
+                return Err(ClientError::Malformed("Connection closed mid-chunk"));
+            }
+            if !read_line(reader, &mut budget)?.is_empty() {
+                return Err(ClientError::Malformed("Chunk longer than its size"));
+            }
+        }
+    }
+
+    fn has_header(&self, name: &str) -> bool {
+        self.headers.iter().any(|(header, _)| header.eq_ignore_ascii_case(name))
+    }
+}
+
+pub struct ClientResponse {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl ClientResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The body as text, with anything that isn't UTF-8 replaced.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Url(String),
+    Tls,
+    Io(io::Error),
+    Malformed(&'static str),
+    TooLarge,
+    // A header name or value with a CR or LF in it, or an empty name.
+    InvalidHeader(String),
+}
+
+impl From<io::Error> for ClientError {
+    fn from(error: io::Error) -> ClientError {
+        ClientError::Io(error)
+    }
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Url(message) => write!(f, "{message}"),
+            ClientError::Tls => write!(f, "https:// URLs aren't supported yet"),
+            ClientError::Io(error) => write!(f, "{error}"),
+            ClientError::Malformed(message) => write!(f, "Malformed response: {message}"),
+            ClientError::TooLarge => write!(f, "Response body too large"),
+            ClientError::InvalidHeader(name) => write!(f, "Can't send header {name:?}"),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+// Where a URL points: what to connect to, and what to send as Host and as
+// the request target.
+struct Target {
+    host: String,
+    port: u16,
+    authority: String,
+    path: String,
+}
+
+impl Target {
+    fn parse(url: &str) -> Result<Target, ClientError> {
+        let (scheme, rest) = url.split_once("://").ok_or_else(|| ClientError::Url(format!("{url} has no scheme")))?;
+        match scheme.to_ascii_lowercase().as_str() {
+            "http" => {}
+            "https" => return Err(ClientError::Tls),
+            _ => return Err(ClientError::Url(format!("Unsupported scheme in {url}"))),
+        }
+        let rest = rest.split('#').next().unwrap_or_default();
+        let split = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, path) = rest.split_at(split);
+        let path = match path {
+            "" => "/".to_string(),
+            path if path.starts_with('?') => format!("/{path}"),
+            path => path.to_string(),
+        };
+        if authority.is_empty() || authority.contains(['@', ' ', '\r', '\n']) || path.contains([' ', '\r', '\n']) {
+            return Err(ClientError::Url(format!("Can't request {url}")));
+        }
+
+        // IPv6 literals come bracketed: http://[::1]:8080/
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, after) = bracketed.split_once(']').ok_or_else(|| ClientError::Url(format!("Bad host in {url}")))?;
+                (host, after.strip_prefix(':'))
+            }
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| ClientError::Url(format!("Bad port in {url}")))?,
+            None => 80,
+        };
+        Ok(Target { host: host.to_string(), port, authority: authority.to_string(), path })
+    }
+}
+
+// One CRLF- (or bare LF-) terminated line, counted against `budget` so a
+// server can't stream an endless head at us.
+fn read_line(reader: &mut BufReader<TcpStream>, budget: &mut usize) -> Result<String, ClientError> {
+    let mut line = vec![];
+    let read = reader.by_ref().take(*budget as u64 + 1).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Err(ClientError::Malformed("Connection closed before the response"));
+    }
+    if read > *budget {
+        return Err(ClientError::Malformed("Response head too large"));
+    }
+    *budget -= read;
+    if line.pop() != Some(b'\n') {
+        return Err(ClientError::Malformed("Connection closed mid-line"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| ClientError::Malformed("Response head isn't UTF-8"))
+}
+
+fn read_headers(reader: &mut BufReader<TcpStream>, budget: &mut usize) -> Result<Vec<(String, String)>, ClientError> {
+    let mut headers = vec![];
+    loop {
+        let line = read_line(reader, budget)?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        let (name, value) = line.split_once(':').ok_or(ClientError::Malformed("Bad header line"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    // Answers one request with `response`, returning the URL to send it to
+    // and what the request was.
+    fn serve_once(response: &'static [u8]) -> (String, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = vec![];
+            while !request.ends_with(b"\r\n\r\n") && reader.read_until(b'\n', &mut request).unwrap_or(0) > 0 {}
+            stream.write_all(response).unwrap();
+            request
+        });
+        (url, server)
+    }
+
+    #[test]
+    fn refuses_headers_that_would_split() {
+        for (name, value) in [("X-A", "1\r\nX-Injected: 1"), ("X-A\nX-Injected", "1"), ("", "1"), ("X-A: 1", "")] {
+            let sent = ClientRequest::new(HttpMethod::GET, "http://127.0.0.1:9/").header(name, value).send();
+            assert!(matches!(sent, Err(ClientError::InvalidHeader(_))), "{name:?}: {value:?}");
+        }
+        assert!(matches!(get("http://a\r\nX-Injected: 1/"), Err(ClientError::Url(_))));
+    }
+
+    #[test]
+    fn huge_chunk_size_is_too_large() {
+        let (url, server) = serve_once(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\n");
+        let sent = ClientRequest::new(HttpMethod::GET, &url).send();
+        assert!(matches!(sent, Err(ClientError::TooLarge)));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn reads_chunked_and_close_delimited_bodies() {
+        let (url, server) = serve_once(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n");
+        let response = ClientRequest::new(HttpMethod::GET, &url).header("X-Trace", "1").send().unwrap();
+        assert_eq!(response.body, b"hello");
+        let request = String::from_utf8(server.join().unwrap()).unwrap();
+        assert!(request.contains("\r\nX-Trace: 1\r\n"), "{request}");
+
+        let (url, server) = serve_once(b"HTTP/1.1 200 OK\r\n\r\nuntil close");
+        let sent = ClientRequest::new(HttpMethod::GET, &url).max_response_size(u64::MAX).send().unwrap();
+        assert_eq!(sent.body, b"until close");
+        server.join().unwrap();
+    }
+}
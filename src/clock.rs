@@ -0,0 +1,94 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+// Where time-dependent parts of the server (Timeout, ResponseCache,
+// CircuitBreaker, Quota, Sessions, and the Date header through
+// `ServerBuilder::clock`) get the time from, so tests can move it forward
+// instead of sleeping:
+//
+//     let clock = MockClock::new();
+//     let cache = ResponseCache::new(100).clock(clock.clone());
+//     ...
+//     clock.advance(Duration::from_secs(61));
+//
+// Everything uses `SystemClock` unless given another.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    // Wall-clock time, for calendar windows and timestamps.
+    fn system_time(&self) -> SystemTime;
+
+    // How long something waiting for a moment to come may sleep before
+    // looking at the clock again. None means sleeping until then is right,
+    // as it is with real time.
+    fn recheck_every(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// Stands still until `advance`d. Clones share the same time.
+#[derive(Clone)]
+pub struct MockClock {
+    started: Instant,
+    started_at: SystemTime,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl MockClock {
+    // Starts at the current time.
+    pub fn new() -> MockClock {
+        MockClock::at(SystemTime::now())
+    }
+
+    // Starts at `time`, as far as `system_time` is concerned.
+    pub fn at(time: SystemTime) -> MockClock {
+        MockClock { started: Instant::now(), started_at: time, elapsed: Arc::new(Mutex::new(Duration::ZERO)) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.started + self.elapsed()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.started_at + self.elapsed()
+    }
+
+    fn recheck_every(&self) -> Option<Duration> {
+        Some(Duration::from_millis(1))
+    }
+}
+
+pub(crate) fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
@@ -0,0 +1,589 @@
+// A small DEFLATE encoder (RFC 1951) with gzip framing (RFC 1952). It only
+// uses the fixed Huffman codes and a single-entry hash table for matches, so
+// it compresses worse than zlib, but text still shrinks to a fraction of its
+// size and it needs no dependencies.
+//
+// The decoder at the bottom handles everything DEFLATE allows, for reading
+// what clients compressed with real zlib.
+
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+    6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| CRC_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    // Magic, deflate, no flags, no mtime, no extra flags, unknown OS.
+    let mut output = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    output.extend(deflate(data));
+    output.extend(crc32(data).to_le_bytes());
+    output.extend((data.len() as u32).to_le_bytes());
+    output
+}
+
+struct BitWriter {
+    output: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        self.buffer |= (value as u64) << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            self.output.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes are defined most significant bit first, the reverse of
+    // how everything else is packed.
+    fn write_code(&mut self, code: u32, bits: u32) {
+        self.write(code.reverse_bits() >> (32 - bits), bits);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.output.push(self.buffer as u8);
+        }
+        self.output
+    }
+}
+
+fn write_literal(writer: &mut BitWriter, symbol: u32) {
+    match symbol {
+        0..=143 => writer.write_code(0x30 + symbol, 8),
+        144..=255 => writer.write_code(0x190 + symbol - 144, 9),
+        256..=279 => writer.write_code(symbol - 256, 7),
+        _ => writer.write_code(0xC0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(writer: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap();
+    write_literal(writer, 257 + code as u32);
+    writer.write((length - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code] as u32);
+
+    let code = DISTANCE_BASE.iter().rposition(|&base| base as usize <= distance).unwrap();
+    writer.write_code(code as u32, 5);
+    writer.write((distance - DISTANCE_BASE[code] as usize) as u32, DISTANCE_EXTRA[code] as u32);
+}
+
+fn hash(data: &[u8]) -> usize {
+    let value = u32::from_le_bytes([data[0], data[1], data[2], 0]);
+    (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+// Compresses `data` as a single fixed-Huffman block.
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    deflate_window(data, WINDOW_SIZE)
+}
+
+// Like `deflate`, but never refers back further than `window` bytes, for
+// decoders that keep less than the usual 32 KiB.
+pub(crate) fn deflate_window(data: &[u8], window: usize) -> Vec<u8> {
+    let mut writer = BitWriter { output: Vec::with_capacity(data.len() / 2), buffer: 0, count: 0 };
+    writer.write(1, 1); // final block
+    writer.write(1, 2); // fixed Huffman codes
+
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut position = 0;
+    while position < data.len() {
+        if position + MIN_MATCH <= data.len() {
+            let slot = hash(&data[position..]);
+            let candidate = table[slot];
+            table[slot] = position;
+
+            if candidate != usize::MAX && position - candidate <= window.min(WINDOW_SIZE) {
+                let limit = MAX_MATCH.min(data.len() - position);
+                let length = (0..limit)
+                    .take_while(|&offset| data[candidate + offset] == data[position + offset])
+                    .count();
+                if length >= MIN_MATCH {
+                    write_match(&mut writer, length, position - candidate);
+                    // Keep the table warm for the bytes the match skipped over.
+                    for skipped in position + 1..(position + length).min(data.len() - MIN_MATCH + 1) {
+                        table[hash(&data[skipped..])] = skipped;
+                    }
+                    position += length;
+                    continue;
+                }
+            }
+        }
+        write_literal(&mut writer, data[position] as u32);
+        position += 1;
+    }
+
+    write_literal(&mut writer, 256); // end of block
+    writer.finish()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum InflateError {
+    // The data isn't valid gzip, zlib or DEFLATE.
+    Invalid(&'static str),
+    // It decompresses to more than the caller's limit.
+    TooLarge,
+}
+
+impl std::fmt::Display for InflateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InflateError::Invalid(reason) => write!(f, "{reason}"),
+            InflateError::TooLarge => write!(f, "decompressed data is over the limit"),
+        }
+    }
+}
+
+// Decompresses a gzip member, giving up once the output passes `limit` bytes.
+pub fn gunzip(data: &[u8], limit: usize) -> Result<Vec<u8>, InflateError> {
+    const FEXTRA: u8 = 4;
+    const FNAME: u8 = 8;
+    const FCOMMENT: u8 = 16;
+    const FHCRC: u8 = 2;
+
+    if data.len() < 18 || data[..3] != [0x1f, 0x8b, 8] {
+        return Err(InflateError::Invalid("not gzip data"));
+    }
+    let flags = data[3];
+    let mut position = 10;
+    let truncated = InflateError::Invalid("truncated gzip header");
+    if flags & FEXTRA != 0 {
+        let length = data.get(position..position + 2).ok_or(truncated)?;
+        position += 2 + u16::from_le_bytes([length[0], length[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = data.get(position..).and_then(|rest| rest.iter().position(|&byte| byte == 0));
+            position += end.ok_or(InflateError::Invalid("truncated gzip header"))? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        position += 2;
+    }
+    let body = data.get(position..).ok_or(InflateError::Invalid("truncated gzip header"))?;
+
+    let (output, used) = inflate_raw(body, limit)?;
+    let trailer = body.get(used..used + 8).ok_or(InflateError::Invalid("truncated gzip trailer"))?;
+    if u32::from_le_bytes(trailer[..4].try_into().unwrap()) != crc32(&output) {
+        return Err(InflateError::Invalid("gzip checksum mismatch"));
+    }
+    Ok(output)
+}
+
+// Decompresses what HTTP calls `deflate`: zlib-wrapped DEFLATE, or the raw
+// kind some clients send instead.
+pub fn inflate(data: &[u8], limit: usize) -> Result<Vec<u8>, InflateError> {
+    let zlib = data.len() >= 2 && data[0] & 0x0F == 8 && u16::from_be_bytes([data[0], data[1]]).is_multiple_of(31);
+    if zlib {
+        if data[1] & 0x20 != 0 {
+            return Err(InflateError::Invalid("zlib preset dictionaries aren't supported"));
+        }
+        return inflate_raw(&data[2..], limit).map(|(output, _)| output);
+    }
+    inflate_raw(data, limit).map(|(output, _)| output)
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, bits: u32) -> Result<u32, InflateError> {
+        while self.count < bits {
+            let byte = *self.data.get(self.position).ok_or(InflateError::Invalid("unexpected end of data"))?;
+            self.position += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u64 << bits) - 1) as u32;
+        self.buffer >>= bits;
+        self.count -= bits;
+        Ok(value)
+    }
+
+    // Stored blocks start on a byte boundary.
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
+
+// A canonical Huffman code, decoded a bit at a time: `counts` has how many
+// codes there are of each length, `symbols` the symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, InflateError> {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // Reject codes that use more bit patterns than exist.
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(InflateError::Invalid("oversubscribed Huffman code"));
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, InflateError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= reader.bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(InflateError::Invalid("invalid Huffman code"))
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    let literals = Huffman::new(&lengths).expect("The fixed code is valid");
+    let distances = Huffman::new(&[5; 30]).expect("The fixed code is valid");
+    (literals, distances)
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), InflateError> {
+    const ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_count = reader.bits(4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err(InflateError::Invalid("too many Huffman codes"));
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &index in &ORDER[..code_count] {
+        code_lengths[index] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths)?;
+
+    let mut lengths = vec![0u8; literal_count + distance_count];
+    let mut index = 0;
+    while index < lengths.len() {
+        let (value, repeat) = match code_lengths.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *index.checked_sub(1).and_then(|previous| lengths.get(previous))
+                    .ok_or(InflateError::Invalid("repeat with no previous length"))?;
+                (previous, 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if index + repeat > lengths.len() {
+            return Err(InflateError::Invalid("too many code lengths"));
+        }
+        lengths[index..index + repeat].fill(value);
+        index += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(InflateError::Invalid("no end-of-block code"));
+    }
+    Ok((Huffman::new(&lengths[..literal_count])?, Huffman::new(&lengths[literal_count..])?))
+}
+
+// Decodes DEFLATE blocks up to the final one, returning the output and how
+// many input bytes they took.
+fn inflate_raw(data: &[u8], limit: usize) -> Result<(Vec<u8>, usize), InflateError> {
+    let mut output = Vec::with_capacity(data.len().saturating_mul(3).min(limit));
+    let used = inflate_blocks(data, &mut output, limit, false)?;
+    Ok((output, used))
+}
+
+// Decodes DEFLATE blocks that may go on past `data`, as each message of a
+// compressed WebSocket does (RFC 7692): it ends at a block boundary, and
+// matches may reach back into `history`, what earlier messages decoded to.
+pub(crate) fn inflate_continuing(data: &[u8], history: &[u8], limit: usize) -> Result<Vec<u8>, InflateError> {
+    let mut output = Vec::with_capacity(history.len() + data.len().saturating_mul(3).min(limit));
+    output.extend_from_slice(history);
+    inflate_blocks(data, &mut output, limit, true)?;
+    Ok(output.split_off(history.len()))
+}
+
+// Appends what the blocks in `data` decode to onto `output`, giving up once
+// that's more than `limit` bytes. Stops after the final block, or with
+// `partial` after the stored block that takes it to the end of `data`.
+// Returns how many input bytes were used.
+fn inflate_blocks(data: &[u8], output: &mut Vec<u8>, limit: usize, partial: bool) -> Result<usize, InflateError> {
+    let mut reader = BitReader { data, position: 0, buffer: 0, count: 0 };
+    let limit = output.len().saturating_add(limit);
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let header = data.get(reader.position..reader.position + 4)
+                    .ok_or(InflateError::Invalid("unexpected end of data"))?;
+                let length = u16::from_le_bytes([header[0], header[1]]);
+                if length != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(InflateError::Invalid("stored block length mismatch"));
+                }
+                let start = reader.position + 4;
+                let stored = data.get(start..start + length as usize)
+                    .ok_or(InflateError::Invalid("unexpected end of data"))?;
+                if output.len() + stored.len() > limit {
+                    return Err(InflateError::TooLarge);
+                }
+                output.extend_from_slice(stored);
+                reader.position = start + length as usize;
+                if partial && !last && reader.position == data.len() {
+                    return Ok(reader.position);
+                }
+            }
+            kind @ (1 | 2) => {
+                let (literals, distances) = if kind == 1 { fixed_codes() } else { dynamic_codes(&mut reader)? };
+                loop {
+                    let symbol = literals.decode(&mut reader)? as usize;
+                    if symbol < 256 {
+                        if output.len() == limit {
+                            return Err(InflateError::TooLarge);
+                        }
+                        output.push(symbol as u8);
+                        continue;
+                    }
+                    if symbol == 256 {
+                        break;
+                    }
+
+                    let code = symbol - 257;
+                    if code >= LENGTH_BASE.len() {
+                        return Err(InflateError::Invalid("invalid length code"));
+                    }
+                    let length = LENGTH_BASE[code] as usize + reader.bits(LENGTH_EXTRA[code] as u32)? as usize;
+                    let code = distances.decode(&mut reader)? as usize;
+                    if code >= DISTANCE_BASE.len() {
+                        return Err(InflateError::Invalid("invalid distance code"));
+                    }
+                    let distance = DISTANCE_BASE[code] as usize + reader.bits(DISTANCE_EXTRA[code] as u32)? as usize;
+                    if distance > output.len() {
+                        return Err(InflateError::Invalid("distance before start of data"));
+                    }
+                    if output.len() + length > limit {
+                        return Err(InflateError::TooLarge);
+                    }
+                    // Matches can overlap what they're copying, so go byte by byte.
+                    let start = output.len() - distance;
+                    for offset in 0..length {
+                        output.push(output[start + offset]);
+                    }
+                }
+            }
+            _ => return Err(InflateError::Invalid("invalid block type")),
+        }
+        if last {
+            // Whatever is left of the current byte is padding.
+            return Ok(reader.position);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // zlib.compress(b"hello hello hello hello", 9): one fixed-Huffman block.
+    const ZLIB_FIXED: &[u8] = &[0x78, 0xda, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0x01, 0x68, 0x03, 0x08, 0xb1];
+    // zlib.compress(lines(), 9): one dynamic-Huffman block.
+    const ZLIB_DYNAMIC: &[u8] = &[
+        0x78, 0xda, 0xa5, 0xd2, 0xbb, 0x0d, 0x83, 0x40, 0x14, 0x05, 0xd1, 0xdc, 0x55, 0xbc, 0x02, 0x1c,
+        0xf8, 0x02, 0xe6, 0x53, 0x8e, 0x25, 0x1e, 0x62, 0x25, 0x60, 0xd1, 0xb2, 0x08, 0x97, 0x8f, 0xc8,
+        0x88, 0x99, 0x64, 0xb2, 0xc9, 0xce, 0x14, 0x16, 0xb7, 0x8f, 0xc5, 0xc1, 0xf2, 0xe8, 0x36, 0x84,
+        0x7f, 0xde, 0x93, 0xbf, 0xed, 0x08, 0x79, 0xb4, 0x2d, 0xce, 0x6e, 0xc9, 0x57, 0xff, 0x65, 0xef,
+        0xed, 0x88, 0xa9, 0xdf, 0xee, 0x7d, 0x4d, 0xd7, 0x2b, 0xf0, 0x16, 0xe0, 0x2d, 0xc1, 0x5b, 0x81,
+        0xf7, 0x0b, 0xde, 0x1a, 0xbc, 0x0d, 0x78, 0x5b, 0xf0, 0x76, 0xc4, 0x06, 0x82, 0x45, 0x64, 0x89,
+        0xd0, 0x12, 0xb1, 0x25, 0x82, 0x4b, 0x44, 0x97, 0x08, 0x2f, 0x11, 0x5f, 0x22, 0xc0, 0xf4, 0x50,
+        0xd8, 0x09, 0xa7, 0xb5, 0xb4, 0x3c,
+    ];
+    // zlib.compress(b"stored", 0): one stored block.
+    const ZLIB_STORED: &[u8] = &[0x78, 0x01, 0x01, 0x06, 0x00, 0xf9, 0xff, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x64, 0x09, 0x3c, 0x02, 0x92];
+    // Python's gzip of b"hello hello hello hello", with FNAME set to a.txt.
+    const GZIP_NAMED: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x08, 0x00, 0x00, 0x00, 0x00, 0x02, 0xff, 0x61, 0x2e, 0x74, 0x78, 0x74, 0x00,
+        0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0x01, 0xe3, 0x51, 0x3d, 0x8d, 0x17, 0x00,
+        0x00, 0x00,
+    ];
+
+    fn lines() -> Vec<u8> {
+        (0..20).flat_map(|line| format!("line {line} of the fixture, with some repeated words words words\n").into_bytes()).collect()
+    }
+
+    // Bytes that hardly repeat, from a fixed LCG.
+    fn noise(length: usize) -> Vec<u8> {
+        let mut state = 1u32;
+        (0..length)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn samples() -> Vec<Vec<u8>> {
+        vec![vec![], b"a".to_vec(), b"abcabcabcabc".to_vec(), vec![0; 100_000], lines(), noise(70_000), [noise(300), noise(300)].concat()]
+    }
+
+    #[test]
+    fn round_trips() {
+        for sample in samples() {
+            assert_eq!(inflate(&deflate(&sample), usize::MAX).unwrap(), sample);
+            assert_eq!(gunzip(&gzip(&sample), usize::MAX).unwrap(), sample);
+            assert_eq!(inflate(&deflate_window(&sample, 512), usize::MAX).unwrap(), sample);
+        }
+    }
+
+    #[test]
+    fn decodes_zlib_output() {
+        assert_eq!(inflate(ZLIB_FIXED, usize::MAX).unwrap(), b"hello hello hello hello");
+        assert_eq!(inflate(ZLIB_DYNAMIC, usize::MAX).unwrap(), lines());
+        assert_eq!(inflate(ZLIB_STORED, usize::MAX).unwrap(), b"stored");
+        assert_eq!(gunzip(GZIP_NAMED, usize::MAX).unwrap(), b"hello hello hello hello");
+        // Raw DEFLATE, without the zlib header.
+        assert_eq!(inflate(&ZLIB_DYNAMIC[2..], usize::MAX).unwrap(), lines());
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        // The last four bytes of each are the Adler-32, which isn't checked.
+        for fixture in [ZLIB_FIXED, ZLIB_DYNAMIC, ZLIB_STORED] {
+            for end in 0..fixture.len() - 4 {
+                assert!(inflate(&fixture[..end], usize::MAX).is_err(), "{end} bytes");
+            }
+        }
+        for end in 0..GZIP_NAMED.len() {
+            assert!(gunzip(&GZIP_NAMED[..end], usize::MAX).is_err(), "{end} bytes");
+        }
+    }
+
+    #[test]
+    fn corrupt_input_does_not_panic() {
+        for fixture in [ZLIB_FIXED, ZLIB_DYNAMIC, ZLIB_STORED, GZIP_NAMED] {
+            for bit in 0..fixture.len() * 8 {
+                let mut corrupt = fixture.to_vec();
+                corrupt[bit / 8] ^= 1 << (bit % 8);
+                let _ = inflate(&corrupt, 1 << 16);
+                let _ = gunzip(&corrupt, 1 << 16);
+            }
+        }
+        let mut checksum = GZIP_NAMED.to_vec();
+        checksum[26] ^= 1;
+        assert_eq!(gunzip(&checksum, usize::MAX), Err(InflateError::Invalid("gzip checksum mismatch")));
+    }
+
+    #[test]
+    fn rejects_bad_codes() {
+        assert!(Huffman::new(&[1, 1]).is_ok());
+        assert!(Huffman::new(&[1, 1, 1]).is_err());
+        assert!(Huffman::new(&[2, 2, 2, 2, 2]).is_err());
+        // An incomplete code is allowed, as zlib allows one for distances.
+        assert!(Huffman::new(&[0, 1]).is_ok());
+        // Block type 3.
+        assert_eq!(inflate(&[0x07], usize::MAX), Err(InflateError::Invalid("invalid block type")));
+        // A stored block whose length and its complement disagree.
+        assert_eq!(inflate(&[0x01, 0x06, 0x00, 0xf9, 0xfe], usize::MAX), Err(InflateError::Invalid("stored block length mismatch")));
+    }
+
+    #[test]
+    fn stops_at_the_limit() {
+        let text = lines();
+        assert_eq!(inflate(ZLIB_DYNAMIC, text.len()).unwrap(), text);
+        assert_eq!(inflate(ZLIB_DYNAMIC, text.len() - 1), Err(InflateError::TooLarge));
+        assert_eq!(inflate(ZLIB_STORED, 6).unwrap(), b"stored");
+        assert_eq!(inflate(ZLIB_STORED, 5), Err(InflateError::TooLarge));
+        assert_eq!(gunzip(GZIP_NAMED, 22), Err(InflateError::TooLarge));
+        assert_eq!(inflate(&deflate(&vec![0; 1 << 20]), 1000), Err(InflateError::TooLarge));
+        assert_eq!(inflate(&deflate(b""), 0).unwrap(), b"");
+    }
+
+    #[test]
+    fn continues_from_history() {
+        // Non-final stored blocks, as a compressed WebSocket message ends.
+        let history = b"hello ".to_vec();
+        assert_eq!(inflate_continuing(&[0x00, 0x00, 0x00, 0xff, 0xff], &history, 10).unwrap(), b"");
+        assert_eq!(inflate_continuing(&[0x00, 0x01, 0x00, 0xfe, 0xff, b'x'], &history, 1).unwrap(), b"x");
+        assert_eq!(inflate_continuing(&[0x00, 0x01, 0x00, 0xfe, 0xff, b'x'], &history, 0), Err(InflateError::TooLarge));
+        // A fixed block whose match reaches back into the history.
+        let mut writer = BitWriter { output: vec![], buffer: 0, count: 0 };
+        writer.write(1, 1);
+        writer.write(1, 2);
+        write_match(&mut writer, 6, 6);
+        write_literal(&mut writer, 256);
+        let block = writer.finish();
+        assert_eq!(inflate_continuing(&block, &history, 6).unwrap(), b"hello ");
+        assert_eq!(inflate_continuing(&block, b"", 6), Err(InflateError::Invalid("distance before start of data")));
+    }
+}
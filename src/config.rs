@@ -0,0 +1,888 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::{Display, Formatter},
+    fs,
+    str::FromStr,
+    time::Duration,
+};
+use crate::{regex::Regex, rewrite};
+
+// Everything a deployment might want to change without recompiling. Loaded
+// from a small TOML file:
+//
+//     [server]
+//     ip = "0.0.0.0"
+//     port = 8080
+//     workers = 8
+//
+//     [timeouts]
+//     request = 30
+//
+//     [logging]
+//     access_log = true
+//     file = "/var/log/webserve/access.log"
+//
+//     [mirror]
+//     upstream = "http://10.0.0.7:8080"
+//     percent = 5
+//     routes = ["/api"]
+//
+//     [[mount]]
+//     path = "/"
+//     file = "main.html"
+//
+//     [[mount]]
+//     path = "/app"
+//     dir = "dist"
+//     spa = true
+//     cache = "immutable"
+//
+//     [[mount]]
+//     path = "/staff"
+//     dir = "internal"
+//     cache = "no-store"
+//
+//     [mount.auth]
+//     realm = "staff"
+//
+//     [mount.auth.users]
+//     ada = "correct horse"
+//
+//     [[redirect]]
+//     from = "/old-app"
+//     to = "/app"
+//     status = 308
+//
+//     [[host_redirect]]
+//     from = "www.example.com"
+//     to = "https://example.com"
+//
+//     [[rewrite]]
+//     from = "^/blog/(\\d+)$"
+//     to = "/posts/$1"
+//     redirect = 301
+//
+// Durations are given in seconds. Any setting can also be overridden with a
+// WEB_SERVER_* environment variable (see `apply_env`).
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub ip: String,
+    pub port: u32,
+    pub workers: usize,
+    pub max_workers: Option<usize>,
+    pub acceptors: usize,
+    pub worker_idle_timeout: Duration,
+    pub proxy_protocol: bool,
+    pub request_timeout: Option<Duration>,
+    pub max_queued: Option<usize>,
+    pub max_in_flight: Option<usize>,
+    pub retry_after: Duration,
+    pub max_body_size: Option<u64>,
+    pub access_log: bool,
+    // Where the access log goes instead of stdout, and when it rotates.
+    pub access_log_file: Option<String>,
+    pub access_log_max_size: Option<u64>,
+    pub access_log_keep: Option<usize>,
+    pub access_log_json: bool,
+    pub tls: Option<TlsPaths>,
+    pub acme_webroot: Option<String>,
+    pub mirror: Option<MirrorConfig>,
+    pub mounts: Vec<Mount>,
+    pub redirects: Vec<Redirect>,
+    pub host_redirects: Vec<HostRedirect>,
+    pub rewrites: Vec<Rewrite>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TlsPaths {
+    pub cert: String,
+    pub key: String,
+}
+
+// Copies of a share of requests for a second upstream; see `Mirror`.
+#[derive(Clone, Debug)]
+pub struct MirrorConfig {
+    pub upstream: String,
+    pub percent: f64,
+    pub routes: Vec<String>,
+}
+
+// A `[[mount]]`: one file served at exactly `path`, or a directory served
+// under it with the StaticDir options of the same names.
+#[derive(Clone, Debug)]
+pub struct Mount {
+    pub path: String,
+    pub target: MountTarget,
+    pub spa: bool,
+    pub hidden: bool,
+    pub markdown: bool,
+    pub includes: bool,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    // The Cache-Control header for what it serves, from `cache`: a number of
+    // seconds, "immutable", "no-cache" or "no-store".
+    pub cache: Option<String>,
+    pub auth: Option<MountAuth>,
+}
+
+#[derive(Clone, Debug)]
+pub enum MountTarget {
+    File(String),
+    Dir(String),
+}
+
+// Digest authentication in front of a mount; see `DigestAuth`.
+#[derive(Clone, Debug)]
+pub struct MountAuth {
+    pub realm: String,
+    pub users: Vec<(String, String)>,
+}
+
+// A `[[redirect]]` from exactly `from`, or with `prefix = true` from
+// everything under it, keeping the rest of the path. 301 unless `status`
+// says otherwise.
+#[derive(Clone, Debug)]
+pub struct Redirect {
+    pub from: String,
+    pub to: String,
+    pub status: u16,
+    pub prefix: bool,
+}
+
+// A `[[host_redirect]]`, checked before anything else; see
+// `Server::add_host_redirect`. `to` is a host, optionally with a scheme,
+// and `scheme` alone switches the scheme but keeps the host, so
+//
+//     [[host_redirect]]
+//     from = "*"
+//     scheme = "https"
+//
+// sends every plain HTTP request to HTTPS. 308 unless `status` says
+// otherwise.
+#[derive(Clone, Debug)]
+pub struct HostRedirect {
+    pub from: String,
+    pub scheme: Option<String>,
+    pub to: Option<String>,
+    pub status: u16,
+}
+
+// See `Server::add_rewrite` and `Server::add_redirect`.
+#[derive(Clone, Debug)]
+pub struct Rewrite {
+    pub from: String,
+    pub to: String,
+    pub redirect: Option<u16>,
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub(crate) fn new(message: String) -> ConfigError {
+        ConfigError { line: None, message }
+    }
+
+    fn at(line: usize, message: &str) -> ConfigError {
+        ConfigError { line: Some(line), message: message.to_string() }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {line}: {}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            ip: "127.0.0.1".to_string(),
+            port: 7878,
+            workers: 4,
+            max_workers: None,
+            acceptors: 1,
+            worker_idle_timeout: Duration::from_secs(30),
+            proxy_protocol: false,
+            request_timeout: None,
+            max_queued: None,
+            max_in_flight: None,
+            retry_after: Duration::from_secs(1),
+            max_body_size: None,
+            access_log: false,
+            access_log_file: None,
+            access_log_max_size: None,
+            access_log_keep: None,
+            access_log_json: false,
+            tls: None,
+            acme_webroot: None,
+            mirror: None,
+            mounts: vec![],
+            redirects: vec![],
+            host_redirects: vec![],
+            rewrites: vec![],
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path)
+            .map_err(|error| ConfigError::new(format!("Error reading {path}: {error}")))?;
+        Config::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let document = parse_toml(text)?;
+        let mut config = Config::default();
+
+        for (name, value) in &document {
+            match (name.as_str(), value) {
+                ("server", Value::Table(table)) => config.read_server(table)?,
+                ("timeouts", Value::Table(table)) => config.read_timeouts(table)?,
+                ("limits", Value::Table(table)) => config.read_limits(table)?,
+                ("logging", Value::Table(table)) => config.read_logging(table)?,
+                ("tls", Value::Table(table)) => {
+                    config.tls = Some(TlsPaths {
+                        cert: required_string(table, "tls", "cert")?,
+                        key: required_string(table, "tls", "key")?,
+                    });
+                }
+                ("acme", Value::Table(table)) => {
+                    config.acme_webroot = Some(required_string(table, "acme", "webroot")?);
+                    if let Some(key) = table.keys().find(|key| *key != "webroot") {
+                        return Err(unknown_key("acme", key));
+                    }
+                }
+                ("mirror", Value::Table(table)) => config.mirror = Some(read_mirror(table)?),
+                ("mount", Value::Array(mounts)) => {
+                    for mount in mounts {
+                        let Value::Table(table) = mount else {
+                            return Err(ConfigError::new("mount must be [[mount]] tables".to_string()));
+                        };
+                        config.mounts.push(read_mount(table)?);
+                    }
+                }
+                ("redirect", Value::Array(redirects)) => {
+                    for redirect in redirects {
+                        let Value::Table(table) = redirect else {
+                            return Err(ConfigError::new("redirect must be [[redirect]] tables".to_string()));
+                        };
+                        config.redirects.push(read_redirect(table)?);
+                    }
+                }
+                ("host_redirect", Value::Array(rules)) => {
+                    for rule in rules {
+                        let Value::Table(table) = rule else {
+                            return Err(ConfigError::new("host_redirect must be [[host_redirect]] tables".to_string()));
+                        };
+                        config.host_redirects.push(read_host_redirect(table)?);
+                    }
+                }
+                ("rewrite", Value::Array(rules)) => {
+                    for rule in rules {
+                        let Value::Table(table) = rule else {
+                            return Err(ConfigError::new("rewrite must be [[rewrite]] tables".to_string()));
+                        };
+                        config.rewrites.push(read_rewrite(table)?);
+                    }
+                }
+                _ => return Err(ConfigError::new(format!("Unknown setting `{name}`"))),
+            }
+        }
+        Ok(config)
+    }
+
+    // Overrides settings from WEB_SERVER_* environment variables, so the same
+    // file can be reused across containers that differ only in their env:
+    //
+    //     WEB_SERVER_IP, WEB_SERVER_PORT, WEB_SERVER_WORKERS,
+    //     WEB_SERVER_MAX_WORKERS, WEB_SERVER_ACCEPTORS,
+    //     WEB_SERVER_WORKER_IDLE_TIMEOUT, WEB_SERVER_PROXY_PROTOCOL,
+    //     WEB_SERVER_REQUEST_TIMEOUT,
+    //     WEB_SERVER_MAX_QUEUED, WEB_SERVER_MAX_IN_FLIGHT,
+    //     WEB_SERVER_RETRY_AFTER, WEB_SERVER_MAX_BODY_SIZE,
+    //     WEB_SERVER_ACCESS_LOG,
+    //     WEB_SERVER_TLS_CERT, WEB_SERVER_TLS_KEY, WEB_SERVER_ACME_WEBROOT
+    pub fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(ip) = env_var("IP")? {
+            self.ip = ip;
+        }
+        if let Some(port) = env_var("PORT")? {
+            self.port = port;
+        }
+        if let Some(workers) = env_count("WORKERS")? {
+            self.workers = workers;
+        }
+        if let Some(max_workers) = env_count("MAX_WORKERS")? {
+            self.max_workers = Some(max_workers);
+        }
+        if let Some(acceptors) = env_count("ACCEPTORS")? {
+            self.acceptors = acceptors;
+        }
+        if let Some(timeout) = env_duration("WORKER_IDLE_TIMEOUT")? {
+            self.worker_idle_timeout = timeout;
+        }
+        if let Some(proxy_protocol) = env_var("PROXY_PROTOCOL")? {
+            self.proxy_protocol = proxy_protocol;
+        }
+        if let Some(timeout) = env_duration("REQUEST_TIMEOUT")? {
+            self.request_timeout = Some(timeout);
+        }
+        if let Some(max_queued) = env_count("MAX_QUEUED")? {
+            self.max_queued = Some(max_queued);
+        }
+        if let Some(max_in_flight) = env_count("MAX_IN_FLIGHT")? {
+            self.max_in_flight = Some(max_in_flight);
+        }
+        if let Some(delay) = env_duration("RETRY_AFTER")? {
+            self.retry_after = delay;
+        }
+        if let Some(bytes) = env_var("MAX_BODY_SIZE")? {
+            self.max_body_size = Some(bytes);
+        }
+        if let Some(access_log) = env_var("ACCESS_LOG")? {
+            self.access_log = access_log;
+        }
+        if let Some(path) = env_var("ACCESS_LOG_FILE")? {
+            self.access_log_file = Some(path);
+        }
+        if let Some(webroot) = env_var("ACME_WEBROOT")? {
+            self.acme_webroot = Some(webroot);
+        }
+
+        let cert = env_var::<String>("TLS_CERT")?;
+        let key = env_var::<String>("TLS_KEY")?;
+        if cert.is_some() || key.is_some() {
+            let current = self.tls.take();
+            let cert = cert.or_else(|| current.as_ref().map(|tls| tls.cert.clone()));
+            let key = key.or_else(|| current.as_ref().map(|tls| tls.key.clone()));
+            match (cert, key) {
+                (Some(cert), Some(key)) => self.tls = Some(TlsPaths { cert, key }),
+                _ => return Err(ConfigError::new("WEB_SERVER_TLS_CERT and WEB_SERVER_TLS_KEY must be set together".to_string())),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_server(&mut self, table: &Table) -> Result<(), ConfigError> {
+        for (key, value) in table {
+            match key.as_str() {
+                "ip" => self.ip = value.as_string("server.ip")?,
+                "port" => self.port = value.as_integer::<u32>("server.port")?,
+                "workers" => self.workers = value.as_count("server.workers")?,
+                "max_workers" => self.max_workers = Some(value.as_count("server.max_workers")?),
+                "acceptors" => self.acceptors = value.as_count("server.acceptors")?,
+                "worker_idle_timeout" => self.worker_idle_timeout = value.as_duration("server.worker_idle_timeout")?,
+                "proxy_protocol" => self.proxy_protocol = value.as_bool("server.proxy_protocol")?,
+                _ => return Err(unknown_key("server", key)),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_timeouts(&mut self, table: &Table) -> Result<(), ConfigError> {
+        for (key, value) in table {
+            match key.as_str() {
+                "request" => self.request_timeout = Some(value.as_duration("timeouts.request")?),
+                _ => return Err(unknown_key("timeouts", key)),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_limits(&mut self, table: &Table) -> Result<(), ConfigError> {
+        for (key, value) in table {
+            match key.as_str() {
+                "max_queued" => self.max_queued = Some(value.as_count("limits.max_queued")?),
+                "max_in_flight" => self.max_in_flight = Some(value.as_count("limits.max_in_flight")?),
+                "retry_after" => self.retry_after = value.as_duration("limits.retry_after")?,
+                "max_body_size" => self.max_body_size = Some(value.as_count("limits.max_body_size")? as u64),
+                _ => return Err(unknown_key("limits", key)),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_logging(&mut self, table: &Table) -> Result<(), ConfigError> {
+        for (key, value) in table {
+            match key.as_str() {
+                "access_log" => self.access_log = value.as_bool("logging.access_log")?,
+                "file" => self.access_log_file = Some(value.as_string("logging.file")?),
+                "max_size" => self.access_log_max_size = Some(value.as_count("logging.max_size")? as u64),
+                "keep" => self.access_log_keep = Some(value.as_count("logging.keep")?),
+                "format" => self.access_log_json = match value.as_string("logging.format")?.as_str() {
+                    "text" => false,
+                    "json" => true,
+                    other => return Err(ConfigError::new(format!("Unknown logging.format {other:?}; use \"text\" or \"json\""))),
+                },
+                _ => return Err(unknown_key("logging", key)),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_mirror(table: &Table) -> Result<MirrorConfig, ConfigError> {
+    let mut mirror = MirrorConfig { upstream: required_string(table, "mirror", "upstream")?, percent: 100.0, routes: vec![] };
+    for (key, value) in table {
+        match key.as_str() {
+            "upstream" => {}
+            "percent" => mirror.percent = match value {
+                Value::Integer(percent) if (0..=100).contains(percent) => *percent as f64,
+                Value::Float(percent) if (0.0..=100.0).contains(percent) => *percent,
+                _ => return Err(value.type_error("mirror.percent", "a percentage")),
+            },
+            "routes" => mirror.routes = value.as_strings("mirror.routes")?,
+            _ => return Err(unknown_key("mirror", key)),
+        }
+    }
+    if !mirror.upstream.starts_with("http://") {
+        return Err(ConfigError::new(format!("mirror.upstream `{}` must be an http:// URL", mirror.upstream)));
+    }
+    Ok(mirror)
+}
+
+fn read_mount(table: &Table) -> Result<Mount, ConfigError> {
+    let path = required_string(table, "mount", "path")?;
+    let target = match (table.get("file"), table.get("dir")) {
+        (Some(file), None) => MountTarget::File(file.as_string("mount.file")?),
+        (None, Some(dir)) => MountTarget::Dir(dir.as_string("mount.dir")?),
+        _ => return Err(ConfigError::new(format!("[[mount]] `{path}` needs one of `file` or `dir`"))),
+    };
+    let mut mount = Mount {
+        path,
+        target,
+        spa: false,
+        hidden: false,
+        markdown: false,
+        includes: false,
+        allow: vec![],
+        deny: vec![],
+        cache: None,
+        auth: None,
+    };
+    for (key, value) in table {
+        match key.as_str() {
+            "path" | "file" | "dir" => {}
+            "spa" => mount.spa = value.as_bool("mount.spa")?,
+            "hidden" => mount.hidden = value.as_bool("mount.hidden")?,
+            "markdown" => mount.markdown = value.as_bool("mount.markdown")?,
+            "includes" => mount.includes = value.as_bool("mount.includes")?,
+            "allow" => mount.allow = value.as_strings("mount.allow")?,
+            "deny" => mount.deny = value.as_strings("mount.deny")?,
+            "cache" => mount.cache = Some(cache_control(value)?),
+            "auth" => mount.auth = Some(read_auth(value)?),
+            _ => return Err(unknown_key("mount", key)),
+        }
+    }
+    if matches!(mount.target, MountTarget::File(_)) {
+        let dir_only = ["spa", "hidden", "markdown", "includes", "allow", "deny"];
+        if let Some(key) = dir_only.iter().find(|key| table.contains_key(**key)) {
+            return Err(ConfigError::new(format!("[[mount]] `{}`: `{key}` only applies to a `dir`", mount.path)));
+        }
+    }
+    Ok(mount)
+}
+
+fn cache_control(value: &Value) -> Result<String, ConfigError> {
+    match value {
+        Value::Integer(seconds) if *seconds >= 0 => Ok(format!("public, max-age={seconds}")),
+        Value::String(policy) => match policy.as_str() {
+            "immutable" => Ok("public, max-age=31536000, immutable".to_string()),
+            "no-cache" | "no-store" => Ok(policy.clone()),
+            _ => Err(ConfigError::new(format!("Unknown mount.cache {policy:?}; use seconds, \"immutable\", \"no-cache\" or \"no-store\""))),
+        },
+        _ => Err(value.type_error("mount.cache", "a number of seconds or a policy name")),
+    }
+}
+
+fn read_auth(value: &Value) -> Result<MountAuth, ConfigError> {
+    let Value::Table(table) = value else {
+        return Err(value.type_error("mount.auth", "a [mount.auth] table"));
+    };
+    let mut auth = MountAuth { realm: required_string(table, "mount.auth", "realm")?, users: vec![] };
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("realm", _) => {}
+            ("users", Value::Table(users)) => {
+                for (user, password) in users {
+                    auth.users.push((user.clone(), password.as_string(&format!("mount.auth.users.{user}"))?));
+                }
+            }
+            ("users", _) => return Err(value.type_error("mount.auth.users", "a [mount.auth.users] table")),
+            _ => return Err(unknown_key("mount.auth", key)),
+        }
+    }
+    if auth.users.is_empty() {
+        return Err(ConfigError::new(format!("[mount.auth] for realm {:?} has no users", auth.realm)));
+    }
+    Ok(auth)
+}
+
+fn read_redirect(table: &Table) -> Result<Redirect, ConfigError> {
+    let redirect = Redirect {
+        from: required_string(table, "redirect", "from")?,
+        to: required_string(table, "redirect", "to")?,
+        status: match table.get("status") {
+            Some(status) => status.as_integer::<u16>("redirect.status")?,
+            None => 301,
+        },
+        prefix: match table.get("prefix") {
+            Some(prefix) => prefix.as_bool("redirect.prefix")?,
+            None => false,
+        },
+    };
+    if let Some(key) = table.keys().find(|key| !["from", "to", "status", "prefix"].contains(&key.as_str())) {
+        return Err(unknown_key("redirect", key));
+    }
+    if rewrite::redirect_status(redirect.status).is_none() {
+        return Err(ConfigError::new(format!("Invalid redirect status for `{}`; use 301, 302, 307 or 308", redirect.from)));
+    }
+    Ok(redirect)
+}
+
+fn read_host_redirect(table: &Table) -> Result<HostRedirect, ConfigError> {
+    let from = required_string(table, "host_redirect", "from")?;
+    let (mut scheme, to) = match table.get("to") {
+        Some(to) => {
+            let to = to.as_string("host_redirect.to")?;
+            let (scheme, host) = rewrite::split_target(&to);
+            (scheme.map(str::to_string), Some(host.to_string()))
+        }
+        None => (None, None),
+    };
+    if let Some(value) = table.get("scheme") {
+        if scheme.is_some() {
+            return Err(ConfigError::new(format!("[[host_redirect]] `{from}` gives a scheme in both `to` and `scheme`")));
+        }
+        scheme = Some(value.as_string("host_redirect.scheme")?);
+    }
+    if scheme.as_deref().is_some_and(|scheme| !["http", "https"].contains(&scheme)) {
+        return Err(ConfigError::new(format!("[[host_redirect]] `{from}`: the scheme must be http or https")));
+    }
+    if scheme.is_none() && to.is_none() {
+        return Err(ConfigError::new(format!("[[host_redirect]] `{from}` needs `to`, `scheme` or both")));
+    }
+    let status = match table.get("status") {
+        Some(status) => status.as_integer::<u16>("host_redirect.status")?,
+        None => 308,
+    };
+    if let Some(key) = table.keys().find(|key| !["from", "to", "scheme", "status"].contains(&key.as_str())) {
+        return Err(unknown_key("host_redirect", key));
+    }
+    if rewrite::redirect_status(status).is_none() {
+        return Err(ConfigError::new(format!("Invalid redirect status for `{from}`; use 301, 302, 307 or 308")));
+    }
+    Ok(HostRedirect { from, scheme, to, status })
+}
+
+fn read_rewrite(table: &Table) -> Result<Rewrite, ConfigError> {
+    let rewrite = Rewrite {
+        from: required_string(table, "rewrite", "from")?,
+        to: required_string(table, "rewrite", "to")?,
+        redirect: match table.get("redirect") {
+            Some(status) => Some(status.as_integer::<u16>("rewrite.redirect")?),
+            None => None,
+        },
+    };
+    if let Some(key) = table.keys().find(|key| !["from", "to", "redirect"].contains(&key.as_str())) {
+        return Err(unknown_key("rewrite", key));
+    }
+    // Check now rather than when the server is built.
+    if let Err(error) = Regex::new(&rewrite.from) {
+        return Err(ConfigError::new(format!("Invalid rewrite `{}`: {error}", rewrite.from)));
+    }
+    if rewrite.redirect.is_some_and(|status| rewrite::redirect_status(status).is_none()) {
+        return Err(ConfigError::new(format!("Invalid redirect status for `{}`; use 301, 302, 307 or 308", rewrite.from)));
+    }
+    Ok(rewrite)
+}
+
+// Reads WEB_SERVER_<name>, if set.
+pub(crate) fn env_var<T: FromStr>(name: &str) -> Result<Option<T>, ConfigError> {
+    let variable = format!("WEB_SERVER_{name}");
+    match env::var(&variable) {
+        Ok(value) => value.trim().parse().map(Some).map_err(|_| {
+            ConfigError::new(format!("Invalid value `{value}` for {variable}"))
+        }),
+        Err(_) => Ok(None),
+    }
+}
+
+pub(crate) fn env_count(name: &str) -> Result<Option<usize>, ConfigError> {
+    match env_var::<usize>(name)? {
+        Some(0) => Err(ConfigError::new(format!("WEB_SERVER_{name} must be at least 1"))),
+        count => Ok(count),
+    }
+}
+
+pub(crate) fn env_duration(name: &str) -> Result<Option<Duration>, ConfigError> {
+    match env_var::<f64>(name)? {
+        Some(seconds) if seconds < 0.0 => Err(ConfigError::new(format!("WEB_SERVER_{name} can't be negative"))),
+        Some(seconds) => Duration::try_from_secs_f64(seconds)
+            .map(Some)
+            .map_err(|_| ConfigError::new(format!("WEB_SERVER_{name} is out of range"))),
+        None => Ok(None),
+    }
+}
+
+fn unknown_key(section: &str, key: &str) -> ConfigError {
+    ConfigError::new(format!("Unknown key `{key}` in [{section}]"))
+}
+
+fn required_string(table: &Table, section: &str, key: &str) -> Result<String, ConfigError> {
+    match table.get(key) {
+        Some(value) => value.as_string(&format!("{section}.{key}")),
+        None => Err(ConfigError::new(format!("[{section}] is missing `{key}`"))),
+    }
+}
+
+// Just enough TOML for configuration files: tables, arrays of tables, and
+// string/integer/float/boolean/array values.
+type Table = BTreeMap<String, Value>;
+
+#[derive(Clone, Debug)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl Value {
+    fn type_error(&self, key: &str, expected: &str) -> ConfigError {
+        ConfigError::new(format!("`{key}` should be {expected}, found {self:?}"))
+    }
+
+    fn as_string(&self, key: &str) -> Result<String, ConfigError> {
+        match self {
+            Value::String(string) => Ok(string.clone()),
+            _ => Err(self.type_error(key, "a string")),
+        }
+    }
+
+    // An integer that has to fit in `T`, so a status of 65837 or a negative
+    // port is an error rather than wrapping around to something else.
+    fn as_integer<T: TryFrom<i64>>(&self, key: &str) -> Result<T, ConfigError> {
+        match self {
+            Value::Integer(integer) => T::try_from(*integer)
+                .map_err(|_| self.type_error(key, &format!("an integer that fits in a {}", std::any::type_name::<T>()))),
+            _ => Err(self.type_error(key, "an integer")),
+        }
+    }
+
+    fn as_count(&self, key: &str) -> Result<usize, ConfigError> {
+        match self {
+            Value::Integer(integer) if *integer > 0 => Ok(*integer as usize),
+            _ => Err(self.type_error(key, "a positive integer")),
+        }
+    }
+
+    fn as_strings(&self, key: &str) -> Result<Vec<String>, ConfigError> {
+        match self {
+            Value::Array(values) => values.iter().map(|value| value.as_string(key)).collect(),
+            _ => Err(self.type_error(key, "an array of strings")),
+        }
+    }
+
+    fn as_bool(&self, key: &str) -> Result<bool, ConfigError> {
+        match self {
+            Value::Boolean(boolean) => Ok(*boolean),
+            _ => Err(self.type_error(key, "true or false")),
+        }
+    }
+
+    fn as_duration(&self, key: &str) -> Result<Duration, ConfigError> {
+        match self {
+            Value::Integer(seconds) if *seconds >= 0 => Ok(Duration::from_secs(*seconds as u64)),
+            Value::Float(seconds) if *seconds >= 0.0 => {
+                Duration::try_from_secs_f64(*seconds).map_err(|_| self.type_error(key, "a number of seconds"))
+            }
+            _ => Err(self.type_error(key, "a number of seconds")),
+        }
+    }
+}
+
+fn parse_toml(text: &str) -> Result<Table, ConfigError> {
+    let mut document = Table::new();
+    // The path of the table that keys currently go into, and whether it's
+    // the last element of an array of tables.
+    let mut current: Vec<String> = vec![];
+    let mut in_array = false;
+
+    let mut lines = text.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let number = index + 1;
+        let mut line = strip_comment(line).trim().to_string();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix("[[").and_then(|rest| rest.strip_suffix("]]")) {
+            current = header.split('.').map(|part| part.trim().to_string()).collect();
+            in_array = true;
+            let (last, parents) = current.split_last().unwrap();
+            let parent = table_at(&mut document, parents, number)?;
+            match parent.entry(last.clone()).or_insert_with(|| Value::Array(vec![])) {
+                Value::Array(array) => array.push(Value::Table(Table::new())),
+                _ => return Err(ConfigError::at(number, "Key redefined as an array of tables")),
+            }
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+            current = header.split('.').map(|part| part.trim().to_string()).collect();
+            in_array = false;
+            table_at(&mut document, &current, number)?;
+            continue;
+        }
+
+        // Arrays may span several lines; keep reading until the brackets close.
+        while bracket_depth(&line) > 0 {
+            match lines.next() {
+                Some((_, next)) => {
+                    line.push(' ');
+                    line.push_str(strip_comment(next).trim());
+                }
+                None => return Err(ConfigError::at(number, "Unclosed array")),
+            }
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(ConfigError::at(number, "Expected `key = value`"));
+        };
+        let key = key.trim().trim_matches('"').to_string();
+        let (value, rest) = parse_value(value.trim()).map_err(|message| ConfigError::at(number, &message))?;
+        if !rest.trim().is_empty() {
+            return Err(ConfigError::at(number, "Unexpected text after value"));
+        }
+
+        let table = if in_array {
+            let (last, parents) = current.split_last().unwrap();
+            match table_at(&mut document, parents, number)?.get_mut(last) {
+                Some(Value::Array(array)) => match array.last_mut() {
+                    Some(Value::Table(table)) => table,
+                    _ => unreachable!("arrays of tables only hold tables"),
+                },
+                _ => unreachable!("the array was created by its header"),
+            }
+        } else {
+            table_at(&mut document, &current, number)?
+        };
+        if table.insert(key, value).is_some() {
+            return Err(ConfigError::at(number, "Duplicate key"));
+        }
+    }
+    Ok(document)
+}
+
+fn table_at<'table>(mut table: &'table mut Table, path: &[String], line: usize) -> Result<&'table mut Table, ConfigError> {
+    for part in path {
+        table = match table.entry(part.clone()).or_insert_with(|| Value::Table(Table::new())) {
+            Value::Table(table) => table,
+            // A dotted header into an array of tables means its last entry.
+            Value::Array(array) => match array.last_mut() {
+                Some(Value::Table(table)) => table,
+                _ => return Err(ConfigError::at(line, "Not a table")),
+            },
+            _ => return Err(ConfigError::at(line, "Not a table")),
+        };
+    }
+    Ok(table)
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = None;
+    for (index, character) in line.char_indices() {
+        match (character, in_string) {
+            ('"' | '\'', None) => in_string = Some(character),
+            (quote, Some(open)) if quote == open => in_string = None,
+            ('#', None) => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn bracket_depth(line: &str) -> i32 {
+    let mut depth = 0;
+    let mut in_string = None;
+    let value = line.split_once('=').map_or(line, |(_, value)| value);
+    for character in value.chars() {
+        match (character, in_string) {
+            ('"' | '\'', None) => in_string = Some(character),
+            (quote, Some(open)) if quote == open => in_string = None,
+            ('[', None) => depth += 1,
+            (']', None) => depth -= 1,
+            _ => {}
+        }
+    }
+    depth
+}
+
+// Parses one value from the start of `text`, returning it and whatever follows.
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut string = String::new();
+        let mut characters = rest.char_indices();
+        while let Some((index, character)) = characters.next() {
+            match character {
+                '"' => return Ok((Value::String(string), &rest[index + 1..])),
+                '\\' => match characters.next() {
+                    Some((_, 'n')) => string.push('\n'),
+                    Some((_, 't')) => string.push('\t'),
+                    Some((_, 'r')) => string.push('\r'),
+                    Some((_, escaped @ ('"' | '\\'))) => string.push(escaped),
+                    _ => return Err("Unsupported escape sequence".to_string()),
+                },
+                _ => string.push(character),
+            }
+        }
+        return Err("Unterminated string".to_string());
+    }
+    if let Some(rest) = text.strip_prefix('\'') {
+        let end = rest.find('\'').ok_or("Unterminated string")?;
+        return Ok((Value::String(rest[..end].to_string()), &rest[end + 1..]));
+    }
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut values = vec![];
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(values), after));
+            }
+            let (value, after) = parse_value(rest)?;
+            values.push(value);
+            rest = after.trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest);
+        }
+    }
+
+    let end = text.find(|character: char| character == ',' || character == ']' || character.is_whitespace())
+        .unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    let value = match word {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => {
+            let number = word.replace('_', "");
+            if let Ok(integer) = number.parse() {
+                Value::Integer(integer)
+            } else if let Ok(float) = number.parse() {
+                Value::Float(float)
+            } else {
+                return Err(format!("Invalid value `{word}`"));
+            }
+        }
+    };
+    Ok((value, rest))
+}
@@ -0,0 +1,98 @@
+use std::sync::Arc;
+use crate::{
+    http::{self, Request, Response, StatusCode},
+    server::Handler,
+};
+
+// Several handlers for one path, picked by the body's Content-Type or by
+// what the client's Accept header asks for, for `Server::add_dispatch`:
+//
+//     server.add_dispatch("/comments", Dispatch::new()
+//         .content_type("application/json", post_json)
+//         .content_type("application/x-www-form-urlencoded", post_form)
+//         .accept("text/html", comments_page)
+//         .accept("application/json", comments_json));
+//
+// A request with a body goes by its Content-Type, and gets a 415 if none
+// of the handlers takes it; anything else goes by Accept, with a 406 if
+// nothing registered is acceptable. Content types may be ranges like
+// "text/*".
+pub struct Dispatch {
+    content_types: Vec<(String, Handler)>,
+    accepts: Vec<(String, Handler)>,
+}
+
+impl Dispatch {
+    pub fn new() -> Dispatch {
+        Dispatch { content_types: vec![], accepts: vec![] }
+    }
+
+    // Handles bodies of type `media`, like "application/json".
+    pub fn content_type<F>(mut self, media: &str, handler: F) -> Dispatch
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.content_types.push((media.to_ascii_lowercase(), Arc::new(handler)));
+        self
+    }
+
+    // Handles requests that accept `media`. When several are acceptable, the
+    // client's preference decides, then the order they were added in.
+    pub fn accept<F>(mut self, media: &str, handler: F) -> Dispatch
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.accepts.push((media.to_ascii_lowercase(), Arc::new(handler)));
+        self
+    }
+
+    pub(crate) fn respond(&self, request: &Request) -> Response {
+        let has_body = request.header("Content-Type").is_some() || request.content_length().is_some_and(|length| length > 0);
+        if has_body && !self.content_types.is_empty() || self.accepts.is_empty() {
+            let essence = http::media_type(request.header("Content-Type").unwrap_or_default());
+            let handler = self.content_types.iter().find(|(media, _)| covers(media, &essence));
+            return match handler {
+                Some((_, handler)) => handler(request),
+                None => {
+                    let types: Vec<&str> = self.content_types.iter().map(|(media, _)| media.as_str()).collect();
+                    Response::new(StatusCode::UnsupportedMediaType, "Unsupported Media Type")
+                        .with_header("Accept-Post", &types.join(", "))
+                }
+            };
+        }
+        let accept = request.header("Accept").unwrap_or("*/*");
+        let mut best: Option<(f64, &Handler)> = None;
+        for (media, handler) in &self.accepts {
+            let quality = http::accept_quality(accept, media);
+            if quality > 0.0 && best.is_none_or(|(most, _)| quality > most) {
+                best = Some((quality, handler));
+            }
+        }
+        match best {
+            Some((_, handler)) => {
+                let mut response = handler(request);
+                if response.header("Vary").is_none() {
+                    response.set_header("Vary", "Accept");
+                }
+                response
+            }
+            None => Response::new(StatusCode::NotAcceptable, "Not Acceptable"),
+        }
+    }
+}
+
+impl Default for Dispatch {
+    fn default() -> Dispatch {
+        Dispatch::new()
+    }
+}
+
+// Whether the registered `range` ("text/plain", "text/*" or "*/*") takes a
+// body of type `media`.
+fn covers(range: &str, media: &str) -> bool {
+    match range.strip_suffix("/*") {
+        Some("*") => true,
+        Some(kind) => media.split('/').next() == Some(kind),
+        None => range == media,
+    }
+}
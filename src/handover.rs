@@ -0,0 +1,126 @@
+// Passes listening sockets from a running server to its replacement, so a
+// new binary can take over without the port ever closing. The old process
+// listens on a Unix socket; the new one connects, receives the listeners as
+// SCM_RIGHTS ancillary data, and the old one drains and exits.
+use std::{
+    ffi::c_void,
+    io,
+    mem,
+    net::TcpListener,
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        raw::c_int,
+        unix::net::UnixStream,
+    },
+    ptr,
+};
+
+const SOL_SOCKET: c_int = 1;
+const SCM_RIGHTS: c_int = 1;
+const MSG_CMSG_CLOEXEC: c_int = 0x4000_0000;
+// More listeners than anyone runs acceptors.
+const MAX_FDS: usize = 64;
+
+#[repr(C)]
+struct IoVec {
+    base: *mut c_void,
+    len: usize,
+}
+
+#[repr(C)]
+struct MsgHdr {
+    name: *mut c_void,
+    name_len: u32,
+    iov: *mut IoVec,
+    iov_len: usize,
+    control: *mut c_void,
+    control_len: usize,
+    flags: c_int,
+}
+
+#[repr(C)]
+struct CmsgHdr {
+    len: usize,
+    level: c_int,
+    kind: c_int,
+}
+
+extern "C" {
+    fn sendmsg(fd: c_int, message: *const MsgHdr, flags: c_int) -> isize;
+    fn recvmsg(fd: c_int, message: *mut MsgHdr, flags: c_int) -> isize;
+}
+
+// Room for a header followed by `MAX_FDS` descriptors, kept 8-byte aligned
+// the way CMSG_SPACE would.
+#[repr(C, align(8))]
+struct Control([u8; mem::size_of::<CmsgHdr>() + MAX_FDS * mem::size_of::<c_int>()]);
+
+pub fn send(stream: &UnixStream, listeners: &[TcpListener]) -> io::Result<()> {
+    if listeners.len() > MAX_FDS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Too many listeners to hand over"));
+    }
+    let fds: Vec<c_int> = listeners.iter().map(|listener| listener.as_raw_fd()).collect();
+    let data_len = mem::size_of_val(fds.as_slice());
+
+    let mut control = Control([0; mem::size_of::<Control>()]);
+    let header = CmsgHdr { len: mem::size_of::<CmsgHdr>() + data_len, level: SOL_SOCKET, kind: SCM_RIGHTS };
+    unsafe {
+        ptr::write_unaligned(control.0.as_mut_ptr() as *mut CmsgHdr, header);
+        let data = control.0.as_mut_ptr().add(mem::size_of::<CmsgHdr>());
+        ptr::copy_nonoverlapping(fds.as_ptr() as *const u8, data, data_len);
+    }
+
+    // At least one byte of real data has to go along with the descriptors.
+    let mut count = [fds.len() as u8];
+    let mut iov = IoVec { base: count.as_mut_ptr() as *mut c_void, len: 1 };
+    let message = MsgHdr {
+        name: ptr::null_mut(),
+        name_len: 0,
+        iov: &mut iov,
+        iov_len: 1,
+        control: control.0.as_mut_ptr() as *mut c_void,
+        // Padded up to a multiple of 8, like CMSG_SPACE.
+        control_len: (mem::size_of::<CmsgHdr>() + data_len).next_multiple_of(8),
+        flags: 0,
+    };
+    if unsafe { sendmsg(stream.as_raw_fd(), &message, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn receive(stream: &UnixStream) -> io::Result<Vec<TcpListener>> {
+    let mut control = Control([0; mem::size_of::<Control>()]);
+    let mut count = [0u8];
+    let mut iov = IoVec { base: count.as_mut_ptr() as *mut c_void, len: 1 };
+    let mut message = MsgHdr {
+        name: ptr::null_mut(),
+        name_len: 0,
+        iov: &mut iov,
+        iov_len: 1,
+        control: control.0.as_mut_ptr() as *mut c_void,
+        control_len: mem::size_of::<Control>(),
+        flags: 0,
+    };
+    let read = unsafe { recvmsg(stream.as_raw_fd(), &mut message, MSG_CMSG_CLOEXEC) };
+    if read < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if read == 0 || message.control_len < mem::size_of::<CmsgHdr>() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "No sockets were handed over"));
+    }
+
+    let header: CmsgHdr = unsafe { ptr::read_unaligned(control.0.as_ptr() as *const CmsgHdr) };
+    if header.level != SOL_SOCKET || header.kind != SCM_RIGHTS {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected control message"));
+    }
+    let fds = (header.len - mem::size_of::<CmsgHdr>()) / mem::size_of::<c_int>();
+    let listeners = (0..fds)
+        .map(|index| {
+            let offset = mem::size_of::<CmsgHdr>() + index * mem::size_of::<c_int>();
+            let fd = unsafe { ptr::read_unaligned(control.0.as_ptr().add(offset) as *const c_int) };
+            unsafe { TcpListener::from_raw_fd(fd) }
+        })
+        .collect();
+    Ok(listeners)
+}
@@ -0,0 +1,708 @@
+use std::{
+    fmt::{Display, Formatter},
+    fs,
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::Utf8Error,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use crate::{
+    body::{BodyFile, BodyReader},
+    clock::Clock,
+    json::{self, Value},
+    msgpack,
+    server::Connection,
+    session::Session,
+    static_files::{content_type, percent_decode},
+    trace::TraceContext,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HttpMethod {
+    GET,
+    HEAD,
+    POST,
+    PUT,
+    DELETE,
+    PATCH,
+    OPTIONS,
+    // WebDAV's, for `Server::add_webdav`.
+    PROPFIND,
+    MKCOL,
+    COPY,
+    MOVE,
+}
+
+pub struct Request {
+    pub method: HttpMethod,
+    pub path: String,
+    pub protocol: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    // The body as read, when it isn't UTF-8 and `body` had to replace bits.
+    pub(crate) raw_body: Option<Vec<u8>>,
+    // Likewise the values of any headers that weren't UTF-8.
+    pub(crate) raw_headers: Vec<(String, Vec<u8>)>,
+    pub(crate) deadline: Option<Instant>,
+    // What the deadline goes by, from the Timeout that set it.
+    pub(crate) clock: Option<Arc<dyn Clock>>,
+    pub(crate) connection: Option<Arc<Connection>>,
+    pub(crate) client_addr: Option<SocketAddr>,
+    pub(crate) params: Vec<(String, String)>,
+    pub(crate) route: Option<String>,
+    pub(crate) body_stream: Mutex<Option<BodyReader>>,
+    pub(crate) body_file: Option<BodyFile>,
+    // (form field, token), set by the Csrf middleware.
+    pub(crate) csrf: Option<(String, String)>,
+    pub(crate) session: Option<Session>,
+    pub(crate) principal: Option<String>,
+    pub(crate) geo: Option<GeoInfo>,
+    // The request-target as the client sent it, before any rewrite of `path`.
+    pub(crate) target: String,
+}
+
+impl Request {
+    pub fn new(method: HttpMethod, path: &str) -> Request {
+        Request {
+            method,
+            path: path.to_string(),
+            protocol: "HTTP/1.1".to_string(),
+            headers: vec![],
+            body: String::new(),
+            raw_body: None,
+            raw_headers: vec![],
+            deadline: None,
+            clock: None,
+            connection: None,
+            client_addr: None,
+            params: vec![],
+            route: None,
+            body_stream: Mutex::new(None),
+            body_file: None,
+            csrf: None,
+            session: None,
+            principal: None,
+            geo: None,
+            target: path.to_string(),
+        }
+    }
+
+    // Header names are case-insensitive, so lookups are too. Values that
+    // weren't UTF-8 have the odd bytes replaced; see `header_bytes`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // A header's value exactly as sent.
+    pub fn header_bytes(&self, name: &str) -> Option<&[u8]> {
+        let raw = self.raw_headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name));
+        match raw {
+            Some((_, value)) => Some(value),
+            None => self.header(name).map(str::as_bytes),
+        }
+    }
+
+    // A header's value, or an error if it wasn't UTF-8.
+    pub fn header_utf8(&self, name: &str) -> Result<Option<&str>, Utf8Error> {
+        match self.raw_headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)) {
+            Some((_, value)) => std::str::from_utf8(value).map(Some),
+            None => Ok(self.header(name)),
+        }
+    }
+
+    // A header's value read as ISO-8859-1, which is what older clients
+    // meant by bytes past ASCII.
+    pub fn header_latin1(&self, name: &str) -> Option<String> {
+        self.header_bytes(name).map(|value| value.iter().map(|byte| *byte as char).collect())
+    }
+
+    // A query string parameter, decoded: `page` in "/list?page=2".
+    pub fn query(&self, name: &str) -> Option<String> {
+        let (_, query) = self.path.split_once('?')?;
+        query.split('&')
+            .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+            .find(|(key, _)| percent_decode(&key.replace('+', " ")) == name)
+            .map(|(_, value)| percent_decode(&value.replace('+', " ")))
+    }
+
+    // A cookie the client sent, by name.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case("Cookie"))
+            .flat_map(|(_, value)| value.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(cookie, _)| *cookie == name)
+            .map(|(_, value)| value.trim_matches('"'))
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.header("Content-Length").and_then(|length| length.trim().parse().ok())
+    }
+
+    // The body exactly as sent; `body` has anything that isn't UTF-8
+    // replaced, so read binary bodies from here.
+    pub fn body_bytes(&self) -> &[u8] {
+        self.raw_body.as_deref().unwrap_or(self.body.as_bytes())
+    }
+
+    // The body, or an error if it wasn't UTF-8.
+    pub fn body_utf8(&self) -> Result<&str, Utf8Error> {
+        match &self.raw_body {
+            Some(raw) => std::str::from_utf8(raw),
+            None => Ok(&self.body),
+        }
+    }
+
+    // The body as a JSON or MessagePack document, going by Content-Type, or
+    // JSON when there's none.
+    pub fn body_value(&self) -> Result<Value, String> {
+        let essence = media_type(self.header("Content-Type").unwrap_or("application/json"));
+        match essence.as_str() {
+            essence if MSGPACK_TYPES.contains(&essence) => msgpack::decode(self.body_bytes()).map_err(|error| error.to_string()),
+            "application/json" => json::parse(&self.body).map_err(|error| error.to_string()),
+            essence if essence.ends_with("+json") => json::parse(&self.body).map_err(|error| error.to_string()),
+            _ => Err(format!("Can't read a {essence} body")),
+        }
+    }
+
+    // On routes registered with `.stream_body(..)`, the body to read as it
+    // arrives; `body` is left empty. Can only be taken once.
+    pub fn body_reader(&self) -> Option<BodyReader> {
+        self.body_stream.lock().unwrap().take()
+    }
+
+    // When the server spills big bodies to disk (see
+    // `ServerBuilder::spill_bodies_over`), where this one went; `body` is
+    // left empty.
+    pub fn body_file(&self) -> Option<&BodyFile> {
+        self.body_file.as_ref()
+    }
+
+    // A value captured by the route that matched, like `name` in
+    // "/files/{name}", or "1" for the first group of a regex route.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(param, _)| param == name).map(|(_, value)| value.as_str())
+    }
+
+    // What the route that matched was registered as, like "/users/{id}",
+    // or None if no route did.
+    pub fn route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
+
+    // The path and query as the client sent them. Unlike `path`, rewrite
+    // rules leave this alone, so it's what signatures over the URI cover.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    // Who sent the request: the peer's address, or the client's as reported
+    // by the load balancer when the PROXY protocol is enabled.
+    pub fn client_addr(&self) -> Option<SocketAddr> {
+        self.client_addr
+    }
+
+    // The distributed trace this request is part of, from its traceparent
+    // and tracestate headers.
+    pub fn trace_context(&self) -> Option<TraceContext> {
+        TraceContext::from_request(self)
+    }
+
+    // Where the client is, as far as the GeoBlock middleware's resolver
+    // could tell.
+    pub fn geo(&self) -> Option<&GeoInfo> {
+        self.geo.as_ref()
+    }
+
+    // Who the client authenticated as, once an auth middleware has said so.
+    pub fn principal(&self) -> Option<&str> {
+        self.principal.as_deref()
+    }
+
+    // For auth middleware: records who the client proved to be, for guards
+    // and handlers further down the chain.
+    pub fn set_principal(&mut self, principal: &str) {
+        self.principal = Some(principal.to_string());
+    }
+
+    // The client's session, behind the Sessions middleware.
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    // The anti-forgery token, behind the Csrf middleware, for pages that
+    // submit state changes from script (in an X-CSRF-Token header).
+    pub fn csrf_token(&self) -> Option<&str> {
+        self.csrf.as_ref().map(|(_, token)| token.as_str())
+    }
+
+    // The same token as a hidden input, ready to paste into a <form>.
+    // Empty without the middleware.
+    pub fn csrf_field(&self) -> String {
+        match &self.csrf {
+            Some((field, token)) => format!("<input type=\"hidden\" name=\"{field}\" value=\"{token}\">"),
+            None => String::new(),
+        }
+    }
+
+    // When the server will give up on this request, if a timeout applies.
+    // Long-running handlers can check this and stop early.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn time_remaining(&self) -> Option<Duration> {
+        let now = self.clock.as_ref().map_or_else(Instant::now, |clock| clock.now());
+        self.deadline.map(|deadline| deadline.saturating_duration_since(now))
+    }
+
+    pub fn deadline_passed(&self) -> bool {
+        self.time_remaining() == Some(Duration::ZERO)
+    }
+
+    // Sends a 103 Early Hints ahead of the response, with a Link header for
+    // each of `links`, like "</app.css>; rel=preload; as=style", so the
+    // browser can start fetching them while the handler is still working.
+    // HTTP/1.0 clients don't know interim responses and aren't sent one.
+    pub fn early_hints(&self, links: &[&str]) -> io::Result<()> {
+        let Some(connection) = &self.connection else {
+            return Ok(());
+        };
+        if self.protocol != "HTTP/1.1" || links.is_empty() {
+            return Ok(());
+        }
+        let mut head = format!("HTTP/1.1 {}\r\n", StatusCode::EarlyHints.status_line());
+        for link in links {
+            head.push_str(&format!("Link: {}\r\n", link.replace(['\r', '\n'], "")));
+        }
+        head.push_str("\r\n");
+        connection.with_stream(|stream| stream.write_all(head.as_bytes()))
+    }
+}
+
+// What a GeoBlock resolver knows about an address.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GeoInfo {
+    // ISO 3166-1 alpha-2, like "DE".
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatusCode {
+    SwitchingProtocols = 101,
+    EarlyHints = 103,
+    Ok = 200,
+    Created = 201,
+    NoContent = 204,
+    PartialContent = 206,
+    MultiStatus = 207,
+    NotModified = 304,
+    MovedPermanently = 301,
+    Found = 302,
+    TemporaryRedirect = 307,
+    PermanentRedirect = 308,
+    BadRequest = 400,
+    Unauthorized = 401,
+    Forbidden = 403,
+    NotFound = 404,
+    MethodNotAllowed = 405,
+    NotAcceptable = 406,
+    Conflict = 409,
+    PreconditionFailed = 412,
+    PayloadTooLarge = 413,
+    UriTooLong = 414,
+    UnsupportedMediaType = 415,
+    RangeNotSatisfiable = 416,
+    UpgradeRequired = 426,
+    TooManyRequests = 429,
+    InternalServerError = 500,
+    NotImplemented = 501,
+    BadGateway = 502,
+    ServiceUnavailable = 503,
+    GatewayTimeout = 504,
+}
+
+impl StatusCode {
+    const ALL: [StatusCode; 31] = [
+        StatusCode::SwitchingProtocols, StatusCode::EarlyHints, StatusCode::Ok, StatusCode::Created,
+        StatusCode::NoContent, StatusCode::PartialContent, StatusCode::MultiStatus, StatusCode::NotModified,
+        StatusCode::MovedPermanently, StatusCode::Found, StatusCode::TemporaryRedirect,
+        StatusCode::PermanentRedirect, StatusCode::BadRequest, StatusCode::Unauthorized, StatusCode::Forbidden,
+        StatusCode::NotFound, StatusCode::MethodNotAllowed, StatusCode::NotAcceptable, StatusCode::Conflict,
+        StatusCode::PreconditionFailed, StatusCode::PayloadTooLarge, StatusCode::UriTooLong,
+        StatusCode::UnsupportedMediaType, StatusCode::RangeNotSatisfiable, StatusCode::UpgradeRequired,
+        StatusCode::TooManyRequests, StatusCode::InternalServerError, StatusCode::NotImplemented,
+        StatusCode::BadGateway, StatusCode::ServiceUnavailable, StatusCode::GatewayTimeout,
+    ];
+
+    pub fn code(&self) -> u16 {
+        *self as u16
+    }
+
+    // 1xx, 204 and 304 responses end with their head (RFC 9112 section 6.3).
+    pub(crate) fn allows_body(&self) -> bool {
+        !matches!(self.code(), 100..=199 | 204 | 304)
+    }
+
+    // The code and reason phrase, as they go in a status line: "200 OK".
+    pub fn status_line(&self) -> &'static str {
+        match self {
+            StatusCode::SwitchingProtocols => "101 Switching Protocols",
+            StatusCode::EarlyHints => "103 Early Hints",
+            StatusCode::Ok => "200 OK",
+            StatusCode::Created => "201 Created",
+            StatusCode::NoContent => "204 No Content",
+            StatusCode::PartialContent => "206 Partial Content",
+            StatusCode::MultiStatus => "207 Multi-Status",
+            StatusCode::NotModified => "304 Not Modified",
+            StatusCode::MovedPermanently => "301 Moved Permanently",
+            StatusCode::Found => "302 Found",
+            StatusCode::TemporaryRedirect => "307 Temporary Redirect",
+            StatusCode::PermanentRedirect => "308 Permanent Redirect",
+            StatusCode::BadRequest => "400 Bad Request",
+            StatusCode::Unauthorized => "401 Unauthorized",
+            StatusCode::Forbidden => "403 Forbidden",
+            StatusCode::NotFound => "404 Not Found",
+            StatusCode::MethodNotAllowed => "405 Method Not Allowed",
+            StatusCode::NotAcceptable => "406 Not Acceptable",
+            StatusCode::Conflict => "409 Conflict",
+            StatusCode::PreconditionFailed => "412 Precondition Failed",
+            StatusCode::PayloadTooLarge => "413 Payload Too Large",
+            StatusCode::UriTooLong => "414 URI Too Long",
+            StatusCode::UnsupportedMediaType => "415 Unsupported Media Type",
+            StatusCode::RangeNotSatisfiable => "416 Range Not Satisfiable",
+            StatusCode::UpgradeRequired => "426 Upgrade Required",
+            StatusCode::TooManyRequests => "429 Too Many Requests",
+            StatusCode::InternalServerError => "500 Internal Server Error",
+            StatusCode::NotImplemented => "501 Not Implemented",
+            StatusCode::BadGateway => "502 Bad Gateway",
+            StatusCode::ServiceUnavailable => "503 Service Unavailable",
+            StatusCode::GatewayTimeout => "504 Gateway Timeout",
+        }
+    }
+
+    // None for codes there's no variant for.
+    pub fn from_code(code: u16) -> Option<StatusCode> {
+        StatusCode::ALL.into_iter().find(|status| status.code() == code)
+    }
+}
+
+impl Display for StatusCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.status_line())
+    }
+}
+
+#[derive(Clone)]
+pub struct Response {
+    pub protocol: String,
+    pub status_code: StatusCode,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // Already written by a streaming handler; nothing left to send.
+    pub(crate) streamed: bool,
+    // Sent from disk after the (empty) body instead of being held in memory.
+    pub(crate) file: Option<FileBody>,
+    // How header names are written; see `ServerBuilder::header_case`.
+    pub(crate) header_case: HeaderCase,
+    // An answer to HEAD: the head as for GET, Content-Length included, but
+    // no body after it.
+    pub(crate) head_only: bool,
+}
+
+// How response header names go out. Either way they keep the order they were
+// added in, and request headers are always kept exactly as they came.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HeaderCase {
+    // Exactly as the handler and middleware wrote them.
+    #[default]
+    AsSet,
+    // "Content-Type", "WWW-Authenticate", "ETag", whatever they were set as,
+    // for clients that only recognise the usual spelling.
+    Canonical,
+    // "content-type", as HTTP/2 spells them, for proxies that expect it.
+    Lower,
+}
+
+impl HeaderCase {
+    pub(crate) fn apply<'a>(&self, name: &'a str) -> std::borrow::Cow<'a, str> {
+        match self {
+            HeaderCase::AsSet => name.into(),
+            HeaderCase::Lower => name.to_ascii_lowercase().into(),
+            HeaderCase::Canonical => {
+                let lower = name.to_ascii_lowercase();
+                let special = ["www-authenticate", "etag", "te", "dnt", "content-md5", "x-xss-protection", "x-ua-compatible"];
+                let spellings = ["WWW-Authenticate", "ETag", "TE", "DNT", "Content-MD5", "X-XSS-Protection", "X-UA-Compatible"];
+                if let Some(index) = special.iter().position(|special| *special == lower) {
+                    return spellings[index].into();
+                }
+                let words: Vec<String> = lower.split('-')
+                    .map(|word| {
+                        let mut chars = word.chars();
+                        chars.next().map(|first| first.to_ascii_uppercase().to_string() + chars.as_str()).unwrap_or_default()
+                    })
+                    .collect();
+                words.join("-").into()
+            }
+        }
+    }
+}
+
+// A stretch of a file to send as the body, read a chunk at a time only once
+// the response is written.
+#[derive(Clone)]
+pub(crate) struct FileBody {
+    pub(crate) path: PathBuf,
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+}
+
+impl Response {
+    pub fn new(status_code: StatusCode, body: &str) -> Response {
+        Response::from_bytes(status_code, body.as_bytes().to_vec())
+    }
+
+    // For bodies that aren't text, like images or compressed data.
+    pub fn from_bytes(status_code: StatusCode, body: Vec<u8>) -> Response {
+        Response {
+            protocol: "HTTP/1.1".to_string(),
+            status_code,
+            headers: vec![],
+            body,
+            streamed: false,
+            file: None,
+            header_case: HeaderCase::AsSet,
+            head_only: false,
+        }
+    }
+
+    pub fn json(status_code: StatusCode, value: &Value) -> Response {
+        Response::new(status_code, &value.to_string()).with_header("Content-Type", "application/json")
+    }
+
+    pub fn msgpack(status_code: StatusCode, value: &Value) -> Response {
+        Response::from_bytes(status_code, msgpack::encode(value)).with_header("Content-Type", "application/msgpack")
+    }
+
+    // `value` as MessagePack if the request's Accept prefers it, else JSON.
+    pub fn value(request: &Request, status_code: StatusCode, value: &Value) -> Response {
+        let accept = request.header("Accept").unwrap_or("*/*");
+        let quality = |types: &[&str]| types.iter().map(|media| accept_quality(accept, media)).fold(0.0, f64::max);
+        let response = if quality(&MSGPACK_TYPES) > quality(&["application/json"]) {
+            Response::msgpack(status_code, value)
+        } else {
+            Response::json(status_code, value)
+        };
+        response.with_header("Vary", "Accept")
+    }
+
+    // A download of `data`, saved by browsers as `filename`, with the type
+    // that name implies.
+    pub fn attachment(data: Vec<u8>, filename: &str) -> Response {
+        Response::from_bytes(StatusCode::Ok, data)
+            .with_header("Content-Type", content_type(Path::new(filename)))
+            .with_header("Content-Disposition", &content_disposition(filename))
+    }
+
+    // A download of the file at `path`, sent from disk however big it is.
+    // Saved as `filename`, which needn't match the name on disk.
+    pub fn attachment_file(path: &str, filename: &str) -> io::Result<Response> {
+        let metadata = fs::metadata(path)?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{path} isn't a file")));
+        }
+        let mut response = Response::attachment(vec![], filename);
+        response.file = Some(FileBody { path: PathBuf::from(path), offset: 0, length: metadata.len() });
+        Ok(response)
+    }
+
+    // The length of the body, wherever it's coming from.
+    pub fn body_length(&self) -> u64 {
+        match &self.file {
+            Some(file) => file.length,
+            None => self.body.len() as u64,
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Replaces any existing values of the header instead of adding another,
+    // keeping the place of the first.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        let mut found = false;
+        self.headers.retain_mut(|(header, existing)| {
+            if !header.eq_ignore_ascii_case(name) {
+                return true;
+            }
+            if found {
+                return false;
+            }
+            found = true;
+            *header = name.to_string();
+            *existing = value.to_string();
+            true
+        });
+        if !found {
+            self.headers.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    // Lets browsers and shared caches reuse the response for `duration`.
+    pub fn cache_for(mut self, duration: Duration) -> Response {
+        self.set_header("Cache-Control", &format!("public, max-age={}", duration.as_secs()));
+        self.set_header("Expires", &http_date(SystemTime::now() + duration));
+        self
+    }
+
+    // For responses nothing should keep a copy of, like account pages.
+    pub fn no_store(mut self) -> Response {
+        self.set_header("Cache-Control", "no-store");
+        self.headers.retain(|(header, _)| !header.eq_ignore_ascii_case("Expires"));
+        self
+    }
+
+    // Tags this version of the response. A GET whose If-None-Match has the
+    // same tag gets a bodiless 304 instead, so clients revalidate cheaply.
+    // Quotes are added unless `value` already has them (or is a weak W/ tag).
+    pub fn etag(mut self, value: &str) -> Response {
+        let tag = if value.starts_with('"') || value.starts_with("W/") {
+            value.to_string()
+        } else {
+            format!("\"{value}\"")
+        };
+        self.set_header("ETag", &tag);
+        self
+    }
+
+    // Turns a 200 into a 304 when the request's If-None-Match lists its ETag.
+    // Tags are compared weakly, as RFC 9110 asks for If-None-Match.
+    pub(crate) fn make_conditional(&mut self, request: &Request) {
+        let (Some(etag), Some(wanted)) = (self.header("ETag"), request.header("If-None-Match")) else {
+            return;
+        };
+        let matches = wanted.trim() == "*"
+            || wanted.split(',').any(|tag| tag.trim().trim_start_matches("W/") == etag.trim_start_matches("W/"));
+        let cacheable = matches!(request.method, HttpMethod::GET | HttpMethod::HEAD) && self.status_code == StatusCode::Ok;
+        if matches && cacheable {
+            self.status_code = StatusCode::NotModified;
+            self.body.clear();
+            self.file = None;
+            self.headers.retain(|(header, _)| {
+                !["Content-Type", "Content-Encoding"].iter().any(|name| header.eq_ignore_ascii_case(name))
+            });
+        }
+    }
+}
+
+const MSGPACK_TYPES: [&str; 3] = ["application/msgpack", "application/x-msgpack", "application/vnd.msgpack"];
+
+// A Content-Type without its parameters, lowercased: "text/html" for
+// "text/HTML; charset=utf-8".
+pub(crate) fn media_type(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+// How much an Accept header says the client wants `media`, from 0 to 1,
+// counting "type/*" and "*/*" ranges, with the most specific range that
+// matches deciding.
+pub(crate) fn accept_quality(accept: &str, media: &str) -> f64 {
+    let (kind, _) = media.split_once('/').unwrap_or((media, ""));
+    let mut best: Option<(u8, f64)> = None;
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let range = parts.next().unwrap_or_default().trim();
+        let specificity = if range.eq_ignore_ascii_case(media) {
+            2
+        } else if range.strip_suffix("/*").is_some_and(|range| range.eq_ignore_ascii_case(kind)) {
+            1
+        } else if range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        let quality = parts.find_map(|param| param.trim().strip_prefix("q=")?.trim().parse::<f64>().ok()).unwrap_or(1.0);
+        if best.is_none_or(|(most, _)| specificity > most) {
+            best = Some((specificity, quality.clamp(0.0, 1.0)));
+        }
+    }
+    best.map_or(0.0, |(_, quality)| quality)
+}
+
+// `attachment` with the filename twice: plain ASCII for old clients, and
+// percent-encoded UTF-8 (RFC 5987) for everyone else.
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename.chars()
+        .map(|c| if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' { c } else { '_' })
+        .collect();
+    let mut encoded = String::new();
+    for byte in filename.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+// Formats a time the way HTTP headers want it: "Sun, 06 Nov 1994 08:49:37 GMT".
+pub fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (year, month, day) = civil_date(seconds / 86400);
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} GMT",
+        DAYS[(seconds / 86400 % 7) as usize],
+        MONTHS[month as usize - 1],
+        seconds % 86400 / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// Days since the epoch to (year, month, day), after Howard Hinnant's
+// algorithm.
+pub(crate) fn civil_date(days: u64) -> (u64, u64, u64) {
+    let shifted = days + 719_468;
+    let era = shifted / 146_097;
+    let day_of_era = shifted % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    (year_of_era + era * 400 + u64::from(month <= 2), month, day)
+}
+
+// The other way: (year, month, day) to days since the epoch. None for
+// dates before it, or that don't exist.
+pub(crate) fn days_from_civil(year: u64, month: u64, day: u64) -> Option<u64> {
+    if !(1..=12).contains(&month) || day == 0 || day > 31 || year < 1970 {
+        return None;
+    }
+    // Years start in March here, so leap days fall at the end.
+    let shifted_year = if month <= 2 { year - 1 } else { year };
+    let era = shifted_year / 400;
+    let year_of_era = shifted_year % 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146_097 + day_of_era).checked_sub(719_468)?;
+    // Rejects days past the end of the month, like February 30th.
+    (civil_date(days) == (year, month, day)).then_some(days)
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use crate::{
+    http::{self, HttpMethod, Request, Response, StatusCode},
+    json::{self, Value},
+};
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+
+type Method = Box<dyn Fn(&Request, &Value) -> Result<Value, RpcError> + Send + Sync>;
+
+// A JSON-RPC 2.0 endpoint for `Server::add_jsonrpc`: calls are POSTed as
+// JSON and dispatched by method name to the handlers registered here.
+//
+//     server.add_jsonrpc("/rpc", JsonRpc::new()
+//         .method("add", |_, params| {
+//             let [a, b] = params.as_array().unwrap_or_default() else {
+//                 return Err(RpcError::invalid_params("expected [a, b]"));
+//             };
+//             match (a.as_f64(), b.as_f64()) {
+//                 (Some(a), Some(b)) => Ok(Value::Number(a + b)),
+//                 _ => Err(RpcError::invalid_params("expected numbers")),
+//             }
+//         }));
+//
+// Handlers get the call's params as sent (Null when there were none) and
+// return a result or an error object. Batches are answered with an array
+// of the responses, and notifications, calls without an id, aren't
+// answered at all; a 204 if nothing in the POST needed answering.
+pub struct JsonRpc {
+    methods: HashMap<String, Method>,
+    max_batch: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    pub data: Option<Value>,
+}
+
+impl JsonRpc {
+    // Takes batches of up to 100 calls.
+    pub fn new() -> JsonRpc {
+        JsonRpc { methods: HashMap::new(), max_batch: 100 }
+    }
+
+    pub fn method<F>(mut self, name: &str, handler: F) -> JsonRpc
+    where
+        F: Fn(&Request, &Value) -> Result<Value, RpcError> + Send + Sync + 'static,
+    {
+        self.methods.insert(name.to_string(), Box::new(handler));
+        self
+    }
+
+    // Bigger batches are refused whole, with a single Invalid Request.
+    pub fn max_batch(mut self, calls: usize) -> JsonRpc {
+        self.max_batch = calls.max(1);
+        self
+    }
+
+    pub(crate) fn respond(&self, request: &Request) -> Response {
+        if request.method != HttpMethod::POST {
+            return Response::new(StatusCode::MethodNotAllowed, "Method Not Allowed").with_header("Allow", "POST");
+        }
+        if request.header("Content-Type").is_some_and(|content_type| !is_json(content_type)) {
+            return Response::new(StatusCode::UnsupportedMediaType, "Unsupported Media Type");
+        }
+        let answer = match json::parse(&request.body) {
+            Err(error) => Some(error_response(Value::Null, &RpcError::new(PARSE_ERROR, &format!("Parse error: {error}")))),
+            Ok(Value::Array(calls)) if calls.is_empty() => {
+                Some(error_response(Value::Null, &RpcError::new(INVALID_REQUEST, "Invalid Request: empty batch")))
+            }
+            Ok(Value::Array(calls)) if calls.len() > self.max_batch => {
+                Some(error_response(Value::Null, &RpcError::new(INVALID_REQUEST, "Invalid Request: batch too large")))
+            }
+            Ok(Value::Array(calls)) => {
+                let answers: Vec<Value> = calls.iter().filter_map(|call| self.call(request, call)).collect();
+                (!answers.is_empty()).then_some(Value::Array(answers))
+            }
+            Ok(call) => self.call(request, &call),
+        };
+        match answer {
+            Some(answer) => Response::new(StatusCode::Ok, &answer.to_string()).with_header("Content-Type", "application/json"),
+            None => Response::new(StatusCode::NoContent, ""),
+        }
+    }
+
+    // The response object for one call; None for a notification.
+    fn call(&self, request: &Request, call: &Value) -> Option<Value> {
+        let Value::Object(_) = call else {
+            return Some(error_response(Value::Null, &RpcError::new(INVALID_REQUEST, "Invalid Request")));
+        };
+        let id = match call.get("id") {
+            None => None,
+            Some(id @ (Value::Null | Value::Number(_) | Value::String(_))) => Some(id.clone()),
+            Some(_) => return Some(error_response(Value::Null, &RpcError::new(INVALID_REQUEST, "Invalid Request: bad id"))),
+        };
+        let params = call.get("params").unwrap_or(&Value::Null);
+        let (Some("2.0"), Some(name)) = (call.get("jsonrpc").and_then(Value::as_str), call.get("method").and_then(Value::as_str)) else {
+            return Some(error_response(id.unwrap_or(Value::Null), &RpcError::new(INVALID_REQUEST, "Invalid Request")));
+        };
+        if !matches!(params, Value::Null | Value::Array(_) | Value::Object(_)) {
+            return Some(error_response(id.unwrap_or(Value::Null), &RpcError::new(INVALID_REQUEST, "Invalid Request: bad params")));
+        }
+        let result = match self.methods.get(name) {
+            Some(method) => method(request, params),
+            None => Err(RpcError::new(METHOD_NOT_FOUND, &format!("Method not found: {name}"))),
+        };
+        // Notifications aren't answered, even when they fail.
+        let id = id?;
+        Some(match result {
+            Ok(result) => Value::Object(vec![
+                ("jsonrpc".to_string(), Value::String("2.0".to_string())),
+                ("result".to_string(), result),
+                ("id".to_string(), id),
+            ]),
+            Err(error) => error_response(id, &error),
+        })
+    }
+}
+
+impl Default for JsonRpc {
+    fn default() -> JsonRpc {
+        JsonRpc::new()
+    }
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: &str) -> RpcError {
+        RpcError { code, message: message.to_string(), data: None }
+    }
+
+    pub fn invalid_params(message: &str) -> RpcError {
+        RpcError::new(INVALID_PARAMS, message)
+    }
+
+    pub fn internal(message: &str) -> RpcError {
+        RpcError::new(INTERNAL_ERROR, message)
+    }
+
+    // Anything more the client might want to know, sent as the error's
+    // "data" member.
+    pub fn with_data(mut self, data: Value) -> RpcError {
+        self.data = Some(data);
+        self
+    }
+}
+
+fn error_response(id: Value, error: &RpcError) -> Value {
+    let mut members = vec![
+        ("code".to_string(), Value::Number(error.code as f64)),
+        ("message".to_string(), Value::String(error.message.clone())),
+    ];
+    if let Some(data) = &error.data {
+        members.push(("data".to_string(), data.clone()));
+    }
+    Value::Object(vec![
+        ("jsonrpc".to_string(), Value::String("2.0".to_string())),
+        ("error".to_string(), Value::Object(members)),
+        ("id".to_string(), id),
+    ])
+}
+
+fn is_json(content_type: &str) -> bool {
+    matches!(http::media_type(content_type).as_str(), "application/json" | "application/json-rpc")
+}
@@ -1,30 +1,165 @@
+pub mod body;
+pub mod broadcast;
+pub mod chaos;
+pub mod clock;
+pub mod client;
+pub mod compress;
+pub mod config;
+pub mod dispatch;
+pub mod http;
+pub mod json;
+pub mod jsonrpc;
+pub mod log;
+pub mod longpoll;
+pub mod markdown;
+pub mod metrics;
+pub mod middleware;
+pub mod mirror;
+pub mod msgpack;
+pub mod oauth;
+pub mod parser;
+pub mod plugin;
+pub mod quota;
+pub mod recorder;
 pub mod server;
+pub mod session;
+pub mod sigv4;
+pub mod static_files;
+pub mod stream;
+pub mod tarpit;
+pub mod trace;
+pub mod upload;
+pub mod webdav;
+pub mod webhook;
+pub mod websocket;
+mod base64;
+mod buffer;
+#[cfg(target_os = "linux")]
+mod handover;
+mod md5;
+mod proxy_protocol;
+mod random;
+mod regex;
+mod rewrite;
+mod sha1;
+mod sha256;
+#[cfg(unix)]
+mod signal;
+mod socket;
+#[cfg(unix)]
+mod systemd;
 
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    cell::Cell,
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    workers: Mutex<Vec<Worker>>,
+    state: Arc<PoolState>,
 }
 type Job = Box<dyn FnOnce() + Send + 'static>;
-impl ThreadPool {
-    pub fn new<'pool_lifetime>(size: usize) -> ThreadPool {
-        assert!(size > 0);
+// A job and when it was queued.
+type Queued = (Instant, Job);
+
+thread_local! {
+    static QUEUE_WAIT: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let mut workers = Vec::with_capacity(size);
+// A snapshot of how busy the pool is, for spotting saturation.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolStats {
+    pub size: usize,
+    pub idle: usize,
+    pub queued: usize,
+    pub jobs_executed: u64,
+    pub panics_recovered: u64,
+    // Total time workers have spent running jobs.
+    pub busy_time: Duration,
+    // Total time jobs have spent queued before a worker got to them. Rising
+    // faster than busy_time means the pool is too small, not the jobs slow.
+    pub queue_time: Duration,
+}
 
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+// Shared between the pool and its workers. Jobs are spread over one queue per
+// potential worker instead of a single channel everyone fights over; a worker
+// drains its own queue first and steals from the others when it runs dry.
+struct PoolState {
+    min_size: usize,
+    max_size: usize,
+    idle_timeout: Duration,
+    queues: Vec<Mutex<VecDeque<Queued>>>,
+    next_queue: AtomicUsize,
+    size: AtomicUsize,
+    idle: AtomicUsize,
+    queued: AtomicUsize,
+    next_id: AtomicUsize,
+    jobs_executed: AtomicU64,
+    panics_recovered: AtomicU64,
+    busy_nanos: AtomicU64,
+    queue_nanos: AtomicU64,
+    shutting_down: AtomicBool,
+    sleep: Mutex<()>,
+    wake: Condvar,
+}
+
+impl PoolState {
+    fn find_job(&self, home: usize) -> Option<Queued> {
+        if let Some(job) = self.queues[home].lock().unwrap().pop_front() {
+            return Some(job);
         }
+        // Steal from the back so the owner keeps its oldest jobs in order.
+        (1..self.queues.len())
+            .map(|offset| (home + offset) % self.queues.len())
+            .find_map(|index| self.queues[index].lock().unwrap().pop_back())
+    }
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> ThreadPool {
+        ThreadPool::dynamic(size, size, Duration::from_secs(30))
+    }
 
-        ThreadPool { workers,
-            sender: Some(sender),
+    // Starts with `min_size` workers, spawns more (up to `max_size`) whenever a
+    // job arrives and nobody is idle, and lets extra workers exit after sitting
+    // idle for `idle_timeout`.
+    pub fn dynamic(min_size: usize, max_size: usize, idle_timeout: Duration) -> ThreadPool {
+        assert!(min_size > 0);
+        assert!(max_size >= min_size);
+
+        let pool = ThreadPool {
+            workers: Mutex::new(Vec::with_capacity(max_size)),
+            state: Arc::new(PoolState {
+                min_size,
+                max_size,
+                idle_timeout,
+                queues: (0..max_size).map(|_| Mutex::new(VecDeque::new())).collect(),
+                next_queue: AtomicUsize::new(0),
+                size: AtomicUsize::new(0),
+                idle: AtomicUsize::new(0),
+                queued: AtomicUsize::new(0),
+                next_id: AtomicUsize::new(0),
+                jobs_executed: AtomicU64::new(0),
+                panics_recovered: AtomicU64::new(0),
+                busy_nanos: AtomicU64::new(0),
+                queue_nanos: AtomicU64::new(0),
+                shutting_down: AtomicBool::new(false),
+                sleep: Mutex::new(()),
+                wake: Condvar::new(),
+            }),
+        };
+
+        for _ in 0..min_size {
+            pool.spawn_worker();
         }
+        pool
     }
 
     pub fn execute<F>(&self, f: F)
@@ -32,17 +167,106 @@ impl ThreadPool {
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(f);
-        self.sender.as_ref().unwrap().send(job).unwrap();
+        let state = &self.state;
+        // Counted before it's visible so a worker never takes it off the count
+        // before it's added.
+        let queued = state.queued.fetch_add(1, Ordering::SeqCst) + 1;
+        let index = state.next_queue.fetch_add(1, Ordering::Relaxed) % state.queues.len();
+        state.queues[index].lock().unwrap().push_back((Instant::now(), job));
+
+        let idle = state.idle.load(Ordering::SeqCst);
+        if idle > 0 {
+            let _guard = state.sleep.lock().unwrap();
+            state.wake.notify_one();
+        }
+        if queued > idle && self.size() < state.max_size {
+            self.spawn_worker();
+        }
+    }
+
+    // Number of live workers.
+    pub fn size(&self) -> usize {
+        self.state.size.load(Ordering::SeqCst)
+    }
+
+    // Jobs waiting for a worker to pick them up.
+    pub fn queue_depth(&self) -> usize {
+        self.state.queued.load(Ordering::SeqCst)
+    }
+
+    // Blocks until every queued job has run and no worker is busy.
+    pub fn wait_until_idle(&self) {
+        while !self.is_idle() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Like `wait_until_idle`, but gives up after `timeout`. Returns whether
+    // the pool went idle.
+    pub fn wait_until_idle_for(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !self.is_idle() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
     }
 
+    fn is_idle(&self) -> bool {
+        let stats = self.stats();
+        stats.queued == 0 && stats.idle >= stats.size
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let state = &self.state;
+        PoolStats {
+            size: state.size.load(Ordering::SeqCst),
+            idle: state.idle.load(Ordering::SeqCst),
+            queued: state.queued.load(Ordering::SeqCst),
+            jobs_executed: state.jobs_executed.load(Ordering::Relaxed),
+            panics_recovered: state.panics_recovered.load(Ordering::Relaxed),
+            busy_time: Duration::from_nanos(state.busy_nanos.load(Ordering::Relaxed)),
+            queue_time: Duration::from_nanos(state.queue_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
+    // How long the job running on this thread sat in its pool's queue;
+    // zero outside a pool's workers.
+    pub fn queue_wait() -> Duration {
+        QUEUE_WAIT.with(Cell::get)
+    }
+
+    fn spawn_worker(&self) {
+        let state = &self.state;
+        let reserved = state.size.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |size| {
+            (size < state.max_size).then_some(size + 1)
+        });
+        if reserved.is_err() {
+            return;
+        }
+
+        let id = state.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut workers = self.workers.lock().unwrap();
+        // Workers that shrank away are already finished; no need to keep them.
+        workers.retain(|worker| worker.thread.as_ref().is_some_and(|thread| !thread.is_finished()));
+        workers.push(Worker::new(id, Arc::clone(state)));
+    }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
+        {
+            let _guard = self.state.sleep.lock().unwrap();
+            self.state.shutting_down.store(true, Ordering::SeqCst);
+            self.state.wake.notify_all();
+        }
 
-        for worker in &mut self.workers {
-            println!("Dropping worker {}", worker.id);
+        for worker in self.workers.get_mut().unwrap() {
+            if log::enabled(log::Level::Debug) {
+                println!("Dropping worker {}", worker.id);
+            }
 
             if let Some(thread) = worker.thread.take() {
                 thread.join().unwrap();
@@ -57,23 +281,63 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
-
-            match message {
-                Ok(job) => {
+    fn new(id: usize, state: Arc<PoolState>) -> Worker {
+        let home = id % state.queues.len();
+        let thread = thread::Builder::new().name(format!("webserver-worker-{id}")).spawn(move || loop {
+            if let Some((queued_at, job)) = state.find_job(home) {
+                state.queued.fetch_sub(1, Ordering::SeqCst);
+                let waited = queued_at.elapsed();
+                state.queue_nanos.fetch_add(waited.as_nanos() as u64, Ordering::Relaxed);
+                QUEUE_WAIT.with(|wait| wait.set(waited));
+                if log::enabled(log::Level::Debug) {
                     println!("Worker {id} got a job; executing.");
-                    job();
                 }
-                Err(error) => {
-                    println!("Worker {id} shutting down: {error}");
-                    break;
+
+                // A panicking job shouldn't take the worker down with it.
+                let started = Instant::now();
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    eprintln!("Worker {id} recovered from a panicking job.");
+                    state.panics_recovered.fetch_add(1, Ordering::Relaxed);
                 }
+                state.busy_nanos.fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                state.jobs_executed.fetch_add(1, Ordering::Relaxed);
+                continue;
             }
 
-        });
+            // Counting ourselves idle before looking at the queue means a job
+            // submitted in between either gets seen here or wakes us up.
+            let guard = state.sleep.lock().unwrap();
+            state.idle.fetch_add(1, Ordering::SeqCst);
+            if state.queued.load(Ordering::SeqCst) > 0 {
+                state.idle.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+            // Queued jobs are always drained before shutting down.
+            if state.shutting_down.load(Ordering::SeqCst) {
+                if log::enabled(log::Level::Debug) {
+                    println!("Worker {id} shutting down.");
+                }
+                state.idle.fetch_sub(1, Ordering::SeqCst);
+                state.size.fetch_sub(1, Ordering::SeqCst);
+                break;
+            }
+
+            let (_guard, wait) = state.wake.wait_timeout(guard, state.idle_timeout).unwrap();
+            state.idle.fetch_sub(1, Ordering::SeqCst);
+
+            if wait.timed_out() && state.queued.load(Ordering::SeqCst) == 0 {
+                let shrunk = state.size.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |size| {
+                    (size > state.min_size).then(|| size - 1)
+                });
+                if shrunk.is_ok() {
+                    if log::enabled(log::Level::Debug) {
+                        println!("Worker {id} idle; shutting down.");
+                    }
+                    break;
+                }
+            }
+        }).expect("Failed to spawn worker thread");
 
         Worker { id, thread: Some(thread) }
     }
-}
\ No newline at end of file
+}
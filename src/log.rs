@@ -0,0 +1,229 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use crate::http::civil_date;
+
+// How chatty the server is on stdout. Errors always go to stderr; Info
+// covers the access log and lifecycle messages, Debug the workers' chatter.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 0,
+    Info = 1,
+    Debug = 2,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Debug as u8);
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Level> {
+        match name.to_ascii_lowercase().as_str() {
+            "error" => Some(Level::Error),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+}
+
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => Level::Error,
+        1 => Level::Info,
+        _ => Level::Debug,
+    }
+}
+
+// Takes effect immediately, for every thread.
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= self::level()
+}
+
+// A log file that rotates itself: once it passes a size or age limit it's
+// renamed to `<path>.1` (pushing older ones to `.2`, `.3`, ...) and a fresh
+// one started, keeping only the newest few. Lines are buffered and flushed
+// by a background thread every so often, and written under one lock so they
+// never interleave.
+#[derive(Clone)]
+pub struct LogFile {
+    state: Arc<Mutex<LogState>>,
+}
+
+struct LogState {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    written: u64,
+    opened: Instant,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    keep: usize,
+    flush_every: Duration,
+}
+
+impl LogFile {
+    // Appends to `path`, creating it if needed. By default the file rotates
+    // at 100 MiB, five old files are kept and lines are flushed every second.
+    pub fn open(path: &str) -> io::Result<LogFile> {
+        let path = PathBuf::from(path);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        let state = Arc::new(Mutex::new(LogState {
+            path,
+            writer: BufWriter::new(file),
+            written,
+            opened: Instant::now(),
+            max_size: Some(100 * 1024 * 1024),
+            max_age: None,
+            keep: 5,
+            flush_every: Duration::from_secs(1),
+        }));
+
+        let weak = Arc::downgrade(&state);
+        thread::Builder::new()
+            .name("webserver-log-flush".to_string())
+            .spawn(move || LogFile::flush_periodically(weak))?;
+        Ok(LogFile { state })
+    }
+
+    // Rotates once the file reaches `bytes`; `None` lets it grow forever.
+    pub fn max_size(self, bytes: Option<u64>) -> LogFile {
+        self.state.lock().unwrap().max_size = bytes;
+        self
+    }
+
+    // Rotates once the current file has been written to for `age`, e.g. a
+    // day, whatever its size.
+    pub fn rotate_every(self, age: Duration) -> LogFile {
+        self.state.lock().unwrap().max_age = Some(age);
+        self
+    }
+
+    // How many rotated files to keep besides the current one.
+    pub fn keep(self, files: usize) -> LogFile {
+        self.state.lock().unwrap().keep = files;
+        self
+    }
+
+    pub fn flush_every(self, interval: Duration) -> LogFile {
+        self.state.lock().unwrap().flush_every = interval.max(Duration::from_millis(10));
+        self
+    }
+
+    // Adds `line` and a newline.
+    pub fn write_line(&self, line: &str) {
+        let mut state = self.state.lock().unwrap();
+        let size_reached = state.max_size.is_some_and(|max| state.written + line.len() as u64 + 1 > max);
+        let age_reached = state.max_age.is_some_and(|max| state.opened.elapsed() >= max);
+        if (size_reached || age_reached) && state.written > 0 {
+            if let Err(error) = state.rotate() {
+                eprintln!("Error rotating {}: {error}", state.path.display());
+            }
+        }
+        match writeln!(state.writer, "{line}") {
+            Ok(()) => state.written += line.len() as u64 + 1,
+            Err(error) => eprintln!("Error writing to {}: {error}", state.path.display()),
+        }
+    }
+
+    pub fn flush(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Err(error) = state.writer.flush() {
+            eprintln!("Error flushing {}: {error}", state.path.display());
+        }
+    }
+
+    // Stops once every LogFile handle is gone; BufWriter flushes what's left
+    // when the state is dropped.
+    fn flush_periodically(state: Weak<Mutex<LogState>>) {
+        loop {
+            let interval = match state.upgrade() {
+                Some(state) => state.lock().unwrap().flush_every,
+                None => return,
+            };
+            thread::sleep(interval);
+            match state.upgrade() {
+                Some(state) => LogFile { state }.flush(),
+                None => return,
+            }
+        }
+    }
+}
+
+impl LogState {
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{index}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // The oldest falls off the end; missing files are just gaps.
+            let _ = fs::remove_file(self.rotated(self.keep));
+            for index in (1..self.keep).rev() {
+                let _ = fs::rename(self.rotated(index), self.rotated(index + 1));
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        self.written = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+// An RFC 3339 UTC timestamp with milliseconds: "2024-05-01T12:34:56.789Z".
+pub fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_date(seconds / 86400);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        seconds % 86400 / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// `text` as a quoted JSON string.
+pub fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,116 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// Somewhere for long-poll handlers to park until something happens, keyed
+// by topic, and for anything else in the app to wake them:
+//
+//     let wakeups = server.wakeups();
+//     let waiting = wakeups.clone();
+//     server.add_handler("/messages/poll", move |_| {
+//         waiting.wait_for("messages", Duration::from_secs(30));
+//         Response::new(StatusCode::Ok, &messages.latest())
+//     });
+//     ...
+//     wakeups.notify("messages");
+//
+// Only a notify that comes after `wait_for` starts wakes it, so a client
+// should say what it has already seen and be answered straight away if
+// there's more. Each parked handler holds a worker, so size the pool for
+// them. The server's own registry wakes everyone once it starts shutting
+// down, and from then on `wait_for` returns straight away.
+#[derive(Clone, Default)]
+pub struct Wakeups {
+    state: Arc<WakeupState>,
+}
+
+#[derive(Default)]
+struct WakeupState {
+    topics: Mutex<HashMap<String, Arc<Topic>>>,
+    closed: AtomicBool,
+}
+
+// Bumped on every notify, so a waiter can tell a wakeup from a spurious one.
+#[derive(Default)]
+struct Topic {
+    generation: Mutex<u64>,
+    changed: Condvar,
+}
+
+impl Wakeups {
+    pub fn new() -> Wakeups {
+        Wakeups::default()
+    }
+
+    // Blocks until `topic` is notified or `timeout` passes. Returns whether
+    // it was notified.
+    pub fn wait_for(&self, topic: &str, timeout: Duration) -> bool {
+        if self.is_closed() {
+            return false;
+        }
+        let waiting = Arc::clone(self.state.topics.lock().unwrap().entry(topic.to_string()).or_default());
+        let deadline = Instant::now() + timeout;
+        let mut generation = waiting.generation.lock().unwrap();
+        let started = *generation;
+        while *generation == started && !self.is_closed() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            generation = waiting.changed.wait_timeout(generation, deadline - now).unwrap().0;
+        }
+        let notified = *generation != started;
+        drop(generation);
+        self.forget_if_unused(topic, waiting);
+        notified
+    }
+
+    // Wakes everything waiting on `topic`, and returns how many that was.
+    pub fn notify(&self, topic: &str) -> usize {
+        let Some(waiting) = self.state.topics.lock().unwrap().get(topic).cloned() else {
+            return 0;
+        };
+        // The map and this function hold the other two references.
+        let waiters = Arc::strong_count(&waiting) - 2;
+        *waiting.generation.lock().unwrap() += 1;
+        waiting.changed.notify_all();
+        waiters
+    }
+
+    // How many handlers are parked on `topic` right now.
+    pub fn waiting(&self, topic: &str) -> usize {
+        self.state.topics.lock().unwrap().get(topic).map_or(0, |waiting| Arc::strong_count(waiting) - 1)
+    }
+
+    // Wakes every waiter on every topic and makes later waits return
+    // straight away.
+    pub fn close(&self) {
+        self.state.closed.store(true, Ordering::SeqCst);
+        let topics: Vec<Arc<Topic>> = self.state.topics.lock().unwrap().values().cloned().collect();
+        for waiting in topics {
+            // Taking the lock means no waiter is between checking `closed`
+            // and going to sleep.
+            drop(waiting.generation.lock().unwrap());
+            waiting.changed.notify_all();
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.closed.load(Ordering::SeqCst)
+    }
+
+    // Drops the topic once nobody is waiting on it, so the map only holds
+    // topics in use.
+    fn forget_if_unused(&self, topic: &str, waiting: Arc<Topic>) {
+        let mut topics = self.state.topics.lock().unwrap();
+        drop(waiting);
+        if topics.get(topic).is_some_and(|waiting| Arc::strong_count(waiting) == 1) {
+            topics.remove(topic);
+        }
+    }
+}
@@ -0,0 +1,388 @@
+// Turns Markdown into HTML, for `StaticDir::markdown`. It covers what
+// documentation tends to use: headings, paragraphs, emphasis, code spans and
+// fenced blocks, links and images, lists (nested by indenting), blockquotes
+// and horizontal rules. Raw HTML in the source is escaped rather than passed
+// through, so a page can't inject script.
+pub fn to_html(markdown: &str) -> String {
+    let lines: Vec<&str> = markdown.lines().collect();
+    let mut html = String::new();
+    render_blocks(&lines, &mut html);
+    html
+}
+
+// The text of the first level-one heading, for the page title.
+pub fn title(markdown: &str) -> Option<String> {
+    markdown.lines()
+        .find_map(|line| line.trim_start().strip_prefix("# "))
+        .map(|title| strip_inline(title.trim().trim_end_matches('#').trim()))
+}
+
+fn render_blocks(lines: &[&str], html: &mut String) {
+    let mut index = 0;
+    let mut paragraph: Vec<&str> = vec![];
+    while index < lines.len() {
+        let line = lines[index];
+        let trimmed = line.trim_start();
+        let indent = line.len() - trimmed.len();
+
+        if trimmed.is_empty() {
+            flush_paragraph(&mut paragraph, html);
+            index += 1;
+        } else if let Some(fence) = fence(trimmed) {
+            flush_paragraph(&mut paragraph, html);
+            let language = trimmed[fence.len()..].trim();
+            let end = lines[index + 1..].iter()
+                .position(|line| line.trim_start().starts_with(fence))
+                .map_or(lines.len(), |end| index + 1 + end);
+            match language.split_whitespace().next() {
+                Some(language) => html.push_str(&format!("<pre><code class=\"language-{}\">", escape(language))),
+                None => html.push_str("<pre><code>"),
+            }
+            for line in &lines[index + 1..end] {
+                html.push_str(&escape(line));
+                html.push('\n');
+            }
+            html.push_str("</code></pre>\n");
+            index = end + 1;
+        } else if indent >= 4 && paragraph.is_empty() {
+            let end = lines[index..].iter()
+                .position(|line| !line.trim().is_empty() && !line.starts_with("    ") && !line.starts_with('\t'))
+                .map_or(lines.len(), |end| index + end);
+            let mut code: Vec<&str> = lines[index..end].iter()
+                .map(|line| line.strip_prefix("    ").or_else(|| line.strip_prefix('\t')).unwrap_or(""))
+                .collect();
+            while code.last().is_some_and(|line| line.is_empty()) {
+                code.pop();
+            }
+            html.push_str("<pre><code>");
+            for line in code {
+                html.push_str(&escape(line));
+                html.push('\n');
+            }
+            html.push_str("</code></pre>\n");
+            index = end;
+        } else if let Some((level, text)) = heading(trimmed) {
+            flush_paragraph(&mut paragraph, html);
+            html.push_str(&format!("<h{level} id=\"{}\">{}</h{level}>\n", slug(text), inline(text)));
+            index += 1;
+        } else if is_rule(trimmed) {
+            flush_paragraph(&mut paragraph, html);
+            html.push_str("<hr>\n");
+            index += 1;
+        } else if trimmed.starts_with('>') {
+            flush_paragraph(&mut paragraph, html);
+            let end = lines[index..].iter()
+                .position(|line| !line.trim_start().starts_with('>'))
+                .map_or(lines.len(), |end| index + end);
+            let quoted: Vec<&str> = lines[index..end].iter()
+                .map(|line| {
+                    let line = line.trim_start().trim_start_matches('>');
+                    line.strip_prefix(' ').unwrap_or(line)
+                })
+                .collect();
+            html.push_str("<blockquote>\n");
+            render_blocks(&quoted, html);
+            html.push_str("</blockquote>\n");
+            index = end;
+        } else if let Some(ordered) = list_marker(trimmed).map(|(ordered, _)| ordered) {
+            flush_paragraph(&mut paragraph, html);
+            index = render_list(lines, index, indent, ordered, html);
+        } else {
+            paragraph.push(line);
+            index += 1;
+        }
+    }
+    flush_paragraph(&mut paragraph, html);
+}
+
+fn flush_paragraph(paragraph: &mut Vec<&str>, html: &mut String) {
+    if paragraph.is_empty() {
+        return;
+    }
+    html.push_str("<p>");
+    for (index, line) in paragraph.iter().enumerate() {
+        if index > 0 {
+            html.push('\n');
+        }
+        // Two trailing spaces make a line break.
+        let hard_break = line.ends_with("  ") && index + 1 < paragraph.len();
+        html.push_str(&inline(line.trim()));
+        if hard_break {
+            html.push_str("<br>");
+        }
+    }
+    html.push_str("</p>\n");
+    paragraph.clear();
+}
+
+// Renders the list starting at `start`, returning the line after it. Each
+// item takes the lines indented under it, which are rendered as blocks of
+// their own, so nested lists and multi-paragraph items work.
+fn render_list(lines: &[&str], start: usize, indent: usize, ordered: bool, html: &mut String) -> usize {
+    let mut items: Vec<Vec<&str>> = vec![];
+    let mut loose = false;
+    let mut index = start;
+    while index < lines.len() {
+        let line = lines[index];
+        let trimmed = line.trim_start();
+        let line_indent = line.len() - trimmed.len();
+        match list_marker(trimmed) {
+            Some((item_ordered, content)) if line_indent == indent && item_ordered == ordered => {
+                items.push(vec![content]);
+            }
+            _ if trimmed.is_empty() => {
+                // A blank line inside the list only counts if it goes on.
+                let continues = lines[index + 1..].iter()
+                    .find(|line| !line.trim().is_empty())
+                    .is_some_and(|next| {
+                        let next_indent = next.len() - next.trim_start().len();
+                        let same_list = list_marker(next.trim_start()).is_some_and(|(next_ordered, _)| next_ordered == ordered);
+                        next_indent > indent || (next_indent == indent && same_list)
+                    });
+                if !continues {
+                    break;
+                }
+                loose = true;
+                if let Some(item) = items.last_mut() {
+                    item.push("");
+                }
+            }
+            _ if line_indent > indent => {
+                let content = &line[(indent + 2).min(line_indent)..];
+                if let Some(item) = items.last_mut() {
+                    item.push(content);
+                }
+            }
+            _ => break,
+        }
+        index += 1;
+    }
+
+    html.push_str(if ordered { "<ol>\n" } else { "<ul>\n" });
+    for item in items {
+        html.push_str("<li>");
+        let simple = !loose && item.iter().skip(1).all(|line| list_marker(line.trim_start()).is_none() && !line.trim().is_empty());
+        if simple {
+            html.push_str(&inline(&item.iter().map(|line| line.trim()).collect::<Vec<_>>().join("\n")));
+        } else if !loose {
+            // A tight item with a nested list: the first line stays bare.
+            let nested = item.iter().position(|line| list_marker(line.trim_start()).is_some()).unwrap_or(item.len());
+            html.push_str(&inline(&item[..nested].iter().map(|line| line.trim()).collect::<Vec<_>>().join("\n")));
+            html.push('\n');
+            render_blocks(&item[nested..], html);
+        } else {
+            html.push('\n');
+            render_blocks(&item, html);
+        }
+        html.push_str("</li>\n");
+    }
+    html.push_str(if ordered { "</ol>\n" } else { "</ul>\n" });
+    index
+}
+
+// Whether the line starts a list item, and if so whether it's numbered and
+// what follows the marker.
+fn list_marker(line: &str) -> Option<(bool, &str)> {
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(content) = line.strip_prefix(bullet) {
+            return Some((false, content));
+        }
+    }
+    let digits = line.bytes().take_while(u8::is_ascii_digit).count();
+    if (1..10).contains(&digits) {
+        let rest = &line[digits..];
+        if let Some(content) = rest.strip_prefix(". ").or_else(|| rest.strip_prefix(") ")) {
+            return Some((true, content));
+        }
+    }
+    None
+}
+
+fn fence(line: &str) -> Option<&'static str> {
+    ["```", "~~~"].into_iter().find(|fence| line.starts_with(fence))
+}
+
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.bytes().take_while(|&byte| byte == b'#').count();
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+    let rest = &line[level..];
+    if !rest.is_empty() && !rest.starts_with(' ') {
+        return None;
+    }
+    Some((level, rest.trim().trim_end_matches('#').trim_end()))
+}
+
+fn is_rule(line: &str) -> bool {
+    let marks: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+    marks.len() >= 3 && ["-", "*", "_"].iter().any(|mark| marks.chars().all(|c| c.to_string() == *mark))
+}
+
+// A heading's id, for linking to it: "Getting Started!" becomes
+// "getting-started".
+fn slug(text: &str) -> String {
+    let plain = strip_inline(text).to_lowercase();
+    let mut slug = String::new();
+    for c in plain.chars() {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if (c == ' ' || c == '-') && !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+// Inline markup with the markup itself dropped, for titles and ids.
+fn strip_inline(text: &str) -> String {
+    let html = inline(text);
+    let mut plain = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => plain.push(c),
+            _ => {}
+        }
+    }
+    plain.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&amp;", "&")
+}
+
+fn inline(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut html = String::new();
+    let mut index = 0;
+    while index < chars.len() {
+        let c = chars[index];
+        match c {
+            '\\' if chars.get(index + 1).is_some_and(|next| next.is_ascii_punctuation()) => {
+                html.push_str(&escape(&chars[index + 1].to_string()));
+                index += 2;
+            }
+            '`' => {
+                let ticks = chars[index..].iter().take_while(|&&c| c == '`').count();
+                let fence: String = "`".repeat(ticks);
+                let rest: String = chars[index + ticks..].iter().collect();
+                match rest.find(&fence) {
+                    Some(end) => {
+                        html.push_str(&format!("<code>{}</code>", escape(rest[..end].trim())));
+                        index += ticks + rest[..end].chars().count() + ticks;
+                    }
+                    None => {
+                        html.push_str(&fence);
+                        index += ticks;
+                    }
+                }
+            }
+            '!' if chars.get(index + 1) == Some(&'[') => match link(&chars, index + 1) {
+                Some((alt, url, end)) => {
+                    html.push_str(&format!("<img src=\"{}\" alt=\"{}\">", escape(&safe_url(&url)), escape(&strip_inline(&alt))));
+                    index = end;
+                }
+                None => {
+                    html.push('!');
+                    index += 1;
+                }
+            },
+            '[' => match link(&chars, index) {
+                Some((label, url, end)) => {
+                    html.push_str(&format!("<a href=\"{}\">{}</a>", escape(&safe_url(&url)), inline(&label)));
+                    index = end;
+                }
+                None => {
+                    html.push('[');
+                    index += 1;
+                }
+            },
+            '<' => {
+                // <https://example.com> autolinks; anything else is escaped.
+                let rest: String = chars[index + 1..].iter().collect();
+                match rest.find('>').map(|end| &rest[..end]) {
+                    Some(url) if (url.starts_with("http://") || url.starts_with("https://")) && !url.contains(' ') => {
+                        html.push_str(&format!("<a href=\"{0}\">{0}</a>", escape(url)));
+                        index += url.chars().count() + 2;
+                    }
+                    _ => {
+                        html.push_str("&lt;");
+                        index += 1;
+                    }
+                }
+            }
+            '*' | '_' => {
+                let run = chars[index..].iter().take_while(|&&next| next == c).count().min(2);
+                let delimiter: String = c.to_string().repeat(run);
+                // Underscores inside words (snake_case) aren't emphasis.
+                let intraword = c == '_' && index > 0 && chars[index - 1].is_alphanumeric();
+                let rest: String = chars[index + run..].iter().collect();
+                let close = rest.find(&delimiter).filter(|&end| {
+                    end > 0 && !rest.starts_with(' ') && !rest[..end].ends_with(' ')
+                });
+                match close {
+                    Some(end) if !intraword => {
+                        let tag = if run == 2 { "strong" } else { "em" };
+                        html.push_str(&format!("<{tag}>{}</{tag}>", inline(&rest[..end])));
+                        index += run + rest[..end].chars().count() + run;
+                    }
+                    _ => {
+                        html.push_str(&delimiter);
+                        index += run;
+                    }
+                }
+            }
+            c => {
+                html.push_str(&escape(&c.to_string()));
+                index += 1;
+            }
+        }
+    }
+    html
+}
+
+// A `[label](url)` starting at `start`: the label, the url and where it ends.
+fn link(chars: &[char], start: usize) -> Option<(String, String, usize)> {
+    let mut depth = 0;
+    let mut close = None;
+    for (offset, &c) in chars[start..].iter().enumerate() {
+        match c {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    close = Some(start + offset);
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    let close = close?;
+    if chars.get(close + 1) != Some(&'(') {
+        return None;
+    }
+    // Urls may hold balanced parentheses of their own.
+    let mut depth = 0;
+    let end = close + 2 + chars[close + 2..].iter().position(|&c| {
+        depth += match c { '(' => 1, ')' => -1, _ => 0 };
+        depth < 0
+    })?;
+    let label: String = chars[start + 1..close].iter().collect();
+    let target: String = chars[close + 2..end].iter().collect();
+    // Drop any "title" after the url.
+    let url = target.split_whitespace().next().unwrap_or_default().to_string();
+    Some((label, url, end + 1))
+}
+
+// Keeps script out of hrefs and srcs.
+fn safe_url(url: &str) -> String {
+    let scheme = url.split(':').next().unwrap_or_default().to_ascii_lowercase();
+    if url.contains(':') && ["javascript", "vbscript", "data"].contains(&scheme.trim()) {
+        return "#".to_string();
+    }
+    url.to_string()
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
@@ -0,0 +1,400 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use crate::{
+    http::{Request, Response, StatusCode},
+    log::json_string,
+    middleware::{Middleware, Next},
+};
+
+// Eight buckets per power of two, so a quantile is never more than about
+// 12% above the true value, covering everything a u64 can hold.
+const SUB_BITS: u32 = 3;
+const SUB_BUCKETS: u64 = 1 << SUB_BITS;
+const BUCKETS: usize = ((64 - SUB_BITS + 1) as u64 * SUB_BUCKETS) as usize;
+
+// A fixed set of logarithmic buckets counted with atomics, in the spirit of
+// HdrHistogram: recording is a couple of relaxed adds and never locks.
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, value: u64) {
+        self.buckets[Histogram::index(value)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed)
+    }
+
+    // The value `quantile` (0.0 to 1.0) of recordings are at or below, to
+    // within a bucket; 0 if nothing has been recorded.
+    pub fn quantile(&self, quantile: f64) -> u64 {
+        let counts: Vec<u64> = self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Histogram::upper_bound(index);
+            }
+        }
+        Histogram::upper_bound(BUCKETS - 1)
+    }
+
+    // Values below SUB_BUCKETS get a bucket each; above that, each power of
+    // two is split into SUB_BUCKETS by the bits after the leading one.
+    fn index(value: u64) -> usize {
+        if value < SUB_BUCKETS {
+            return value as usize;
+        }
+        let power = 63 - value.leading_zeros();
+        let sub = (value >> (power - SUB_BITS)) & (SUB_BUCKETS - 1);
+        ((power - SUB_BITS + 1) as u64 * SUB_BUCKETS + sub) as usize
+    }
+
+    fn upper_bound(index: usize) -> u64 {
+        let index = index as u64;
+        if index < SUB_BUCKETS {
+            return index;
+        }
+        let shift = (index / SUB_BUCKETS - 1) as u32;
+        let sub = index % SUB_BUCKETS;
+        ((SUB_BUCKETS + sub + 1) << shift).wrapping_sub(1)
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+// Latency and response size for one route.
+#[derive(Default)]
+pub struct RouteStats {
+    // In microseconds.
+    pub latency: Histogram,
+    pub size: Histogram,
+    pub errors: AtomicU64,
+}
+
+// Records how long each route takes and how much it sends back. Add it as
+// middleware and serve `handler()` somewhere to have Prometheus scrape it:
+//
+//     let metrics = Metrics::new();
+//     server.add_middleware(metrics.clone());
+//     server.add_handler("/metrics", metrics.handler());
+//
+// Routes are keyed by what they were registered as ("/users/{id}", not each
+// user's path) so the number of series stays bounded.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    routes: Arc<RwLock<BTreeMap<String, Arc<RouteStats>>>>,
+    // Keyed by name and rendered labels, so each name's series sort
+    // together.
+    counters: Arc<RwLock<BTreeMap<CounterKey, Arc<AtomicU64>>>>,
+    // In microseconds; see `record_queue_wait`.
+    queue_wait: Arc<Histogram>,
+}
+
+type CounterKey = (String, String);
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    // The stats for `route`, or None if it hasn't had a request yet.
+    pub fn route(&self, route: &str) -> Option<Arc<RouteStats>> {
+        self.routes.read().unwrap().get(route).cloned()
+    }
+
+    pub fn routes(&self) -> Vec<(String, Arc<RouteStats>)> {
+        self.routes.read().unwrap().iter().map(|(route, stats)| (route.clone(), Arc::clone(stats))).collect()
+    }
+
+    // Adds one to a counter of the app's own, rendered along with the
+    // route stats: `increment("jobs_total", &[("queue", "mail")])`.
+    pub fn increment(&self, name: &str, labels: &[(&str, &str)]) {
+        let labels: Vec<String> = labels.iter().map(|(label, value)| format!("{label}=\"{}\"", escape_label(value))).collect();
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels.join(",")) };
+        let key = (name.to_string(), labels);
+        if let Some(counter) = self.counters.read().unwrap().get(&key) {
+            counter.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.counters.write().unwrap().entry(key).or_default().fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Records how long a connection waited for a worker. The server does
+    // this itself when given the metrics with `ServerBuilder::metrics`.
+    pub fn record_queue_wait(&self, wait: Duration) {
+        self.queue_wait.record(wait.as_micros() as u64);
+    }
+
+    pub fn queue_wait(&self) -> &Histogram {
+        &self.queue_wait
+    }
+
+    fn stats_for(&self, route: &str) -> Arc<RouteStats> {
+        if let Some(stats) = self.route(route) {
+            return stats;
+        }
+        Arc::clone(self.routes.write().unwrap().entry(route.to_string()).or_default())
+    }
+
+    fn record(&self, route: &str, elapsed: Duration, response: &Response) {
+        let stats = self.stats_for(route);
+        stats.latency.record(elapsed.as_micros() as u64);
+        stats.size.record(response.body_length());
+        if response.status_code.code() >= 500 {
+            stats.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Everything recorded so far in the Prometheus text format, as summaries
+    // with p50, p90 and p99.
+    pub fn render(&self) -> String {
+        const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+        let mut output = String::new();
+        let routes = self.routes();
+
+        output.push_str("# TYPE web_server_request_duration_seconds summary\n");
+        for (route, stats) in &routes {
+            let route = escape_label(route);
+            for quantile in QUANTILES {
+                let seconds = stats.latency.quantile(quantile) as f64 / 1e6;
+                let _ = writeln!(output, "web_server_request_duration_seconds{{route=\"{route}\",quantile=\"{quantile}\"}} {seconds}");
+            }
+            let sum = stats.latency.sum() as f64 / 1e6;
+            let _ = writeln!(output, "web_server_request_duration_seconds_sum{{route=\"{route}\"}} {sum}");
+            let _ = writeln!(output, "web_server_request_duration_seconds_count{{route=\"{route}\"}} {}", stats.latency.count());
+        }
+
+        output.push_str("# TYPE web_server_response_size_bytes summary\n");
+        for (route, stats) in &routes {
+            let route = escape_label(route);
+            for quantile in QUANTILES {
+                let _ = writeln!(output, "web_server_response_size_bytes{{route=\"{route}\",quantile=\"{quantile}\"}} {}", stats.size.quantile(quantile));
+            }
+            let _ = writeln!(output, "web_server_response_size_bytes_sum{{route=\"{route}\"}} {}", stats.size.sum());
+            let _ = writeln!(output, "web_server_response_size_bytes_count{{route=\"{route}\"}} {}", stats.size.count());
+        }
+
+        output.push_str("# TYPE web_server_errors_total counter\n");
+        for (route, stats) in &routes {
+            let _ = writeln!(output, "web_server_errors_total{{route=\"{}\"}} {}", escape_label(route), stats.errors.load(Ordering::Relaxed));
+        }
+
+        if self.queue_wait.count() > 0 {
+            output.push_str("# TYPE web_server_queue_wait_seconds summary\n");
+            for quantile in QUANTILES {
+                let seconds = self.queue_wait.quantile(quantile) as f64 / 1e6;
+                let _ = writeln!(output, "web_server_queue_wait_seconds{{quantile=\"{quantile}\"}} {seconds}");
+            }
+            let _ = writeln!(output, "web_server_queue_wait_seconds_sum {}", self.queue_wait.sum() as f64 / 1e6);
+            let _ = writeln!(output, "web_server_queue_wait_seconds_count {}", self.queue_wait.count());
+        }
+
+        let mut previous = None;
+        for ((name, labels), counter) in self.counters.read().unwrap().iter() {
+            if previous != Some(name) {
+                let _ = writeln!(output, "# TYPE {name} counter");
+                previous = Some(name);
+            }
+            let _ = writeln!(output, "{name}{labels} {}", counter.load(Ordering::Relaxed));
+        }
+        output
+    }
+
+    // A handler serving `render()`.
+    pub fn handler(&self) -> impl Fn(&Request) -> Response + Send + Sync + 'static {
+        let metrics = self.clone();
+        move |_| {
+            Response::new(StatusCode::Ok, &metrics.render())
+                .with_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+        }
+    }
+}
+
+impl Middleware for Metrics {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let started = Instant::now();
+        let response = next(request);
+        let route = request.route().unwrap_or("unmatched").to_string();
+        self.record(&route, started.elapsed(), &response);
+        response
+    }
+}
+
+// Bytes each route and each client has sent and been sent, for billing APIs
+// by usage. Clients are told apart by `client_key`, typically pulling an API
+// key or user out of the request; requests it gives no key for are only
+// counted against their route.
+//
+//     let usage = Usage::new().client_key(|request| request.header("X-Api-Key").map(String::from));
+//     server.add_middleware(usage.clone());
+//     server.add_handler("/usage", usage.handler());
+//
+// Bytes are bodies only, not heads. Streamed responses count as empty, since
+// the middleware never sees what they wrote.
+#[derive(Clone, Default)]
+pub struct Usage {
+    client_key: Option<ClientKey>,
+    routes: Arc<RwLock<BTreeMap<String, Arc<ByteCounters>>>>,
+    clients: Arc<RwLock<BTreeMap<String, Arc<ByteCounters>>>>,
+}
+
+pub(crate) type ClientKey = Arc<dyn Fn(&Request) -> Option<String> + Send + Sync>;
+
+#[derive(Default)]
+struct ByteCounters {
+    requests: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+// What one route or client has used so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ByteCounts {
+    pub requests: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl ByteCounters {
+    fn add(&self, bytes_in: u64, bytes_out: u64) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+    }
+
+    fn counts(&self) -> ByteCounts {
+        ByteCounts {
+            requests: self.requests.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Usage {
+    pub fn new() -> Usage {
+        Usage::default()
+    }
+
+    pub fn client_key<F>(mut self, key: F) -> Usage
+    where
+        F: Fn(&Request) -> Option<String> + Send + Sync + 'static,
+    {
+        self.client_key = Some(Arc::new(key));
+        self
+    }
+
+    pub fn route(&self, route: &str) -> ByteCounts {
+        self.routes.read().unwrap().get(route).map(|counters| counters.counts()).unwrap_or_default()
+    }
+
+    pub fn client(&self, key: &str) -> ByteCounts {
+        self.clients.read().unwrap().get(key).map(|counters| counters.counts()).unwrap_or_default()
+    }
+
+    pub fn routes(&self) -> Vec<(String, ByteCounts)> {
+        Usage::snapshot(&self.routes)
+    }
+
+    pub fn clients(&self) -> Vec<(String, ByteCounts)> {
+        Usage::snapshot(&self.clients)
+    }
+
+    // Zeroes everything, e.g. after each billing period has been read out.
+    pub fn reset(&self) {
+        self.routes.write().unwrap().clear();
+        self.clients.write().unwrap().clear();
+    }
+
+    fn snapshot(counters: &RwLock<BTreeMap<String, Arc<ByteCounters>>>) -> Vec<(String, ByteCounts)> {
+        counters.read().unwrap().iter().map(|(key, counters)| (key.clone(), counters.counts())).collect()
+    }
+
+    fn counters(map: &RwLock<BTreeMap<String, Arc<ByteCounters>>>, key: &str) -> Arc<ByteCounters> {
+        if let Some(counters) = map.read().unwrap().get(key) {
+            return Arc::clone(counters);
+        }
+        Arc::clone(map.write().unwrap().entry(key.to_string()).or_default())
+    }
+
+    // Everything counted so far as JSON: {"routes": {...}, "clients": {...}},
+    // each entry holding requests, bytes_in and bytes_out.
+    pub fn render(&self) -> String {
+        let object = |entries: Vec<(String, ByteCounts)>| {
+            let fields: Vec<String> = entries.iter().map(|(key, counts)| {
+                format!(
+                    "{}:{{\"requests\":{},\"bytes_in\":{},\"bytes_out\":{}}}",
+                    json_string(key),
+                    counts.requests,
+                    counts.bytes_in,
+                    counts.bytes_out
+                )
+            }).collect();
+            format!("{{{}}}", fields.join(","))
+        };
+        format!("{{\"routes\":{},\"clients\":{}}}", object(self.routes()), object(self.clients()))
+    }
+
+    // A handler serving `render()`.
+    pub fn handler(&self) -> impl Fn(&Request) -> Response + Send + Sync + 'static {
+        let usage = self.clone();
+        move |_| Response::new(StatusCode::Ok, &usage.render()).with_header("Content-Type", "application/json")
+    }
+}
+
+impl Middleware for Usage {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        // Streamed and spilled bodies aren't in `request.body`, but their
+        // length was declared up front.
+        let bytes_in = request.content_length().unwrap_or(request.body.len() as u64);
+        let client = self.client_key.as_ref().and_then(|key| key(request));
+        let response = next(request);
+        let bytes_out = response.body_length();
+
+        let route = request.route().unwrap_or("unmatched");
+        Usage::counters(&self.routes, route).add(bytes_in, bytes_out);
+        if let Some(client) = client {
+            Usage::counters(&self.clients, &client).add(bytes_in, bytes_out);
+        }
+        response
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,1406 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use crate::{
+    clock::{self, Clock},
+    compress,
+    http::{GeoInfo, HttpMethod, Request, Response, StatusCode},
+    log::{self, LogFile},
+    md5,
+    random,
+    server::{Connection, Handler},
+    sha256,
+    static_files::percent_decode,
+};
+
+// The rest of the chain: the remaining middleware and finally the handler.
+pub type Next<'a> = &'a dyn Fn(&mut Request) -> Response;
+
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &mut Request, next: Next) -> Response;
+}
+
+pub(crate) fn run(middleware: &[Arc<dyn Middleware>], request: &mut Request, handler: &Handler) -> Response {
+    match middleware.split_first() {
+        Some((first, rest)) => first.handle(request, &|request| run(rest, request, handler)),
+        None => handler(request),
+    }
+}
+
+// Logs one line per request once its response is ready, to stdout or to a
+// LogFile.
+pub struct AccessLog {
+    file: Option<LogFile>,
+    format: LogFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    // `127.0.0.1 GET /path 200 512B 3ms`
+    Text,
+    // One JSON object per line, for log pipelines like Loki or Elastic.
+    Json,
+}
+
+impl AccessLog {
+    pub fn new() -> AccessLog {
+        AccessLog { file: None, format: LogFormat::Text }
+    }
+
+    pub fn to_file(mut self, file: LogFile) -> AccessLog {
+        self.file = Some(file);
+        self
+    }
+
+    pub fn format(mut self, format: LogFormat) -> AccessLog {
+        self.format = format;
+        self
+    }
+
+    fn json_line(request: &Request, response: &Response, path: &str, method: &HttpMethod, elapsed: Duration) -> String {
+        let optional = |value: Option<&str>| value.map_or("null".to_string(), log::json_string);
+        // A request ID set by a proxy in front, or by the handler.
+        let request_id = request.header("X-Request-Id").or_else(|| response.header("X-Request-Id"));
+        format!(
+            "{{\"timestamp\":{},\"method\":\"{method:?}\",\"path\":{},\"status\":{},\"duration_ms\":{:.3},\"bytes\":{},\"remote_ip\":{},\"request_id\":{},\"user_agent\":{},\"country\":{}}}",
+            log::json_string(&log::timestamp(SystemTime::now())),
+            log::json_string(path),
+            response.status_code.code(),
+            elapsed.as_secs_f64() * 1000.0,
+            response.body_length(),
+            optional(request.client_addr.map(|address| address.ip().to_string()).as_deref()),
+            optional(request_id),
+            optional(request.header("User-Agent")),
+            optional(request.geo().and_then(|geo| geo.country.as_deref())),
+        )
+    }
+}
+
+impl Default for AccessLog {
+    fn default() -> AccessLog {
+        AccessLog::new()
+    }
+}
+
+impl Middleware for AccessLog {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let started = Instant::now();
+        let (method, path) = (request.method.clone(), request.path.clone());
+        let response = next(request);
+        let line = match self.format {
+            LogFormat::Text => {
+                let client = request.client_addr.map_or("-".to_string(), |address| address.ip().to_string());
+                format!(
+                    "{client} {method:?} {path} {} {}B {}ms",
+                    response.status_code.code(),
+                    response.body_length(),
+                    started.elapsed().as_millis()
+                )
+            }
+            LogFormat::Json => AccessLog::json_line(request, &response, &path, &method, started.elapsed()),
+        };
+        match &self.file {
+            Some(file) => file.write_line(&line),
+            None if log::enabled(log::Level::Info) => println!("{line}"),
+            None => {}
+        }
+        response
+    }
+}
+
+// Lets HTML forms, which can only GET or POST, reach PUT, PATCH and DELETE
+// handlers: a POST carrying an `X-HTTP-Method-Override` header or a `_method`
+// form field is handled as that method instead. Other methods are never
+// overridden, so a link or GET can't be turned into a DELETE.
+pub struct MethodOverride {
+    header: bool,
+    form_field: bool,
+}
+
+impl MethodOverride {
+    pub fn new() -> MethodOverride {
+        MethodOverride { header: true, form_field: true }
+    }
+
+    pub fn header(mut self, enabled: bool) -> MethodOverride {
+        self.header = enabled;
+        self
+    }
+
+    pub fn form_field(mut self, enabled: bool) -> MethodOverride {
+        self.form_field = enabled;
+        self
+    }
+
+    fn requested(&self, request: &Request) -> Option<String> {
+        if self.header {
+            if let Some(method) = request.header("X-HTTP-Method-Override") {
+                return Some(method.to_string());
+            }
+        }
+        let is_form = request.header("Content-Type")
+            .is_some_and(|content_type| content_type.starts_with("application/x-www-form-urlencoded"));
+        if self.form_field && is_form {
+            return request.body.split('&')
+                .filter_map(|field| field.split_once('='))
+                .find(|(name, _)| *name == "_method")
+                .map(|(_, method)| percent_decode(&method.replace('+', " ")));
+        }
+        None
+    }
+}
+
+impl Default for MethodOverride {
+    fn default() -> MethodOverride {
+        MethodOverride::new()
+    }
+}
+
+impl Middleware for MethodOverride {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        if request.method == HttpMethod::POST {
+            let method = match self.requested(request).map(|method| method.trim().to_ascii_uppercase()).as_deref() {
+                Some("PUT") => Some(HttpMethod::PUT),
+                Some("PATCH") => Some(HttpMethod::PATCH),
+                Some("DELETE") => Some(HttpMethod::DELETE),
+                _ => None,
+            };
+            if let Some(method) = method {
+                request.method = method;
+            }
+        }
+        next(request)
+    }
+}
+
+// Sends a 103 Early Hints for a page's assets before its handler runs, so
+// the browser fetches them in the meantime, and repeats the links on the
+// final response for clients that ignored the hints:
+//
+//     server.add_handler("/", home).with(EarlyHints::new(&[
+//         "</app.css>; rel=preload; as=style",
+//         "</app.js>; rel=preload; as=script",
+//     ]));
+//
+// Only GETs get hints; anything else goes straight through.
+pub struct EarlyHints {
+    links: Vec<String>,
+}
+
+impl EarlyHints {
+    pub fn new(links: &[&str]) -> EarlyHints {
+        EarlyHints { links: links.iter().map(|link| link.to_string()).collect() }
+    }
+}
+
+impl Middleware for EarlyHints {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        if request.method != HttpMethod::GET {
+            return next(request);
+        }
+        let links: Vec<&str> = self.links.iter().map(String::as_str).collect();
+        if let Err(error) = request.early_hints(&links) {
+            eprintln!("Error sending early hints for {}: {error}", request.path);
+        }
+        let mut response = next(request);
+        if response.header("Link").is_none() && response.status_code.code() < 300 {
+            for link in &self.links {
+                response.headers.push(("Link".to_string(), link.clone()));
+            }
+        }
+        response
+    }
+}
+
+// Sets Cache-Control on successful responses that don't set their own:
+//
+//     server.add_static_dir("/assets", StaticDir::new("dist/assets"))
+//         .with(CacheControl::new("public, max-age=31536000, immutable"));
+pub struct CacheControl {
+    value: String,
+}
+
+impl CacheControl {
+    pub fn new(value: &str) -> CacheControl {
+        CacheControl { value: value.to_string() }
+    }
+}
+
+impl Middleware for CacheControl {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let mut response = next(request);
+        let code = response.status_code.code();
+        if (code < 300 || code == 304) && response.header("Cache-Control").is_none() {
+            response.set_header("Cache-Control", &self.value);
+        }
+        response
+    }
+}
+
+// Gzips responses for clients that accept it. Small bodies and types that
+// are already compressed (images, video, archives) are left alone.
+pub struct Compression {
+    min_size: usize,
+}
+
+impl Compression {
+    pub fn new() -> Compression {
+        Compression { min_size: 256 }
+    }
+
+    pub fn min_size(mut self, bytes: usize) -> Compression {
+        self.min_size = bytes;
+        self
+    }
+
+    fn accepts_gzip(request: &Request) -> bool {
+        request.header("Accept-Encoding").is_some_and(|accepted| {
+            accepted.split(',').any(|encoding| {
+                let mut parts = encoding.split(';').map(str::trim);
+                parts.next() == Some("gzip") && !parts.any(|parameter| parameter.replace(' ', "") == "q=0")
+            })
+        })
+    }
+
+    fn compressible(content_type: Option<&str>) -> bool {
+        match content_type {
+            Some(content_type) => {
+                content_type.starts_with("text/")
+                    || ["json", "javascript", "xml", "svg", "wasm"].iter().any(|kind| content_type.contains(kind))
+            }
+            None => true,
+        }
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new()
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let accepts_gzip = Compression::accepts_gzip(request);
+        let mut response = next(request);
+        if response.body.len() < self.min_size
+            || response.file.is_some()
+            // A Content-Range counts bytes of the body as it is.
+            || response.status_code == StatusCode::PartialContent
+            || response.header("Content-Encoding").is_some()
+            || !Compression::compressible(response.header("Content-Type"))
+        {
+            return response;
+        }
+
+        // Caches must keep the two variants apart whether or not this client
+        // got the compressed one.
+        response.headers.push(("Vary".to_string(), "Accept-Encoding".to_string()));
+        if accepts_gzip {
+            response.body = compress::gzip(&response.body);
+            response.headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+        }
+        response
+    }
+}
+
+// Keeps GET responses that say they're cacheable (a `max-age` in their
+// Cache-Control, as `Response::cache_for` sets) and answers repeats from
+// memory until they expire. Responses are stored per variant: requests only
+// share one when they agree on every header its `Vary` names, so gzipped and
+// plain, or English and French, copies are never mixed up. As a shared
+// cache, it never keeps responses that set cookies, or answers to requests
+// with Authorization unless they're marked `public` or have an `s-maxage`.
+// Add it before Compression so it sees the final variants.
+// Clones share their entries, so one can be kept to `clear` the cache.
+#[derive(Clone)]
+pub struct ResponseCache {
+    max_entries: usize,
+    cookie_variants: bool,
+    entries: Arc<Mutex<HashMap<String, Vec<Cached>>>>,
+    clock: Arc<dyn Clock>,
+}
+
+struct Cached {
+    // The headers named by Vary and what this variant's request sent for
+    // each, lowercased names first.
+    vary: Vec<(String, Option<String>)>,
+    response: Response,
+    stored: Instant,
+    expires: Instant,
+}
+
+impl ResponseCache {
+    pub fn new(max_entries: usize) -> ResponseCache {
+        ResponseCache {
+            max_entries,
+            cookie_variants: false,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            clock: clock::system(),
+        }
+    }
+
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> ResponseCache {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    // Drops everything stored, returning how many responses that was.
+    pub fn clear(&self) -> usize {
+        let entries = std::mem::take(&mut *self.entries.lock().unwrap());
+        entries.values().map(Vec::len).sum()
+    }
+
+    // Responses that vary on Cookie are usually per user, so they aren't
+    // cached unless this is turned on.
+    pub fn cookie_variants(mut self, enabled: bool) -> ResponseCache {
+        self.cookie_variants = enabled;
+        self
+    }
+
+    fn request_value(request: &Request, name: &str) -> Option<String> {
+        request.header(name).map(|value| value.replace(' ', ""))
+    }
+
+    // How long `response` may be kept, if at all.
+    fn lifetime(&self, request: &Request, response: &Response, vary: &[String]) -> Option<Duration> {
+        if response.status_code != StatusCode::Ok || response.header("Set-Cookie").is_some() {
+            return None;
+        }
+        let cache_control = response.header("Cache-Control")?.to_ascii_lowercase();
+        let directives: Vec<&str> = cache_control.split(',').map(str::trim).collect();
+        if directives.iter().any(|directive| ["no-store", "no-cache", "private"].contains(directive)) {
+            return None;
+        }
+        if vary.iter().any(|name| name == "*" || (name == "cookie" && !self.cookie_variants)) {
+            return None;
+        }
+        let shared_max_age = directives.iter().find_map(|directive| directive.strip_prefix("s-maxage="));
+        if request.header("Authorization").is_some() && shared_max_age.is_none() && !directives.contains(&"public") {
+            return None;
+        }
+        let max_age = shared_max_age.or_else(|| directives.iter().find_map(|directive| directive.strip_prefix("max-age=")));
+        let max_age: u64 = max_age?.parse().ok()?;
+        (max_age > 0).then(|| Duration::from_secs(max_age))
+    }
+
+    fn lookup(&self, request: &Request) -> Option<Response> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = self.clock.now();
+        let variants = entries.get_mut(&request.path)?;
+        variants.retain(|cached| cached.expires > now);
+        let cached = variants.iter().find(|cached| {
+            cached.vary.iter().all(|(name, value)| ResponseCache::request_value(request, name) == *value)
+        })?;
+        let mut response = cached.response.clone();
+        response.set_header("Age", &now.duration_since(cached.stored).as_secs().to_string());
+        Some(response)
+    }
+
+    fn store(&self, request: &Request, response: &Response) {
+        let vary: Vec<String> = response.headers.iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Vary"))
+            .flat_map(|(_, value)| value.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        let Some(lifetime) = self.lifetime(request, response, &vary) else {
+            return;
+        };
+        let vary = vary.into_iter().map(|name| {
+            let value = ResponseCache::request_value(request, &name);
+            (name, value)
+        }).collect::<Vec<_>>();
+
+        let mut entries = self.entries.lock().unwrap();
+        let now = self.clock.now();
+        if entries.values().map(Vec::len).sum::<usize>() >= self.max_entries {
+            for variants in entries.values_mut() {
+                variants.retain(|cached| cached.expires > now);
+            }
+            entries.retain(|_, variants| !variants.is_empty());
+        }
+        if entries.values().map(Vec::len).sum::<usize>() >= self.max_entries {
+            // Still full: make room by dropping whatever expires soonest.
+            let soonest = entries.iter()
+                .flat_map(|(path, variants)| variants.iter().enumerate().map(move |(index, cached)| (path, index, cached.expires)))
+                .min_by_key(|(_, _, expires)| *expires)
+                .map(|(path, index, _)| (path.clone(), index));
+            match soonest {
+                Some((path, index)) => {
+                    entries.get_mut(&path).unwrap().remove(index);
+                }
+                None => return,
+            }
+        }
+
+        let variants = entries.entry(request.path.clone()).or_default();
+        variants.retain(|cached| cached.vary != vary);
+        variants.push(Cached { vary, response: response.clone(), stored: now, expires: now + lifetime });
+    }
+}
+
+impl Middleware for ResponseCache {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        if request.method != HttpMethod::GET {
+            return next(request);
+        }
+        if let Some(response) = self.lookup(request) {
+            return response;
+        }
+        let response = next(request);
+        self.store(request, &response);
+        response
+    }
+}
+
+// Gives handlers a maximum amount of time. Once it passes the client gets an
+// error response straight away, and whatever the handler eventually returns
+// is thrown away. Handlers can see the deadline on the request and give up
+// early themselves.
+pub struct Timeout {
+    limit: Duration,
+    status: StatusCode,
+    watchdog: Arc<Watchdog>,
+}
+
+impl Timeout {
+    pub fn new(limit: Duration) -> Timeout {
+        Timeout::start(limit, clock::system())
+    }
+
+    // A Timeout going by `clock` instead of real time.
+    pub fn with_clock<C: Clock + 'static>(limit: Duration, clock: C) -> Timeout {
+        Timeout::start(limit, Arc::new(clock))
+    }
+
+    fn start(limit: Duration, clock: Arc<dyn Clock>) -> Timeout {
+        let watchdog = Arc::new(Watchdog {
+            state: Mutex::new(WatchdogState { next_id: 0, watched: vec![], stopped: false }),
+            changed: Condvar::new(),
+            clock,
+        });
+        let thread_watchdog = Arc::clone(&watchdog);
+        thread::Builder::new()
+            .name("webserver-timeouts".to_string())
+            .spawn(move || thread_watchdog.run())
+            .expect("Failed to spawn timeout thread");
+
+        Timeout { limit, status: StatusCode::ServiceUnavailable, watchdog }
+    }
+
+    // The status sent when a handler runs out of time; 503 by default.
+    pub fn status(mut self, status: StatusCode) -> Timeout {
+        self.status = status;
+        self
+    }
+}
+
+impl Middleware for Timeout {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let deadline = self.watchdog.clock.now() + self.limit;
+        request.deadline = Some(request.deadline.map_or(deadline, |current| current.min(deadline)));
+        request.clock = Some(Arc::clone(&self.watchdog.clock));
+
+        let Some(connection) = request.connection.clone() else {
+            return next(request);
+        };
+        let id = self.watchdog.watch(deadline, connection, self.status);
+        let response = next(request);
+        self.watchdog.unwatch(id);
+        response
+    }
+}
+
+impl Drop for Timeout {
+    fn drop(&mut self) {
+        self.watchdog.state.lock().unwrap().stopped = true;
+        self.watchdog.changed.notify_one();
+    }
+}
+
+// One thread per Timeout sleeps until the earliest deadline rather than every
+// request spawning its own timer.
+struct Watchdog {
+    state: Mutex<WatchdogState>,
+    changed: Condvar,
+    clock: Arc<dyn Clock>,
+}
+
+struct WatchdogState {
+    next_id: u64,
+    watched: Vec<Watched>,
+    stopped: bool,
+}
+
+struct Watched {
+    id: u64,
+    deadline: Instant,
+    connection: Arc<Connection>,
+    status: StatusCode,
+}
+
+impl Watchdog {
+    fn watch(&self, deadline: Instant, connection: Arc<Connection>, status: StatusCode) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.watched.push(Watched { id, deadline, connection, status });
+        self.changed.notify_one();
+        id
+    }
+
+    fn unwatch(&self, id: u64) {
+        self.state.lock().unwrap().watched.retain(|watched| watched.id != id);
+    }
+
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        while !state.stopped {
+            let now = self.clock.now();
+            let (expired, watched): (Vec<_>, Vec<_>) = std::mem::take(&mut state.watched)
+                .into_iter()
+                .partition(|watched| watched.deadline <= now);
+            state.watched = watched;
+
+            if !expired.is_empty() {
+                drop(state);
+                for watched in expired {
+                    eprintln!("Request timed out; abandoning its response");
+                    let response = Response::new(watched.status, "Request timed out");
+                    watched.connection.abandon(response);
+                }
+                state = self.state.lock().unwrap();
+                continue;
+            }
+
+            let wait = state.watched.iter().map(|watched| watched.deadline - now).min();
+            state = match (wait, self.clock.recheck_every()) {
+                (Some(wait), Some(recheck)) => self.changed.wait_timeout(state, wait.min(recheck)).unwrap().0,
+                (Some(wait), None) => self.changed.wait_timeout(state, wait).unwrap().0,
+                (None, _) => self.changed.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+// Caps how many requests a route runs at once, so one expensive endpoint
+// (report generation, say) can't tie up the whole pool. Attach it to the
+// route with `.with(ConcurrencyLimit::new(2))`. By default requests past the
+// cap get a 503 straight away; `queue` lets a few wait their turn instead,
+// though each one waiting still holds a worker.
+pub struct ConcurrencyLimit {
+    max: usize,
+    max_waiting: usize,
+    wait: Duration,
+    retry_after: Duration,
+    state: Mutex<Permits>,
+    released: Condvar,
+}
+
+struct Permits {
+    running: usize,
+    waiting: usize,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(max: usize) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            max: max.max(1),
+            max_waiting: 0,
+            wait: Duration::ZERO,
+            retry_after: Duration::from_secs(1),
+            state: Mutex::new(Permits { running: 0, waiting: 0 }),
+            released: Condvar::new(),
+        }
+    }
+
+    // Lets up to `max_waiting` requests wait as long as `wait` for a turn
+    // before being turned away.
+    pub fn queue(mut self, max_waiting: usize, wait: Duration) -> ConcurrencyLimit {
+        self.max_waiting = max_waiting;
+        self.wait = wait;
+        self
+    }
+
+    // The Retry-After sent with rejections; one second by default.
+    pub fn retry_after(mut self, delay: Duration) -> ConcurrencyLimit {
+        self.retry_after = delay;
+        self
+    }
+
+    fn acquire(&self, deadline: Option<Instant>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.running < self.max {
+            state.running += 1;
+            return true;
+        }
+        if state.waiting >= self.max_waiting {
+            return false;
+        }
+        // Never wait past the request's own deadline.
+        let give_up = deadline.map_or(Instant::now() + self.wait, |deadline| deadline.min(Instant::now() + self.wait));
+        state.waiting += 1;
+        while state.running >= self.max {
+            let now = Instant::now();
+            if now >= give_up {
+                state.waiting -= 1;
+                return false;
+            }
+            state = self.released.wait_timeout(state, give_up - now).unwrap().0;
+        }
+        state.waiting -= 1;
+        state.running += 1;
+        true
+    }
+
+    fn release(&self) {
+        self.state.lock().unwrap().running -= 1;
+        self.released.notify_one();
+    }
+}
+
+// Gives the permit back however the rest of the chain finishes, panics
+// included.
+struct Permit<'a>(&'a ConcurrencyLimit);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+impl Middleware for ConcurrencyLimit {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        if !self.acquire(request.deadline()) {
+            let retry_after = self.retry_after.as_secs().max(1).to_string();
+            return Response::new(StatusCode::ServiceUnavailable, "Too many concurrent requests")
+                .with_header("Retry-After", &retry_after);
+        }
+        let _permit = Permit(self);
+        next(request)
+    }
+}
+
+// Stops sending requests to a route that keeps failing, to let whatever it
+// depends on recover rather than piling more load on it. Once enough of the
+// recent requests have failed (a 5xx, by default half of at least ten within
+// a minute) the breaker opens and answers 503 without running the route.
+// After the cool-down one request is let through as a probe: if it succeeds
+// the breaker closes again, if not it stays open for another cool-down.
+//
+// As global middleware every route gets its own breaker; attached to one
+// route with `.with`, it only watches that one.
+pub struct CircuitBreaker {
+    failure_ratio: f64,
+    min_requests: u64,
+    window: Duration,
+    cool_down: Duration,
+    breakers: Mutex<HashMap<String, Breaker>>,
+    clock: Arc<dyn Clock>,
+}
+
+struct Breaker {
+    state: BreakerState,
+    window_start: Instant,
+    requests: u64,
+    failures: u64,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum BreakerState {
+    Closed,
+    Open { until: Instant },
+    // A probe is out; everyone else is still turned away.
+    HalfOpen,
+}
+
+impl CircuitBreaker {
+    pub fn new() -> CircuitBreaker {
+        CircuitBreaker {
+            failure_ratio: 0.5,
+            min_requests: 10,
+            window: Duration::from_secs(60),
+            cool_down: Duration::from_secs(30),
+            breakers: Mutex::new(HashMap::new()),
+            clock: clock::system(),
+        }
+    }
+
+    // Opens once `ratio` (0.0 to 1.0) of at least `min_requests` requests in
+    // the window have failed.
+    pub fn failure_ratio(mut self, ratio: f64, min_requests: u64) -> CircuitBreaker {
+        self.failure_ratio = ratio.clamp(0.0, 1.0);
+        self.min_requests = min_requests.max(1);
+        self
+    }
+
+    pub fn window(mut self, window: Duration) -> CircuitBreaker {
+        self.window = window;
+        self
+    }
+
+    // How long the breaker stays open before probing.
+    pub fn cool_down(mut self, cool_down: Duration) -> CircuitBreaker {
+        self.cool_down = cool_down;
+        self
+    }
+
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> CircuitBreaker {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    // Whether a request may go through, making it the probe if the breaker
+    // has cooled down.
+    fn admit(&self, route: &str) -> Result<(), Duration> {
+        let mut breakers = self.breakers.lock().unwrap();
+        let now = self.clock.now();
+        let breaker = breakers.entry(route.to_string()).or_insert_with(|| Breaker {
+            state: BreakerState::Closed,
+            window_start: now,
+            requests: 0,
+            failures: 0,
+        });
+        match breaker.state {
+            BreakerState::Closed => Ok(()),
+            BreakerState::Open { until } if now >= until => {
+                breaker.state = BreakerState::HalfOpen;
+                Ok(())
+            }
+            BreakerState::Open { until } => Err(until - now),
+            BreakerState::HalfOpen => Err(self.cool_down),
+        }
+    }
+
+    fn record(&self, route: &str, failed: bool) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let Some(breaker) = breakers.get_mut(route) else {
+            return;
+        };
+        let now = self.clock.now();
+        match breaker.state {
+            BreakerState::HalfOpen if failed => {
+                eprintln!("Circuit breaker for {route} probe failed; staying open");
+                breaker.state = BreakerState::Open { until: now + self.cool_down };
+            }
+            BreakerState::HalfOpen => {
+                eprintln!("Circuit breaker for {route} closed");
+                *breaker = Breaker { state: BreakerState::Closed, window_start: now, requests: 0, failures: 0 };
+            }
+            BreakerState::Closed => {
+                if now.duration_since(breaker.window_start) >= self.window {
+                    breaker.window_start = now;
+                    breaker.requests = 0;
+                    breaker.failures = 0;
+                }
+                breaker.requests += 1;
+                breaker.failures += failed as u64;
+                if breaker.requests >= self.min_requests
+                    && breaker.failures as f64 >= self.failure_ratio * breaker.requests as f64
+                    && breaker.failures > 0
+                {
+                    eprintln!(
+                        "Circuit breaker for {route} opened: {} of {} requests failed",
+                        breaker.failures, breaker.requests
+                    );
+                    breaker.state = BreakerState::Open { until: now + self.cool_down };
+                }
+            }
+            // Requests admitted before it opened are still finishing.
+            BreakerState::Open { .. } => {}
+        }
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> CircuitBreaker {
+        CircuitBreaker::new()
+    }
+}
+
+impl Middleware for CircuitBreaker {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let route = request.route().unwrap_or("unmatched").to_string();
+        if let Err(retry_in) = self.admit(&route) {
+            let retry_after = retry_in.as_secs().max(1).to_string();
+            return Response::new(StatusCode::ServiceUnavailable, "Service Unavailable")
+                .with_header("Retry-After", &retry_after);
+        }
+        // A panic counts as a failure too; the server turns it into a 500.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| next(request)));
+        let failed = result.as_ref().map_or(true, |response| response.status_code.code() >= 500);
+        self.record(&route, failed);
+        match result {
+            Ok(response) => response,
+            Err(panic) => panic::resume_unwind(panic),
+        }
+    }
+}
+
+// Cross-site request forgery protection with double-submit cookies: each
+// client gets a random token in a cookie, and requests that change state
+// (anything but GET, OPTIONS and PROPFIND) must send it back in an
+// X-CSRF-Token header or a csrf_token form field. Another site can make the
+// browser send the cookie but can't read it, so it can't forge the copy.
+//
+// Handlers put the token in their forms with `request.csrf_field()`, or
+// hand `request.csrf_token()` to script. Failing requests get a 403.
+pub struct Csrf {
+    cookie: String,
+    header: String,
+    field: String,
+    secure: bool,
+}
+
+impl Csrf {
+    pub fn new() -> Csrf {
+        Csrf {
+            cookie: "csrf_token".to_string(),
+            header: "X-CSRF-Token".to_string(),
+            field: "csrf_token".to_string(),
+            secure: false,
+        }
+    }
+
+    pub fn cookie_name(mut self, name: &str) -> Csrf {
+        self.cookie = name.to_string();
+        self
+    }
+
+    pub fn header_name(mut self, name: &str) -> Csrf {
+        self.header = name.to_string();
+        self
+    }
+
+    pub fn field_name(mut self, name: &str) -> Csrf {
+        self.field = name.to_string();
+        self
+    }
+
+    // Marks the cookie Secure, for sites served only over HTTPS.
+    pub fn secure(mut self, secure: bool) -> Csrf {
+        self.secure = secure;
+        self
+    }
+
+    fn matches(&self, request: &Request, token: &str) -> bool {
+        self.submitted(request).is_some_and(|given| sha256::constant_time_eq(given.as_bytes(), token.as_bytes()))
+    }
+
+    fn submitted(&self, request: &Request) -> Option<String> {
+        if let Some(token) = request.header(&self.header) {
+            return Some(token.trim().to_string());
+        }
+        let is_form = request.header("Content-Type")
+            .is_some_and(|content_type| content_type.starts_with("application/x-www-form-urlencoded"));
+        if !is_form {
+            return None;
+        }
+        request.body.split('&')
+            .filter_map(|field| field.split_once('='))
+            .find(|(name, _)| percent_decode(name) == self.field)
+            .map(|(_, token)| percent_decode(&token.replace('+', " ")))
+    }
+}
+
+impl Default for Csrf {
+    fn default() -> Csrf {
+        Csrf::new()
+    }
+}
+
+impl Middleware for Csrf {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let existing = request.cookie(&self.cookie)
+            .filter(|token| token.len() == 64 && token.bytes().all(|byte| byte.is_ascii_hexdigit()))
+            .map(str::to_string);
+        let issued = existing.is_none();
+        let token = existing.unwrap_or_else(|| random::hex(32));
+
+        // A client without the cookie has nothing to match yet.
+        let safe = matches!(request.method, HttpMethod::GET | HttpMethod::HEAD | HttpMethod::OPTIONS | HttpMethod::PROPFIND);
+        let response = if safe || !issued && self.matches(request, &token) {
+            request.csrf = Some((self.field.clone(), token.clone()));
+            next(request)
+        } else {
+            Response::new(StatusCode::Forbidden, "CSRF token missing or invalid")
+        };
+
+        if !issued {
+            return response;
+        }
+        // Not HttpOnly: script has to be able to read it to send the header.
+        let secure = if self.secure { "; Secure" } else { "" };
+        response.with_header("Set-Cookie", &format!("{}={token}; Path=/; SameSite=Lax{secure}", self.cookie))
+    }
+}
+
+// Lets a route through only for clients holding the grants (roles,
+// permissions, whatever the app calls them) it requires. Who the client is
+// comes from the auth middleware before it (`request.principal()`); what
+// they're allowed comes from the app's policy:
+//
+//     let guard = Guard::new(|user, _request| directory.roles_of(user));
+//     server.add_handler("/admin", admin).with(guard.clone().require("admin"));
+//     server.add_handler("/reports", reports).with(guard.require_any(&["admin", "auditor"]));
+//
+// Anonymous clients get a 401 and the rest a 403, or whatever `on_denied`
+// answers instead.
+pub type Policy = Arc<dyn Fn(&str, &Request) -> Vec<String> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct Guard {
+    policy: Policy,
+    // Every clause must be met, by holding any one of its grants.
+    required: Vec<Vec<String>>,
+    denied: Option<Handler>,
+}
+
+impl Guard {
+    pub fn new<F>(policy: F) -> Guard
+    where
+        F: Fn(&str, &Request) -> Vec<String> + Send + Sync + 'static,
+    {
+        Guard { policy: Arc::new(policy), required: vec![], denied: None }
+    }
+
+    pub fn require(mut self, grant: &str) -> Guard {
+        self.required.push(vec![grant.to_string()]);
+        self
+    }
+
+    pub fn require_any(mut self, grants: &[&str]) -> Guard {
+        self.required.push(grants.iter().map(|grant| grant.to_string()).collect());
+        self
+    }
+
+    // Builds the response for an authenticated client that lacks a grant.
+    pub fn on_denied<F>(mut self, denied: F) -> Guard
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.denied = Some(Arc::new(denied));
+        self
+    }
+}
+
+impl Middleware for Guard {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let Some(principal) = request.principal() else {
+            return Response::new(StatusCode::Unauthorized, "Unauthorized");
+        };
+        let grants = (self.policy)(principal, request);
+        let allowed = self.required.iter().all(|clause| clause.iter().any(|grant| grants.contains(grant)));
+        if allowed {
+            return next(request);
+        }
+        match &self.denied {
+            Some(denied) => denied(request),
+            None => Response::new(StatusCode::Forbidden, "Forbidden"),
+        }
+    }
+}
+
+// HTTP Digest authentication (RFC 7616): the client proves it knows the
+// password by hashing it with a one-time server nonce, so the password
+// itself never goes over the wire. Offers SHA-256 and, for the many clients
+// that only know it, MD5; always with qop=auth.
+//
+//     server.add_middleware(DigestAuth::new("staff").user("ada", "correct horse"));
+//
+// Authenticated requests carry the username as `request.principal()`.
+// Nonces last five minutes, after which clients are told to retry with a
+// fresh one (stale=true) without asking the user again. Each nonce count
+// is only accepted once, so captured requests can't be replayed. At most
+// `max_nonces` are remembered, 10,000 by default; past that the oldest are
+// forgotten, and their clients asked to retry the same way.
+pub type PasswordLookup = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+pub struct DigestAuth {
+    realm: String,
+    users: HashMap<String, String>,
+    lookup: Option<PasswordLookup>,
+    md5: bool,
+    nonce_lifetime: Duration,
+    max_nonces: usize,
+    nonces: Mutex<Nonces>,
+}
+
+#[derive(Default)]
+struct Nonces {
+    // When each was issued, and the highest nonce count seen.
+    issued: HashMap<String, (Instant, u64)>,
+    // The same nonces, oldest first.
+    order: VecDeque<String>,
+}
+
+impl DigestAuth {
+    pub fn new(realm: &str) -> DigestAuth {
+        DigestAuth {
+            realm: realm.to_string(),
+            users: HashMap::new(),
+            lookup: None,
+            md5: true,
+            nonce_lifetime: Duration::from_secs(300),
+            max_nonces: 10_000,
+            nonces: Mutex::new(Nonces::default()),
+        }
+    }
+
+    pub fn user(mut self, name: &str, password: &str) -> DigestAuth {
+        self.users.insert(name.to_string(), password.to_string());
+        self
+    }
+
+    // Finds passwords somewhere else (a database, say) for users not added
+    // with `user`.
+    pub fn lookup<F>(mut self, lookup: F) -> DigestAuth
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        self.lookup = Some(Arc::new(lookup));
+        self
+    }
+
+    // Turn off to accept SHA-256 only.
+    pub fn md5(mut self, enabled: bool) -> DigestAuth {
+        self.md5 = enabled;
+        self
+    }
+
+    pub fn nonce_lifetime(mut self, lifetime: Duration) -> DigestAuth {
+        self.nonce_lifetime = lifetime;
+        self
+    }
+
+    pub fn max_nonces(mut self, max: usize) -> DigestAuth {
+        self.max_nonces = max.max(1);
+        self
+    }
+
+    fn password(&self, user: &str) -> Option<String> {
+        self.users.get(user).cloned().or_else(|| self.lookup.as_ref().and_then(|lookup| lookup(user)))
+    }
+
+    fn challenge(&self, stale: bool) -> Response {
+        let nonce = random::hex(16);
+        let now = Instant::now();
+        let mut nonces = self.nonces.lock().unwrap();
+        let Nonces { issued, order } = &mut *nonces;
+        while let Some(oldest) = order.front() {
+            let expired = issued.get(oldest).is_none_or(|(at, _)| now.duration_since(*at) >= self.nonce_lifetime);
+            if !expired && issued.len() < self.max_nonces {
+                break;
+            }
+            issued.remove(oldest);
+            order.pop_front();
+        }
+        issued.insert(nonce.clone(), (now, 0));
+        order.push_back(nonce.clone());
+        drop(nonces);
+
+        let stale = if stale { ", stale=true" } else { "" };
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut response = Response::new(StatusCode::Unauthorized, "Unauthorized").with_header(
+            "WWW-Authenticate",
+            &format!("Digest realm=\"{realm}\", qop=\"auth\", algorithm=SHA-256, nonce=\"{nonce}\"{stale}"),
+        );
+        if self.md5 {
+            response = response.with_header(
+                "WWW-Authenticate",
+                &format!("Digest realm=\"{realm}\", qop=\"auth\", algorithm=MD5, nonce=\"{nonce}\"{stale}"),
+            );
+        }
+        response
+    }
+
+    // Ok with the username, or Err(stale) if the client should be asked again.
+    fn verify(&self, request: &Request) -> Result<String, bool> {
+        let header = request.header("Authorization").ok_or(false)?;
+        let (scheme, params) = header.split_once(' ').ok_or(false)?;
+        if !scheme.eq_ignore_ascii_case("Digest") {
+            return Err(false);
+        }
+        let params = digest_params(params);
+        let param = |name: &str| params.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str());
+
+        let (Some(user), Some(realm), Some(nonce), Some(uri), Some(given), Some(nc), Some(cnonce)) = (
+            param("username"), param("realm"), param("nonce"), param("uri"),
+            param("response"), param("nc"), param("cnonce"),
+        ) else {
+            return Err(false);
+        };
+        let hash: fn(&str) -> String = match param("algorithm").unwrap_or("MD5") {
+            algorithm if algorithm.eq_ignore_ascii_case("SHA-256") => |text| sha256::hex(&sha256::digest(text.as_bytes())),
+            algorithm if algorithm.eq_ignore_ascii_case("MD5") && self.md5 => |text| sha256::hex(&md5::digest(text.as_bytes())),
+            _ => return Err(false),
+        };
+        // The URI has to be this request's, or an answer to one request
+        // could be replayed against another.
+        if param("qop") != Some("auth") || realm != self.realm || uri != request.target() {
+            return Err(false);
+        }
+        let count = u64::from_str_radix(nc, 16).map_err(|_| false)?;
+
+        let password = self.password(user).ok_or(false)?;
+        let ha1 = hash(&format!("{user}:{realm}:{password}"));
+        let ha2 = hash(&format!("{:?}:{uri}", request.method));
+        let expected = hash(&format!("{ha1}:{nonce}:{nc}:{cnonce}:auth:{ha2}"));
+        let given = given.to_ascii_lowercase();
+        if !sha256::constant_time_eq(given.as_bytes(), expected.as_bytes()) {
+            return Err(false);
+        }
+
+        // Right password; now is the nonce still good for this count?
+        let mut nonces = self.nonces.lock().unwrap();
+        match nonces.issued.get_mut(nonce) {
+            Some((issued, _)) if issued.elapsed() >= self.nonce_lifetime => Err(true),
+            Some((_, last)) if count > *last => {
+                *last = count;
+                Ok(user.to_string())
+            }
+            Some(_) => Err(false),
+            // Forgotten: expired, evicted, or from before a restart.
+            None => Err(true),
+        }
+    }
+}
+
+impl Middleware for DigestAuth {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        match self.verify(request) {
+            Ok(user) => {
+                request.set_principal(&user);
+                next(request)
+            }
+            Err(stale) => self.challenge(stale),
+        }
+    }
+}
+
+// `key=value, key="quoted, value"` pairs.
+fn digest_params(text: &str) -> Vec<(String, String)> {
+    let mut params = vec![];
+    let mut rest = text.trim();
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_string();
+        let after = after.trim_start();
+        let (value, remainder) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let mut end = quoted.len();
+                while let Some((index, c)) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = index + 1;
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+                (value, &quoted[end..])
+            }
+            None => {
+                let end = after.find(',').unwrap_or(after.len());
+                (after[..end].trim().to_string(), &after[end..])
+            }
+        };
+        params.push((key, value));
+        rest = remainder.trim_start().trim_start_matches(',');
+    }
+    params
+}
+
+pub type GeoResolver = Arc<dyn Fn(IpAddr) -> Option<GeoInfo> + Send + Sync>;
+
+// Allows or refuses clients by where they are, as told by the app's
+// resolver (a GeoIP database lookup, usually):
+//
+//     server.add_middleware(GeoBlock::new(|ip| geoip.lookup(ip)).deny_country("KP").deny_asn(64496));
+//
+// Deny rules win; once there's any allow rule, only clients matching one get
+// through. Clients the resolver knows nothing about are let through unless
+// `allow_unknown(false)`. What was resolved is kept on the request
+// (`request.geo()`) for handlers, and for the JSON access log's "country".
+// Lookups are cached per address for ten minutes.
+pub struct GeoBlock {
+    resolver: GeoResolver,
+    allow_countries: Vec<String>,
+    deny_countries: Vec<String>,
+    allow_asns: Vec<u32>,
+    deny_asns: Vec<u32>,
+    allow_unknown: bool,
+    cache: Mutex<HashMap<IpAddr, (Option<GeoInfo>, Instant)>>,
+}
+
+const GEO_CACHE_TTL: Duration = Duration::from_secs(600);
+const GEO_CACHE_SIZE: usize = 10_000;
+
+impl GeoBlock {
+    pub fn new<F>(resolver: F) -> GeoBlock
+    where
+        F: Fn(IpAddr) -> Option<GeoInfo> + Send + Sync + 'static,
+    {
+        GeoBlock {
+            resolver: Arc::new(resolver),
+            allow_countries: vec![],
+            deny_countries: vec![],
+            allow_asns: vec![],
+            deny_asns: vec![],
+            allow_unknown: true,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn allow_country(mut self, country: &str) -> GeoBlock {
+        self.allow_countries.push(country.to_ascii_uppercase());
+        self
+    }
+
+    pub fn deny_country(mut self, country: &str) -> GeoBlock {
+        self.deny_countries.push(country.to_ascii_uppercase());
+        self
+    }
+
+    pub fn allow_asn(mut self, asn: u32) -> GeoBlock {
+        self.allow_asns.push(asn);
+        self
+    }
+
+    pub fn deny_asn(mut self, asn: u32) -> GeoBlock {
+        self.deny_asns.push(asn);
+        self
+    }
+
+    pub fn allow_unknown(mut self, allowed: bool) -> GeoBlock {
+        self.allow_unknown = allowed;
+        self
+    }
+
+    fn resolve(&self, ip: IpAddr) -> Option<GeoInfo> {
+        if let Some((info, resolved)) = self.cache.lock().unwrap().get(&ip) {
+            if resolved.elapsed() < GEO_CACHE_TTL {
+                return info.clone();
+            }
+        }
+        // Outside the lock; resolvers can be slow.
+        let info = (self.resolver)(ip);
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= GEO_CACHE_SIZE {
+            cache.retain(|_, (_, resolved)| resolved.elapsed() < GEO_CACHE_TTL);
+            if cache.len() >= GEO_CACHE_SIZE {
+                cache.clear();
+            }
+        }
+        cache.insert(ip, (info.clone(), Instant::now()));
+        info
+    }
+
+    fn allowed(&self, info: Option<&GeoInfo>) -> bool {
+        let country = info.and_then(|info| info.country.as_deref()).map(str::to_ascii_uppercase);
+        let asn = info.and_then(|info| info.asn);
+        if country.is_none() && asn.is_none() {
+            return self.allow_unknown;
+        }
+        let denied = country.as_ref().is_some_and(|country| self.deny_countries.contains(country))
+            || asn.is_some_and(|asn| self.deny_asns.contains(&asn));
+        if denied {
+            return false;
+        }
+        if self.allow_countries.is_empty() && self.allow_asns.is_empty() {
+            return true;
+        }
+        country.as_ref().is_some_and(|country| self.allow_countries.contains(country))
+            || asn.is_some_and(|asn| self.allow_asns.contains(&asn))
+    }
+}
+
+impl Middleware for GeoBlock {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        // Kept even when refused, so the access log says where they were.
+        request.geo = request.client_addr().and_then(|address| self.resolve(address.ip()));
+        if !self.allowed(request.geo.as_ref()) {
+            let client = request.client_addr().map_or("unknown".to_string(), |address| address.ip().to_string());
+            eprintln!("Refusing {client}: blocked by location");
+            return Response::new(StatusCode::Forbidden, "Forbidden");
+        }
+        next(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::cell::Cell;
+
+    fn get(path: &str) -> Request {
+        Request::new(HttpMethod::GET, path)
+    }
+
+    #[test]
+    fn cached_responses_expire_with_the_clock() {
+        let clock = MockClock::new();
+        let cache = ResponseCache::new(10).clock(clock.clone());
+        let calls = Cell::new(0);
+        let handler = |_: &mut Request| {
+            calls.set(calls.get() + 1);
+            Response::new(StatusCode::Ok, "hi").with_header("Cache-Control", "max-age=60")
+        };
+
+        cache.handle(&mut get("/"), &handler);
+        clock.advance(Duration::from_secs(30));
+        let hit = cache.handle(&mut get("/"), &handler);
+        assert_eq!(calls.get(), 1);
+        assert_eq!(hit.header("Age"), Some("30"));
+
+        clock.advance(Duration::from_secs(31));
+        let miss = cache.handle(&mut get("/"), &handler);
+        assert_eq!(calls.get(), 2);
+        assert_eq!(miss.header("Age"), None);
+    }
+
+    #[test]
+    fn circuit_breaker_closes_after_cooling_down() {
+        let clock = MockClock::new();
+        let breaker = CircuitBreaker::new()
+            .failure_ratio(0.5, 2)
+            .cool_down(Duration::from_secs(30))
+            .clock(clock.clone());
+        let failing = Cell::new(true);
+        let calls = Cell::new(0);
+        let handler = |_: &mut Request| {
+            calls.set(calls.get() + 1);
+            match failing.get() {
+                true => Response::new(StatusCode::InternalServerError, "down"),
+                false => Response::new(StatusCode::Ok, "up"),
+            }
+        };
+
+        breaker.handle(&mut get("/"), &handler);
+        breaker.handle(&mut get("/"), &handler);
+        let open = breaker.handle(&mut get("/"), &handler);
+        assert_eq!(open.status_code, StatusCode::ServiceUnavailable);
+        assert_eq!(open.header("Retry-After"), Some("30"));
+        assert_eq!(calls.get(), 2);
+
+        failing.set(false);
+        clock.advance(Duration::from_secs(29));
+        let still_open = breaker.handle(&mut get("/"), &handler);
+        assert_eq!(still_open.status_code, StatusCode::ServiceUnavailable);
+        assert_eq!(calls.get(), 2);
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(breaker.handle(&mut get("/"), &handler).status_code, StatusCode::Ok);
+        assert_eq!(breaker.handle(&mut get("/"), &handler).status_code, StatusCode::Ok);
+        assert_eq!(calls.get(), 4);
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use crate::{
+    client::{ClientError, ClientRequest},
+    http::{Request, Response},
+    log,
+    middleware::{Middleware, Next},
+    random,
+};
+
+// Headers that belong to the one connection rather than the request, and
+// aren't copied to the mirror.
+const HOP_BY_HOP: [&str; 8] = [
+    "Connection", "Keep-Alive", "Transfer-Encoding", "TE", "Upgrade", "Host", "Content-Length", "Proxy-Connection",
+];
+
+// Copies a share of requests to a second upstream and throws its answers
+// away, for trying a new backend version on real traffic:
+//
+//     server.add_middleware(Mirror::new("http://10.0.0.7:8080").percent(10.0).route("/api"));
+//
+// Copies go out from background threads, so the client never waits on the
+// mirror, and when the mirror falls behind they're dropped rather than
+// queued without end. They carry an `X-Mirrored: 1` header, so the shadow
+// can skip side effects like sending mail. Requests whose bodies are
+// streamed to the handler can't be copied and aren't mirrored. Clones share
+// their senders and stats, so keep one to look at `stats` with.
+#[derive(Clone)]
+pub struct Mirror {
+    upstream: String,
+    percent: f64,
+    routes: Vec<String>,
+    timeout: Duration,
+    senders: usize,
+    queue: Arc<Mutex<Option<SyncSender<ClientRequest>>>>,
+    capacity: usize,
+    stats: Arc<Counters>,
+}
+
+// How a Mirror has been getting on; see `Mirror::stats`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MirrorStats {
+    pub mirrored: u64,
+    // Copies the upstream couldn't be reached for or didn't answer.
+    pub failed: u64,
+    // Copies never sent, because too many were already waiting.
+    pub dropped: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    mirrored: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Mirror {
+    // Mirrors every request to `upstream`, an http:// base URL the request
+    // paths are appended to, from two threads with up to 100 copies waiting.
+    pub fn new(upstream: &str) -> Mirror {
+        Mirror {
+            upstream: upstream.trim_end_matches('/').to_string(),
+            percent: 100.0,
+            routes: vec![],
+            timeout: Duration::from_secs(5),
+            senders: 2,
+            queue: Arc::new(Mutex::new(None)),
+            capacity: 100,
+            stats: Arc::new(Counters::default()),
+        }
+    }
+
+    pub fn percent(mut self, percent: f64) -> Mirror {
+        self.percent = percent.clamp(0.0, 100.0);
+        self
+    }
+
+    // Only mirrors requests under `prefix`; everything, if never called.
+    pub fn route(mut self, prefix: &str) -> Mirror {
+        self.routes.push(prefix.to_string());
+        self
+    }
+
+    // How long connecting, or any one read or write, may take before a
+    // copy counts as failed.
+    pub fn timeout(mut self, timeout: Duration) -> Mirror {
+        self.timeout = timeout;
+        self
+    }
+
+    // How many copies may be on their way at once, and how many more may
+    // wait for a sender.
+    pub fn senders(mut self, senders: usize, queued: usize) -> Mirror {
+        self.senders = senders.max(1);
+        self.capacity = queued;
+        self
+    }
+
+    pub fn stats(&self) -> MirrorStats {
+        MirrorStats {
+            mirrored: self.stats.mirrored.load(Ordering::Relaxed),
+            failed: self.stats.failed.load(Ordering::Relaxed),
+            dropped: self.stats.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    fn applies(&self, request: &Request) -> bool {
+        let path = request.path.split('?').next().unwrap_or_default();
+        let routed = self.routes.is_empty() || self.routes.iter().any(|prefix| path.starts_with(prefix.as_str()));
+        let streamed = request.body_stream.lock().unwrap().is_some() || request.body_file.is_some();
+        routed && !streamed && self.percent > 0.0 && random::unit() * 100.0 < self.percent
+    }
+
+    fn copy(&self, request: &Request) -> ClientRequest {
+        let mut copy = ClientRequest::new(request.method.clone(), &format!("{}{}", self.upstream, request.path))
+            .connect_timeout(self.timeout)
+            .timeout(self.timeout)
+            .body(request.body_bytes());
+        for (name, value) in &request.headers {
+            if !HOP_BY_HOP.iter().any(|header| header.eq_ignore_ascii_case(name)) {
+                copy = copy.header(name, value);
+            }
+        }
+        if let Some(host) = request.header("Host") {
+            copy = copy.header("X-Forwarded-Host", host);
+        }
+        if let Some(address) = request.client_addr {
+            copy = copy.header("X-Forwarded-For", &address.ip().to_string());
+        }
+        copy.header("X-Mirrored", "1")
+    }
+
+    // Hands `copy` to the senders, starting them the first time.
+    fn enqueue(&self, copy: ClientRequest) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.is_none() {
+            let (sender, receiver) = mpsc::sync_channel(self.capacity);
+            let receiver = Arc::new(Mutex::new(receiver));
+            for _ in 0..self.senders {
+                let receiver = Arc::clone(&receiver);
+                let stats = Arc::clone(&self.stats);
+                let spawned = thread::Builder::new()
+                    .name("webserver-mirror".to_string())
+                    .spawn(move || Mirror::send_copies(&receiver, &stats));
+                if let Err(error) = spawned {
+                    eprintln!("Error starting a mirror thread: {error}");
+                }
+            }
+            *queue = Some(sender);
+        }
+        let Some(sender) = queue.as_ref() else {
+            return;
+        };
+        if sender.try_send(copy).is_err() {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Runs until every clone of the Mirror, and with them the sending half,
+    // is dropped.
+    fn send_copies(receiver: &Mutex<Receiver<ClientRequest>>, stats: &Counters) {
+        loop {
+            let Ok(copy) = receiver.lock().unwrap().recv() else {
+                return;
+            };
+            let counter = match copy.send() {
+                // The answer is only read to be thrown away.
+                Ok(_) | Err(ClientError::TooLarge) => &stats.mirrored,
+                Err(error) => {
+                    if log::enabled(log::Level::Debug) {
+                        println!("Mirror: error sending a copy: {error}");
+                    }
+                    &stats.failed
+                }
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Middleware for Mirror {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        if self.applies(request) {
+            self.enqueue(self.copy(request));
+        }
+        next(request)
+    }
+}
@@ -0,0 +1,331 @@
+use std::fmt::{Display, Formatter};
+use crate::json::Value;
+
+const MAX_DEPTH: usize = 128;
+
+// MessagePack, the same documents as JSON in fewer bytes, for clients that
+// would rather not pay for text. Works on json::Value, so a handler can
+// take and send either; see `Request::body_value` and `Response::value`.
+//
+// Numbers are f64 either way, so integers past 2^53 lose precision, and
+// MessagePack's bin and ext types, having no JSON equivalent, are refused.
+#[derive(Debug)]
+pub struct MsgpackError {
+    // Byte offset into the data.
+    pub offset: usize,
+    pub message: &'static str,
+}
+
+impl Display for MsgpackError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.offset)
+    }
+}
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = vec![];
+    write_value(value, &mut out);
+    out
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Value, MsgpackError> {
+    let mut decoder = Decoder { bytes, position: 0 };
+    let value = decoder.value(0)?;
+    if decoder.position < bytes.len() {
+        return Err(decoder.error("Trailing bytes"));
+    }
+    Ok(value)
+}
+
+fn write_value(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        // Whole numbers go as the smallest integer that holds them.
+        Value::Number(number) if number.fract() == 0.0 && number.abs() < 9.2e18 => write_int(*number as i64, out),
+        Value::Number(number) => {
+            out.push(0xcb);
+            out.extend_from_slice(&number.to_be_bytes());
+        }
+        Value::String(text) => {
+            write_length(text.len(), [0xa0, 0xd9, 0xda, 0xdb], 31, out);
+            out.extend_from_slice(text.as_bytes());
+        }
+        Value::Array(items) => {
+            write_length(items.len(), [0x90, 0, 0xdc, 0xdd], 15, out);
+            for item in items {
+                write_value(item, out);
+            }
+        }
+        Value::Object(members) => {
+            write_length(members.len(), [0x80, 0, 0xde, 0xdf], 15, out);
+            for (name, value) in members {
+                write_value(&Value::String(name.clone()), out);
+                write_value(value, out);
+            }
+        }
+    }
+}
+
+fn write_int(number: i64, out: &mut Vec<u8>) {
+    match number {
+        0..=0x7f => out.push(number as u8),
+        -32..=-1 => out.push(number as i8 as u8),
+        0x80..=0xff => out.extend_from_slice(&[0xcc, number as u8]),
+        0x100..=0xffff => {
+            out.push(0xcd);
+            out.extend_from_slice(&(number as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(0xce);
+            out.extend_from_slice(&(number as u32).to_be_bytes());
+        }
+        -0x80..=-33 => out.extend_from_slice(&[0xd0, number as i8 as u8]),
+        -0x8000..=-0x81 => {
+            out.push(0xd1);
+            out.extend_from_slice(&(number as i16).to_be_bytes());
+        }
+        -0x8000_0000..=-0x8001 => {
+            out.push(0xd2);
+            out.extend_from_slice(&(number as i32).to_be_bytes());
+        }
+        _ => {
+            out.push(0xd3);
+            out.extend_from_slice(&number.to_be_bytes());
+        }
+    }
+}
+
+// `markers` are the fix, 8-, 16- and 32-bit length forms; arrays and maps
+// have no 8-bit form, hence the 0.
+fn write_length(length: usize, markers: [u8; 4], fix_max: usize, out: &mut Vec<u8>) {
+    if length <= fix_max {
+        out.push(markers[0] | length as u8);
+    } else if length <= 0xff && markers[1] != 0 {
+        out.extend_from_slice(&[markers[1], length as u8]);
+    } else if length <= 0xffff {
+        out.push(markers[2]);
+        out.extend_from_slice(&(length as u16).to_be_bytes());
+    } else {
+        out.push(markers[3]);
+        out.extend_from_slice(&(length as u32).to_be_bytes());
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Decoder<'_> {
+    fn value(&mut self, depth: usize) -> Result<Value, MsgpackError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("Nested too deeply"));
+        }
+        let marker = self.take(1)?[0];
+        Ok(match marker {
+            0x00..=0x7f => Value::Number(marker as f64),
+            0xe0..=0xff => Value::Number(marker as i8 as f64),
+            0x80..=0x8f => self.map((marker & 0x0f) as usize, depth)?,
+            0x90..=0x9f => self.array((marker & 0x0f) as usize, depth)?,
+            0xa0..=0xbf => self.string((marker & 0x1f) as usize)?,
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xca => Value::Number(f32::from_be_bytes(self.fixed()?) as f64),
+            0xcb => Value::Number(f64::from_be_bytes(self.fixed()?)),
+            0xcc => Value::Number(u8::from_be_bytes(self.fixed()?) as f64),
+            0xcd => Value::Number(u16::from_be_bytes(self.fixed()?) as f64),
+            0xce => Value::Number(u32::from_be_bytes(self.fixed()?) as f64),
+            0xcf => Value::Number(u64::from_be_bytes(self.fixed()?) as f64),
+            0xd0 => Value::Number(i8::from_be_bytes(self.fixed()?) as f64),
+            0xd1 => Value::Number(i16::from_be_bytes(self.fixed()?) as f64),
+            0xd2 => Value::Number(i32::from_be_bytes(self.fixed()?) as f64),
+            0xd3 => Value::Number(i64::from_be_bytes(self.fixed()?) as f64),
+            0xd9 => {
+                let length = self.take(1)?[0] as usize;
+                self.string(length)?
+            }
+            0xda => {
+                let length = u16::from_be_bytes(self.fixed()?) as usize;
+                self.string(length)?
+            }
+            0xdb => {
+                let length = u32::from_be_bytes(self.fixed()?) as usize;
+                self.string(length)?
+            }
+            0xdc => {
+                let length = u16::from_be_bytes(self.fixed()?) as usize;
+                self.array(length, depth)?
+            }
+            0xdd => {
+                let length = u32::from_be_bytes(self.fixed()?) as usize;
+                self.array(length, depth)?
+            }
+            0xde => {
+                let length = u16::from_be_bytes(self.fixed()?) as usize;
+                self.map(length, depth)?
+            }
+            0xdf => {
+                let length = u32::from_be_bytes(self.fixed()?) as usize;
+                self.map(length, depth)?
+            }
+            0xc4..=0xc6 => return Err(self.error("Binary data isn't supported")),
+            0xc7..=0xc9 | 0xd4..=0xd8 => return Err(self.error("Extension types aren't supported")),
+            0xc1 => return Err(self.error("Reserved marker")),
+        })
+    }
+
+    // Lengths come from the data, so nothing is reserved up front: every
+    // element takes at least a byte, and running out ends it.
+    fn array(&mut self, length: usize, depth: usize) -> Result<Value, MsgpackError> {
+        let mut items = vec![];
+        for _ in 0..length {
+            items.push(self.value(depth + 1)?);
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn map(&mut self, length: usize, depth: usize) -> Result<Value, MsgpackError> {
+        let mut members = vec![];
+        for _ in 0..length {
+            let Value::String(name) = self.value(depth + 1)? else {
+                return Err(self.error("Map keys must be strings"));
+            };
+            members.push((name, self.value(depth + 1)?));
+        }
+        Ok(Value::Object(members))
+    }
+
+    fn string(&mut self, length: usize) -> Result<Value, MsgpackError> {
+        let start = self.position;
+        let bytes = self.take(length)?;
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Ok(Value::String(text.to_string())),
+            Err(_) => Err(MsgpackError { offset: start, message: "String isn't UTF-8" }),
+        }
+    }
+
+    fn fixed<const N: usize>(&mut self) -> Result<[u8; N], MsgpackError> {
+        Ok(self.take(N)?.try_into().unwrap_or([0; N]))
+    }
+
+    fn take(&mut self, length: usize) -> Result<&[u8], MsgpackError> {
+        if self.bytes.len() - self.position < length {
+            return Err(self.error("Unexpected end of data"));
+        }
+        self.position += length;
+        Ok(&self.bytes[self.position - length..self.position])
+    }
+
+    fn error(&self, message: &'static str) -> MsgpackError {
+        MsgpackError { offset: self.position, message }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    fn number(number: f64) -> Vec<u8> {
+        encode(&Value::Number(number))
+    }
+
+    // Every integer form at both ends of its range.
+    #[test]
+    fn encodes_integers_in_the_smallest_form() {
+        let cases: [(f64, &[u8]); 16] = [
+            (0.0, &[0x00]),
+            (127.0, &[0x7f]),
+            (128.0, &[0xcc, 0x80]),
+            (255.0, &[0xcc, 0xff]),
+            (256.0, &[0xcd, 0x01, 0x00]),
+            (65535.0, &[0xcd, 0xff, 0xff]),
+            (65536.0, &[0xce, 0x00, 0x01, 0x00, 0x00]),
+            (4294967296.0, &[0xd3, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]),
+            (-1.0, &[0xff]),
+            (-32.0, &[0xe0]),
+            (-33.0, &[0xd0, 0xdf]),
+            (-128.0, &[0xd0, 0x80]),
+            (-129.0, &[0xd1, 0xff, 0x7f]),
+            (-32769.0, &[0xd2, 0xff, 0xff, 0x7f, 0xff]),
+            (-2147483649.0, &[0xd3, 0xff, 0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff]),
+            (1.5, &[0xcb, 0x3f, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(number(value), expected, "{value}");
+            assert_eq!(decode(expected).unwrap(), Value::Number(value), "{value}");
+        }
+    }
+
+    // The example on msgpack.org.
+    #[test]
+    fn encodes_the_spec_example() {
+        let value = json::parse(r#"{"compact":true,"schema":0}"#).unwrap();
+        let expected = [
+            0x82, 0xa7, 0x63, 0x6f, 0x6d, 0x70, 0x61, 0x63, 0x74, 0xc3, 0xa6, 0x73, 0x63, 0x68, 0x65, 0x6d, 0x61, 0x00,
+        ];
+        assert_eq!(encode(&value), expected);
+        assert_eq!(decode(&expected).unwrap(), value);
+    }
+
+    #[test]
+    fn length_forms() {
+        let text = |length: usize| encode(&Value::String("x".repeat(length)));
+        assert_eq!(text(31)[0], 0xbf);
+        assert_eq!(text(32)[..2], [0xd9, 32]);
+        assert_eq!(text(256)[..3], [0xda, 0x01, 0x00]);
+        assert_eq!(text(65536)[..5], [0xdb, 0x00, 0x01, 0x00, 0x00]);
+
+        let array = |length: usize| encode(&Value::Array(vec![Value::Null; length]));
+        assert_eq!(array(15)[0], 0x9f);
+        assert_eq!(array(16)[..3], [0xdc, 0x00, 0x10]);
+        assert_eq!(array(65536)[..5], [0xdd, 0x00, 0x01, 0x00, 0x00]);
+
+        let map = |length: usize| encode(&Value::Object((0..length).map(|key| (key.to_string(), Value::Null)).collect()));
+        assert_eq!(map(15)[0], 0x8f);
+        assert_eq!(map(16)[..3], [0xde, 0x00, 0x10]);
+
+        for length in [0, 31, 32, 255, 256, 65536] {
+            assert_eq!(decode(&text(length)).unwrap(), Value::String("x".repeat(length)));
+        }
+        for length in [15, 16, 65536] {
+            assert_eq!(decode(&array(length)).unwrap(), Value::Array(vec![Value::Null; length]));
+        }
+    }
+
+    #[test]
+    fn decodes_forms_it_never_writes() {
+        assert_eq!(decode(&[0xca, 0x3f, 0xc0, 0x00, 0x00]).unwrap(), Value::Number(1.5));
+        assert_eq!(decode(&[0xcf, 0, 0, 0, 0, 0, 0, 0x01, 0x00]).unwrap(), Value::Number(256.0));
+        assert_eq!(decode(&[0xd9, 0x02, b'h', b'i']).unwrap(), Value::String("hi".to_string()));
+        assert_eq!(decode(&[0xdc, 0x00, 0x01, 0xc3]).unwrap(), Value::Array(vec![Value::Bool(true)]));
+        assert_eq!(decode(&[0xdf, 0, 0, 0, 0x01, 0xa1, b'a', 0xc2]).unwrap(), Value::Object(vec![("a".to_string(), Value::Bool(false))]));
+    }
+
+    #[test]
+    fn round_trips_json() {
+        let text = r#"{"name":"café","tags":["a","b"],"nested":{"list":[1,-2,3.25,null,true,false]},"big":1e300,"empty":{}}"#;
+        let value = json::parse(text).unwrap();
+        assert_eq!(decode(&encode(&value)).unwrap(), value);
+    }
+
+    #[test]
+    fn rejects_bad_data() {
+        let error = |bytes: &[u8]| decode(bytes).unwrap_err();
+        assert_eq!(error(&[]).message, "Unexpected end of data");
+        assert_eq!(error(&[0xcd, 0x01]).message, "Unexpected end of data");
+        assert_eq!(error(&[0xa3, b'a']).message, "Unexpected end of data");
+        assert_eq!(error(&[0xdd, 0xff, 0xff, 0xff, 0xff]).message, "Unexpected end of data");
+        assert_eq!(error(&[0xc0, 0xc0]).message, "Trailing bytes");
+        assert_eq!(error(&[0xc1]).message, "Reserved marker");
+        assert_eq!(error(&[0xc4, 0x01, 0x00]).message, "Binary data isn't supported");
+        assert_eq!(error(&[0xd4, 0x01, 0x00]).message, "Extension types aren't supported");
+        assert_eq!(error(&[0x81, 0x01, 0xc0]).message, "Map keys must be strings");
+        let invalid = error(&[0x92, 0xc0, 0xa2, 0xc3, 0x28]);
+        assert_eq!((invalid.message, invalid.offset), ("String isn't UTF-8", 3));
+        assert_eq!(error(&[0x91; 200]).message, "Nested too deeply");
+    }
+}
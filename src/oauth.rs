@@ -0,0 +1,243 @@
+use crate::{
+    base64,
+    client::ClientRequest,
+    http::{HttpMethod, Request, Response, StatusCode},
+    json::{self, Value},
+    random,
+    session::Session,
+    sha256,
+    static_files::percent_encode,
+};
+
+// "Login with X": the OAuth 2.0 authorization code flow (RFC 6749, with
+// PKCE from RFC 7636), and OpenID Connect's ID token on top. Mounted with
+// `Server::add_oauth`, behind the Sessions middleware:
+//
+//     let provider = OAuth::new(
+//         "client-id", "client-secret",
+//         "https://accounts.example.com/authorize",
+//         "http://auth-proxy.internal/token",
+//         "https://app.example.com/auth/callback",
+//     ).scope("email");
+//     server.add_middleware(Sessions::new().principal_key("user"));
+//     server.add_oauth("/auth/login", "/auth/callback", provider);
+//
+// The login route sends the browser to the provider (links can pass
+// `?return_to=/some/page`); the callback checks `state`, swaps the code for
+// tokens, and stores who logged in in the session: their subject as "user",
+// and every claim, as JSON, for `oauth::claims`.
+//
+// The code exchange goes through the crate's client, which doesn't speak
+// TLS yet, so the token (and userinfo) endpoints have to be reachable over
+// plain http:// for now, through a local proxy for instance.
+pub struct OAuth {
+    client_id: String,
+    client_secret: String,
+    authorize_url: String,
+    token_url: String,
+    redirect_uri: String,
+    scopes: Vec<String>,
+    userinfo_url: Option<String>,
+}
+
+const STATE: &str = "oauth_state";
+const VERIFIER: &str = "oauth_verifier";
+const RETURN_TO: &str = "oauth_return_to";
+const CLAIMS: &str = "oauth_claims";
+const ACCESS_TOKEN: &str = "oauth_access_token";
+
+impl OAuth {
+    // Asks for the "openid" scope; add others with `scope`.
+    pub fn new(client_id: &str, client_secret: &str, authorize_url: &str, token_url: &str, redirect_uri: &str) -> OAuth {
+        OAuth {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            authorize_url: authorize_url.to_string(),
+            token_url: token_url.to_string(),
+            redirect_uri: redirect_uri.to_string(),
+            scopes: vec!["openid".to_string()],
+            userinfo_url: None,
+        }
+    }
+
+    pub fn scope(mut self, scope: &str) -> OAuth {
+        self.scopes.push(scope.to_string());
+        self
+    }
+
+    // Fetches claims from here as well, with the access token, for plain
+    // OAuth providers that don't issue ID tokens.
+    pub fn userinfo_url(mut self, url: &str) -> OAuth {
+        self.userinfo_url = Some(url.to_string());
+        self
+    }
+
+    pub(crate) fn login(&self, request: &Request) -> Response {
+        let Some(session) = request.session() else {
+            return OAuth::no_session();
+        };
+        let state = random::hex(16);
+        let verifier = random::hex(32);
+        session.set(STATE, &state);
+        session.set(VERIFIER, &verifier);
+        // Only local paths, so the login can't be used as an open redirect.
+        match request.query("return_to").filter(|path| path.starts_with('/') && !path.starts_with("//")) {
+            Some(path) => session.set(RETURN_TO, &path),
+            None => {
+                session.remove(RETURN_TO);
+            }
+        }
+
+        let challenge = base64::encode_url(&sha256::digest(verifier.as_bytes()));
+        let separator = if self.authorize_url.contains('?') { '&' } else { '?' };
+        let location = format!(
+            "{}{separator}response_type=code&client_id={}&redirect_uri={}&scope={}&state={state}&code_challenge={challenge}&code_challenge_method=S256",
+            self.authorize_url,
+            percent_encode(&self.client_id),
+            percent_encode(&self.redirect_uri),
+            percent_encode(&self.scopes.join(" ")),
+        );
+        Response::new(StatusCode::Found, "").with_header("Location", &location)
+    }
+
+    pub(crate) fn callback(&self, request: &Request) -> Response {
+        let Some(session) = request.session() else {
+            return OAuth::no_session();
+        };
+        // One use only, whatever happens next.
+        let (expected, verifier) = (session.remove(STATE), session.remove(VERIFIER));
+        if let Some(error) = request.query("error") {
+            eprintln!("OAuth login refused by the provider: {error}");
+            return Response::new(StatusCode::Forbidden, "Login was cancelled or refused");
+        }
+        let (Some(expected), Some(verifier), Some(state), Some(code)) =
+            (expected, verifier, request.query("state"), request.query("code"))
+        else {
+            return Response::new(StatusCode::BadRequest, "Login expired; please try again");
+        };
+        if !sha256::constant_time_eq(state.as_bytes(), expected.as_bytes()) {
+            return Response::new(StatusCode::BadRequest, "Login expired; please try again");
+        }
+
+        let (claims, access_token) = match self.exchange(&code, &verifier) {
+            Ok(exchanged) => exchanged,
+            Err(error) => {
+                eprintln!("OAuth login failed: {error}");
+                return Response::new(StatusCode::BadGateway, "Login failed");
+            }
+        };
+        let Some(subject) = claims.get("sub").or_else(|| claims.get("id")).map(claim_text) else {
+            eprintln!("OAuth login failed: the provider didn't say who logged in");
+            return Response::new(StatusCode::BadGateway, "Login failed");
+        };
+
+        session.set("user", &subject);
+        session.set(CLAIMS, &claims.to_string());
+        match access_token {
+            Some(token) => session.set(ACCESS_TOKEN, &token),
+            None => {
+                session.remove(ACCESS_TOKEN);
+            }
+        }
+        session.renew();
+        let location = session.remove(RETURN_TO).unwrap_or_else(|| "/".to_string());
+        Response::new(StatusCode::Found, "").with_header("Location", &location)
+    }
+
+    // Trades the code for tokens and gathers the claims: the ID token's,
+    // then the userinfo endpoint's.
+    fn exchange(&self, code: &str, verifier: &str) -> Result<(Value, Option<String>), String> {
+        let form = format!(
+            "grant_type=authorization_code&code={}&redirect_uri={}&client_id={}&client_secret={}&code_verifier={verifier}",
+            percent_encode(code),
+            percent_encode(&self.redirect_uri),
+            percent_encode(&self.client_id),
+            percent_encode(&self.client_secret),
+        );
+        let response = ClientRequest::new(HttpMethod::POST, &self.token_url)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Accept", "application/json")
+            .body(form)
+            .send()
+            .map_err(|error| format!("token request failed: {error}"))?;
+        let tokens = json::parse(&response.text()).map_err(|error| format!("token response isn't JSON: {error}"))?;
+        if !response.is_success() {
+            let error = tokens.get("error").and_then(Value::as_str).unwrap_or("unknown error");
+            return Err(format!("token endpoint answered {}: {error}", response.status));
+        }
+
+        let mut claims = vec![];
+        // The ID token came straight from the token endpoint, which OpenID
+        // Connect accepts in place of checking its signature as long as that
+        // connection is trusted; keep the hop to the proxy local.
+        if let Some(id_token) = tokens.get("id_token").and_then(Value::as_str) {
+            let Value::Object(members) = id_token_claims(id_token)? else {
+                return Err("ID token claims aren't an object".to_string());
+            };
+            if members.iter().any(|(name, value)| name == "aud" && !audience_includes(value, &self.client_id)) {
+                return Err("ID token is for another client".to_string());
+            }
+            claims = members;
+        }
+        let access_token = tokens.get("access_token").and_then(Value::as_str).map(str::to_string);
+        if let (Some(url), Some(token)) = (&self.userinfo_url, &access_token) {
+            let response = ClientRequest::new(HttpMethod::GET, url)
+                .header("Authorization", &format!("Bearer {token}"))
+                .header("Accept", "application/json")
+                .send()
+                .map_err(|error| format!("userinfo request failed: {error}"))?;
+            if !response.is_success() {
+                return Err(format!("userinfo endpoint answered {}", response.status));
+            }
+            let Ok(Value::Object(members)) = json::parse(&response.text()) else {
+                return Err("userinfo response isn't a JSON object".to_string());
+            };
+            for (name, value) in members {
+                if !claims.iter().any(|(claim, _)| *claim == name) {
+                    claims.push((name, value));
+                }
+            }
+        }
+        Ok((Value::Object(claims), access_token))
+    }
+
+    fn no_session() -> Response {
+        eprintln!("OAuth routes need the Sessions middleware");
+        Response::new(StatusCode::InternalServerError, "Internal Server Error")
+    }
+}
+
+// The claims of whoever logged in through `Server::add_oauth`.
+pub fn claims(session: &Session) -> Option<Value> {
+    json::parse(&session.get(CLAIMS)?).ok()
+}
+
+// The provider's access token, for calling its APIs on the user's behalf.
+pub fn access_token(session: &Session) -> Option<String> {
+    session.get(ACCESS_TOKEN)
+}
+
+// The middle part of header.payload.signature.
+fn id_token_claims(id_token: &str) -> Result<Value, String> {
+    let payload = id_token.split('.').nth(1).ok_or("ID token isn't a JWT")?;
+    let payload = base64::decode(payload).ok_or("ID token isn't a JWT")?;
+    let payload = String::from_utf8(payload).map_err(|_| "ID token isn't a JWT")?;
+    json::parse(&payload).map_err(|error| format!("ID token claims aren't JSON: {error}"))
+}
+
+fn audience_includes(audience: &Value, client_id: &str) -> bool {
+    match audience {
+        Value::String(audience) => audience == client_id,
+        Value::Array(audiences) => audiences.iter().any(|audience| audience.as_str() == Some(client_id)),
+        _ => false,
+    }
+}
+
+// Subjects are strings in OpenID Connect, but some OAuth providers send a
+// numeric user id.
+fn claim_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
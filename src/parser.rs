@@ -0,0 +1,403 @@
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+};
+use crate::http::{HttpMethod, StatusCode};
+use self::Verdict::{Accept, Reject};
+
+// How forgiving the request parser is; see `ServerBuilder::strict_parsing`.
+//
+// Either way, anything that could make the server frame a body differently
+// from a proxy in front of it is refused: Transfer-Encoding (bodies are only
+// read by Content-Length), Content-Lengths that disagree or aren't plain
+// digits, framing headers with space before the colon or folded across
+// lines, and bare CRs or NULs anywhere in the head. Lenient parsing then
+// takes whatever else it can make sense of, the way the server always has:
+// bare LF line endings, folded header lines, missing Host headers, odd
+// request lines. Strict parsing holds requests to RFC 9112 and answers the
+// rest with a 400, or a 501 for methods the server doesn't know.
+//
+// `CORPUS` is a set of malformed and borderline requests with what each
+// mode should do with them; `check_corpus` runs it, and `webserve
+// --check-parser` prints the results, so the difference can be seen before
+// picking one.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Parsing {
+    #[default]
+    Lenient,
+    Strict,
+}
+
+// A request head, split up. Header values are kept as sent, since they
+// needn't be UTF-8.
+pub(crate) struct Head {
+    pub(crate) method: HttpMethod,
+    pub(crate) path: String,
+    pub(crate) protocol: String,
+    pub(crate) headers: Vec<(String, Vec<u8>)>,
+}
+
+// Why a head was refused, and with what.
+#[derive(Debug)]
+pub(crate) struct Rejection {
+    pub(crate) status: StatusCode,
+    pub(crate) reason: &'static str,
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.reason)
+    }
+}
+
+impl Error for Rejection {}
+
+fn bad(reason: &'static str) -> Rejection {
+    Rejection { status: StatusCode::BadRequest, reason }
+}
+
+// `head` runs up to and including the blank line ending it.
+pub(crate) fn parse_head(head: &[u8], parsing: Parsing) -> Result<Head, Rejection> {
+    let strict = parsing == Parsing::Strict;
+    if head.contains(&0) {
+        return Err(bad("NUL in request head"));
+    }
+    if head.iter().enumerate().any(|(index, byte)| *byte == b'\r' && head.get(index + 1) != Some(&b'\n')) {
+        return Err(bad("Bare CR in request head"));
+    }
+    let mut lines = vec![];
+    for line in head.split_inclusive(|byte| *byte == b'\n') {
+        let ended = line.ends_with(b"\r\n");
+        if strict && line.ends_with(b"\n") && !ended {
+            return Err(bad("Line ended with a bare LF"));
+        }
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+    let request_line = lines.first().copied().unwrap_or_default();
+    let (method, path, protocol) = if strict { strict_request_line(request_line)? } else { lenient_request_line(request_line)? };
+
+    let mut headers: Vec<(String, Vec<u8>)> = vec![];
+    for line in lines.iter().skip(1) {
+        // obs-fold: a header value carried on to the next line.
+        if line.starts_with(b" ") || line.starts_with(b"\t") {
+            if strict {
+                return Err(bad("Folded header line"));
+            }
+            let Some((name, value)) = headers.last_mut() else {
+                return Err(bad("Folded line before any header"));
+            };
+            // Folding onto a framing header, or a folded line that reads as
+            // one to a proxy that doesn't unfold, would frame the body two
+            // ways.
+            let folded = trim(line);
+            let hidden = folded.iter().position(|byte| *byte == b':').is_some_and(|colon| is_framing(&folded[..colon]));
+            if is_framing(name.as_bytes()) || hidden {
+                return Err(bad("Folded framing header"));
+            }
+            value.push(b' ');
+            value.extend_from_slice(trim(line));
+            continue;
+        }
+        let Some(colon) = line.iter().position(|byte| *byte == b':') else {
+            if strict {
+                return Err(bad("Header line without a colon"));
+            }
+            continue;
+        };
+        let (name, value) = (&line[..colon], &line[colon + 1..]);
+        if (name.ends_with(b" ") || name.ends_with(b"\t")) && (strict || is_framing(name)) {
+            return Err(bad("Space before the colon in a header"));
+        }
+        if strict {
+            if name.is_empty() || !name.iter().all(|byte| is_token(*byte)) {
+                return Err(bad("Invalid header name"));
+            }
+            if value.iter().any(|byte| byte.is_ascii_control() && *byte != b'\t') {
+                return Err(bad("Control character in a header value"));
+            }
+        }
+        headers.push((String::from_utf8_lossy(trim(name)).into_owned(), trim(value).to_vec()));
+    }
+
+    check_framing(&mut headers, strict)?;
+    if strict && protocol == "HTTP/1.1" {
+        let hosts = headers.iter().filter(|(name, _)| name.eq_ignore_ascii_case("Host")).count();
+        if hosts != 1 {
+            return Err(bad("HTTP/1.1 request needs exactly one Host"));
+        }
+    }
+    Ok(Head { method, path, protocol, headers })
+}
+
+// Optional whitespace, as header values and names are padded with.
+fn trim(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|byte| !matches!(byte, b' ' | b'\t')).unwrap_or(bytes.len());
+    let end = bytes.iter().rposition(|byte| !matches!(byte, b' ' | b'\t')).map_or(start, |end| end + 1);
+    &bytes[start..end]
+}
+
+// What the server has always done: split on whitespace, and guess at a
+// missing target or version. An unknown method is refused all the same, as
+// a GET handler's answer to it could leave the connection out of step.
+fn lenient_request_line(line: &[u8]) -> Result<(HttpMethod, String, String), Rejection> {
+    let line = String::from_utf8_lossy(line);
+    let mut parts = line.split_whitespace();
+    let Some(name) = parts.next() else {
+        return Err(bad("Malformed request line"));
+    };
+    let Some(method) = method(name) else {
+        return Err(Rejection { status: StatusCode::NotImplemented, reason: "Unknown method" });
+    };
+    let path = parts.next().unwrap_or("/").to_string();
+    let protocol = parts.next().unwrap_or("HTTP/1.1").to_string();
+    Ok((method, path, protocol))
+}
+
+// method SP request-target SP HTTP-version, exactly.
+fn strict_request_line(line: &[u8]) -> Result<(HttpMethod, String, String), Rejection> {
+    // Targets are percent-encoded, so anything past ASCII is a mistake.
+    let Some(line) = line.is_ascii().then(|| String::from_utf8_lossy(line)) else {
+        return Err(bad("Invalid request target"));
+    };
+    let parts: Vec<&str> = line.split(' ').collect();
+    let [name, target, protocol] = parts[..] else {
+        return Err(bad("Malformed request line"));
+    };
+    if name.is_empty() || !name.bytes().all(is_token) {
+        return Err(bad("Invalid method"));
+    }
+    let Some(method) = method(name) else {
+        return Err(Rejection { status: StatusCode::NotImplemented, reason: "Unknown method" });
+    };
+    let form_ok = target.starts_with('/') || target == "*" || target.starts_with("http://") || target.starts_with("https://");
+    if !form_ok || target.chars().any(|c| c.is_control()) {
+        return Err(bad("Invalid request target"));
+    }
+    if protocol != "HTTP/1.1" && protocol != "HTTP/1.0" {
+        return Err(bad("Unsupported HTTP version"));
+    }
+    Ok((method, target.to_string(), protocol.to_string()))
+}
+
+fn method(name: &str) -> Option<HttpMethod> {
+    Some(match name {
+        "GET" => HttpMethod::GET,
+        "HEAD" => HttpMethod::HEAD,
+        "POST" => HttpMethod::POST,
+        "PUT" => HttpMethod::PUT,
+        "DELETE" => HttpMethod::DELETE,
+        "PATCH" => HttpMethod::PATCH,
+        "OPTIONS" => HttpMethod::OPTIONS,
+        "PROPFIND" => HttpMethod::PROPFIND,
+        "MKCOL" => HttpMethod::MKCOL,
+        "COPY" => HttpMethod::COPY,
+        "MOVE" => HttpMethod::MOVE,
+        _ => return None,
+    })
+}
+
+// The server reads bodies by Content-Length alone, so it has to be one
+// unambiguous number. Lenient parsing takes repeats of the same value (as
+// RFC 9112 allows) and leaves a single header behind.
+fn check_framing(headers: &mut Vec<(String, Vec<u8>)>, strict: bool) -> Result<(), Rejection> {
+    let has_length = headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Content-Length"));
+    if headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Transfer-Encoding")) {
+        if has_length {
+            return Err(bad("Both Content-Length and Transfer-Encoding"));
+        }
+        return Err(Rejection { status: StatusCode::NotImplemented, reason: "Transfer-Encoding isn't supported" });
+    }
+    if !has_length {
+        return Ok(());
+    }
+    let mut values = vec![];
+    for (_, value) in headers.iter().filter(|(name, _)| name.eq_ignore_ascii_case("Content-Length")) {
+        for value in value.split(|byte| *byte == b',').map(trim) {
+            if value.is_empty() || !value.iter().all(|byte| byte.is_ascii_digit()) {
+                return Err(bad("Invalid Content-Length"));
+            }
+            let value = std::str::from_utf8(value).unwrap_or_default();
+            values.push(value.parse::<u64>().map_err(|_| bad("Invalid Content-Length"))?);
+        }
+    }
+    if values.iter().any(|value| *value != values[0]) {
+        return Err(bad("Conflicting Content-Lengths"));
+    }
+    if values.len() > 1 {
+        if strict {
+            return Err(bad("More than one Content-Length"));
+        }
+        let mut kept = false;
+        headers.retain_mut(|(name, value)| {
+            if !name.eq_ignore_ascii_case("Content-Length") {
+                return true;
+            }
+            if kept {
+                return false;
+            }
+            kept = true;
+            *value = values[0].to_string().into_bytes();
+            true
+        });
+    }
+    Ok(())
+}
+
+// Content-Length or Transfer-Encoding, give or take padding.
+fn is_framing(name: &[u8]) -> bool {
+    [&b"content-length"[..], b"transfer-encoding"].iter().any(|framing| trim(name).eq_ignore_ascii_case(framing))
+}
+
+// RFC 9110's tchar, what method and header names are made of.
+fn is_token(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Accept,
+    Reject,
+}
+
+pub struct Case {
+    pub name: &'static str,
+    pub head: &'static [u8],
+    pub lenient: Verdict,
+    pub strict: Verdict,
+}
+
+const fn case(name: &'static str, head: &'static [u8], lenient: Verdict, strict: Verdict) -> Case {
+    Case { name, head, lenient, strict }
+}
+
+pub const CORPUS: &[Case] = &[
+    case("plain GET", b"GET / HTTP/1.1\r\nHost: a\r\n\r\n", Accept, Accept),
+    case("POST with Content-Length", b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\n", Accept, Accept),
+    case("HTTP/1.0 without Host", b"GET / HTTP/1.0\r\n\r\n", Accept, Accept),
+    case("absolute-form target", b"GET http://a/ HTTP/1.1\r\nHost: a\r\n\r\n", Accept, Accept),
+    case("HEAD", b"HEAD / HTTP/1.1\r\nHost: a\r\n\r\n", Accept, Accept),
+    case("asterisk-form OPTIONS", b"OPTIONS * HTTP/1.1\r\nHost: a\r\n\r\n", Accept, Accept),
+    case("Content-Length with trailing space", b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5 \r\n\r\n", Accept, Accept),
+    case("obs-text in a value", b"GET / HTTP/1.1\r\nHost: a\r\nX-Name: caf\xe9\r\n\r\n", Accept, Accept),
+    case("tab in a value", b"GET / HTTP/1.1\r\nHost: a\r\nX-List: a\tb\r\n\r\n", Accept, Accept),
+    // Body framing: refused either way.
+    case("Transfer-Encoding: chunked", b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n", Reject, Reject),
+    case("CL.TE", b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 6\r\nTransfer-Encoding: chunked\r\n\r\n", Reject, Reject),
+    case("TE.CL", b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nContent-Length: 6\r\n\r\n", Reject, Reject),
+    case("TE with space before colon", b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding : chunked\r\nContent-Length: 6\r\n\r\n", Reject, Reject),
+    case("TE with a tab", b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding:\tchunked\r\n\r\n", Reject, Reject),
+    case("TE xchunked", b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: xchunked\r\n\r\n", Reject, Reject),
+    case("TE on HTTP/1.0", b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n", Reject, Reject),
+    case("lowercase transfer-encoding", b"POST / HTTP/1.1\r\nHost: a\r\ntransfer-encoding: chunked\r\n\r\n", Reject, Reject),
+    case("conflicting Content-Lengths", b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n", Reject, Reject),
+    case("conflicting Content-Length list", b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5, 6\r\n\r\n", Reject, Reject),
+    case("Content-Length +5", b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: +5\r\n\r\n", Reject, Reject),
+    case("Content-Length -1", b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: -1\r\n\r\n", Reject, Reject),
+    case("Content-Length 0x5", b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 0x5\r\n\r\n", Reject, Reject),
+    case("empty Content-Length", b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length:\r\n\r\n", Reject, Reject),
+    case("Content-Length overflow", b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 99999999999999999999999\r\n\r\n", Reject, Reject),
+    case("Content-Length 5 5", b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5 5\r\n\r\n", Reject, Reject),
+    case("Content-Length with space before colon", b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length : 5\r\n\r\n", Reject, Reject),
+    case("bare CR in a value", b"GET / HTTP/1.1\r\nHost: a\r\nX-A: 1\rContent-Length: 5\r\n\r\n", Reject, Reject),
+    case("NUL in a value", b"GET / HTTP/1.1\r\nHost: a\r\nX-A: 1\x00\r\n\r\n", Reject, Reject),
+    case("fold before any header", b"GET / HTTP/1.1\r\n Host: a\r\n\r\n", Reject, Reject),
+    case("folded Content-Length line", b"GET / HTTP/1.1\r\nHost: a\r\nX-A: 1\r\n Content-Length: 5\r\n\r\n", Reject, Reject),
+    case("folded Content-Length value", b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n 5\r\n\r\n", Reject, Reject),
+    // A GET handler's answer to a method it doesn't know could desync the
+    // connection, so these are refused either way too.
+    case("unknown method", b"FOO / HTTP/1.1\r\nHost: a\r\n\r\n", Reject, Reject),
+    case("lowercase method", b"get / HTTP/1.1\r\nHost: a\r\n\r\n", Reject, Reject),
+    // Tolerated unless strict.
+    case("repeated Content-Length", b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n", Accept, Reject),
+    case("Content-Length list 5, 5", b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5, 5\r\n\r\n", Accept, Reject),
+    case("bare LF line endings", b"GET / HTTP/1.1\nHost: a\n\r\n", Accept, Reject),
+    case("folded header value", b"GET / HTTP/1.1\r\nHost: a\r\nX-Long: one\r\n two\r\n\r\n", Accept, Reject),
+    case("space before colon", b"GET / HTTP/1.1\r\nHost: a\r\nX-Foo : bar\r\n\r\n", Accept, Reject),
+    case("header line without colon", b"GET / HTTP/1.1\r\nHost: a\r\nnonsense\r\n\r\n", Accept, Reject),
+    case("invalid header name", b"GET / HTTP/1.1\r\nHost: a\r\nX@Foo: 1\r\n\r\n", Accept, Reject),
+    case("control character in a value", b"GET / HTTP/1.1\r\nHost: a\r\nX-A: 1\x01\r\n\r\n", Accept, Reject),
+    case("missing Host", b"GET / HTTP/1.1\r\n\r\n", Accept, Reject),
+    case("two Hosts", b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n", Accept, Reject),
+    case("double space in request line", b"GET  / HTTP/1.1\r\nHost: a\r\n\r\n", Accept, Reject),
+    case("tab in request line", b"GET\t/ HTTP/1.1\r\nHost: a\r\n\r\n", Accept, Reject),
+    case("missing version", b"GET /\r\nHost: a\r\n\r\n", Accept, Reject),
+    case("HTTP/1.2", b"GET / HTTP/1.2\r\nHost: a\r\n\r\n", Accept, Reject),
+    case("lowercase version", b"GET / http/1.1\r\nHost: a\r\n\r\n", Accept, Reject),
+    case("relative target", b"GET index.html HTTP/1.1\r\nHost: a\r\n\r\n", Accept, Reject),
+    case("control character in target", b"GET /a\x7fb HTTP/1.1\r\nHost: a\r\n\r\n", Accept, Reject),
+];
+
+// One corpus case, and whether parsing went as it should.
+pub struct Outcome {
+    pub name: &'static str,
+    pub expected: Verdict,
+    pub got: Verdict,
+    // Why it was rejected, if it was.
+    pub reason: Option<&'static str>,
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        self.expected == self.got
+    }
+}
+
+pub fn check_corpus(parsing: Parsing) -> Vec<Outcome> {
+    CORPUS.iter()
+        .map(|case| {
+            let expected = if parsing == Parsing::Strict { case.strict } else { case.lenient };
+            let result = parse_head(case.head, parsing);
+            Outcome {
+                name: case.name,
+                expected,
+                got: if result.is_ok() { Accept } else { Reject },
+                reason: result.err().map(|rejection| rejection.reason),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failures(parsing: Parsing) -> Vec<String> {
+        check_corpus(parsing)
+            .iter()
+            .filter(|outcome| !outcome.passed())
+            .map(|outcome| format!("{}: expected {:?}, got {:?} ({:?})", outcome.name, outcome.expected, outcome.got, outcome.reason))
+            .collect()
+    }
+
+    #[test]
+    fn lenient_corpus() {
+        assert_eq!(failures(Parsing::Lenient), Vec::<String>::new());
+    }
+
+    #[test]
+    fn strict_corpus() {
+        assert_eq!(failures(Parsing::Strict), Vec::<String>::new());
+    }
+
+    #[test]
+    fn unfolds_and_keeps_one_content_length() {
+        let head = parse_head(b"POST / HTTP/1.1\r\nX-Long: one\r\n two\r\nContent-Length: 5, 5\r\n\r\n", Parsing::Lenient).unwrap();
+        assert_eq!(head.headers, vec![
+            ("X-Long".to_string(), b"one two".to_vec()),
+            ("Content-Length".to_string(), b"5".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn unknown_method_is_not_implemented() {
+        for parsing in [Parsing::Lenient, Parsing::Strict] {
+            let rejection = parse_head(b"BREW / HTTP/1.1\r\nHost: a\r\n\r\n", parsing).err().unwrap();
+            assert_eq!(rejection.status, StatusCode::NotImplemented);
+        }
+    }
+}
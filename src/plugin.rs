@@ -0,0 +1,36 @@
+use crate::server::{Server, ServerBuilder};
+
+// A reusable bundle of server setup, such as an auth pack, a metrics
+// endpoint or a set of static mounts, that can live in its own crate and
+// be added in one line:
+//
+//     struct HealthCheck;
+//
+//     impl Plugin for HealthCheck {
+//         fn register(&self, server: &mut Server) {
+//             server.add_fast_response("/healthz", Response::new(StatusCode::Ok, "ok"));
+//         }
+//     }
+//
+//     let server = Server::builder("0.0.0.0", 8080).plugin(HealthCheck).build();
+//
+// `configure` gets the builder first, to set options like limits or
+// metrics; `register` then gets the built server, to add routes,
+// middleware and hooks. Plugins run in the order they were added, each
+// seeing what the ones before it did. `Server::plugin` adds one to a
+// server that's already built, skipping `configure`.
+pub trait Plugin {
+    fn configure(&self, builder: ServerBuilder) -> ServerBuilder {
+        builder
+    }
+
+    fn register(&self, server: &mut Server);
+}
+
+// A closure over the built server is a plugin too, for setup that needs no
+// builder options.
+impl<F: Fn(&mut Server)> Plugin for F {
+    fn register(&self, server: &mut Server) {
+        self(server)
+    }
+}
@@ -0,0 +1,92 @@
+// The PROXY protocol preamble load balancers like HAProxy and AWS NLB send
+// ahead of the proxied connection, carrying the real client's address:
+// https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+use std::{
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// The longest possible v1 line, CRLF included.
+const V1_MAX_LENGTH: usize = 107;
+
+// Reads the preamble off the front of `stream`, leaving the request behind
+// it unread. Returns the client's address, or None when the proxy sent the
+// connection on its own behalf (health checks, "UNKNOWN" sources).
+pub fn read_header(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0u8; 6];
+    stream.read_exact(&mut start)?;
+    if &start == b"PROXY " {
+        read_v1(stream)
+    } else if start == V2_SIGNATURE[..6] {
+        read_v2(stream, start)
+    } else {
+        Err(invalid("Connection didn't start with a PROXY protocol header"))
+    }
+}
+
+fn read_v1(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+    // Byte by byte, so nothing past the line is consumed.
+    let mut line = Vec::with_capacity(V1_MAX_LENGTH);
+    let mut byte = [0u8];
+    while !line.ends_with(b"\r\n") {
+        if line.len() + 6 >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY header too long"));
+        }
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("PROXY header isn't text"))?;
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        ["TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("Invalid PROXY source address"))?;
+            let port: u16 = source_port.parse().map_err(|_| invalid("Invalid PROXY source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("Malformed PROXY header")),
+    }
+}
+
+fn read_v2(stream: &mut impl Read, start: [u8; 6]) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0u8; 16];
+    header[..6].copy_from_slice(&start);
+    stream.read_exact(&mut header[6..])?;
+    if header[..12] != V2_SIGNATURE || header[12] >> 4 != 2 {
+        return Err(invalid("Invalid PROXY v2 header"));
+    }
+    let command = header[12] & 0x0F;
+    let family = header[13];
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+
+    // Always read the whole payload, TLVs and all, so the request that
+    // follows starts where we expect it to.
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload)?;
+
+    // LOCAL: the proxy talking to us itself.
+    if command == 0 {
+        return Ok(None);
+    }
+    match family {
+        // TCP over IPv4: source, destination, source port, destination port.
+        0x11 if length >= 12 => {
+            let ip = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        0x21 if length >= 36 => {
+            let ip: [u8; 16] = payload[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)))
+        }
+        // Unix sockets and unspecified families carry no usable address.
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,266 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+use crate::{
+    clock::{self, Clock},
+    http::{Request, Response, StatusCode},
+    metrics::ClientKey,
+    middleware::{Middleware, Next},
+    static_files::{percent_decode, percent_encode},
+};
+
+// Long-horizon quotas: at most `limit` requests per client per period (a
+// day, say), counted in calendar windows aligned to the Unix epoch, so a
+// daily quota resets at midnight UTC. Every response says where the client
+// stands:
+//
+//     X-RateLimit-Limit: 1000
+//     X-RateLimit-Remaining: 412
+//     X-RateLimit-Reset: 30124      (seconds until the window resets)
+//
+// and requests over the quota get a 429 with Retry-After. Clients are told
+// apart by `request.principal()`, which only auth middleware sets, then by
+// address, unless `key` says otherwise. Anything the client can make up,
+// like an API key header nobody has checked, would get it a fresh quota on
+// every request, so a `key` going by one has to verify it. Counts live in
+// a `QuotaStore`: in memory by default, or in a `FileStore` to survive
+// restarts.
+pub struct Quota {
+    limit: u64,
+    period: Duration,
+    key: ClientKey,
+    store: Box<dyn QuotaStore>,
+    clock: Arc<dyn Clock>,
+}
+
+// Where quota counts are kept. Implement it to share counts between
+// servers, or to keep them in SQLite or another database; the crate has no
+// driver for one itself.
+pub trait QuotaStore: Send + Sync {
+    // Counts one more request by `key` in `window`, and returns how many
+    // there have been in that window. Counts from earlier windows can be
+    // forgotten.
+    fn increment(&self, key: &str, window: u64) -> io::Result<u64>;
+}
+
+impl Quota {
+    pub fn new(limit: u64, period: Duration) -> Quota {
+        Quota {
+            limit,
+            period: period.max(Duration::from_secs(1)),
+            key: Arc::new(Quota::default_key),
+            store: Box::new(MemoryStore::new()),
+            clock: clock::system(),
+        }
+    }
+
+    pub fn per_day(limit: u64) -> Quota {
+        Quota::new(limit, Duration::from_secs(86400))
+    }
+
+    // Which client a request counts against; None lets it through
+    // uncounted.
+    pub fn key<F>(mut self, key: F) -> Quota
+    where
+        F: Fn(&Request) -> Option<String> + Send + Sync + 'static,
+    {
+        self.key = Arc::new(key);
+        self
+    }
+
+    pub fn store<S: QuotaStore + 'static>(mut self, store: S) -> Quota {
+        self.store = Box::new(store);
+        self
+    }
+
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Quota {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    fn default_key(request: &Request) -> Option<String> {
+        if let Some(principal) = request.principal() {
+            return Some(principal.to_string());
+        }
+        request.client_addr().map(|address| address.ip().to_string())
+    }
+}
+
+impl Middleware for Quota {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let Some(key) = (self.key)(request) else {
+            return next(request);
+        };
+        let now = self.clock.system_time().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let period = self.period.as_secs();
+        let window = now / period;
+        let reset = ((window + 1) * period - now).to_string();
+        let count = match self.store.increment(&key, window) {
+            Ok(count) => count,
+            Err(error) => {
+                // Better to serve uncounted than to fail everyone.
+                eprintln!("Error counting quota for {key}: {error}");
+                return next(request);
+            }
+        };
+
+        let response = if count > self.limit {
+            Response::new(StatusCode::TooManyRequests, "Quota exceeded").with_header("Retry-After", &reset)
+        } else {
+            next(request)
+        };
+        response
+            .with_header("X-RateLimit-Limit", &self.limit.to_string())
+            .with_header("X-RateLimit-Remaining", &self.limit.saturating_sub(count).to_string())
+            .with_header("X-RateLimit-Reset", &reset)
+    }
+}
+
+// Counts in memory, lost on restart.
+pub struct MemoryStore {
+    counts: Mutex<HashMap<String, (u64, u64)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore { counts: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> MemoryStore {
+        MemoryStore::new()
+    }
+}
+
+impl QuotaStore for MemoryStore {
+    fn increment(&self, key: &str, window: u64) -> io::Result<u64> {
+        let mut counts = self.counts.lock().unwrap();
+        // Drop last window's clients while we're at it.
+        if counts.values().next().is_some_and(|(seen, _)| *seen < window) {
+            counts.retain(|_, (seen, _)| *seen >= window);
+        }
+        Ok(bump(&mut counts, key, window))
+    }
+}
+
+// Counts kept in a file, so quotas survive restarts: one line per request,
+// "<window> <key> <count>", replayed on open. The file is compacted to one
+// line per client on open and whenever it grows well past that.
+pub struct FileStore {
+    path: PathBuf,
+    state: Mutex<FileState>,
+}
+
+struct FileState {
+    counts: HashMap<String, (u64, u64)>,
+    file: File,
+    lines: usize,
+}
+
+impl FileStore {
+    pub fn open(path: &str) -> io::Result<FileStore> {
+        let path = PathBuf::from(path);
+        let mut counts = HashMap::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    let mut fields = line.split(' ');
+                    let (Some(window), Some(key), Some(count)) = (fields.next(), fields.next(), fields.next()) else {
+                        continue;
+                    };
+                    // A line cut short by a crash is just skipped.
+                    if let (Ok(window), Ok(count)) = (window.parse(), count.parse()) {
+                        counts.insert(percent_decode(key), (window, count));
+                    }
+                }
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error),
+        }
+        let file = FileStore::compact(&path, &counts)?;
+        let lines = counts.len();
+        Ok(FileStore { path, state: Mutex::new(FileState { counts, file, lines }) })
+    }
+
+    // Rewrites the file with just the current counts, returning it open
+    // for appending.
+    fn compact(path: &Path, counts: &HashMap<String, (u64, u64)>) -> io::Result<File> {
+        let temporary = path.with_extension("compacting");
+        let mut file = File::create(&temporary)?;
+        for (key, (window, count)) in counts {
+            writeln!(file, "{window} {} {count}", percent_encode(key))?;
+        }
+        file.sync_all()?;
+        fs::rename(&temporary, path)?;
+        OpenOptions::new().append(true).open(path)
+    }
+}
+
+impl QuotaStore for FileStore {
+    fn increment(&self, key: &str, window: u64) -> io::Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let count = bump(&mut state.counts, key, window);
+        writeln!(state.file, "{window} {} {count}", percent_encode(key))?;
+        state.lines += 1;
+
+        if state.lines > 1024 && state.lines > 4 * state.counts.len() {
+            state.counts.retain(|_, (seen, _)| *seen >= window);
+            state.file = FileStore::compact(&self.path, &state.counts)?;
+            state.lines = state.counts.len();
+        }
+        Ok(count)
+    }
+}
+
+fn bump(counts: &mut HashMap<String, (u64, u64)>, key: &str, window: u64) -> u64 {
+    let entry = counts.entry(key.to_string()).or_insert((window, 0));
+    if entry.0 != window {
+        *entry = (window, 0);
+    }
+    entry.1 += 1;
+    entry.1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::MockClock, http::HttpMethod};
+    use std::time::SystemTime;
+
+    fn visit(quota: &Quota) -> Response {
+        quota.handle(&mut Request::new(HttpMethod::GET, "/"), &|_| Response::new(StatusCode::Ok, ""))
+    }
+
+    #[test]
+    fn quotas_reset_with_the_window() {
+        let clock = MockClock::at(SystemTime::UNIX_EPOCH + Duration::from_secs(120));
+        let quota = Quota::new(2, Duration::from_secs(60))
+            .clock(clock.clone())
+            .key(|_| Some("client".to_string()));
+
+        assert_eq!(visit(&quota).header("X-RateLimit-Remaining"), Some("1"));
+        clock.advance(Duration::from_secs(15));
+        let last = visit(&quota);
+        assert_eq!(last.header("X-RateLimit-Remaining"), Some("0"));
+        assert_eq!(last.header("X-RateLimit-Reset"), Some("45"));
+
+        clock.advance(Duration::from_secs(40));
+        let over = visit(&quota);
+        assert_eq!(over.status_code, StatusCode::TooManyRequests);
+        assert_eq!(over.header("Retry-After"), Some("5"));
+
+        clock.advance(Duration::from_secs(5));
+        let next = visit(&quota);
+        assert_eq!(next.status_code, StatusCode::Ok);
+        assert_eq!(next.header("X-RateLimit-Remaining"), Some("1"));
+        assert_eq!(next.header("X-RateLimit-Reset"), Some("60"));
+    }
+}
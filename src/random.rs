@@ -0,0 +1,38 @@
+use std::{
+    collections::hash_map::RandomState,
+    fs::File,
+    hash::{BuildHasher, Hasher},
+    io::Read,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Fills `buffer` from the OS's random source, falling back to std's
+// randomly seeded hasher where there's no /dev/urandom.
+pub(crate) fn fill(buffer: &mut [u8]) {
+    if File::open("/dev/urandom").and_then(|mut urandom| urandom.read_exact(buffer)).is_ok() {
+        return;
+    }
+    for chunk in buffer.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes()[..chunk.len()]);
+    }
+}
+
+// `bytes` random bytes as lowercase hex.
+pub(crate) fn hex(bytes: usize) -> String {
+    let mut buffer = vec![0; bytes];
+    fill(&mut buffer);
+    buffer.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// A random number in [0, 1).
+pub(crate) fn unit() -> f64 {
+    let mut buffer = [0; 8];
+    fill(&mut buffer);
+    (u64::from_le_bytes(buffer) >> 11) as f64 / (1u64 << 53) as f64
+}
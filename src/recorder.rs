@@ -0,0 +1,419 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use crate::{
+    base64,
+    http::{days_from_civil, Request, Response, StatusCode},
+    json::{self, Value},
+    log::{self, json_string, LogFile},
+    middleware::{Middleware, Next},
+    sha256,
+    static_files::percent_decode,
+};
+
+// Keeps the last `capacity` requests and responses, headers and the start
+// of each body, for working out what a client is really sending:
+//
+//     let recorder = Recorder::new(100).route("/api/partner");
+//     server.add_middleware(recorder.clone());
+//     server.register_recorder(&recorder);
+//
+// The admin endpoint serves them at GET /recordings, and as a HAR file at
+// GET /recordings/har (DELETE /recordings clears them); `to_file` also
+// appends each exchange to a log as a JSON line, and `dump` writes out
+// what's held. Credentials and cookies are recorded as "[redacted]".
+// Streamed bodies and bodies sent from disk aren't seen, so they're
+// recorded as empty.
+#[derive(Clone)]
+pub struct Recorder {
+    capacity: usize,
+    max_body: usize,
+    routes: Vec<String>,
+    redacted: Vec<String>,
+    file: Option<LogFile>,
+    exchanges: Arc<Mutex<VecDeque<Exchange>>>,
+}
+
+// One recorded request and what it was answered with.
+#[derive(Clone, Debug)]
+pub struct Exchange {
+    pub time: SystemTime,
+    pub duration: Duration,
+    pub method: String,
+    pub path: String,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: Vec<u8>,
+    pub status: u16,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: Vec<u8>,
+    // Whether either body was cut off at `max_body`.
+    pub truncated: bool,
+}
+
+const REDACTED: &str = "[redacted]";
+
+impl Recorder {
+    pub fn new(capacity: usize) -> Recorder {
+        Recorder {
+            capacity: capacity.max(1),
+            max_body: 64 * 1024,
+            routes: vec![],
+            redacted: ["authorization", "proxy-authorization", "cookie", "set-cookie"].map(String::from).to_vec(),
+            file: None,
+            exchanges: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    // Only records paths under `prefix`; everything, if never called.
+    pub fn route(mut self, prefix: &str) -> Recorder {
+        self.routes.push(prefix.to_string());
+        self
+    }
+
+    // How much of each body to keep, 64 KiB by default.
+    pub fn max_body(mut self, bytes: usize) -> Recorder {
+        self.max_body = bytes;
+        self
+    }
+
+    // Records this header's value as "[redacted]" too.
+    pub fn redact(mut self, header: &str) -> Recorder {
+        self.redacted.push(header.to_ascii_lowercase());
+        self
+    }
+
+    pub fn to_file(mut self, file: LogFile) -> Recorder {
+        self.file = Some(file);
+        self
+    }
+
+    // What's held, oldest first.
+    pub fn exchanges(&self) -> Vec<Exchange> {
+        self.exchanges.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn clear(&self) -> usize {
+        let mut exchanges = self.exchanges.lock().unwrap();
+        let cleared = exchanges.len();
+        exchanges.clear();
+        cleared
+    }
+
+    // Everything held as a JSON array.
+    pub fn render(&self) -> String {
+        let exchanges: Vec<String> = self.exchanges.lock().unwrap().iter().map(Exchange::to_json).collect();
+        format!("[{}]", exchanges.join(","))
+    }
+
+    // What's held as an HTTP Archive, for opening in a browser's devtools
+    // or attaching to a bug report.
+    pub fn har(&self) -> String {
+        har(&self.exchanges())
+    }
+
+    // Writes what's held to `path`, one JSON line per exchange, in the same
+    // form `to_file` logs them.
+    pub fn dump(&self, path: &str) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        for exchange in self.exchanges() {
+            writeln!(file, "{}", exchange.to_json())?;
+        }
+        file.flush()
+    }
+
+    fn records(&self, path: &str) -> bool {
+        let path = path.split('?').next().unwrap_or_default();
+        self.routes.is_empty() || self.routes.iter().any(|prefix| path.starts_with(prefix.as_str()))
+    }
+
+    fn headers(&self, headers: &[(String, String)]) -> Vec<(String, String)> {
+        headers.iter().map(|(name, value)| {
+            let value = if self.redacted.contains(&name.to_ascii_lowercase()) { REDACTED } else { value };
+            (name.clone(), value.to_string())
+        }).collect()
+    }
+}
+
+impl Middleware for Recorder {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        if !self.records(&request.path) {
+            return next(request);
+        }
+        let time = SystemTime::now();
+        let started = Instant::now();
+        // Taken now, before handlers get to change them.
+        let method = format!("{:?}", request.method);
+        let request_headers = self.headers(&request.headers);
+        let body = request.body_bytes();
+        let request_body = body[..body.len().min(self.max_body)].to_vec();
+        let body_length = body.len();
+
+        let response = next(request);
+        let response_body = response.body[..response.body.len().min(self.max_body)].to_vec();
+        let exchange = Exchange {
+            time,
+            duration: started.elapsed(),
+            method,
+            path: request.path.clone(),
+            truncated: request_body.len() < body_length || response_body.len() < response.body.len(),
+            request_headers,
+            request_body,
+            status: response.status_code.code(),
+            response_headers: self.headers(&response.headers),
+            response_body,
+        };
+
+        if let Some(file) = &self.file {
+            file.write_line(&exchange.to_json());
+        }
+        let mut exchanges = self.exchanges.lock().unwrap();
+        if exchanges.len() == self.capacity {
+            exchanges.pop_front();
+        }
+        exchanges.push_back(exchange);
+        response
+    }
+}
+
+impl Exchange {
+    // {"time":..., "duration_ms":..., "method":..., "path":...,
+    //  "request":{"headers":[[name, value], ...], "body":...},
+    //  "response":{"status":..., "headers":[...], "body":...}, "truncated":...}
+    //
+    // Bodies that aren't UTF-8 are written as "body_base64" instead.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"time\":{},\"duration_ms\":{:.3},\"method\":\"{}\",\"path\":{},\"request\":{{\"headers\":{},{}}},\"response\":{{\"status\":{},\"headers\":{},{}}},\"truncated\":{}}}",
+            json_string(&log::timestamp(self.time)),
+            self.duration.as_secs_f64() * 1000.0,
+            self.method,
+            json_string(&self.path),
+            headers_json(&self.request_headers),
+            body_json(&self.request_body),
+            self.status,
+            headers_json(&self.response_headers),
+            body_json(&self.response_body),
+            self.truncated,
+        )
+    }
+
+    // Reads back a line `to_json` wrote; None if it isn't one.
+    pub fn from_json(line: &str) -> Option<Exchange> {
+        let value = json::parse(line).ok()?;
+        let (request, response) = (value.get("request")?, value.get("response")?);
+        Some(Exchange {
+            time: value.get("time").and_then(Value::as_str).and_then(parse_timestamp).unwrap_or(UNIX_EPOCH),
+            duration: Duration::from_secs_f64(value.get("duration_ms").and_then(Value::as_f64).unwrap_or(0.0).max(0.0) / 1000.0),
+            method: value.get("method")?.as_str()?.to_string(),
+            path: value.get("path")?.as_str()?.to_string(),
+            request_headers: headers_from_json(request.get("headers")?)?,
+            request_body: body_from_json(request)?,
+            status: response.get("status")?.as_f64()? as u16,
+            response_headers: headers_from_json(response.get("headers")?)?,
+            response_body: body_from_json(response)?,
+            truncated: value.get("truncated").and_then(Value::as_bool).unwrap_or(false),
+        })
+    }
+}
+
+// Exchanges in the HTTP Archive format (HAR 1.2). Sizes and timings beyond
+// the total aren't recorded, so they're given as unknown.
+pub fn har(exchanges: &[Exchange]) -> String {
+    let entries: Vec<String> = exchanges.iter().map(|exchange| {
+        let header = |name: &str| {
+            exchange.request_headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+        };
+        let scheme = header("X-Forwarded-Proto").unwrap_or("http");
+        let url = format!("{scheme}://{}{}", header("Host").unwrap_or("localhost"), exchange.path);
+        let query: Vec<String> = exchange.path.split_once('?').map_or("", |(_, query)| query).split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+            .map(|(name, value)| har_pair(&percent_decode(&name.replace('+', " ")), &percent_decode(&value.replace('+', " "))))
+            .collect();
+        let post_data = match exchange.request_body.is_empty() {
+            true => String::new(),
+            false => format!(
+                ",\"postData\":{{\"mimeType\":{},\"text\":{}}}",
+                json_string(header("Content-Type").unwrap_or("")),
+                json_string(&String::from_utf8_lossy(&exchange.request_body))
+            ),
+        };
+        let mime_type = exchange.response_headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case("Content-Type"))
+            .map_or("", |(_, value)| value.as_str());
+        let content = match std::str::from_utf8(&exchange.response_body) {
+            Ok(text) => format!("\"text\":{}", json_string(text)),
+            Err(_) => format!("\"text\":\"{}\",\"encoding\":\"base64\"", base64::encode(&exchange.response_body)),
+        };
+        let status_text = StatusCode::from_code(exchange.status)
+            .and_then(|status| status.status_line().split_once(' '))
+            .map_or("", |(_, text)| text);
+        let milliseconds = exchange.duration.as_secs_f64() * 1000.0;
+        format!(
+            "{{\"startedDateTime\":{},\"time\":{milliseconds:.3},\"request\":{{\"method\":\"{}\",\"url\":{},\"httpVersion\":\"HTTP/1.1\",\"cookies\":[],\"headers\":{},\"queryString\":[{}]{post_data},\"headersSize\":-1,\"bodySize\":{}}},\"response\":{{\"status\":{},\"statusText\":{},\"httpVersion\":\"HTTP/1.1\",\"cookies\":[],\"headers\":{},\"content\":{{\"size\":{},\"mimeType\":{},{content}}},\"redirectURL\":\"\",\"headersSize\":-1,\"bodySize\":{}}},\"cache\":{{}},\"timings\":{{\"send\":0,\"wait\":{milliseconds:.3},\"receive\":0}}}}",
+            json_string(&log::timestamp(exchange.time)),
+            exchange.method,
+            json_string(&url),
+            har_headers(&exchange.request_headers),
+            query.join(","),
+            exchange.request_body.len(),
+            exchange.status,
+            json_string(status_text),
+            har_headers(&exchange.response_headers),
+            exchange.response_body.len(),
+            json_string(mime_type),
+            exchange.response_body.len(),
+        )
+    }).collect();
+    format!(
+        "{{\"log\":{{\"version\":\"1.2\",\"creator\":{{\"name\":\"web_server\",\"version\":\"\"}},\"entries\":[{}]}}}}",
+        entries.join(",")
+    )
+}
+
+fn har_headers(headers: &[(String, String)]) -> String {
+    let pairs: Vec<String> = headers.iter().map(|(name, value)| har_pair(name, value)).collect();
+    format!("[{}]", pairs.join(","))
+}
+
+fn har_pair(name: &str, value: &str) -> String {
+    format!("{{\"name\":{},\"value\":{}}}", json_string(name), json_string(value))
+}
+
+// Serves recorded responses back as fixtures, so this server can stand in
+// for a service another one is being tested against:
+//
+//     server.add_middleware(Replay::load("recorded.jsonl")?.strict(true));
+//
+// A request gets the response recorded for the same method, path (with its
+// query) and body. When one was recorded several times, the responses are
+// served in the order they were recorded, the last one repeating. Requests
+// whose body was cut off when recorded match on method and path alone.
+// Anything else goes on to the routes, or with `strict`, gets a 404.
+pub struct Replay {
+    responses: HashMap<String, Vec<Response>>,
+    served: Mutex<HashMap<String, usize>>,
+    strict: bool,
+}
+
+impl Replay {
+    pub fn new(exchanges: &[Exchange]) -> Replay {
+        let mut responses: HashMap<String, Vec<Response>> = HashMap::new();
+        for exchange in exchanges {
+            let Some(status) = StatusCode::from_code(exchange.status) else {
+                eprintln!("Not replaying {} {}: status {} isn't supported", exchange.method, exchange.path, exchange.status);
+                continue;
+            };
+            let mut response = Response::new(status, "");
+            response.body = exchange.response_body.clone();
+            // The server sets these itself, and redacted values can't be
+            // given back.
+            response.headers = exchange.response_headers.iter()
+                .filter(|(name, value)| value != REDACTED && !is_framing(name))
+                .cloned()
+                .collect();
+            let body = (!exchange.truncated).then_some(exchange.request_body.as_slice());
+            responses.entry(Replay::key(&exchange.method, &exchange.path, body)).or_default().push(response);
+        }
+        Replay { responses, served: Mutex::new(HashMap::new()), strict: false }
+    }
+
+    // Reads a file `Recorder::dump` or `Recorder::to_file` wrote.
+    pub fn load(path: &str) -> io::Result<Replay> {
+        let text = fs::read_to_string(path)?;
+        let mut exchanges = vec![];
+        for (number, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            match Exchange::from_json(line) {
+                Some(exchange) => exchanges.push(exchange),
+                None => eprintln!("Skipping line {} of {path}: not a recorded exchange", number + 1),
+            }
+        }
+        Ok(Replay::new(&exchanges))
+    }
+
+    // Answers requests nothing was recorded for with a 404 instead of
+    // passing them on.
+    pub fn strict(mut self, strict: bool) -> Replay {
+        self.strict = strict;
+        self
+    }
+
+    fn key(method: &str, path: &str, body: Option<&[u8]>) -> String {
+        match body {
+            Some(body) => format!("{method} {path} {}", sha256::hex(&sha256::digest(body))),
+            None => format!("{method} {path}"),
+        }
+    }
+}
+
+impl Middleware for Replay {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let method = format!("{:?}", request.method);
+        let exact = Replay::key(&method, &request.path, Some(request.body_bytes()));
+        let key = match self.responses.contains_key(&exact) {
+            true => exact,
+            false => Replay::key(&method, &request.path, None),
+        };
+        let Some(responses) = self.responses.get(&key) else {
+            if !self.strict {
+                return next(request);
+            }
+            eprintln!("No recorded response for {method} {}", request.path);
+            return Response::new(StatusCode::NotFound, "No recorded response");
+        };
+        let mut served = self.served.lock().unwrap();
+        let index = served.entry(key).or_insert(0);
+        let response = responses[(*index).min(responses.len() - 1)].clone();
+        *index += 1;
+        response
+    }
+}
+
+fn is_framing(name: &str) -> bool {
+    ["content-length", "transfer-encoding", "connection"].contains(&name.to_ascii_lowercase().as_str())
+}
+
+fn headers_json(headers: &[(String, String)]) -> String {
+    let pairs: Vec<String> = headers.iter()
+        .map(|(name, value)| format!("[{},{}]", json_string(name), json_string(value)))
+        .collect();
+    format!("[{}]", pairs.join(","))
+}
+
+fn body_json(body: &[u8]) -> String {
+    match std::str::from_utf8(body) {
+        Ok(text) => format!("\"body\":{}", json_string(text)),
+        // Cut off partway through a character by `max_body`.
+        Err(error) if error.error_len().is_none() => {
+            format!("\"body\":{}", json_string(std::str::from_utf8(&body[..error.valid_up_to()]).unwrap_or_default()))
+        }
+        Err(_) => format!("\"body_base64\":\"{}\"", base64::encode(body)),
+    }
+}
+
+fn headers_from_json(headers: &Value) -> Option<Vec<(String, String)>> {
+    headers.as_array()?.iter().map(|pair| match pair.as_array()? {
+        [name, value] => Some((name.as_str()?.to_string(), value.as_str()?.to_string())),
+        _ => None,
+    }).collect()
+}
+
+fn body_from_json(message: &Value) -> Option<Vec<u8>> {
+    match (message.get("body"), message.get("body_base64")) {
+        (Some(body), _) => Some(body.as_str()?.as_bytes().to_vec()),
+        (None, Some(body)) => base64::decode(body.as_str()?),
+        (None, None) => Some(vec![]),
+    }
+}
+
+// 2024-01-01T12:00:00.000Z, as `log::timestamp` writes them.
+fn parse_timestamp(text: &str) -> Option<SystemTime> {
+    let number = |range: std::ops::Range<usize>| text.get(range)?.parse::<u64>().ok();
+    let days = days_from_civil(number(0..4)?, number(5..7)?, number(8..10)?)?;
+    let seconds = days * 86400 + number(11..13)? * 3600 + number(14..16)? * 60 + number(17..19)?;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds) + Duration::from_millis(number(20..23).unwrap_or(0)))
+}
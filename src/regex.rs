@@ -0,0 +1,521 @@
+// A small backtracking regular expression engine, enough for routing and
+// rewrite rules: literals, `.`, classes (`[a-z0-9-]`, `\d`, `\w`, `\s`),
+// groups (capturing, `(?:...)` and named `(?P<name>...)`/`(?<name>...)`),
+// alternation, anchors, and greedy or lazy `*`, `+`, `?` and `{n,m}`.
+// Backtracking is exponential in the worst case, so patterns should come
+// from the people running the server, never from requests.
+
+#[derive(Debug)]
+enum Node {
+    Char(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat { node: Box<Node>, min: usize, max: Option<usize>, greedy: bool },
+}
+
+#[derive(Debug)]
+struct Class {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl Class {
+    fn of(ranges: &[(char, char)], negated: bool) -> Class {
+        Class { ranges: ranges.to_vec(), negated }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        self.ranges.iter().any(|&(low, high)| low <= c && c <= high) != self.negated
+    }
+}
+
+const DIGIT: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+const SPACE: &[(char, char)] = &[(' ', ' '), ('\t', '\r')];
+
+fn shorthand(c: char) -> Option<&'static [(char, char)]> {
+    match c {
+        'd' => Some(DIGIT),
+        'w' => Some(WORD),
+        's' => Some(SPACE),
+        _ => None,
+    }
+}
+
+fn unescape(c: char) -> char {
+    match c {
+        'n' => '\n',
+        't' => '\t',
+        c => c,
+    }
+}
+
+#[derive(Debug)]
+pub struct Regex {
+    root: Node,
+    // Group names by index; group 0 is the whole match.
+    names: Vec<Option<String>>,
+}
+
+// What a match captured, by group index; 0 is the whole match.
+#[derive(Debug)]
+pub struct Captures<'t> {
+    text: &'t str,
+    groups: Vec<Option<(usize, usize)>>,
+    names: &'t [Option<String>],
+}
+
+impl<'t> Captures<'t> {
+    pub fn get(&self, index: usize) -> Option<&'t str> {
+        let (start, end) = (*self.groups.get(index)?)?;
+        Some(&self.text[start..end])
+    }
+
+    pub fn name(&self, name: &str) -> Option<&'t str> {
+        let index = self.names.iter().position(|group| group.as_deref() == Some(name))?;
+        self.get(index)
+    }
+
+    // Fills in `$1`, `${1}` or `${name}` in `template` with what those groups
+    // captured (empty if they didn't take part); `$$` is a literal `$`.
+    pub fn expand(&self, template: &str) -> String {
+        let mut expanded = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(dollar) = rest.find('$') {
+            expanded.push_str(&rest[..dollar]);
+            rest = &rest[dollar + 1..];
+
+            let (reference, after) = if let Some(braced) = rest.strip_prefix('{') {
+                match braced.find('}') {
+                    Some(close) => (&braced[..close], &braced[close + 1..]),
+                    None => ("", rest),
+                }
+            } else if rest.starts_with('$') {
+                expanded.push('$');
+                rest = &rest[1..];
+                continue;
+            } else {
+                let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                (&rest[..digits], &rest[digits..])
+            };
+
+            if reference.is_empty() {
+                expanded.push('$');
+                continue;
+            }
+            let value = match reference.parse() {
+                Ok(index) => self.get(index),
+                Err(_) => self.name(reference),
+            };
+            expanded.push_str(value.unwrap_or_default());
+            rest = after;
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+
+    // Every group that took part in the match as (name, value) pairs, with
+    // unnamed groups named by their number.
+    pub fn pairs(&self) -> Vec<(String, String)> {
+        (1..self.groups.len())
+            .filter_map(|index| {
+                let value = self.get(index)?;
+                let name = self.names[index].clone().unwrap_or_else(|| index.to_string());
+                Some((name, value.to_string()))
+            })
+            .collect()
+    }
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let mut parser = Parser { chars: pattern.chars().collect(), position: 0, names: vec![None] };
+        let root = parser.alternation()?;
+        if parser.position < parser.chars.len() {
+            return Err(format!("Unmatched `)` in regex `{pattern}`"));
+        }
+        Ok(Regex { root, names: parser.names })
+    }
+
+    // The leftmost match anywhere in `text`; anchor with `^` and `$` to
+    // match the whole thing.
+    pub fn captures<'t>(&'t self, text: &'t str) -> Option<Captures<'t>> {
+        let chars: Vec<(usize, char)> = text.char_indices().collect();
+        let matcher = Matcher { chars: chars.iter().map(|&(_, c)| c).collect() };
+        let offset = |index: usize| chars.get(index).map_or(text.len(), |&(offset, _)| offset);
+
+        for start in 0..=chars.len() {
+            let mut groups = vec![None; self.names.len()];
+            let mut end = None;
+            let found = matcher.node(&self.root, start, &mut groups, &mut |position, _| {
+                end = Some(position);
+                true
+            });
+            if found {
+                groups[0] = Some((start, end.unwrap()));
+                let groups = groups.into_iter()
+                    .map(|group| group.map(|(start, end)| (offset(start), offset(end))))
+                    .collect();
+                return Some(Captures { text, groups, names: &self.names });
+            }
+        }
+        None
+    }
+}
+
+type Groups = Vec<Option<(usize, usize)>>;
+type Continuation<'a> = &'a mut dyn FnMut(usize, &mut Groups) -> bool;
+
+struct Matcher {
+    chars: Vec<char>,
+}
+
+impl Matcher {
+    // Tries to match `node` at `position`, calling `next` with where it
+    // ended; backtracks into the node whenever `next` fails.
+    fn node(&self, node: &Node, position: usize, groups: &mut Groups, next: Continuation) -> bool {
+        let current = self.chars.get(position).copied();
+        match node {
+            Node::Char(c) => current == Some(*c) && next(position + 1, groups),
+            Node::Any => current.is_some() && next(position + 1, groups),
+            Node::Class(class) => current.is_some_and(|c| class.matches(c)) && next(position + 1, groups),
+            Node::Start => position == 0 && next(position, groups),
+            Node::End => position == self.chars.len() && next(position, groups),
+            Node::Group(inner, None) => self.node(inner, position, groups, next),
+            Node::Group(inner, Some(index)) => {
+                let index = *index;
+                self.node(inner, position, groups, &mut |end, groups| {
+                    let saved = groups[index];
+                    groups[index] = Some((position, end));
+                    if next(end, groups) {
+                        return true;
+                    }
+                    groups[index] = saved;
+                    false
+                })
+            }
+            Node::Concat(nodes) => self.sequence(nodes, position, groups, next),
+            Node::Alternate(options) => {
+                for option in options {
+                    if self.node(option, position, groups, next) {
+                        return true;
+                    }
+                }
+                false
+            }
+            Node::Repeat { node, min, max, greedy } => {
+                self.repeat(node, (*min, *max, *greedy), 0, position, groups, next)
+            }
+        }
+    }
+
+    fn sequence(&self, nodes: &[Node], position: usize, groups: &mut Groups, next: Continuation) -> bool {
+        match nodes.split_first() {
+            None => next(position, groups),
+            Some((first, rest)) => {
+                self.node(first, position, groups, &mut |end, groups| self.sequence(rest, end, groups, next))
+            }
+        }
+    }
+
+    fn repeat(
+        &self,
+        node: &Node,
+        bounds: (usize, Option<usize>, bool),
+        count: usize,
+        position: usize,
+        groups: &mut Groups,
+        next: Continuation,
+    ) -> bool {
+        let (min, max, greedy) = bounds;
+        if !greedy && count >= min && next(position, groups) {
+            return true;
+        }
+        if max.is_none_or(|max| count < max) {
+            let more = self.node(node, position, groups, &mut |end, groups| {
+                // An iteration that matched nothing would loop forever.
+                (end != position || count < min) && self.repeat(node, bounds, count + 1, end, groups, next)
+            });
+            if more {
+                return true;
+            }
+        }
+        greedy && count >= min && next(position, groups)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    names: Vec<Option<String>>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let matched = self.peek() == Some(c);
+        if matched {
+            self.position += 1;
+        }
+        matched
+    }
+
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut options = vec![self.sequence()?];
+        while self.eat('|') {
+            options.push(self.sequence()?);
+        }
+        Ok(if options.len() == 1 { options.pop().unwrap() } else { Node::Alternate(options) })
+    }
+
+    fn sequence(&mut self) -> Result<Node, String> {
+        let mut nodes = vec![];
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantifier(atom)?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let c = self.peek().unwrap();
+        self.position += 1;
+        Ok(match c {
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '[' => Node::Class(self.class()?),
+            '(' => self.group()?,
+            '\\' => self.escape()?,
+            '*' | '+' | '?' => return Err(format!("Nothing to repeat before `{c}`")),
+            c => Node::Char(c),
+        })
+    }
+
+    fn group(&mut self) -> Result<Node, String> {
+        let index = if self.eat('?') {
+            if self.eat(':') {
+                None
+            } else {
+                self.eat('P');
+                if !self.eat('<') {
+                    return Err("Unsupported group syntax".to_string());
+                }
+                let mut name = String::new();
+                while let Some(c) = self.peek().filter(|&c| c != '>') {
+                    name.push(c);
+                    self.position += 1;
+                }
+                if !self.eat('>') || name.is_empty() {
+                    return Err("Invalid group name".to_string());
+                }
+                self.names.push(Some(name));
+                Some(self.names.len() - 1)
+            }
+        } else {
+            self.names.push(None);
+            Some(self.names.len() - 1)
+        };
+
+        let inner = self.alternation()?;
+        if !self.eat(')') {
+            return Err("Unclosed `(`".to_string());
+        }
+        Ok(Node::Group(Box::new(inner), index))
+    }
+
+    fn escape(&mut self) -> Result<Node, String> {
+        let c = self.peek().ok_or("Trailing `\\`")?;
+        self.position += 1;
+        if let Some(ranges) = shorthand(c.to_ascii_lowercase()) {
+            return Ok(Node::Class(Class::of(ranges, c.is_ascii_uppercase())));
+        }
+        Ok(Node::Char(unescape(c)))
+    }
+
+    fn class(&mut self) -> Result<Class, String> {
+        let negated = self.eat('^');
+        let mut ranges = vec![];
+        let mut first = true;
+        loop {
+            let c = self.peek().ok_or("Unclosed `[`")?;
+            self.position += 1;
+            let low = match c {
+                ']' if !first => break,
+                '\\' => {
+                    let escaped = self.peek().ok_or("Unclosed `[`")?;
+                    self.position += 1;
+                    if let Some(shorthand) = shorthand(escaped) {
+                        ranges.extend_from_slice(shorthand);
+                        first = false;
+                        continue;
+                    }
+                    unescape(escaped)
+                }
+                c => c,
+            };
+            first = false;
+
+            // A range, unless the `-` is the last thing in the class.
+            if self.peek() == Some('-') && self.chars.get(self.position + 1).is_some_and(|&c| c != ']') {
+                self.position += 1;
+                let mut high = self.peek().ok_or("Unclosed `[`")?;
+                self.position += 1;
+                if high == '\\' {
+                    high = self.peek().ok_or("Unclosed `[`")?;
+                    self.position += 1;
+                }
+                if high < low {
+                    return Err(format!("Invalid class range {low}-{high}"));
+                }
+                ranges.push((low, high));
+            } else {
+                ranges.push((low, low));
+            }
+        }
+        Ok(Class { ranges, negated })
+    }
+
+    fn quantifier(&mut self, atom: Node) -> Result<Node, String> {
+        let operator = match self.peek() {
+            Some('*') => Some((0, None)),
+            Some('+') => Some((1, None)),
+            Some('?') => Some((0, Some(1))),
+            _ => None,
+        };
+        let (min, max) = match operator {
+            Some(bounds) => {
+                self.position += 1;
+                bounds
+            }
+            None if self.peek() == Some('{') => match self.counts() {
+                Some(bounds) => bounds,
+                // Not a valid repetition, so a literal brace.
+                None => return Ok(atom),
+            },
+            None => return Ok(atom),
+        };
+        if max.is_some_and(|max| max < min) {
+            return Err(format!("Invalid repetition {{{min},{}}}", max.unwrap()));
+        }
+        let greedy = !self.eat('?');
+        Ok(Node::Repeat { node: Box::new(atom), min, max, greedy })
+    }
+
+    // Parses `{n}`, `{n,}` or `{n,m}`, consuming it only if it's valid.
+    fn counts(&mut self) -> Option<(usize, Option<usize>)> {
+        let close = self.chars[self.position..].iter().position(|&c| c == '}')? + self.position;
+        let inside: String = self.chars[self.position + 1..close].iter().collect();
+        let bounds = match inside.split_once(',') {
+            None => {
+                let count = inside.parse().ok()?;
+                (count, Some(count))
+            }
+            Some((min, "")) => (min.parse().ok()?, None),
+            Some((min, max)) => (min.parse().ok()?, Some(max.parse().ok()?)),
+        };
+        self.position = close + 1;
+        Some(bounds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, text: &str) -> Option<String> {
+        Regex::new(pattern).unwrap().captures(text).map(|captures| captures.get(0).unwrap().to_string())
+    }
+
+    #[test]
+    fn matches_leftmost() {
+        let cases: [(&str, &str, Option<&str>); 20] = [
+            ("abc", "xxabcxx", Some("abc")),
+            ("a.c", "abc", Some("abc")),
+            ("a.c", "ac", None),
+            ("^abc$", "abc", Some("abc")),
+            ("^abc$", "abcd", None),
+            ("[a-c]+", "xxbcaz", Some("bca")),
+            ("[^a-c]+", "abxyzc", Some("xyz")),
+            ("[a-]+", "x-a-", Some("-a-")),
+            ("\\d+", "abc123def", Some("123")),
+            ("\\D+", "123abc", Some("abc")),
+            ("\\w+", " a_1 ", Some("a_1")),
+            ("\\s", "a\tb", Some("\t")),
+            ("\\.", "a.b", Some(".")),
+            ("cat|category", "category", Some("cat")),
+            ("a{2,3}", "aaaa", Some("aaa")),
+            ("a{2}", "a", None),
+            ("a{2,}", "aaaaa", Some("aaaaa")),
+            ("a{x}", "a{x}", Some("a{x}")),
+            ("(a*)*b", "aaab", Some("aaab")),
+            ("", "abc", Some("")),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(find(pattern, text).as_deref(), expected, "{pattern} on {text}");
+        }
+    }
+
+    #[test]
+    fn greedy_and_lazy() {
+        assert_eq!(find("<.*>", "<a><b>").as_deref(), Some("<a><b>"));
+        assert_eq!(find("<.*?>", "<a><b>").as_deref(), Some("<a>"));
+        assert_eq!(find("a+?", "aaa").as_deref(), Some("a"));
+        assert_eq!(find("a??b", "ab").as_deref(), Some("ab"));
+    }
+
+    #[test]
+    fn captures_groups() {
+        let regex = Regex::new("^/users/(?P<id>\\d+)(?:/(\\w+))?$").unwrap();
+        let captures = regex.captures("/users/42/posts").unwrap();
+        assert_eq!(captures.get(1), Some("42"));
+        assert_eq!(captures.name("id"), Some("42"));
+        assert_eq!(captures.get(2), Some("posts"));
+        assert_eq!(captures.pairs(), vec![("id".to_string(), "42".to_string()), ("2".to_string(), "posts".to_string())]);
+
+        let captures = regex.captures("/users/42").unwrap();
+        assert_eq!(captures.get(2), None);
+        assert_eq!(captures.pairs(), vec![("id".to_string(), "42".to_string())]);
+
+        // The last iteration of a repeated group is what it captured.
+        let regex = Regex::new("(?<letter>[a-z])+").unwrap();
+        assert_eq!(regex.captures("abc").unwrap().name("letter"), Some("c"));
+    }
+
+    #[test]
+    fn byte_offsets_with_multibyte_text() {
+        let regex = Regex::new("(é+)(.)").unwrap();
+        let captures = regex.captures("caféé!").unwrap();
+        assert_eq!(captures.get(0), Some("éé!"));
+        assert_eq!(captures.get(1), Some("éé"));
+        assert_eq!(captures.get(2), Some("!"));
+    }
+
+    #[test]
+    fn expands_templates() {
+        let regex = Regex::new("^/old/(?P<page>\\w+)/(\\d+)$").unwrap();
+        let captures = regex.captures("/old/about/7").unwrap();
+        assert_eq!(captures.expand("/new/${page}/$2"), "/new/about/7");
+        assert_eq!(captures.expand("$1-${2}x"), "about-7x");
+        assert_eq!(captures.expand("$$1 $ ${missing} $9"), "$1 $  ");
+        assert_eq!(captures.expand("${unclosed"), "${unclosed");
+    }
+
+    #[test]
+    fn rejects_bad_patterns() {
+        for pattern in ["(", "a)", "[abc", "*a", "a|+", "[z-a]", "a{3,1}", "(?x)", "(?P<>a)", "\\"] {
+            assert!(Regex::new(pattern).is_err(), "{pattern}");
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::{
+    http::{Request, StatusCode},
+    regex::Regex,
+};
+
+// Maps old URLs onto new ones before routing. A rule's regex is searched for
+// in the request path (without the query string); on the first rule that
+// matches, the target has `$1`, `${name}` etc. replaced with the captures and
+// either becomes the path that gets routed, or the Location of a redirect.
+pub(crate) struct RewriteRule {
+    pattern: Regex,
+    target: String,
+    redirect: Option<StatusCode>,
+}
+
+pub(crate) enum Rewritten {
+    Path(String),
+    Redirect(StatusCode, String),
+}
+
+impl RewriteRule {
+    pub(crate) fn new(from: &str, to: &str, redirect: Option<StatusCode>) -> Result<RewriteRule, String> {
+        Ok(RewriteRule { pattern: Regex::new(from)?, target: to.to_string(), redirect })
+    }
+
+    pub(crate) fn apply(&self, path: &str) -> Option<Rewritten> {
+        let (path, query) = match path.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (path, None),
+        };
+        let captures = self.pattern.captures(path)?;
+        let mut target = captures.expand(&self.target);
+        // Carry the query over unless the target brings its own.
+        if let Some(query) = query.filter(|_| !target.contains('?')) {
+            target = format!("{target}?{query}");
+        }
+        Some(match self.redirect {
+            Some(status) => Rewritten::Redirect(status, target),
+            None => Rewritten::Path(target),
+        })
+    }
+}
+
+// Sends requests for one host, or the wrong scheme, to the canonical one
+// before anything else is looked at: www.example.com to example.com, or
+// plain HTTP to HTTPS. Path and query are kept.
+//
+// `from` is a host name, `*.example.com` for any subdomain of it, or `*`
+// for every host. The scheme a request came in over is taken from
+// X-Forwarded-Proto, since TLS is terminated in front of the server, and is
+// http without one.
+pub(crate) struct HostRule {
+    from: String,
+    // What to change; None keeps the request's own.
+    scheme: Option<String>,
+    host: Option<String>,
+    status: StatusCode,
+}
+
+impl HostRule {
+    pub(crate) fn new(from: &str, scheme: Option<&str>, host: Option<&str>, status: StatusCode) -> HostRule {
+        HostRule {
+            from: from.to_ascii_lowercase(),
+            scheme: scheme.map(str::to_ascii_lowercase),
+            host: host.map(str::to_string),
+            status,
+        }
+    }
+
+    // The status and Location to redirect `request` to, unless it's already
+    // where this rule would send it.
+    pub(crate) fn apply(&self, request: &Request) -> Option<(StatusCode, String)> {
+        let host = request.header("Host").filter(|host| !host.is_empty())?;
+        if !self.covers(host_name(host)) {
+            return None;
+        }
+        let scheme = request_scheme(request);
+        let target_scheme = self.scheme.as_deref().unwrap_or(&scheme);
+        let target_host = self.host.as_deref().unwrap_or(host);
+        if target_scheme == scheme && target_host.eq_ignore_ascii_case(host) {
+            return None;
+        }
+        Some((self.status, format!("{target_scheme}://{target_host}{}", request.path)))
+    }
+
+    fn covers(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        match self.from.strip_prefix("*") {
+            Some("") => true,
+            Some(suffix) => name.ends_with(suffix),
+            None => name == self.from,
+        }
+    }
+}
+
+// Splits a redirect target like "https://example.com" into its scheme, if
+// it has one, and host.
+pub(crate) fn split_target(to: &str) -> (Option<&str>, &str) {
+    match to.split_once("://") {
+        Some((scheme, host)) => (Some(scheme), host.trim_end_matches('/')),
+        None => (None, to.trim_end_matches('/')),
+    }
+}
+
+// The Host header without its port, minding the colons in IPv6 literals.
+pub(crate) fn host_name(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    }
+}
+
+fn request_scheme(request: &Request) -> String {
+    request.header("X-Forwarded-Proto")
+        .and_then(|proto| proto.split(',').next())
+        .map(|proto| proto.trim().to_ascii_lowercase())
+        .filter(|proto| !proto.is_empty())
+        .unwrap_or_else(|| "http".to_string())
+}
+
+// The statuses a redirect rule may use.
+pub(crate) fn redirect_status(code: u16) -> Option<StatusCode> {
+    match code {
+        301 => Some(StatusCode::MovedPermanently),
+        302 => Some(StatusCode::Found),
+        307 => Some(StatusCode::TemporaryRedirect),
+        308 => Some(StatusCode::PermanentRedirect),
+        _ => None,
+    }
+}
@@ -1,195 +1,2922 @@
 use std::{
+    collections::HashMap,
+    env,
     fs,
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
+    io::{self, prelude::*, IoSlice},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, RwLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
-use std::fmt::{Display, Formatter};
-use crate::{ThreadPool};
+use crate::{
+    body::{BodyFile, BodyReader},
+    broadcast::Broadcast,
+    buffer::BufferPool,
+    clock::{self, Clock},
+    compress::{self, InflateError},
+    config::{self, Config, ConfigError, Mount, MountTarget, Redirect},
+    dispatch::Dispatch,
+    http::{http_date, FileBody, HeaderCase, HttpMethod, Request, Response, StatusCode},
+    jsonrpc::JsonRpc,
+    log::{self, LogFile},
+    longpoll::Wakeups,
+    metrics::Metrics,
+    parser::{self, Parsing, Rejection},
+    plugin::Plugin,
+    middleware::{self, AccessLog, CacheControl, DigestAuth, LogFormat, Middleware, ResponseCache, Timeout},
+    mirror::Mirror,
+    oauth::OAuth,
+    recorder::Recorder,
+    proxy_protocol,
+    regex::Regex,
+    rewrite::{self, HostRule, RewriteRule, Rewritten},
+    socket::{self, KeepAlive, SocketOptions},
+    static_files::{self, StaticDir},
+    stream::ResponseWriter,
+    upload::{UploadOptions, Uploads},
+    webdav::WebDav,
+    webhook::Webhook,
+    websocket::{self, OpenSockets, WebSocket, WebSocketOptions},
+    ThreadPool,
+};
+#[cfg(target_os = "linux")]
+use crate::handover;
+#[cfg(target_os = "linux")]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use crate::{signal, systemd};
+
+mod admin;
+
+use admin::Admin;
+
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_URI_LENGTH: usize = 8 * 1024;
+// Room for the method, version and spaces around the target.
+const REQUEST_LINE_SLACK: usize = 64;
+const SEND_FILE_CHUNK: u64 = 64 * 1024;
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+const READ_CHUNK_SIZE: usize = 4 * 1024;
+const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge";
+
+static READ_BUFFERS: BufferPool = BufferPool::new(64, MAX_HEAD_SIZE + READ_CHUNK_SIZE);
+static WRITE_BUFFERS: BufferPool = BufferPool::new(64, 16 * 1024);
+
+pub struct Server {
+    listeners: Vec<TcpListener>,
+    socket_options: SocketOptions,
+    load_shedding: LoadShedding,
+    pool: ThreadPool,
+    shared: Arc<Shared>,
+    shutdown: ShutdownHandle,
+    config_path: Option<String>,
+    handle_signals: bool,
+    handover_socket: Option<String>,
+    shutdown_grace: Option<Duration>,
+    drain_timeout_hook: Option<DrainTimeoutHook>,
+    admin: Option<Admin>,
+    wakeups: Wakeups,
+    broadcasts: Vec<Broadcast>,
+    websockets: OpenSockets,
+    // A descriptor held back to give up when accept runs out of them.
+    reserved_fd: Mutex<Option<fs::File>>,
+}
+
+// What the workers need to answer requests: everything after the acceptor
+// has handed over a connection, from reading its first request head to
+// any further requests on the same keep-alive connection.
+struct Shared {
+    // Requests take a reference to the current table and let go of the
+    // lock straight away; a reload or a RoutesHandle swaps in a new one.
+    routes: Arc<RwLock<Arc<Routes>>>,
+    shutdown: ShutdownHandle,
+    max_body_size: u64,
+    spill: Option<Spill>,
+    decompress_ratio: Option<u64>,
+    keep_alive_timeout: Duration,
+    head_timeout: Duration,
+    max_pipelined: usize,
+    proxy_protocol: bool,
+    open_connections: OpenConnections,
+    max_idle_connections: Option<usize>,
+    idle_timeout: Option<Duration>,
+    metrics: Option<Metrics>,
+    slow_log: Option<SlowLog>,
+    queue_wait_header: bool,
+    header_case: HeaderCase,
+    head_limits: HeadLimits,
+    send_rate: Option<SendRate>,
+    // What the Date header on responses goes by.
+    clock: Arc<dyn Clock>,
+}
+
+// The slowest a client may read a response, after `grace`.
+#[derive(Clone, Copy)]
+struct SendRate {
+    bytes_per_second: u64,
+    grace: Duration,
+}
+
+// How much of a response has gone out since it started, checked against
+// the send rate as it goes.
+struct Progress {
+    rate: Option<SendRate>,
+    started: Instant,
+    sent: u64,
+}
+
+impl Progress {
+    fn new(rate: Option<SendRate>) -> Progress {
+        Progress { rate, started: Instant::now(), sent: 0 }
+    }
+
+    fn sent(&mut self, bytes: usize) -> io::Result<()> {
+        self.sent += bytes as u64;
+        let Some(rate) = self.rate else {
+            return Ok(());
+        };
+        let elapsed = self.started.elapsed();
+        if elapsed > rate.grace && (self.sent as f64) < rate.bytes_per_second as f64 * elapsed.as_secs_f64() {
+            return Err(Progress::too_slow(rate));
+        }
+        Ok(())
+    }
+
+    fn too_slow(rate: SendRate) -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, format!("Client read slower than {} bytes/s", rate.bytes_per_second))
+    }
+}
+
+// What `read_stream` holds request heads to.
+struct HeadLimits {
+    parsing: Parsing,
+    max_uri_length: usize,
+}
+
+// Where requests that took longer than `threshold` get logged, with where
+// the time went; stdout without a file.
+struct SlowLog {
+    threshold: Duration,
+    file: Option<LogFile>,
+}
+
+// How long a request spent waiting for a worker, reading its body, in
+// middleware and the handler, and being written out.
+struct Timing {
+    queued: Duration,
+    read: Duration,
+    handler: Duration,
+    write: Duration,
+}
+
+type DrainTimeoutHook = Box<dyn Fn(usize) + Send + Sync>;
+
+// Every connection between being accepted and closed, so a shutdown can
+// close the idle keep-alive ones straight away and, once it runs out of
+// patience, whatever is left. It also lets idle connections be capped and
+// reaped; see `ServerBuilder::max_idle_connections` and `idle_timeout`.
+#[derive(Default)]
+struct OpenConnections {
+    next_id: AtomicU64,
+    streams: Mutex<HashMap<u64, (TcpStream, ConnectionState)>>,
+}
+
+#[derive(Clone, Copy)]
+enum ConnectionState {
+    // Accepted, and waiting for its first request, since when.
+    Waiting(Instant),
+    Busy,
+    // Waiting for its next request, since when.
+    Idle(Instant),
+}
+
+impl OpenConnections {
+    fn track(&self, stream: &TcpStream) -> Option<u64> {
+        let stream = stream.try_clone().ok()?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.streams.lock().unwrap().insert(id, (stream, ConnectionState::Waiting(Instant::now())));
+        Some(id)
+    }
+
+    fn untrack(&self, id: Option<u64>) {
+        if let Some(id) = id {
+            self.streams.lock().unwrap().remove(&id);
+        }
+    }
+
+    fn set_idle(&self, id: Option<u64>, idle: bool) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some((_, state)) = id.and_then(|id| streams.get_mut(&id)) {
+            *state = if idle { ConnectionState::Idle(Instant::now()) } else { ConnectionState::Busy };
+        }
+    }
+
+    // How many connections are open, and how many of those are idle.
+    fn counts(&self) -> (usize, usize) {
+        let streams = self.streams.lock().unwrap();
+        let idle = streams.values().filter(|(_, state)| matches!(state, ConnectionState::Idle(_))).count();
+        (streams.len(), idle)
+    }
+
+    // Shuts down connections with no request in progress, waking the
+    // workers blocked waiting for one.
+    fn close_idle(&self) {
+        for (stream, state) in self.streams.lock().unwrap().values() {
+            if let ConnectionState::Idle(_) = state {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    // Closes the longest idle connections past the first `max`. Returns how
+    // many it closed.
+    fn cap_idle(&self, max: usize) -> usize {
+        let mut streams = self.streams.lock().unwrap();
+        let mut idle: Vec<(Instant, u64)> = streams.iter()
+            .filter_map(|(id, (_, state))| match state {
+                ConnectionState::Idle(since) => Some((*since, *id)),
+                _ => None,
+            })
+            .collect();
+        if idle.len() <= max {
+            return 0;
+        }
+        idle.sort();
+        let excess = idle.len() - max;
+        for (_, id) in &idle[..excess] {
+            if let Some((stream, _)) = streams.remove(id) {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        excess
+    }
+
+    // Closes connections that have gone without a request, or without
+    // finishing the head of one, for longer than `timeout`. Returns how many
+    // it closed.
+    fn reap(&self, timeout: Duration) -> usize {
+        let mut streams = self.streams.lock().unwrap();
+        let before = streams.len();
+        streams.retain(|_, (stream, state)| match state {
+            ConnectionState::Waiting(since) | ConnectionState::Idle(since) if since.elapsed() > timeout => {
+                let _ = stream.shutdown(Shutdown::Both);
+                false
+            }
+            _ => true,
+        });
+        before - streams.len()
+    }
+
+    // Shuts down every connection still open, failing the reads and writes
+    // of the handlers behind them. Returns how many there were.
+    fn close_all(&self) -> usize {
+        let streams = std::mem::take(&mut *self.streams.lock().unwrap());
+        for (stream, _) in streams.values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        streams.len()
+    }
+}
+
+// Untracks a connection however its worker stops serving it.
+struct Tracked<'a> {
+    connections: &'a OpenConnections,
+    id: Option<u64>,
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        self.connections.untrack(self.id);
+    }
+}
+
+// Everything that decides how a request gets answered. It sits behind a lock
+// so a config reload can swap it while the server is running.
+#[derive(Clone, Default)]
+struct Routes {
+    endpoints: Vec<Endpoint>,
+    middleware: Arc<Vec<Arc<dyn Middleware>>>,
+    // How many of the middleware at the front of the chain came from the
+    // config file, and get replaced on reload.
+    config_middleware: usize,
+    rewrites: Vec<Arc<RewriteRule>>,
+    config_rewrites: usize,
+    host_redirects: Vec<Arc<HostRule>>,
+    config_host_redirects: usize,
+    fallbacks: Arc<Vec<Fallback>>,
+    // Middleware for every path under a prefix; see `Server::group`.
+    group_middleware: Vec<(String, Arc<dyn Middleware>)>,
+    hooks: Arc<Hooks>,
+    // Whole responses, head and body, to write as they are; see
+    // `Server::add_fast_response`.
+    fast: HashMap<String, Arc<[u8]>>,
+}
+
+
+// Lighter-weight than middleware: see `Server::on_request` and friends.
+#[derive(Clone, Default)]
+struct Hooks {
+    request: Vec<RequestHook>,
+    response: Vec<ResponseHook>,
+    error: Vec<ErrorHook>,
+}
+
+type RequestHook = Arc<dyn Fn(&mut Request) + Send + Sync>;
+type ResponseHook = Arc<dyn Fn(&Request, &mut Response) + Send + Sync>;
+type ErrorHook = Arc<dyn Fn(&Request, &Response) + Send + Sync>;
+
+// What routing picked for a request.
+// Borrows from the route table rather than copying out of it.
+struct Matched<'a> {
+    handler: Handler,
+    route: Option<&'a str>,
+    params: Vec<(String, String)>,
+    middleware: &'a [Arc<dyn Middleware>],
+    stream_body: Option<u64>,
+}
+
+impl Matched<'_> {
+    fn handler(handler: Handler) -> Matched<'static> {
+        Matched { handler, route: None, params: vec![], middleware: &[], stream_body: None }
+    }
+
+    fn endpoint(endpoint: &Endpoint, params: Vec<(String, String)>) -> Matched<'_> {
+        Matched {
+            handler: Arc::clone(&endpoint.handler),
+            route: Some(&endpoint.path),
+            params,
+            middleware: &endpoint.middleware,
+            stream_body: endpoint.stream_body,
+        }
+    }
+}
+
+// Returned when registering a route, to attach middleware to just that
+// route, running after any global and group middleware:
+//
+//     server.add_handler("/admin", admin).with(auth).with(rate_limit);
+pub struct Route<'a> {
+    endpoint: &'a mut Endpoint,
+}
+
+impl Route<'_> {
+    pub fn with<M: Middleware + 'static>(self, middleware: M) -> Self {
+        self.endpoint.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    // Hands the handler the body as a stream (`request.body_reader()`)
+    // instead of reading it into `request.body` first, accepting bodies of
+    // up to `max_size` bytes.
+    pub fn stream_body(self, max_size: u64) -> Self {
+        self.endpoint.stream_body = Some(max_size);
+        self
+    }
+}
+
+// Middleware for every request under a path prefix, whichever route or
+// fallback ends up answering it. See `Server::group`.
+pub struct Group<'a> {
+    routes: &'a mut Routes,
+    prefix: String,
+}
+
+impl Group<'_> {
+    pub fn with<M: Middleware + 'static>(self, middleware: M) -> Self {
+        self.routes.group_middleware.push((self.prefix.clone(), Arc::new(middleware)));
+        self
+    }
+}
+
+pub type Handler = Arc<dyn Fn(&Request) -> Response + Send + Sync>;
+// Like a handler, but may pass (None) to let the next fallback try.
+type Fallback = Arc<dyn Fn(&Request) -> Option<Response> + Send + Sync>;
+
+pub struct ServerBuilder {
+    ip: String,
+    port: u32,
+    workers: usize,
+    max_workers: Option<usize>,
+    worker_idle_timeout: Duration,
+    acceptors: usize,
+    socket_options: SocketOptions,
+    load_shedding: LoadShedding,
+    handover_socket: Option<String>,
+    proxy_protocol: bool,
+    max_body_size: u64,
+    spill: Option<Spill>,
+    decompress_ratio: Option<u64>,
+    shutdown_grace: Option<Duration>,
+    keep_alive_timeout: Duration,
+    head_timeout: Duration,
+    max_pipelined: usize,
+    max_idle_connections: Option<usize>,
+    idle_timeout: Option<Duration>,
+    reserve_fd: bool,
+    plugins: Vec<Box<dyn Plugin>>,
+    admin: Option<(String, String)>,
+    metrics: Option<Metrics>,
+    slow_log: Option<SlowLog>,
+    queue_wait_header: bool,
+    header_case: HeaderCase,
+    parsing: Parsing,
+    max_uri_length: usize,
+    send_rate: Option<SendRate>,
+    clock: Arc<dyn Clock>,
+}
+
+// Where and past what size buffered request bodies go to disk instead.
+#[derive(Clone)]
+struct Spill {
+    threshold: u64,
+    dir: PathBuf,
+}
+
+// High-water marks past which new requests are turned away with a 503 rather
+// than queued behind work that is already running late.
+#[derive(Clone, Copy)]
+struct LoadShedding {
+    max_queued: Option<usize>,
+    max_in_flight: Option<usize>,
+    retry_after: Duration,
+}
+
+impl ServerBuilder {
+    pub fn new(ip: &str, port: u32) -> ServerBuilder {
+        ServerBuilder {
+            ip: ip.to_string(),
+            port,
+            workers: 4,
+            max_workers: None,
+            worker_idle_timeout: Duration::from_secs(30),
+            acceptors: 1,
+            socket_options: SocketOptions::default(),
+            load_shedding: LoadShedding {
+                max_queued: None,
+                max_in_flight: None,
+                retry_after: Duration::from_secs(1),
+            },
+            handover_socket: None,
+            proxy_protocol: false,
+            max_body_size: 8 * 1024 * 1024,
+            spill: None,
+            decompress_ratio: None,
+            shutdown_grace: None,
+            keep_alive_timeout: Duration::from_secs(5),
+            head_timeout: Duration::from_secs(10),
+            max_pipelined: 16,
+            max_idle_connections: None,
+            idle_timeout: None,
+            reserve_fd: false,
+            plugins: vec![],
+            admin: None,
+            metrics: None,
+            slow_log: None,
+            queue_wait_header: false,
+            header_case: HeaderCase::AsSet,
+            parsing: Parsing::Lenient,
+            max_uri_length: DEFAULT_MAX_URI_LENGTH,
+            send_rate: None,
+            clock: clock::system(),
+        }
+    }
+
+    pub fn workers(mut self, workers: usize) -> ServerBuilder {
+        self.workers = workers;
+        self
+    }
+
+    // Lets the pool grow past `workers` up to this many threads while jobs
+    // are queueing, shrinking back once the extra threads sit idle.
+    pub fn max_workers(mut self, max_workers: usize) -> ServerBuilder {
+        self.max_workers = Some(max_workers);
+        self
+    }
+
+    pub fn worker_idle_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.worker_idle_timeout = timeout;
+        self
+    }
+
+    // Spawns this many accept loops, each with its own SO_REUSEPORT listener
+    // on Linux, so accepting isn't limited to a single thread.
+    pub fn acceptors(mut self, acceptors: usize) -> ServerBuilder {
+        assert!(acceptors > 0);
+        self.acceptors = acceptors;
+        self
+    }
+
+    // Disables Nagle's algorithm on accepted connections, trading a few more
+    // packets for lower latency on small responses.
+    pub fn nodelay(mut self, nodelay: bool) -> ServerBuilder {
+        self.socket_options.nodelay = nodelay;
+        self
+    }
+
+    // Enables TCP keepalive probes: the first after `idle` without traffic,
+    // then every `interval`, giving up after `retries` unanswered ones.
+    pub fn keepalive(mut self, idle: Duration, interval: Duration, retries: u32) -> ServerBuilder {
+        self.socket_options.keepalive = Some(KeepAlive { idle, interval, retries });
+        self
+    }
+
+    pub fn backlog(mut self, backlog: i32) -> ServerBuilder {
+        self.socket_options.backlog = backlog;
+        self
+    }
+
+    pub fn send_buffer_size(mut self, size: usize) -> ServerBuilder {
+        self.socket_options.send_buffer_size = Some(size);
+        self
+    }
+
+    pub fn recv_buffer_size(mut self, size: usize) -> ServerBuilder {
+        self.socket_options.recv_buffer_size = Some(size);
+        self
+    }
+
+    // How long closing a connection may block to flush unsent data.
+    pub fn linger(mut self, linger: Duration) -> ServerBuilder {
+        self.socket_options.linger = Some(linger);
+        self
+    }
+
+    // Sheds load once this many requests are waiting for a worker.
+    pub fn max_queued(mut self, requests: usize) -> ServerBuilder {
+        self.load_shedding.max_queued = Some(requests);
+        self
+    }
+
+    // Sheds load once this many requests are queued or running.
+    pub fn max_in_flight(mut self, requests: usize) -> ServerBuilder {
+        self.load_shedding.max_in_flight = Some(requests);
+        self
+    }
+
+    // The Retry-After sent with shed requests; one second by default.
+    pub fn retry_after(mut self, delay: Duration) -> ServerBuilder {
+        self.load_shedding.retry_after = delay;
+        self
+    }
+
+    // Requests with bodies bigger than this are refused with a 413 before
+    // their handler runs. 8 MiB by default; streaming routes set their own.
+    pub fn max_body_size(mut self, bytes: u64) -> ServerBuilder {
+        self.max_body_size = bytes;
+        self
+    }
+
+    // Writes request bodies bigger than `bytes` to a temporary file instead
+    // of memory, for handlers to read through `request.body_file()`. Pair
+    // with a higher `max_body_size` to take big uploads safely.
+    pub fn spill_bodies_over(mut self, bytes: u64) -> ServerBuilder {
+        let dir = self.spill.take().map_or_else(env::temp_dir, |spill| spill.dir);
+        self.spill = Some(Spill { threshold: bytes, dir });
+        self
+    }
+
+    // Where spilled bodies go; the system temp directory by default.
+    pub fn spill_dir(mut self, dir: &str) -> ServerBuilder {
+        let threshold = self.spill.take().map_or(u64::MAX, |spill| spill.threshold);
+        self.spill = Some(Spill { threshold, dir: PathBuf::from(dir) });
+        self
+    }
+
+    // Decompresses gzip and deflate request bodies before handlers see them.
+    // A body that would expand more than `max_ratio` times its compressed
+    // size, or past `max_body_size`, is refused with 413 so a small zip bomb
+    // can't eat the server's memory.
+    pub fn decompress_requests(mut self, max_ratio: u64) -> ServerBuilder {
+        self.decompress_ratio = Some(max_ratio);
+        self
+    }
+
+    // How long a shutdown waits for requests in flight before closing their
+    // connections anyway. Without one it waits as long as they take.
+    pub fn shutdown_grace(mut self, grace: Duration) -> ServerBuilder {
+        self.shutdown_grace = Some(grace);
+        self
+    }
+
+    // How long a connection may sit idle between requests before it's
+    // closed. Zero turns keep-alive off, closing every connection after one
+    // response. While idle, a connection holds on to its worker.
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.keep_alive_timeout = timeout;
+        self
+    }
+
+    // How long a new connection may go quiet while sending its PROXY header
+    // or first request head, 10 seconds by default. A client that connects
+    // and says nothing holds a worker until then.
+    pub fn head_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        if !timeout.is_zero() {
+            self.head_timeout = timeout;
+        }
+        self
+    }
+
+    // How many keep-alive connections may sit idle at once. Past that, the
+    // ones idle longest are closed to make room, each freeing its worker
+    // and file descriptor. Unlimited unless set.
+    pub fn max_idle_connections(mut self, connections: usize) -> ServerBuilder {
+        self.max_idle_connections = Some(connections);
+        self
+    }
+
+    // Runs a reaper that closes connections which have gone `timeout`
+    // without a request: idle keep-alive connections, and new ones that
+    // haven't finished sending their first request head, which nothing
+    // else times out.
+    pub fn idle_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    // Lets `plugin` set options now and add its routes once the server is
+    // built; see `Plugin`.
+    pub fn plugin<P: Plugin + 'static>(self, plugin: P) -> ServerBuilder {
+        let mut builder = plugin.configure(self);
+        builder.plugins.push(Box::new(plugin));
+        builder
+    }
+
+    // Holds a spare file descriptor, so that when the process runs out of
+    // them the server can still accept a connection and answer it with a
+    // 503 rather than leave it queued. Either way, running out makes the
+    // acceptor close idle connections and back off.
+    pub fn reserve_fd(mut self, reserve: bool) -> ServerBuilder {
+        self.reserve_fd = reserve;
+        self
+    }
+
+    // Cuts off clients that read a response slower than `bytes_per_second`
+    // once it has been going for `grace`, and any write that stays blocked
+    // that long, so a few slow readers can't keep workers busy draining
+    // big responses to them. Applies to responses the server writes, not
+    // to streaming handlers, which write at their own pace.
+    pub fn min_send_rate(mut self, bytes_per_second: u64, grace: Duration) -> ServerBuilder {
+        self.send_rate = Some(SendRate { bytes_per_second, grace });
+        self
+    }
+
+    // Where the Date header every response gets comes from; the system
+    // clock unless set, or a MockClock to pin it in tests.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> ServerBuilder {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    // How many requests a client may send ahead of reading the responses
+    // (HTTP/1.1 pipelining) before the connection is closed after the
+    // current one. Requests are still answered one at a time, in order.
+    pub fn max_pipelined(mut self, requests: usize) -> ServerBuilder {
+        self.max_pipelined = requests.max(1);
+        self
+    }
+
+    // Counts what goes wrong below the handlers in `metrics`, as
+    // `web_server_connection_errors_total` labelled with the kind:
+    // "parse" for heads and bodies that can't be made sense of, "timeout"
+    // for requests a Timeout gave up on or that stalled mid-body, "reset"
+    // for clients that went away mid-request, "oversized" for heads and
+    // bodies over their limits, "slow_read" for clients `min_send_rate`
+    // cut off, and "fds" for each time accept ran out of file descriptors.
+    pub fn metrics(mut self, metrics: &Metrics) -> ServerBuilder {
+        self.metrics = Some(metrics.clone());
+        self
+    }
+
+    // Logs every request that takes longer than `threshold`, from its head
+    // being read to its response being written, with its route and how
+    // long it queued for a worker and spent being read, handled and
+    // written. Goes to `file`, or stdout without one.
+    pub fn slow_log(mut self, threshold: Duration, file: Option<LogFile>) -> ServerBuilder {
+        self.slow_log = Some(SlowLog { threshold, file });
+        self
+    }
+
+    // Says in an X-Queue-Ms header how long each request's connection
+    // waited for a worker, in milliseconds, to tell a saturated pool from
+    // slow handlers. Requests after the first on a keep-alive connection
+    // didn't wait, and say 0. The wait goes into `metrics` regardless.
+    pub fn queue_wait_header(mut self, enabled: bool) -> ServerBuilder {
+        self.queue_wait_header = enabled;
+        self
+    }
+
+    // How response header names are spelled on the wire; as the handlers
+    // and middleware set them unless told otherwise. Heads a streaming
+    // handler writes itself go out as written.
+    pub fn header_case(mut self, case: HeaderCase) -> ServerBuilder {
+        self.header_case = case;
+        self
+    }
+
+    // Holds request heads to RFC 9112, answering the rest with a 400 rather
+    // than making what it can of them; see `Parsing` for the difference.
+    // Worth turning on behind proxies that are strict themselves.
+    pub fn strict_parsing(mut self, strict: bool) -> ServerBuilder {
+        self.parsing = if strict { Parsing::Strict } else { Parsing::Lenient };
+        self
+    }
+
+    // Answers requests whose target (path and query) is longer than this
+    // with a 414, 8 KiB unless told otherwise. A request line still going
+    // past it is cut off there, rather than read up to the head limit.
+    pub fn max_uri_length(mut self, bytes: usize) -> ServerBuilder {
+        self.max_uri_length = bytes;
+        self
+    }
+
+    // Serves the admin endpoint (see `Admin` in server/admin.rs) on its own
+    // listener, e.g. ("127.0.0.1", 9000), to requests bearing `token`.
+    pub fn admin(mut self, ip: &str, port: u32, token: &str) -> ServerBuilder {
+        if token.is_empty() {
+            panic!("The admin endpoint needs a token");
+        }
+        self.admin = Some((format!("{ip}:{port}"), token.to_string()));
+        self
+    }
+
+    // Expects every connection to start with a PROXY protocol (v1 or v2)
+    // header, as sent by HAProxy or a TCP load balancer, and takes the client
+    // address from it. Only enable this behind such a proxy: anyone who can
+    // connect directly could claim to be any address.
+    pub fn proxy_protocol(mut self, enabled: bool) -> ServerBuilder {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    // Zero-downtime upgrades (Linux only): on build, if a server is already
+    // running with the same handover socket, take over its listeners instead
+    // of binding, and it drains and exits. Once running, this server offers
+    // its own listeners on the socket to whichever process comes next.
+    pub fn handover_socket(mut self, path: &str) -> ServerBuilder {
+        self.handover_socket = Some(path.to_string());
+        self
+    }
+
+    // Lets WEB_SERVER_IP, WEB_SERVER_PORT, WEB_SERVER_WORKERS,
+    // WEB_SERVER_MAX_WORKERS, WEB_SERVER_ACCEPTORS, WEB_SERVER_MAX_QUEUED,
+    // WEB_SERVER_MAX_IN_FLIGHT, WEB_SERVER_RETRY_AFTER,
+    // WEB_SERVER_PROXY_PROTOCOL, WEB_SERVER_MAX_BODY_SIZE,
+    // WEB_SERVER_STRICT_PARSING, WEB_SERVER_MAX_URI_LENGTH,
+    // WEB_SERVER_MAX_IDLE_CONNECTIONS, WEB_SERVER_IDLE_TIMEOUT and
+    // WEB_SERVER_RESERVE_FD override whatever was set in code so far.
+    pub fn with_env(self) -> ServerBuilder {
+        match self.apply_env() {
+            Ok(builder) => builder,
+            Err(error) => {
+                eprintln!("Error reading environment: {error}");
+                panic!();
+            }
+        }
+    }
+
+    fn apply_env(mut self) -> Result<ServerBuilder, ConfigError> {
+        if let Some(ip) = config::env_var("IP")? {
+            self.ip = ip;
+        }
+        if let Some(port) = config::env_var("PORT")? {
+            self.port = port;
+        }
+        if let Some(workers) = config::env_count("WORKERS")? {
+            self.workers = workers;
+        }
+        if let Some(max_workers) = config::env_count("MAX_WORKERS")? {
+            self.max_workers = Some(max_workers);
+        }
+        if let Some(acceptors) = config::env_count("ACCEPTORS")? {
+            self.acceptors = acceptors;
+        }
+        if let Some(timeout) = config::env_duration("WORKER_IDLE_TIMEOUT")? {
+            self.worker_idle_timeout = timeout;
+        }
+        if let Some(max_queued) = config::env_count("MAX_QUEUED")? {
+            self.load_shedding.max_queued = Some(max_queued);
+        }
+        if let Some(max_in_flight) = config::env_count("MAX_IN_FLIGHT")? {
+            self.load_shedding.max_in_flight = Some(max_in_flight);
+        }
+        if let Some(delay) = config::env_duration("RETRY_AFTER")? {
+            self.load_shedding.retry_after = delay;
+        }
+        if let Some(proxy_protocol) = config::env_var("PROXY_PROTOCOL")? {
+            self.proxy_protocol = proxy_protocol;
+        }
+        if let Some(bytes) = config::env_var("MAX_BODY_SIZE")? {
+            self.max_body_size = bytes;
+        }
+        if let Some(connections) = config::env_var("MAX_IDLE_CONNECTIONS")? {
+            self.max_idle_connections = Some(connections);
+        }
+        if let Some(timeout) = config::env_duration("IDLE_TIMEOUT")? {
+            self.idle_timeout = Some(timeout);
+        }
+        if let Some(reserve) = config::env_var("RESERVE_FD")? {
+            self.reserve_fd = reserve;
+        }
+        if let Some(strict) = config::env_var("STRICT_PARSING")? {
+            self = self.strict_parsing(strict);
+        }
+        if let Some(bytes) = config::env_var("MAX_URI_LENGTH")? {
+            self.max_uri_length = bytes;
+        }
+        Ok(self)
+    }
+
+    fn inherited_listeners() -> Option<Vec<TcpListener>> {
+        #[cfg(unix)]
+        return systemd::listen_fds();
+        #[cfg(not(unix))]
+        return None;
+    }
+
+    fn handed_over_listeners(&self) -> Option<Vec<TcpListener>> {
+        #[cfg(target_os = "linux")]
+        return self.handover_socket.as_deref().and_then(ServerBuilder::take_over);
+        #[cfg(not(target_os = "linux"))]
+        return None;
+    }
+
+    // The listeners of the server we're replacing, if one is running.
+    #[cfg(target_os = "linux")]
+    fn take_over(path: &str) -> Option<Vec<TcpListener>> {
+        // Nobody listening just means there's nothing to take over.
+        let stream = UnixStream::connect(path).ok()?;
+        match handover::receive(&stream) {
+            Ok(listeners) => Some(listeners),
+            Err(error) => {
+                eprintln!("Error taking over sockets from {path}: {error}");
+                None
+            }
+        }
+    }
+
+    pub fn build(mut self) -> Server {
+        let plugins = std::mem::take(&mut self.plugins);
+        let address = format!("{}:{}", self.ip, self.port);
+        let listeners = if let Some(listeners) = ServerBuilder::inherited_listeners() {
+            // Under socket activation systemd decides where we listen.
+            println!("Using {} socket(s) passed in by systemd", listeners.len());
+            Ok(listeners)
+        } else if let Some(listeners) = self.handed_over_listeners() {
+            println!("Took over {} socket(s) from the previous server", listeners.len());
+            Ok(listeners)
+        } else if self.acceptors == 1 {
+            socket::bind(&address, &self.socket_options).map(|listener| vec![listener])
+        } else {
+            socket::bind_acceptors(&address, &self.socket_options, self.acceptors)
+        };
+        let listeners = match listeners {
+            Ok(listeners) => listeners,
+            Err(error) => {
+                eprintln!("Error binding to address {}: {}", address, error);
+                panic!();
+            }
+        };
+        let admin = self.admin.as_ref().map(|(address, token)| match Admin::bind(address, token) {
+            Ok(admin) => admin,
+            Err(error) => {
+                eprintln!("Error binding admin endpoint to {}: {}", address, error);
+                panic!();
+            }
+        });
+        let addresses = listeners.iter().filter_map(|listener| listener.local_addr().ok()).collect();
+        let shutdown = ShutdownHandle {
+            state: Arc::new(ShutdownState {
+                requested: AtomicBool::new(false),
+                running_acceptors: AtomicUsize::new(0),
+                addresses,
+            }),
+        };
+        let mut server = Server {
+            listeners,
+            socket_options: self.socket_options,
+            load_shedding: self.load_shedding,
+            pool: ThreadPool::dynamic(
+                self.workers,
+                self.max_workers.unwrap_or(self.workers).max(self.workers),
+                self.worker_idle_timeout,
+            ),
+            shared: Arc::new(Shared {
+                routes: Arc::new(RwLock::new(Arc::new(Routes::default()))),
+                shutdown: shutdown.clone(),
+                max_body_size: self.max_body_size,
+                spill: self.spill,
+                decompress_ratio: self.decompress_ratio,
+                keep_alive_timeout: self.keep_alive_timeout,
+                head_timeout: self.head_timeout,
+                max_pipelined: self.max_pipelined,
+                proxy_protocol: self.proxy_protocol,
+                open_connections: OpenConnections::default(),
+                max_idle_connections: self.max_idle_connections,
+                idle_timeout: self.idle_timeout,
+                metrics: self.metrics,
+                slow_log: self.slow_log,
+                queue_wait_header: self.queue_wait_header,
+                header_case: self.header_case,
+                head_limits: HeadLimits { parsing: self.parsing, max_uri_length: self.max_uri_length },
+                send_rate: self.send_rate,
+                clock: self.clock,
+            }),
+            shutdown,
+            config_path: None,
+            handle_signals: false,
+            handover_socket: self.handover_socket,
+            shutdown_grace: self.shutdown_grace,
+            drain_timeout_hook: None,
+            admin,
+            wakeups: Wakeups::new(),
+            broadcasts: vec![],
+            websockets: OpenSockets::default(),
+            reserved_fd: Mutex::new(if self.reserve_fd { Server::reserve_fd() } else { None }),
+        };
+        for plugin in &plugins {
+            plugin.register(&mut server);
+        }
+        server
+    }
+}
+
+impl Server {
+
+    pub fn new(ip: String, port: u32) -> Server {
+        ServerBuilder::new(&ip, port).build()
+    }
+
+    pub fn builder(ip: &str, port: u32) -> ServerBuilder {
+        ServerBuilder::new(ip, port)
+    }
+
+    // A server that only redirects every request to the same path and query
+    // over HTTPS on `https_port`, for port 80 in front of a TLS deployment.
+    // Call `add_acme_webroot` on it to keep answering ACME challenges too.
+    pub fn https_redirect(ip: &str, port: u32, https_port: u16) -> Server {
+        let mut server = ServerBuilder::new(ip, port).workers(1).build();
+        let handler: Handler = Arc::new(move |request| Server::redirect_to_https(request, https_port));
+        let endpoint = Endpoint { prefix: true, ..Endpoint::new("/".to_string(), handler) };
+        server.routes_mut().endpoints.push(endpoint);
+        server
+    }
+
+    fn redirect_to_https(request: &Request, https_port: u16) -> Response {
+        let Some(host) = request.header("Host").filter(|host| !host.is_empty()) else {
+            return Response::new(StatusCode::BadRequest, "Missing Host header");
+        };
+        let host = rewrite::host_name(host);
+        let location = match https_port {
+            443 => format!("https://{host}{}", request.path),
+            port => format!("https://{host}:{port}{}", request.path),
+        };
+        Response::new(StatusCode::MovedPermanently, "Moved Permanently").with_header("Location", &location)
+    }
+
+    // Builds a server entirely from a config file; see `Config` for the format.
+    pub fn from_config(path: &str) -> Result<Server, ConfigError> {
+        let mut server = Server::with_config(&Server::load_config(path)?)?;
+        server.config_path = Some(path.to_string());
+        Ok(server)
+    }
+
+    fn load_config(path: &str) -> Result<Config, ConfigError> {
+        let mut config = Config::load(path)?;
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    pub fn with_config(config: &Config) -> Result<Server, ConfigError> {
+        if config.tls.is_some() {
+            // Refuse to quietly serve plaintext when TLS was asked for.
+            return Err(ConfigError::new("[tls] is set, but TLS is not supported yet".to_string()));
+        }
+
+        let mut builder = ServerBuilder::new(&config.ip, config.port)
+            .workers(config.workers)
+            .worker_idle_timeout(config.worker_idle_timeout)
+            .acceptors(config.acceptors)
+            .proxy_protocol(config.proxy_protocol)
+            .retry_after(config.retry_after);
+        if let Some(max_workers) = config.max_workers {
+            builder = builder.max_workers(max_workers);
+        }
+        if let Some(bytes) = config.max_body_size {
+            builder = builder.max_body_size(bytes);
+        }
+        if let Some(max_queued) = config.max_queued {
+            builder = builder.max_queued(max_queued);
+        }
+        if let Some(max_in_flight) = config.max_in_flight {
+            builder = builder.max_in_flight(max_in_flight);
+        }
+
+        let mut server = builder.build();
+        server.routes_mut().replace_config(Server::config_routes(config));
+        Ok(server)
+    }
+
+    // The parts of a config that can be swapped without restarting.
+    fn config_routes(config: &Config) -> Routes {
+        let mut middleware: Vec<Arc<dyn Middleware>> = vec![];
+        if config.access_log {
+            let file = config.access_log_file.as_deref().and_then(|path| match LogFile::open(path) {
+                Ok(mut file) => {
+                    if let Some(bytes) = config.access_log_max_size {
+                        file = file.max_size(Some(bytes));
+                    }
+                    if let Some(keep) = config.access_log_keep {
+                        file = file.keep(keep);
+                    }
+                    Some(file)
+                }
+                Err(error) => {
+                    eprintln!("Error opening access log {path}, logging to stdout instead: {error}");
+                    None
+                }
+            });
+            let format = if config.access_log_json { LogFormat::Json } else { LogFormat::Text };
+            let access_log = AccessLog::new().format(format);
+            middleware.push(Arc::new(match file {
+                Some(file) => access_log.to_file(file),
+                None => access_log,
+            }));
+        }
+        if let Some(limit) = config.request_timeout {
+            middleware.push(Arc::new(Timeout::new(limit)));
+        }
+        if let Some(mirror) = &config.mirror {
+            let copies = mirror.routes.iter()
+                .fold(Mirror::new(&mirror.upstream).percent(mirror.percent), |copies, prefix| copies.route(prefix));
+            middleware.push(Arc::new(copies));
+        }
+        let endpoints = config.mounts.iter()
+            .map(Endpoint::mount)
+            .chain(config.redirects.iter().map(Endpoint::redirect))
+            .chain(config.acme_webroot.as_deref().map(Endpoint::acme_webroot))
+            .map(|endpoint| Endpoint { from_config: true, ..endpoint })
+            .collect();
+        // Config::parse has already checked the patterns and statuses.
+        let rewrites: Vec<Arc<RewriteRule>> = config.rewrites.iter()
+            .filter_map(|rule| {
+                let redirect = rule.redirect.and_then(rewrite::redirect_status);
+                RewriteRule::new(&rule.from, &rule.to, redirect).ok()
+            })
+            .map(Arc::new)
+            .collect();
+        let host_redirects: Vec<Arc<HostRule>> = config.host_redirects.iter()
+            .filter_map(|rule| {
+                let status = rewrite::redirect_status(rule.status)?;
+                Some(Arc::new(HostRule::new(&rule.from, rule.scheme.as_deref(), rule.to.as_deref(), status)))
+            })
+            .collect();
+
+        Routes {
+            endpoints,
+            config_middleware: middleware.len(),
+            middleware: Arc::new(middleware),
+            config_rewrites: rewrites.len(),
+            rewrites,
+            config_host_redirects: host_redirects.len(),
+            host_redirects,
+            ..Routes::default()
+        }
+    }
+
+    // Re-reads the config file the server was started from and swaps in its
+    // mounts and middleware. Listeners and the pool stay as they are, so
+    // changes to addresses or worker counts still need a restart.
+    pub fn reload(&self) {
+        let Some(path) = &self.config_path else {
+            eprintln!("Nothing to reload: the server wasn't started from a config file");
+            return;
+        };
+        Server::notify("RELOADING=1");
+        match Server::load_config(path) {
+            Ok(config) => {
+                let mut routes = self.shared.routes.write().unwrap();
+                // Requests still using the old table keep their copy.
+                Arc::make_mut(&mut routes).replace_config(Server::config_routes(&config));
+                println!("Reloaded configuration from {path}");
+            }
+            Err(error) => eprintln!("Error reloading {path}, keeping the old configuration: {error}"),
+        }
+        Server::notify("READY=1");
+    }
+
+    pub fn pool(&self) -> &ThreadPool {
+        &self.pool
+    }
+
+    // Where long-poll handlers park until woken; see `Wakeups`. Everything
+    // parked is woken when the server starts shutting down, so draining
+    // doesn't wait out their timeouts.
+    pub fn wakeups(&self) -> Wakeups {
+        self.wakeups.clone()
+    }
+
+    // Has `hub` closed when the server starts shutting down, so the
+    // connections streaming from it end instead of holding up the drain.
+    pub fn register_broadcast(&mut self, hub: &Broadcast) {
+        self.broadcasts.push(hub.clone());
+    }
+
+    // Lets the admin endpoint's cache flush empty `cache` and its clones.
+    pub fn register_cache(&mut self, cache: &ResponseCache) {
+        if let Some(admin) = &mut self.admin {
+            admin.caches.push(cache.clone());
+        }
+    }
+
+    // Lets the admin endpoint show what `recorder` and its clones hold.
+    pub fn register_recorder(&mut self, recorder: &Recorder) {
+        if let Some(admin) = &mut self.admin {
+            admin.recorders.push(recorder.clone());
+        }
+    }
+
+    // Adds `plugin`'s routes, middleware and hooks; its builder options, if
+    // it has any, only apply through `ServerBuilder::plugin`.
+    pub fn plugin<P: Plugin>(&mut self, plugin: P) {
+        plugin.register(self);
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // The addresses actually listened on, which for port 0 or sockets taken
+    // over from elsewhere needn't be the ones asked for.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners.iter().filter_map(|listener| listener.local_addr().ok()).collect()
+    }
+
+    // For changing routes while the server runs; see `RoutesHandle`. Take
+    // it once the routes are set up, as the server's own `add_*` methods
+    // can't be used after.
+    pub fn routes_handle(&self) -> RoutesHandle {
+        RoutesHandle { routes: Arc::clone(&self.shared.routes) }
+    }
+
+    // On Unix, makes SIGTERM and SIGINT shut the server down gracefully and
+    // SIGHUP reload its config file, once `run` is called.
+    pub fn handle_signals(&mut self) {
+        self.handle_signals = true;
+    }
+
+    // Serves until shut down, then waits for requests already accepted to
+    // finish before returning.
+    pub fn run(&self) {
+        self.shutdown.state.running_acceptors.store(self.listeners.len(), Ordering::SeqCst);
+        thread::scope(|scope| {
+            #[cfg(unix)]
+            if self.handle_signals {
+                scope.spawn(|| self.watch_signals());
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(path) = &self.handover_socket {
+                scope.spawn(move || self.offer_handover(path));
+            }
+            if let Some(admin) = &self.admin {
+                scope.spawn(move || self.serve_admin(admin));
+            }
+            if let Some(timeout) = self.shared.idle_timeout {
+                scope.spawn(move || self.reap_idle(timeout));
+            }
+            for listener in &self.listeners {
+                scope.spawn(move || {
+                    self.accept_loop(listener);
+                    self.shutdown.state.running_acceptors.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Server::notify("READY=1");
+        });
+        self.drain();
+    }
+
+    fn reap_idle(&self, timeout: Duration) {
+        let tick = (timeout / 4).clamp(Duration::from_millis(100), Duration::from_secs(1));
+        while !self.shutdown.is_shutting_down() {
+            thread::sleep(tick);
+            let reaped = self.shared.open_connections.reap(timeout);
+            if reaped > 0 {
+                eprintln!("Closed {reaped} connection(s) idle for over {}ms", timeout.as_millis());
+            }
+        }
+    }
+
+    // Called with how many requests were cut off when a shutdown's grace
+    // period (see `ServerBuilder::shutdown_grace`) runs out.
+    pub fn on_drain_timeout<F: Fn(usize) + Send + Sync + 'static>(&mut self, hook: F) {
+        self.drain_timeout_hook = Some(Box::new(hook));
+    }
+
+    fn drain(&self) {
+        self.wakeups.close();
+        let sockets = self.websockets.going_away();
+        if sockets > 0 {
+            println!("Closing {sockets} WebSocket(s)");
+        }
+        for hub in &self.broadcasts {
+            hub.close();
+        }
+        let open_connections = &self.shared.open_connections;
+        open_connections.close_idle();
+        let Some(grace) = self.shutdown_grace else {
+            self.pool.wait_until_idle();
+            return;
+        };
+        if self.pool.wait_until_idle_for(grace) {
+            return;
+        }
+        let cut_off = open_connections.close_all();
+        eprintln!("Shutdown grace period of {}s ran out; closed {cut_off} connections", grace.as_secs_f32());
+        if let Some(hook) = &self.drain_timeout_hook {
+            hook(cut_off);
+        }
+    }
+
+    // Tells systemd (if it's watching) what the server is up to.
+    fn notify(state: &str) {
+        #[cfg(unix)]
+        systemd::notify(state);
+        #[cfg(not(unix))]
+        let _ = state;
+    }
+
+    #[cfg(unix)]
+    fn watch_signals(&self) {
+        let mut signals = match signal::listen() {
+            Ok(signals) => signals,
+            Err(error) => {
+                eprintln!("Error installing signal handlers: {error}");
+                return;
+            }
+        };
+        // Wake up now and then to notice shutdowns that didn't come from a signal.
+        signals.set_read_timeout(Some(Duration::from_millis(200))).expect("Nonzero timeout");
+
+        let mut received = [0u8];
+        while !self.shutdown.is_shutting_down() {
+            match signals.read(&mut received) {
+                Ok(1) if received[0] == signal::SIGHUP => self.reload(),
+                Ok(1) => {
+                    println!("Received signal {}; shutting down", received[0]);
+                    self.shutdown.shutdown();
+                }
+                Ok(_) => break,
+                Err(_) => {}
+            }
+        }
+    }
+
+    // Waits for a replacement process to ask for our listeners, hands them
+    // over, and starts draining.
+    #[cfg(target_os = "linux")]
+    fn offer_handover(&self, path: &str) {
+        // Whoever had the socket before us has already handed over.
+        let _ = fs::remove_file(path);
+        let listener = match UnixListener::bind(path).and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        }) {
+            Ok(listener) => listener,
+            Err(error) => {
+                eprintln!("Error listening for handovers on {path}: {error}");
+                return;
+            }
+        };
+
+        while !self.shutdown.is_shutting_down() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    match stream.set_nonblocking(false).and_then(|_| handover::send(&stream, &self.listeners)) {
+                        Ok(()) => {
+                            println!("Handed sockets over to the new server; draining");
+                            self.shutdown.shutdown();
+                        }
+                        Err(error) => eprintln!("Error handing over sockets: {error}"),
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(200)),
+                Err(error) => {
+                    eprintln!("Error accepting handover connection: {error}");
+                    thread::sleep(Duration::from_millis(200));
+                }
+            }
+        }
+    }
+
+    fn accept_loop(&self, listener: &TcpListener) {
+        let mut backoff = MIN_ACCEPT_BACKOFF;
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    self.accept(stream);
+                    backoff = MIN_ACCEPT_BACKOFF;
+                }
+                Err(error) if Server::is_out_of_fds(&error) => {
+                    self.shed_for_fds(listener, &error);
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                }
+                // The client gave up before we got to it.
+                Err(error) if matches!(error.kind(), io::ErrorKind::ConnectionAborted | io::ErrorKind::Interrupted) => {}
+                Err(error) => {
+                    eprintln!("Error accepting connection: {error}");
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                }
+            }
+            // Checked after serving, so a connection accepted just as the
+            // server began shutting down still gets its answer.
+            if self.shutdown.is_shutting_down() {
+                break;
+            }
+        }
+    }
+
+    // EMFILE or ENFILE: this process, or the whole system, is out of file
+    // descriptors.
+    fn is_out_of_fds(error: &io::Error) -> bool {
+        cfg!(unix) && matches!(error.raw_os_error(), Some(23 | 24))
+    }
+
+    // Frees what descriptors it can by closing idle keep-alive connections,
+    // then, with a reserved one to give up, turns away the connection at the
+    // head of the queue with a 503 so its client isn't left waiting.
+    fn shed_for_fds(&self, listener: &TcpListener, error: &io::Error) {
+        eprintln!("Error accepting connection: {error}; closing idle connections");
+        self.shared.open_connections.close_idle();
+        self.shared.count_error("fds");
+        let mut reserved = self.reserved_fd.lock().unwrap();
+        if reserved.take().is_none() {
+            return;
+        }
+        if let Ok((stream, _)) = listener.accept() {
+            let response = Response::new(StatusCode::ServiceUnavailable, "Server out of file descriptors")
+                .with_header("Retry-After", "1")
+                .with_header("Connection", "close");
+            Server::discard_available(&stream);
+            Connection::new(stream).respond(response);
+        }
+        *reserved = Server::reserve_fd();
+    }
+
+    fn reserve_fd() -> Option<fs::File> {
+        let null = if cfg!(windows) { "NUL" } else { "/dev/null" };
+        fs::File::open(null).map_err(|error| eprintln!("Error reserving a file descriptor: {error}")).ok()
+    }
+
+    // Only hands the connection over: reading the request, even the PROXY
+    // header, happens on a worker, so a slow client can't hold up the
+    // connections accepted after it.
+    fn accept(&self, stream: TcpStream) {
+        if let Err(error) = socket::configure_stream(&stream, &self.socket_options) {
+            eprintln!("Error configuring stream: {error}");
+        }
+
+        if self.overloaded() {
+            let client = stream.peer_addr().map_or_else(|_| "unknown client".to_string(), |address| address.to_string());
+            eprintln!("Shedding connection from {client}: server overloaded");
+            let retry_after = self.load_shedding.retry_after.as_secs().max(1).to_string();
+            let response = Response::new(StatusCode::ServiceUnavailable, "Server overloaded")
+                .with_header("Retry-After", &retry_after)
+                .with_header("Connection", "close");
+            Server::discard_available(&stream);
+            Connection::new(stream).respond(response);
+            return;
+        }
+
+        // Execute the handler in a thread
+        let shared = Arc::clone(&self.shared);
+        self.pool.execute(move || shared.serve(stream, ThreadPool::queue_wait()));
+    }
+
+    // Reads and throws away whatever the client has sent so far, without
+    // waiting for more, so closing the connection right after answering
+    // doesn't reset it before the client reads the answer.
+    fn discard_available(mut stream: &TcpStream) {
+        if stream.set_nonblocking(true).is_err() {
+            return;
+        }
+        let mut buffer = [0; READ_CHUNK_SIZE];
+        while matches!(stream.read(&mut buffer), Ok(read) if read > 0) {}
+        let _ = stream.set_nonblocking(false);
+    }
+
+    fn overloaded(&self) -> bool {
+        let stats = self.pool.stats();
+        let in_flight = stats.queued + stats.size.saturating_sub(stats.idle);
+        let limits = &self.load_shedding;
+        limits.max_queued.is_some_and(|max| stats.queued >= max)
+            || limits.max_in_flight.is_some_and(|max| in_flight >= max)
+    }
+
+    // Reads until the end of the request head into a pooled buffer, so a
+    // request costs no per-line allocations and the buffer is reused by the
+    // next request once this one is parsed.
+    fn read_head(mut stream: &TcpStream, buffer: &mut Vec<u8>, max_uri_length: usize) -> io::Result<usize> {
+        let mut searched = 0;
+        loop {
+            if let Some(end) = Server::head_end(buffer, searched) {
+                return Ok(end);
+            }
+            // No point reading the rest of a request line that's already too
+            // long, or waiting for one that never ends.
+            if buffer.len() > max_uri_length.saturating_add(REQUEST_LINE_SLACK) && !buffer.contains(&b'\n') {
+                return Err(Server::uri_too_long());
+            }
+            if buffer.len() >= MAX_HEAD_SIZE {
+                return Err(io::Error::new(io::ErrorKind::FileTooLarge, "Request head too large"));
+            }
+            searched = buffer.len().saturating_sub(2);
+
+            let filled = buffer.len();
+            buffer.resize(filled + READ_CHUNK_SIZE, 0);
+            let read = stream.read(&mut buffer[filled..]);
+            buffer.truncate(filled + read.as_ref().map_or(0, |read| *read));
+            match read {
+                Ok(0) if filled == 0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                // Some clients close their side without a blank line; treat
+                // what we got as the whole head.
+                Ok(0) => return Ok(filled),
+                Ok(_) => {}
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    // Where the blank line ending a head finishes, looking from `from`.
+    // Lenient parsing takes bare LF line endings, so the blank line can be
+    // \n as well as \r\n, after either.
+    fn head_end(buffer: &[u8], from: usize) -> Option<usize> {
+        let mut start = from;
+        while let Some(found) = buffer[start..].iter().position(|byte| *byte == b'\n') {
+            let newline = start + found;
+            match &buffer[newline + 1..] {
+                [b'\n', ..] => return Some(newline + 2),
+                [b'\r', b'\n', ..] => return Some(newline + 3),
+                _ => start = newline + 1,
+            }
+        }
+        None
+    }
+
+    // Parses the request head, returning it along with whatever part of the
+    // body arrived with it.
+    // `pending` holds bytes already read past the previous request on the
+    // connection, and is left holding any that follow this one.
+    fn read_stream(stream: &TcpStream, pending: &mut Vec<u8>, limits: &HeadLimits) -> io::Result<(Request, Vec<u8>)> {
+        let mut buffer = READ_BUFFERS.take();
+        buffer.append(pending);
+        let head_length = Server::read_head(stream, &mut buffer, limits.max_uri_length)?;
+        let head = parser::parse_head(&buffer[..head_length], limits.parsing)
+            .map_err(|rejection| io::Error::new(io::ErrorKind::InvalidData, rejection))?;
+        if head.path.len() > limits.max_uri_length {
+            return Err(Server::uri_too_long());
+        }
+
+        let mut request = Request::new(head.method, &head.path);
+        request.protocol = head.protocol;
+        for (name, value) in head.headers {
+            match String::from_utf8(value) {
+                Ok(value) => request.headers.push((name, value)),
+                Err(error) => {
+                    request.headers.push((name.clone(), String::from_utf8_lossy(error.as_bytes()).into_owned()));
+                    request.raw_headers.push((name, error.into_bytes()));
+                }
+            }
+        }
+
+        // Whatever of the body came in with the head is already in the buffer,
+        // and maybe the start of the next request after it.
+        let length = request.content_length().unwrap_or(0);
+        let body_end = head_length.saturating_add(length.try_into().unwrap_or(usize::MAX)).min(buffer.len());
+        let body = buffer[head_length..body_end].to_vec();
+        pending.extend_from_slice(&buffer[body_end..]);
+        Ok((request, body))
+    }
+
+    fn uri_too_long() -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, Rejection { status: StatusCode::UriTooLong, reason: "Request target too long" })
+    }
+
+    // Reads the rest of a body that `read_stream` got the start of.
+    fn read_body(mut stream: &TcpStream, mut body: Vec<u8>, length: usize) -> io::Result<Vec<u8>> {
+        let filled = body.len();
+        body.resize(length, 0);
+        stream.read_exact(&mut body[filled..])?;
+        Ok(body)
+    }
+
+    // Fails only when the client was cut off for reading too slowly.
+    fn send_response(response: &Response, stream: &mut TcpStream, rate: Option<SendRate>) -> io::Result<()> {
+        // The head goes into a pooled buffer and the body is written straight
+        // from the response, so neither needs to be copied into one String.
+        let mut head = WRITE_BUFFERS.take();
+        Server::write_head(response, &mut head);
+
+        // A write blocked for the whole grace period is as slow as it gets.
+        if let Some(rate) = rate {
+            stream.set_write_timeout(Some(rate.grace))?;
+        }
+        let mut progress = Progress::new(rate);
+        let bodiless = response.head_only || !response.status_code.allows_body();
+        let body: &[u8] = if bodiless { &[] } else { &response.body };
+        let file = response.file.as_ref().filter(|_| !bodiless);
+        let mut slices = [IoSlice::new(&head), IoSlice::new(body)];
+        let written = Server::write_all_vectored(stream, &mut slices, &mut progress)
+            .and_then(|_| file.map_or(Ok(()), |file| Server::send_file(file, stream, &mut progress)));
+        if rate.is_some() {
+            stream.set_write_timeout(None)?;
+        }
+        match written {
+            Ok(()) => Ok(()),
+            // The client went away; there's nobody to tell.
+            Err(error) if Server::is_disconnect(&error) => Ok(()),
+            Err(error) if matches!(error.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) && rate.is_some() => {
+                let _ = stream.shutdown(Shutdown::Both);
+                Err(rate.map(Progress::too_slow).unwrap_or(error))
+            }
+            Err(error) => {
+                eprintln!("Error writing response to stream: {error}");
+                Ok(())
+            }
+        }
+    }
+
+    // Copies the pieces straight in rather than going through `write!`, so
+    // a head costs nothing once the pooled buffer is big enough for it.
+    pub(crate) fn write_head(response: &Response, head: &mut Vec<u8>) {
+        head.extend_from_slice(response.protocol.as_bytes());
+        head.push(b' ');
+        head.extend_from_slice(response.status_code.status_line().as_bytes());
+        head.extend_from_slice(b"\r\n");
+        let case = response.header_case;
+        // 1xx and 204 responses never carry a Content-Length. A 304's is the
+        // length the 200 would have had, so it's only sent if the handler
+        // set one, and as the handler set it.
+        let code = response.status_code.code();
+        let (no_length, length_as_set) = (matches!(code, 100..=199 | 204), code == 304);
+        let mut length_written = false;
+        for (name, value) in &response.headers {
+            // A Content-Length the handler set keeps its place, but otherwise
+            // always says how long the body really is.
+            let is_length = name.eq_ignore_ascii_case("Content-Length");
+            if is_length && (length_written || no_length) {
+                continue;
+            }
+            head.extend_from_slice(case.apply(name).as_bytes());
+            head.extend_from_slice(b": ");
+            if is_length && !length_as_set {
+                Server::write_decimal(response.body_length(), head);
+            } else {
+                head.extend_from_slice(value.as_bytes());
+            }
+            length_written |= is_length;
+            head.extend_from_slice(b"\r\n");
+        }
+        if !length_written && !no_length && !length_as_set {
+            head.extend_from_slice(case.apply("Content-Length").as_bytes());
+            head.extend_from_slice(b": ");
+            Server::write_decimal(response.body_length(), head);
+            head.extend_from_slice(b"\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+    }
+
+    fn write_decimal(mut value: u64, buffer: &mut Vec<u8>) {
+        let mut digits = [0; 20];
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (value % 10) as u8;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        buffer.extend_from_slice(&digits[start..]);
+    }
+
+    // Copies the file a chunk at a time (with sendfile where the platform
+    // has it), so memory stays flat however big it is. With a send rate,
+    // it's checked between chunks.
+    fn send_file(body: &FileBody, stream: &mut TcpStream, progress: &mut Progress) -> io::Result<()> {
+        let mut file = fs::File::open(&body.path)?;
+        file.seek(io::SeekFrom::Start(body.offset))?;
+        let chunk = if progress.rate.is_some() { SEND_FILE_CHUNK } else { body.length };
+        let mut copied = 0;
+        while copied < body.length {
+            let sent = io::copy(&mut (&mut file).take(chunk.min(body.length - copied)), stream)?;
+            if sent == 0 {
+                break;
+            }
+            copied += sent;
+            progress.sent(sent as usize)?;
+        }
+        if copied < body.length {
+            // The file shrank since the head went out, so the body can't be
+            // finished; cut the connection rather than leave it hanging.
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} shrank while being sent", body.path.display())));
+        }
+        Ok(())
+    }
+
+    fn is_disconnect(error: &io::Error) -> bool {
+        matches!(
+            error.kind(),
+            io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+        )
+    }
+
+    fn write_all_vectored(stream: &mut TcpStream, mut slices: &mut [IoSlice], progress: &mut Progress) -> io::Result<()> {
+        while !slices.is_empty() {
+            match stream.write_vectored(slices) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    IoSlice::advance_slices(&mut slices, written);
+                    progress.sent(written)?;
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+
+    fn html_response(file_name: String) -> Response {
+        let contents = fs::read_to_string(file_name.clone()).unwrap_or_else(|error| {
+            eprintln!("Error reading contents of {file_name}: {error}");
+            return fs::read_to_string("unknown.html").unwrap();
+        });
+
+        Response::new(StatusCode::Ok, &contents).with_header("Content-Type", "text/html; charset=utf-8")
+    }
+
+    // What requests no route or fallback answered get: unknown.html if
+    // there is one, otherwise a plain 404.
+    fn not_found() -> Response {
+        match fs::read_to_string("unknown.html") {
+            Ok(contents) => Response::new(StatusCode::NotFound, &contents)
+                .with_header("Content-Type", "text/html; charset=utf-8"),
+            Err(_) => Response::new(StatusCode::NotFound, "Not Found"),
+        }
+    }
+
+    // Answers GETs for exactly `path` with `response`, written out
+    // once now and sent as those bytes from then on, skipping rewrites,
+    // middleware and hooks. For health checks, and plaintext benchmarks:
+    //
+    //     server.add_fast_response("/plaintext", Response::new(StatusCode::Ok, "Hello, World!"));
+    //
+    // Only HTTP/1.1 keep-alive requests without a body take the fast path;
+    // the rest are answered by an ordinary route with the same response.
+    // The bytes never change, so fast responses carry no Date header.
+    pub fn add_fast_response(&mut self, path: &str, mut response: Response) {
+        response.header_case = self.shared.header_case;
+        let mut bytes = vec![];
+        Server::write_head(&response, &mut bytes);
+        if response.status_code.allows_body() {
+            bytes.extend_from_slice(&response.body);
+        }
+        self.routes_mut().fast.insert(path.to_string(), bytes.into());
+        self.add_endpoint(path, Arc::new(move |_| response.clone()));
+    }
+
+    pub fn add_get_endpoint(&mut self, path: &str, file_name: &str) -> Route<'_> {
+        self.push_endpoint(Endpoint::file(path, file_name))
+    }
+
+    // Registers a handler that builds its response per request.
+    pub fn add_handler<F>(&mut self, path: &str, handler: F) -> Route<'_>
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.add_endpoint(path, Arc::new(handler))
+    }
+
+    // Registers a handler that writes its response as it goes, through a
+    // ResponseWriter, for output that takes a while to produce or never
+    // ends. Writes fail once the client disconnects; stop then.
+    pub fn add_stream<F>(&mut self, path: &str, handler: F) -> Route<'_>
+    where
+        F: Fn(&Request, &mut ResponseWriter) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.add_endpoint(path, Arc::new(move |request| {
+            let Some(connection) = request.connection.clone() else {
+                return Response::new(StatusCode::InternalServerError, "Internal Server Error");
+            };
+            let mut writer = ResponseWriter::new(connection);
+            let result = handler(request, &mut writer);
+            writer.finish(result)
+        }))
+    }
+
+    // Answers `path` with whichever of several handlers suits the request's
+    // Content-Type or Accept header; see `Dispatch`.
+    pub fn add_dispatch(&mut self, path: &str, dispatch: Dispatch) -> Route<'_> {
+        self.add_endpoint(path, Arc::new(move |request| dispatch.respond(request)))
+    }
+
+    // Upgrades requests for `path` to WebSockets and hands them to `handler`
+    // for as long as they stay open; see `WebSocket`. Requests that aren't
+    // upgrades get 426.
+    pub fn add_websocket<F>(&mut self, path: &str, options: WebSocketOptions, handler: F) -> Route<'_>
+    where
+        F: Fn(&Request, &mut WebSocket) -> io::Result<()> + Send + Sync + 'static,
+    {
+        let sockets = self.websockets.clone();
+        self.add_endpoint(path, Arc::new(move |request| websocket::serve(request, &options, &sockets, &handler)))
+    }
+
+    // Routes paths matching a template, where `{name}` matches one path
+    // segment and `{name:regex}` whatever the regex allows, e.g.
+    // "/users/{id:\d+}/files/{name:[a-z0-9-]+\.png}". The handler reads the
+    // values with `request.param("name")`.
+    pub fn add_route<F>(&mut self, template: &str, handler: F) -> Route<'_>
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        let pattern = match template_pattern(template) {
+            Ok(pattern) => pattern,
+            Err(error) => {
+                eprintln!("Invalid route {template}: {error}");
+                panic!();
+            }
+        };
+        self.add_pattern(template, &pattern, Arc::new(handler))
+    }
+
+    // Routes paths the regex matches in full. Named groups become params
+    // by name, unnamed ones by number ("1", "2", ...).
+    pub fn add_regex_route<F>(&mut self, regex: &str, handler: F) -> Route<'_>
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.add_pattern(regex, &format!("^(?:{regex})$"), Arc::new(handler))
+    }
+
+    fn add_pattern(&mut self, route: &str, pattern: &str, handler: Handler) -> Route<'_> {
+        let pattern = match Regex::new(pattern) {
+            Ok(pattern) => pattern,
+            Err(error) => {
+                eprintln!("Invalid route {route}: {error}");
+                panic!();
+            }
+        };
+        let endpoint = Endpoint { pattern: Some(Arc::new(pattern)), ..Endpoint::new(route.to_string(), handler) };
+        self.push_endpoint(endpoint)
+    }
+
+    // Before routing, requests whose path matches the regex `from` are
+    // routed as `to` instead, with `$1` or `${name}` in it replaced by what
+    // the groups captured. The query string is kept unless `to` has one.
+    //
+    //     server.add_rewrite("^/blog/(\\d+)$", "/posts/$1");
+    pub fn add_rewrite(&mut self, from: &str, to: &str) {
+        self.add_rewrite_rule(from, to, None);
+    }
 
-pub struct Server {
-    listener: TcpListener,
-    pool: ThreadPool,
-    endpoints: Vec<Endpoint>,
-}
+    // Like `add_rewrite`, but answers with a redirect to `to`, which may be
+    // another path or a full URL.
+    pub fn add_redirect(&mut self, from: &str, to: &str, status: StatusCode) {
+        if rewrite::redirect_status(status.code()).is_none() {
+            eprintln!("Invalid redirect status {status} for {from}");
+            panic!();
+        }
+        self.add_rewrite_rule(from, to, Some(status));
+    }
 
-enum HttpMethod {
-    GET,
-    POST,
-    PUT,
-    DELETE,
-}
+    // Before anything else, redirects requests for the host `from` to the
+    // same path and query on `to`, which may carry a scheme to switch that
+    // too:
+    //
+    //     server.add_host_redirect("www.example.com", "https://example.com", StatusCode::PermanentRedirect);
+    //
+    // `from` may also be `*.example.com` for any subdomain, or `*` for every
+    // host. Requests already on `to` are left alone.
+    pub fn add_host_redirect(&mut self, from: &str, to: &str, status: StatusCode) {
+        let (scheme, host) = rewrite::split_target(to);
+        self.add_host_rule(from, scheme, Some(host), status);
+    }
 
-struct Request {
-    method: HttpMethod,
-    path: String,
-    protocol: String,
-    body: String,
-}
+    // Redirects requests that didn't come in over HTTPS to the same URL on
+    // it. That goes by the X-Forwarded-Proto header of the proxy terminating
+    // TLS in front, so only turn it on behind one that sets it; without it,
+    // every request looks like plain HTTP.
+    pub fn require_https(&mut self, status: StatusCode) {
+        self.add_host_rule("*", Some("https"), None, status);
+    }
 
-#[derive(Clone)]
-enum StatusCode {
-    Ok = 200,
-    BadRequest = 400,
-    NotFound = 404,
-    InternalServerError = 500,
-}
-
-impl Display for StatusCode {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            StatusCode::Ok => write!(f, "200 OK"),
-            StatusCode::BadRequest => write!(f, "400 Bad Request"),
-            StatusCode::NotFound => write!(f, "404 Not Found"),
-            StatusCode::InternalServerError => write!(f, "500 Internal Server Error"),
-        }.expect("Invalid/unimplemented status code");
-        Ok(())
+    fn add_host_rule(&mut self, from: &str, scheme: Option<&str>, host: Option<&str>, status: StatusCode) {
+        if rewrite::redirect_status(status.code()).is_none() {
+            eprintln!("Invalid redirect status {status} for host {from}");
+            panic!();
+        }
+        self.routes_mut().host_redirects.push(Arc::new(HostRule::new(from, scheme, host, status)));
     }
-}
 
-#[derive(Clone)]
-struct Response {
-    protocol: String,
-    status_code: StatusCode,
-    body: String,
+    fn add_rewrite_rule(&mut self, from: &str, to: &str, redirect: Option<StatusCode>) {
+        match RewriteRule::new(from, to, redirect) {
+            Ok(rule) => self.routes_mut().rewrites.push(Arc::new(rule)),
+            Err(error) => {
+                eprintln!("Invalid rewrite {from}: {error}");
+                panic!();
+            }
+        }
+    }
+
+    // Fallbacks run in the order they were added when no route matches; the
+    // first to return a response answers the request. For example, static
+    // files, then an SPA's index, then a JSON 404:
+    //
+    //     server.add_fallback_dir(StaticDir::new("public"));
+    //     server.add_fallback_dir(StaticDir::new("app").spa(true));
+    //     server.add_fallback(|_| Some(Response::new(StatusCode::NotFound, "{}")));
+    pub fn add_fallback<F>(&mut self, fallback: F)
+    where
+        F: Fn(&Request) -> Option<Response> + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.routes_mut().fallbacks).push(Arc::new(fallback));
+    }
+
+    // A fallback serving files from `dir`, passing when there's no such file.
+    pub fn add_fallback_dir(&mut self, dir: StaticDir) {
+        self.add_fallback(move |request| {
+            Some(dir.respond("", request)).filter(|response| response.status_code != StatusCode::NotFound)
+        });
+    }
+
+    // Serves a directory's files under `prefix`, e.g. "/assets" or "/".
+    pub fn add_static_dir(&mut self, prefix: &str, dir: StaticDir) -> Route<'_> {
+        self.push_endpoint(Endpoint::static_dir(prefix, dir))
+    }
+
+    // Stores files uploaded to `path` in `dir`, either as a multipart form
+    // POST or a raw PUT to `path/<name>`, and answers with JSON describing
+    // what was stored:
+    //
+    //     server.accept_uploads("/upload", "uploads", UploadOptions::new().allow_type("image/*"));
+    pub fn accept_uploads(&mut self, path: &str, dir: &str, options: UploadOptions) -> Route<'_> {
+        let limit = options.total_limit();
+        let uploads = Arc::new(Uploads::new(dir, options));
+        let mount = path.trim_end_matches('/').to_string();
+        let handler: Handler = Arc::new(move |request| uploads.respond(&mount, request));
+        let endpoint = Endpoint { prefix: true, ..Endpoint::new(path.to_string(), handler) };
+        self.push_endpoint(endpoint).stream_body(limit)
+    }
+
+    // Shares a directory over WebDAV under `prefix`; see `WebDav`. Uploads
+    // are streamed to disk, up to the share's `max_upload`.
+    pub fn add_webdav(&mut self, prefix: &str, dav: WebDav) -> Route<'_> {
+        let dav = Arc::new(dav);
+        let limit = dav.upload_limit();
+        let mount = prefix.trim_end_matches('/').to_string();
+        let handler: Handler = Arc::new(move |request| dav.respond(&mount, request));
+        let endpoint = Endpoint { prefix: true, ..Endpoint::new(prefix.to_string(), handler) };
+        self.push_endpoint(endpoint).stream_body(limit)
+    }
+
+    // Registers a webhook receiver. `handler` gets the raw body, and only
+    // runs for deliveries whose signature checks out; the rest get a 401.
+    pub fn add_webhook<F>(&mut self, path: &str, webhook: Webhook, handler: F) -> Route<'_>
+    where
+        F: Fn(&Request, &[u8]) -> Response + Send + Sync + 'static,
+    {
+        let limit = webhook.body_limit();
+        self.add_endpoint(path, Arc::new(move |request| webhook.respond(request, &handler))).stream_body(limit)
+    }
+
+    // Answers JSON-RPC 2.0 calls POSTed to `path`; see `JsonRpc`.
+    pub fn add_jsonrpc(&mut self, path: &str, rpc: JsonRpc) -> Route<'_> {
+        self.add_endpoint(path, Arc::new(move |request| rpc.respond(request)))
+    }
+
+    // Mounts an OAuth 2.0 / OpenID Connect login: `login` sends the browser
+    // to the provider, which sends it back to `callback`. Needs the Sessions
+    // middleware; see `OAuth`.
+    pub fn add_oauth(&mut self, login: &str, callback: &str, oauth: OAuth) {
+        let oauth = Arc::new(oauth);
+        let provider = Arc::clone(&oauth);
+        self.add_handler(login, move |request| provider.login(request));
+        self.add_handler(callback, move |request| oauth.callback(request));
+    }
+
+    // Answers ACME HTTP-01 challenges from the files an ACME client like
+    // `certbot certonly --webroot -w <webroot>` writes, so certificates can
+    // be issued and renewed while the site keeps running.
+    pub fn add_acme_webroot(&mut self, webroot: &str) {
+        self.routes_mut().endpoints.push(Endpoint::acme_webroot(webroot));
+    }
+
+    // Answers /robots.txt with `rules`, e.g. "User-agent: *\nDisallow: /admin/".
+    pub fn serve_robots(&mut self, rules: &str) -> Route<'_> {
+        let response = Response::new(StatusCode::Ok, rules)
+            .with_header("Content-Type", "text/plain; charset=utf-8");
+        // Expires has to be worked out per response, not once here.
+        let lifetime = Duration::from_secs(24 * 60 * 60);
+        self.add_endpoint("/robots.txt", Arc::new(move |_| response.clone().cache_for(lifetime)))
+    }
+
+    // Answers /favicon.ico with the icon at `path`, read once now. .png and
+    // .svg icons get their own content types.
+    pub fn serve_favicon(&mut self, path: &str) -> Route<'_> {
+        let icon = fs::read(path).unwrap_or_else(|error| panic!("Failed to read favicon {path}: {error}"));
+        let content_type = static_files::content_type(Path::new(path));
+        self.favicon(icon, content_type)
+    }
+
+    // Like `serve_favicon`, for an icon built into the binary with
+    // `include_bytes!`.
+    pub fn serve_favicon_bytes(&mut self, icon: &[u8]) -> Route<'_> {
+        let content_type = if icon.starts_with(b"\x89PNG") {
+            "image/png"
+        } else if icon.starts_with(b"<svg") || icon.starts_with(b"<?xml") {
+            "image/svg+xml"
+        } else {
+            "image/x-icon"
+        };
+        self.favicon(icon.to_vec(), content_type)
+    }
+
+    fn favicon(&mut self, icon: Vec<u8>, content_type: &str) -> Route<'_> {
+        // Browsers ask for the icon on every page, so let them keep it a week
+        // and revalidate cheaply after that.
+        let etag = format!("{:08x}", compress::crc32(&icon));
+        let response = Response::from_bytes(StatusCode::Ok, icon)
+            .with_header("Content-Type", content_type)
+            .etag(&etag);
+        let lifetime = Duration::from_secs(7 * 24 * 60 * 60);
+        self.add_endpoint("/favicon.ico", Arc::new(move |_| response.clone().cache_for(lifetime)))
+    }
+
+    // Middleware runs in the order it was added, wrapping every handler.
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        Arc::make_mut(&mut self.routes_mut().middleware).push(Arc::new(middleware));
+    }
+
+    // Hooks run around every request, outside all middleware. `on_request`
+    // sees the request once it's routed, before any middleware; `on_response` can
+    // change any response on its way out (say, to add a header everywhere),
+    // and `on_error` observes 5xx responses, including the 500 sent when a
+    // handler panics.
+    pub fn on_request<F>(&mut self, hook: F)
+    where
+        F: Fn(&mut Request) + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.routes_mut().hooks).request.push(Arc::new(hook));
+    }
+
+    pub fn on_response<F>(&mut self, hook: F)
+    where
+        F: Fn(&Request, &mut Response) + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.routes_mut().hooks).response.push(Arc::new(hook));
+    }
+
+    pub fn on_error<F>(&mut self, hook: F)
+    where
+        F: Fn(&Request, &Response) + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.routes_mut().hooks).error.push(Arc::new(hook));
+    }
+
+    // Attaches middleware to everything under `prefix`, after the global
+    // middleware and before any route's own:
+    //
+    //     server.group("/admin").with(auth);
+    pub fn group(&mut self, prefix: &str) -> Group<'_> {
+        Group { routes: self.routes_mut(), prefix: prefix.to_string() }
+    }
+
+    fn add_endpoint(&mut self, path: &str, handler: Handler) -> Route<'_> {
+        self.push_endpoint(Endpoint::new(path.to_string(), handler))
+    }
+
+    // Routes are set up before `run`, while nothing else holds the table.
+    fn routes_mut(&mut self) -> &mut Routes {
+        let shared = Arc::get_mut(&mut self.shared).expect("Routes can't be changed while requests are in flight");
+        let routes = Arc::get_mut(&mut shared.routes).expect("Routes can't be added once there's a RoutesHandle; use it instead");
+        Arc::make_mut(routes.get_mut().unwrap())
+    }
+
+    fn push_endpoint(&mut self, endpoint: Endpoint) -> Route<'_> {
+        let endpoints = &mut self.routes_mut().endpoints;
+        endpoints.push(endpoint);
+        Route { endpoint: endpoints.last_mut().unwrap() }
+    }
 }
 
-impl Server {
+impl Shared {
+    // Answers requests on the connection until it closes, keep-alive runs
+    // out or a request can't be followed by another. Pipelined requests
+    // already sitting in `pending` are answered in order, one at a time, so
+    // responses never interleave.
+    fn serve(&self, mut stream: TcpStream, mut queued: Duration) {
+        let tracked = Tracked { connections: &self.open_connections, id: self.open_connections.track(&stream) };
+        if let Some(metrics) = &self.metrics {
+            metrics.record_queue_wait(queued);
+        }
+        let mut client_addr = stream.peer_addr().ok();
+        if let Err(error) = stream.set_read_timeout(Some(self.head_timeout)) {
+            eprintln!("Error setting head timeout: {error}");
+            return;
+        }
+        if self.proxy_protocol {
+            match proxy_protocol::read_header(&mut stream) {
+                Ok(Some(address)) => client_addr = Some(address),
+                Ok(None) => {}
+                Err(error) => {
+                    eprintln!("Error reading PROXY header: {error}");
+                    self.count_read_error(&error);
+                    return;
+                }
+            }
+        }
 
-    pub fn new(ip: String, port: u32) -> Server {
-        let address = format!("{ip}:{port}");
-        let listener = match TcpListener::bind(&address.to_string()) {
-            Ok(listener) => listener,
+        // read the stream into a Request
+        let mut pending = Vec::new();
+        let (mut request, mut body_start) = match Server::read_stream(&stream, &mut pending, &self.head_limits) {
+            Ok(request) => request,
+            Err(error) if Shared::is_rejection(&error) => {
+                self.reject(stream, &error);
+                return;
+            }
             Err(error) => {
-                eprintln!("Error binding to address {}: {}", address, error);
-                panic!();
+                eprintln!("Error reading request: {error}");
+                // Connecting and leaving without a word is what health
+                // checks do.
+                if error.kind() != io::ErrorKind::UnexpectedEof {
+                    self.count_read_error(&error);
+                }
+                return;
             }
         };
-        let pool = ThreadPool::new(4);
-        let endpoints = vec![];
-        Server {
-            listener,
-            pool,
-            endpoints
+        self.open_connections.set_idle(tracked.id, false);
+        if let Err(error) = stream.set_read_timeout(None) {
+            eprintln!("Error clearing head timeout: {error}");
+            return;
         }
-    }
+        request.client_addr = client_addr;
 
-    pub fn run(&self) {
-        for stream in self.listener.incoming() {
-            // read the stream into a Request
-            let mut stream = stream.expect("Error reading stream");
-            let request = Server::read_stream(&stream);
-
-            // Find the corresponding endpoint
-            let handler = self.find_endpoint(&request.path).unwrap_or_else(|| {
-                eprintln!("No handler found for path: {}", &request.path);
-                Endpoint::default().handler
-            });
+        let mut pipelined = 0;
+        loop {
+            let keep_alive = pipelined < self.max_pipelined && !self.keep_alive_timeout.is_zero();
+            stream = match self.answer(stream, request, body_start, keep_alive, queued) {
+                Some(stream) => stream,
+                None => return,
+            };
+            // Only the first request on a connection waits for a worker.
+            queued = Duration::ZERO;
 
-            // Execute the handler in a thread
-            self.pool.execute(move || {
-                Server::send_response(handler, &mut stream);
-            });
+            // A request already read counts towards the pipelining depth.
+            pipelined = if pending.is_empty() { 0 } else { pipelined + 1 };
+            if pending.is_empty() {
+                self.open_connections.set_idle(tracked.id, true);
+                if let Some(max) = self.max_idle_connections {
+                    self.open_connections.cap_idle(max);
+                }
+                if self.shutdown.is_shutting_down() {
+                    return;
+                }
+                if let Err(error) = stream.set_read_timeout(Some(self.keep_alive_timeout)) {
+                    eprintln!("Error setting keep-alive timeout: {error}");
+                    return;
+                }
+            }
+            (request, body_start) = match Server::read_stream(&stream, &mut pending, &self.head_limits) {
+                Ok(request) => request,
+                // Closing between requests, or staying quiet until the
+                // timeout, are how keep-alive connections normally end.
+                Err(error) if matches!(
+                    error.kind(),
+                    io::ErrorKind::UnexpectedEof | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) || Server::is_disconnect(&error) => return,
+                Err(error) if Shared::is_rejection(&error) => {
+                    self.reject(stream, &error);
+                    return;
+                }
+                Err(error) => {
+                    eprintln!("Error reading request: {error}");
+                    self.count_read_error(&error);
+                    return;
+                }
+            };
+            self.open_connections.set_idle(tracked.id, false);
+            if let Err(error) = stream.set_read_timeout(None) {
+                eprintln!("Error clearing keep-alive timeout: {error}");
+                return;
+            }
+            request.client_addr = client_addr;
         }
     }
 
-    fn find_endpoint(&self, path: &str) -> Option<Response> {
-        for endpoint in self.endpoints.clone() {
-            if path == endpoint.path {
-                return Some(endpoint.handler);
+    // Routes and answers one request. Gives the stream back if the connection
+    // can carry another; `keep_alive` false closes it regardless.
+    fn answer(&self, stream: TcpStream, mut request: Request, body_start: Vec<u8>, keep_alive: bool, queued: Duration) -> Option<TcpStream> {
+        let started = Instant::now();
+        // Find the corresponding endpoint
+        let routes = Arc::clone(&self.routes.read().unwrap());
+        let host_redirect = routes.redirect_host(&request);
+        let fast = routes.fast.get(request.path.as_str()).filter(|_| host_redirect.is_none());
+        if let Some(fast) = fast.filter(|_| keep_alive && self.fast_eligible(&request)) {
+            return match (&stream).write_all(fast) {
+                Ok(()) => Some(stream),
+                Err(error) if Server::is_disconnect(&error) => None,
+                Err(error) => {
+                    eprintln!("Error writing response to stream: {error}");
+                    None
+                }
+            };
+        }
+        let redirect = host_redirect.or_else(|| match routes.rewrite(&request.path) {
+            Some(Rewritten::Path(path)) => {
+                request.path = path;
+                None
+            }
+            Some(Rewritten::Redirect(status, location)) => Some((status, location)),
+            None => None,
+        });
+        let matched = match redirect {
+            Some((status, location)) => {
+                let response = Response::new(status, "Redirecting").with_header("Location", &location);
+                Matched::handler(Arc::new(move |_| response.clone()))
             }
+            None => routes.find_endpoint(&request.path).unwrap_or_else(|| {
+                let fallbacks = Arc::clone(&routes.fallbacks);
+                Matched::handler(Arc::new(move |request| {
+                    fallbacks.iter().find_map(|fallback| fallback(request)).unwrap_or_else(|| {
+                        eprintln!("No handler found for path: {}", &request.path);
+                        Server::not_found()
+                    })
+                }))
+            }),
+        };
+        request.params = matched.params;
+        request.route = matched.route.map(str::to_string);
+        let handler = matched.handler;
+        let stream_body = matched.stream_body;
+        let middleware = routes.middleware_for(&request.path, matched.middleware);
+        let hooks = Arc::clone(&routes.hooks);
+        drop(routes);
+
+        // Only now do we know how big a body this route accepts.
+        let length = request.content_length().unwrap_or(0);
+        let limit = stream_body.unwrap_or(self.max_body_size);
+        if length > limit {
+            eprintln!("Rejecting {}: body of {length} bytes is over the {limit} byte limit", &request.path);
+            let response = Response::new(StatusCode::PayloadTooLarge, "Payload Too Large")
+                .with_header("Connection", "close");
+            self.count_error("oversized");
+            Connection::new(stream).respond(response);
+            return None;
         }
-        None
+        // The handler may not read a streamed body to the end, and then
+        // there's no telling where the next request starts.
+        let mut keep_alive = keep_alive && stream_body.is_none() && Shared::wants_keep_alive(&request);
+        if stream_body.is_some() {
+            match stream.try_clone() {
+                Ok(reader) => *request.body_stream.get_mut().unwrap() = Some(BodyReader::new(body_start, reader, length)),
+                Err(error) => {
+                    eprintln!("Error cloning stream for the body: {error}");
+                    return None;
+                }
+            }
+        } else if let Some(spill) = self.spill.as_ref().filter(|spill| length > spill.threshold) {
+            match BodyFile::spill(&spill.dir, &body_start, &stream, length) {
+                Ok(file) => request.body_file = Some(file),
+                Err(error) => {
+                    eprintln!("Error spilling request body to {}: {error}", spill.dir.display());
+                    self.count_read_error(&error);
+                    return None;
+                }
+            }
+        } else {
+            let body = match Server::read_body(&stream, body_start, length as usize) {
+                Ok(body) => body,
+                Err(error) => {
+                    eprintln!("Error reading request body: {error}");
+                    self.count_read_error(&error);
+                    return None;
+                }
+            };
+            let body = match self.decompress_ratio {
+                Some(ratio) => match self.decompress_body(&mut request, body, ratio) {
+                    Ok(body) => body,
+                    Err(response) => {
+                        let kind = if response.status_code == StatusCode::PayloadTooLarge { "oversized" } else { "parse" };
+                        self.count_error(kind);
+                        Connection::new(stream).respond(response.with_header("Connection", "close"));
+                        return None;
+                    }
+                },
+                None => body,
+            };
+            match String::from_utf8(body) {
+                Ok(body) => request.body = body,
+                Err(error) => {
+                    request.body = String::from_utf8_lossy(error.as_bytes()).into_owned();
+                    request.raw_body = Some(error.into_bytes());
+                }
+            }
+        }
+        let connection = Arc::new(Connection::paced(stream, self.send_rate));
+        request.connection = Some(Arc::clone(&connection));
+        let read = started.elapsed();
+
+        for hook in &hooks.request {
+            hook(&mut request);
+        }
+        let result = panic::catch_unwind(AssertUnwindSafe(|| middleware::run(&middleware, &mut request, &handler)));
+        let (mut response, panicked) = match result {
+            Ok(response) => (response, None),
+            Err(panic) => (Response::new(StatusCode::InternalServerError, "Internal Server Error"), Some(panic)),
+        };
+        for hook in &hooks.response {
+            hook(&request, &mut response);
+        }
+        response.make_conditional(&request);
+        if response.status_code.code() >= 500 {
+            for hook in &hooks.error {
+                hook(&request, &response);
+            }
+        }
+
+        keep_alive = keep_alive
+            && panicked.is_none()
+            && !response.streamed
+            && !self.shutdown.is_shutting_down()
+            && !response.header("Connection").is_some_and(|value| value.eq_ignore_ascii_case("close"));
+        if !keep_alive {
+            response.set_header("Connection", "close");
+        } else if request.protocol == "HTTP/1.0" {
+            response.set_header("Connection", "keep-alive");
+        }
+        if self.queue_wait_header {
+            response.set_header("X-Queue-Ms", &format!("{:.3}", queued.as_secs_f64() * 1000.0));
+        }
+        if response.header("Date").is_none() {
+            response.set_header("Date", &http_date(self.clock.system_time()));
+        }
+        response.header_case = self.header_case;
+        // HEAD is routed like GET; only the body stays behind.
+        response.head_only = request.method == HttpMethod::HEAD;
+        let mut status = response.status_code;
+        let handled = started.elapsed();
+        connection.respond(response);
+        if let Some(abandoned) = connection.abandoned() {
+            self.count_error("timeout");
+            status = abandoned;
+        }
+        if connection.too_slow() {
+            self.count_error("slow_read");
+        }
+        let timing = Timing { queued, read, handler: handled - read, write: started.elapsed() - handled };
+        self.log_if_slow(&request, status, &timing);
+        // Let the pool see the panic too, now the client has its answer.
+        if let Some(panic) = panicked {
+            panic::resume_unwind(panic);
+        }
+        if keep_alive { connection.take() } else { None }
     }
 
-    fn read_stream(stream: &TcpStream) -> Request {
-        let mut lines = BufReader::new(stream).lines().map(|line| line.unwrap());
-        let first_line = lines.next().unwrap();
-        let mut parts = first_line.split_whitespace();
+    // What `read_stream` fails with for heads the parser refused.
+    fn is_rejection(error: &io::Error) -> bool {
+        error.get_ref().is_some_and(|inner| inner.is::<Rejection>())
+    }
 
-        let method = match parts.next().unwrap() {
-            "GET" => HttpMethod::GET,
-            "POST" => HttpMethod::POST,
-            "PUT" => HttpMethod::PUT,
-            "DELETE" => HttpMethod::DELETE,
-            _ => {
-                eprintln!("Invalid HTTP method");
-                HttpMethod::GET
-            }
+    // Answers a head the parser refused and closes the connection, since
+    // there's no knowing where the next request would start.
+    fn reject(&self, stream: TcpStream, error: &io::Error) {
+        let status = error.get_ref()
+            .and_then(|inner| inner.downcast_ref::<Rejection>())
+            .map_or(StatusCode::BadRequest, |rejection| rejection.status);
+        eprintln!("Rejecting request: {error}");
+        self.count_error(if status == StatusCode::UriTooLong { "oversized" } else { "parse" });
+        Connection::new(stream).respond(Response::new(status, &format!("{error}\n")).with_header("Connection", "close"));
+    }
+
+    fn count_error(&self, kind: &str) {
+        if let Some(metrics) = &self.metrics {
+            metrics.increment("web_server_connection_errors_total", &[("kind", kind)]);
+        }
+    }
+
+    fn count_read_error(&self, error: &io::Error) {
+        let kind = match error.kind() {
+            io::ErrorKind::FileTooLarge => "oversized",
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => "timeout",
+            io::ErrorKind::UnexpectedEof => "reset",
+            _ if Server::is_disconnect(error) => "reset",
+            _ => "parse",
         };
+        self.count_error(kind);
+    }
 
-        let path = match parts.next() {
-            Some(path) => path.to_string(),
-            None => {
-                eprintln!("Invalid path");
-                "/".to_string()
-            }
+    fn log_if_slow(&self, request: &Request, status: StatusCode, timing: &Timing) {
+        let Some(slow_log) = &self.slow_log else {
+            return;
         };
+        let total = timing.queued + timing.read + timing.handler + timing.write;
+        if total < slow_log.threshold {
+            return;
+        }
+        let line = format!(
+            "{} slow request: {:?} {} route={} status={} total={}ms queued={}ms read={}ms handler={}ms write={}ms",
+            log::timestamp(SystemTime::now()),
+            request.method,
+            request.path,
+            request.route().unwrap_or("unmatched"),
+            status.code(),
+            total.as_millis(),
+            timing.queued.as_millis(),
+            timing.read.as_millis(),
+            timing.handler.as_millis(),
+            timing.write.as_millis(),
+        );
+        match &slow_log.file {
+            Some(file) => file.write_line(&line),
+            None => println!("{line}"),
+        }
+    }
 
-        let protocol = match parts.next() {
-            Some(protocol) => protocol.to_string(),
-            None => {
-                eprintln!("Invalid protocol");
-                "HTTP/1.1".to_string()
-            }
+    // Fast responses assume the connection stays open and there's no body
+    // to skip past.
+    fn fast_eligible(&self, request: &Request) -> bool {
+        request.method == HttpMethod::GET
+            && request.protocol == "HTTP/1.1"
+            && request.content_length().unwrap_or(0) == 0
+            && request.header("Transfer-Encoding").is_none()
+            && Shared::wants_keep_alive(request)
+            && !self.shutdown.is_shutting_down()
+    }
+
+    // HTTP/1.1 connections stay open unless the client says otherwise;
+    // HTTP/1.0 ones only if it asks.
+    fn wants_keep_alive(request: &Request) -> bool {
+        let connection = request.header("Connection").map(str::to_ascii_lowercase);
+        match request.protocol.as_str() {
+            "HTTP/1.1" => !connection.is_some_and(|connection| connection.split(',').any(|token| token.trim() == "close")),
+            _ => connection.is_some_and(|connection| connection.split(',').any(|token| token.trim() == "keep-alive")),
+        }
+    }
+
+    // Undoes the request's Content-Encoding, last applied first, and drops
+    // the header so handlers see a plain body.
+    fn decompress_body(&self, request: &mut Request, mut body: Vec<u8>, ratio: u64) -> Result<Vec<u8>, Response> {
+        let Some(encodings) = request.header("Content-Encoding").map(str::to_string) else {
+            return Ok(body);
         };
+        let limit = (body.len() as u64).saturating_mul(ratio).min(self.max_body_size) as usize;
+        for encoding in encodings.rsplit(',').map(|encoding| encoding.trim().to_ascii_lowercase()) {
+            let decoded = match encoding.as_str() {
+                "identity" | "" => continue,
+                "gzip" | "x-gzip" => compress::gunzip(&body, limit),
+                "deflate" => compress::inflate(&body, limit),
+                _ => {
+                    eprintln!("Rejecting {}: unsupported Content-Encoding {encoding}", &request.path);
+                    return Err(Response::new(StatusCode::UnsupportedMediaType, "Unsupported Content-Encoding")
+                        .with_header("Accept-Encoding", "gzip, deflate"));
+                }
+            };
+            body = match decoded {
+                Ok(decoded) => decoded,
+                Err(InflateError::TooLarge) => {
+                    eprintln!("Rejecting {}: body decompresses to over {limit} bytes", &request.path);
+                    return Err(Response::new(StatusCode::PayloadTooLarge, "Payload Too Large"));
+                }
+                Err(error) => {
+                    eprintln!("Rejecting {}: invalid {encoding} body: {error}", &request.path);
+                    return Err(Response::new(StatusCode::BadRequest, "Invalid compressed body"));
+                }
+            };
+        }
+        request.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Encoding"));
+        for (name, value) in &mut request.headers {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                *value = body.len().to_string();
+            }
+        }
+        Ok(body)
+    }
+
+}
 
-        let body = " ".to_string(); //lines.collect::<Vec<String>>().join("\n");
+impl Routes {
+    fn find_endpoint(&self, path: &str) -> Option<Matched<'_>> {
+        let path = path.split('?').next().unwrap_or_default();
+        for endpoint in &self.endpoints {
+            if endpoint.pattern.is_none() && !endpoint.prefix && path == endpoint.path {
+                return Some(Matched::endpoint(endpoint, vec![]));
+            }
+        }
+        // Then patterns, in the order they were added.
+        for endpoint in &self.endpoints {
+            if let Some(captures) = endpoint.pattern.as_ref().and_then(|pattern| pattern.captures(path)) {
+                return Some(Matched::endpoint(endpoint, captures.pairs()));
+            }
+        }
+        // Otherwise the longest mount the path falls under.
+        self.endpoints.iter()
+            .filter(|endpoint| endpoint.prefix && Routes::under_mount(path, &endpoint.path))
+            .max_by_key(|endpoint| endpoint.path.len())
+            .map(|endpoint| Matched::endpoint(endpoint, vec![]))
+    }
 
-        Request {
-            method,
-            path,
-            protocol,
-            body
+    // The global middleware, then that of any groups the path is in, then
+    // the route's own. Shares the global chain when there's nothing to add.
+    fn middleware_for(&self, path: &str, route: &[Arc<dyn Middleware>]) -> Arc<Vec<Arc<dyn Middleware>>> {
+        let path = path.split('?').next().unwrap_or_default();
+        let mut groups = self.group_middleware.iter()
+            .filter(|(prefix, _)| Routes::under_mount(path, prefix))
+            .map(|(_, middleware)| Arc::clone(middleware))
+            .peekable();
+        if groups.peek().is_none() && route.is_empty() {
+            return Arc::clone(&self.middleware);
         }
+        Arc::new(self.middleware.iter().cloned().chain(groups).chain(route.iter().cloned()).collect())
+    }
+
+    fn under_mount(path: &str, mount: &str) -> bool {
+        let mount = mount.trim_end_matches('/');
+        path.strip_prefix(mount).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
     }
 
-    fn send_response(response: Response, stream: &mut TcpStream) {
-        let (protocol, status_code, body) = (&response.protocol, &response.status_code, &response.body);
-        let length = response.body.len();
-        let response =
-            format!("{protocol} {status_code}\r\nContent-Length: {length}\r\n\r\n{body}");
+    // Swaps the config-file parts of the table for `config`'s, keeping
+    // whatever was registered in code.
+    fn replace_config(&mut self, config: Routes) {
+        let mut endpoints = config.endpoints;
+        endpoints.extend(self.endpoints.drain(..).filter(|endpoint| !endpoint.from_config));
 
-        stream.write_all(response.as_bytes()).unwrap_or_else(|error| {
-            eprintln!("Error writing response to stream: {error}");
-        });
+        let mut middleware = config.middleware.to_vec();
+        middleware.extend(self.middleware[self.config_middleware..].iter().cloned());
+
+        let mut rewrites = config.rewrites;
+        rewrites.extend(self.rewrites.drain(self.config_rewrites..));
+
+        self.endpoints = endpoints;
+        self.config_middleware = config.config_middleware;
+        self.middleware = Arc::new(middleware);
+        self.config_rewrites = config.config_rewrites;
+        self.rewrites = rewrites;
+
+        let mut host_redirects = config.host_redirects;
+        host_redirects.extend(self.host_redirects.drain(self.config_host_redirects..));
+        self.config_host_redirects = config.config_host_redirects;
+        self.host_redirects = host_redirects;
     }
 
-    fn html_response(file_name: String) -> Response {
-        let contents = fs::read_to_string(file_name.clone()).unwrap_or_else(|error| {
-            eprintln!("Error reading contents of {file_name}: {error}");
-            return fs::read_to_string("unknown.html").unwrap();
-        });
+    // Runs the request path through the rewrite rules; the first that
+    // matches wins.
+    fn rewrite(&self, path: &str) -> Option<Rewritten> {
+        self.rewrites.iter().find_map(|rule| rule.apply(path))
+    }
+
+    fn redirect_host(&self, request: &Request) -> Option<(StatusCode, String)> {
+        self.host_redirects.iter().find_map(|rule| rule.apply(request))
+    }
+}
+
+// Lets other threads add and remove routes on a running server, for admin
+// tools and plugins:
+//
+//     let routes = server.routes_handle();
+//     routes.update_routes(RouteChanges::new()
+//         .remove("/beta")
+//         .add_static_dir("/docs", StaticDir::new("docs/v2"))
+//         .add_handler("/status", status).with(auth));
+//
+// Each update is swapped in whole, so a request sees the table from before
+// it or after it and never half of one. Requests already running carry on
+// with the old table. Routes added this way survive config reloads.
+#[derive(Clone)]
+pub struct RoutesHandle {
+    routes: Arc<RwLock<Arc<Routes>>>,
+}
+
+impl RoutesHandle {
+    pub fn update_routes(&self, changes: RouteChanges) {
+        let mut routes = self.routes.write().unwrap();
+        let routes = Arc::make_mut(&mut routes);
+        for path in &changes.removed {
+            routes.endpoints.retain(|endpoint| endpoint.path != *path);
+            routes.fast.remove(path);
+        }
+        routes.endpoints.extend(changes.added);
+    }
+
+    // The paths routed now, in the order they're tried.
+    pub fn paths(&self) -> Vec<String> {
+        self.routes.read().unwrap().endpoints.iter().map(|endpoint| endpoint.path.clone()).collect()
+    }
+}
+
+// Routes to add and remove together, with `RoutesHandle::update_routes`.
+// Removals go first, so a path can be removed and added back to replace
+// it; adding a path that's already routed replaces it too.
+#[derive(Default)]
+pub struct RouteChanges {
+    removed: Vec<String>,
+    added: Vec<Endpoint>,
+}
+
+impl RouteChanges {
+    pub fn new() -> RouteChanges {
+        RouteChanges::default()
+    }
+
+    pub fn add_handler<F>(self, path: &str, handler: F) -> RouteChanges
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.add(Endpoint::new(path.to_string(), Arc::new(handler)))
+    }
+
+    pub fn add_static_dir(self, prefix: &str, dir: StaticDir) -> RouteChanges {
+        self.add(Endpoint::static_dir(prefix, dir))
+    }
+
+    // Middleware for the route added last.
+    pub fn with<M: Middleware + 'static>(mut self, middleware: M) -> RouteChanges {
+        if let Some(endpoint) = self.added.last_mut() {
+            endpoint.middleware.push(Arc::new(middleware));
+        }
+        self
+    }
+
+    // Takes away whatever is routed at exactly `path`: a handler, or a
+    // static mount with `path` as its prefix.
+    pub fn remove(mut self, path: &str) -> RouteChanges {
+        self.added.retain(|added| added.path != path);
+        self.removed.push(path.to_string());
+        self
+    }
+
+    fn add(mut self, endpoint: Endpoint) -> RouteChanges {
+        self.removed.push(endpoint.path.clone());
+        self.added.retain(|added| added.path != endpoint.path);
+        self.added.push(endpoint);
+        self
+    }
+}
+
+// Lets other threads stop a running server. Acceptors finish the connection
+// they're on and stop; requests already handed to workers still complete.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+struct ShutdownState {
+    requested: AtomicBool,
+    running_acceptors: AtomicUsize,
+    addresses: Vec<SocketAddr>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        let state = &self.state;
+        state.requested.store(true, Ordering::SeqCst);
+        Server::notify("STOPPING=1");
+
+        // Acceptors are blocked in accept(), so connect to them to wake them
+        // up. With SO_REUSEPORT the kernel picks which one gets each
+        // connection, so keep going until they have all noticed.
+        for _ in 0..100 {
+            if state.running_acceptors.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            for address in &state.addresses {
+                let _ = TcpStream::connect_timeout(&ShutdownHandle::reachable(*address), Duration::from_millis(100));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.requested.load(Ordering::SeqCst)
+    }
+
+    // A listener bound to the unspecified address is reachable on loopback.
+    fn reachable(mut address: SocketAddr) -> SocketAddr {
+        if address.ip().is_unspecified() {
+            address.set_ip(match address.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        address
+    }
+}
+
+// The write half of a connection. Whoever answers first owns it: normally the
+// worker running the handler, but a timeout can answer on the handler's
+// behalf, after which the handler's own response is dropped.
+pub(crate) struct Connection {
+    stream: Mutex<Option<TcpStream>>,
+    // What `abandon` answered with, if it did.
+    abandoned: OnceLock<StatusCode>,
+    send_rate: Option<SendRate>,
+    // Whether `respond` cut the client off for reading too slowly.
+    too_slow: AtomicBool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Connection {
+        Connection::paced(stream, None)
+    }
+
+    fn paced(stream: TcpStream, send_rate: Option<SendRate>) -> Connection {
+        Connection { stream: Mutex::new(Some(stream)), abandoned: OnceLock::new(), send_rate, too_slow: AtomicBool::new(false) }
+    }
+
+    fn respond(&self, response: Response) {
+        let mut stream = self.stream.lock().unwrap();
+        match stream.as_mut() {
+            Some(_) if response.streamed => {}
+            Some(open) => {
+                if let Err(error) = Server::send_response(&response, open, self.send_rate) {
+                    eprintln!("Closing connection: {error}");
+                    self.too_slow.store(true, Ordering::Relaxed);
+                    *stream = None;
+                }
+            }
+            None if response.streamed => {}
+            None => eprintln!("Response abandoned; the client was already answered"),
+        }
+    }
+
+    fn too_slow(&self) -> bool {
+        self.too_slow.load(Ordering::Relaxed)
+    }
+
+    // Gets the stream back to read the connection's next request, unless a
+    // timeout has answered and closed it.
+    fn take(&self) -> Option<TcpStream> {
+        self.stream.lock().unwrap().take()
+    }
+
+    // Runs `write` against the stream, or fails if the connection has been
+    // answered and closed already.
+    pub(crate) fn with_stream<T>(&self, write: impl FnOnce(&mut TcpStream) -> io::Result<T>) -> io::Result<T> {
+        match self.stream.lock().unwrap().as_mut() {
+            Some(stream) => write(stream),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
 
-        Response {
-            protocol: "HTTP/1.1".to_string(),
-            status_code: StatusCode::Ok,
-            body: contents,
+    // Answers with `response` if nobody has yet, and closes the connection so
+    // the handler still running can't write to it.
+    pub(crate) fn abandon(&self, response: Response) {
+        if let Some(mut stream) = self.stream.lock().unwrap().take() {
+            let _ = self.abandoned.set(response.status_code);
+            let _ = Server::send_response(&response, &mut stream, self.send_rate);
         }
     }
 
-    pub fn add_get_endpoint(&mut self, path: &str, file_name: &str) {
-        self.add_endpoint(path, Server::html_response(file_name.to_string()));
+    fn abandoned(&self) -> Option<StatusCode> {
+        self.abandoned.get().copied()
     }
 
-    fn add_endpoint(&mut self, path: &str, handler: Response) {
-        self.endpoints.push(Endpoint::new(path.to_string(), handler));
+    // Closes the connection without answering at all.
+    pub(crate) fn hang_up(&self) {
+        if let Some(stream) = self.stream.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
     }
 }
 
 #[derive(Clone)]
 struct Endpoint {
     path: String,
-    handler: Response, //TODO: Allow for dynamic endpoints
+    handler: Handler,
+    // Matches everything under `path` rather than only `path` itself.
+    prefix: bool,
+    // Matches paths against a regex instead; see `add_route`.
+    pattern: Option<Arc<Regex>>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    stream_body: Option<u64>,
+    from_config: bool,
 }
 
 impl Endpoint {
-    pub fn new(path: String, handler: Response) -> Endpoint {
+    pub fn new(path: String, handler: Handler) -> Endpoint {
         Endpoint {
             path,
             handler,
+            prefix: false,
+            pattern: None,
+            middleware: vec![],
+            stream_body: None,
+            from_config: false,
+        }
+    }
+
+    fn static_dir(prefix: &str, dir: StaticDir) -> Endpoint {
+        let dir = Arc::new(dir);
+        let mount = prefix.trim_end_matches('/').to_string();
+        let handler: Handler = Arc::new(move |request| dir.respond(&mount, request));
+        Endpoint { prefix: true, ..Endpoint::new(prefix.to_string(), handler) }
+    }
+
+    fn acme_webroot(webroot: &str) -> Endpoint {
+        let dir = Path::new(webroot).join(ACME_CHALLENGE_PATH.trim_start_matches('/'));
+        Endpoint::static_dir(ACME_CHALLENGE_PATH, StaticDir::new(&dir.to_string_lossy()))
+    }
+
+    fn file(path: &str, file_name: &str) -> Endpoint {
+        let response = Server::html_response(file_name.to_string());
+        Endpoint::new(path.to_string(), Arc::new(move |_| response.clone()))
+    }
+
+    // A `[[mount]]` from the config file, behind its auth if it has any.
+    fn mount(mount: &Mount) -> Endpoint {
+        let mut endpoint = match &mount.target {
+            MountTarget::File(file) => Endpoint::file(&mount.path, file),
+            MountTarget::Dir(root) => {
+                let mut dir = StaticDir::new(root)
+                    .spa(mount.spa)
+                    .serve_hidden(mount.hidden)
+                    .markdown(mount.markdown)
+                    .includes(mount.includes);
+                for glob in &mount.allow {
+                    dir = dir.allow(glob);
+                }
+                for glob in &mount.deny {
+                    dir = dir.deny(glob);
+                }
+                Endpoint::static_dir(&mount.path, dir)
+            }
+        };
+        if let Some(auth) = &mount.auth {
+            let digest = auth.users.iter()
+                .fold(DigestAuth::new(&auth.realm), |digest, (user, password)| digest.user(user, password));
+            endpoint.middleware.push(Arc::new(digest));
+        }
+        if let Some(cache) = &mount.cache {
+            endpoint.middleware.push(Arc::new(CacheControl::new(cache)));
+        }
+        endpoint
+    }
+
+    // A `[[redirect]]` from the config file. The query string goes along,
+    // and for a prefix so does the rest of the path.
+    fn redirect(redirect: &Redirect) -> Endpoint {
+        // Config::parse has already checked the status.
+        let status = rewrite::redirect_status(redirect.status).unwrap_or(StatusCode::MovedPermanently);
+        let from = redirect.from.trim_end_matches('/').to_string();
+        let to = redirect.to.clone();
+        let prefix = redirect.prefix;
+        let handler: Handler = Arc::new(move |request| {
+            let location = if prefix {
+                format!("{}{}", to.trim_end_matches('/'), &request.path[from.len().min(request.path.len())..])
+            } else {
+                match request.path.split_once('?') {
+                    Some((_, query)) => format!("{to}?{query}"),
+                    None => to.clone(),
+                }
+            };
+            Response::new(status, "Redirecting").with_header("Location", &location)
+        });
+        Endpoint { prefix, ..Endpoint::new(redirect.from.clone(), handler) }
+    }
+
+}
+
+// Turns a route template into an anchored regex: literal text is escaped,
+// `{name}` becomes a group matching one segment and `{name:regex}` a group
+// matching the regex.
+fn template_pattern(template: &str) -> Result<String, String> {
+    let mut pattern = "^".to_string();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        for c in rest[..open].chars() {
+            if !c.is_alphanumeric() && c != '/' {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+
+        // Find the matching brace, allowing for `{2,3}` inside the regex.
+        let mut depth = 0;
+        let close = rest[open..].char_indices()
+            .find(|&(_, c)| {
+                depth += match c { '{' => 1, '}' => -1, _ => 0 };
+                depth == 0
+            })
+            .map(|(index, _)| open + index)
+            .ok_or("Unclosed `{`")?;
+
+        let param = &rest[open + 1..close];
+        let (name, regex) = param.split_once(':').unwrap_or((param, "[^/]+"));
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(format!("Invalid parameter name `{name}`"));
+        }
+        pattern.push_str(&format!("(?P<{name}>{regex})"));
+        rest = &rest[close + 1..];
+    }
+    for c in rest.chars() {
+        if !c.is_alphanumeric() && c != '/' {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('$');
+    Ok(pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    // Sends `bytes` over a real connection and reads requests off the other
+    // end until it closes.
+    fn read_requests(bytes: &[u8], parsing: Parsing) -> Vec<io::Result<(Request, Vec<u8>)>> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        client.write_all(bytes).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        let limits = HeadLimits { parsing, max_uri_length: 8 * 1024 };
+        let mut pending = vec![];
+        let mut requests = vec![];
+        loop {
+            match Server::read_stream(&server, &mut pending, &limits) {
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return requests,
+                result => {
+                    let failed = result.is_err();
+                    requests.push(result);
+                    if failed {
+                        return requests;
+                    }
+                }
+            }
         }
     }
-    pub fn default() -> Endpoint {
-        Endpoint::new("/".to_string(), Server::html_response("unknown.html".to_string()))
+
+    #[test]
+    fn finds_the_end_of_a_head() {
+        assert_eq!(Server::head_end(b"GET / HTTP/1.1\r\nHost: a\r\n\r\nbody", 0), Some(27));
+        assert_eq!(Server::head_end(b"GET / HTTP/1.1\nHost: a\n\nbody", 0), Some(24));
+        assert_eq!(Server::head_end(b"GET / HTTP/1.1\nHost: a\n\r\nbody", 0), Some(25));
+        assert_eq!(Server::head_end(b"GET / HTTP/1.1\r\nHost: a\r\n", 0), None);
+        assert_eq!(Server::head_end(b"GET / HTTP/1.1\r\nHost: a\r\n\r", 0), None);
+    }
+
+    #[test]
+    fn reads_bare_lf_requests() {
+        let requests = read_requests(b"GET /a HTTP/1.1\nHost: a\n\nGET /b HTTP/1.1\nHost: a\n\r\n", Parsing::Lenient);
+        let paths: Vec<String> = requests.into_iter().map(|request| request.unwrap().0.path).collect();
+        assert_eq!(paths, ["/a", "/b"]);
+    }
+
+    #[test]
+    fn refuses_bare_lf_when_strict() {
+        let requests = read_requests(b"GET /a HTTP/1.1\nHost: a\n\n", Parsing::Strict);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].as_ref().err().map(io::Error::kind), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn keeps_the_body_and_what_follows() {
+        let bytes = b"POST /a HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.1\r\nHost: a\r\n\r\n";
+        let requests: Vec<(Request, Vec<u8>)> = read_requests(bytes, Parsing::Strict).into_iter().map(Result::unwrap).collect();
+        assert_eq!(requests.len(), 2);
+        assert_eq!((requests[0].0.path.as_str(), requests[0].1.as_slice()), ("/a", &b"hello"[..]));
+        assert_eq!(requests[1].0.path, "/b");
+    }
+
+    #[test]
+    fn dates_responses_by_the_server_clock() {
+        // Sun, 06 Nov 1994 08:49:37 GMT, the example date in RFC 9110.
+        let clock = clock::MockClock::at(SystemTime::UNIX_EPOCH + Duration::from_secs(784111777));
+        let mut server = Server::builder("127.0.0.1", 0).clock(clock.clone()).build();
+        server.add_handler("/", |_| Response::new(StatusCode::Ok, "hi"));
+        let address = server.local_addrs()[0];
+        let handle = server.shutdown_handle();
+
+        let head = thread::scope(|scope| {
+            scope.spawn(|| server.run());
+            let mut client = TcpStream::connect(address).unwrap();
+            client.write_all(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            handle.shutdown();
+            response
+        });
+        assert!(head.contains("\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n"), "{head}");
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,187 @@
+use std::{
+    fmt::Write,
+    io,
+    net::{TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+use crate::{
+    http::{HttpMethod, Request, Response, StatusCode},
+    log::{self, json_string, Level},
+    middleware::ResponseCache,
+    recorder::{self, Exchange, Recorder},
+    sha256,
+};
+use super::{Connection, Server};
+
+// A second listener for running the server rather than using it: what's
+// routed, how busy the pool is, the log level, flushing caches and shutting
+// down. Every request needs `Authorization: Bearer <token>`, and since it can
+// stop the server it belongs on a loopback address:
+//
+//     GET  /routes             the registered routes, as JSON
+//     GET  /stats              pool and connection counts, as JSON
+//     GET  /log-level          the current level
+//     PUT  /log-level/{level}  error, info or debug
+//     POST /cache/flush        empties every cache passed to `register_cache`
+//     GET  /recordings         what recorders passed to `register_recorder` hold
+//     GET  /recordings/har     the same as a HAR file
+//     DELETE /recordings       clears them
+//     POST /shutdown           drains and stops, as `ShutdownHandle::shutdown`
+//
+// Admin requests are answered one at a time on their own thread, so they
+// still get through when the workers are all busy.
+pub(super) struct Admin {
+    listener: TcpListener,
+    token: String,
+    pub(super) caches: Vec<ResponseCache>,
+    pub(super) recorders: Vec<Recorder>,
+}
+
+impl Admin {
+    pub(super) fn bind(address: &str, token: &str) -> io::Result<Admin> {
+        let listener = TcpListener::bind(address)?;
+        if !listener.local_addr()?.ip().is_loopback() {
+            eprintln!("Warning: the admin endpoint on {address} is reachable from other hosts");
+        }
+        listener.set_nonblocking(true)?;
+        Ok(Admin { listener, token: token.to_string(), caches: vec![], recorders: vec![] })
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(given) = request.header("Authorization").and_then(|value| value.strip_prefix("Bearer ")) else {
+            return false;
+        };
+        sha256::constant_time_eq(given.trim().as_bytes(), self.token.as_bytes())
+    }
+}
+
+impl Server {
+    pub(super) fn serve_admin(&self, admin: &Admin) {
+        while !self.shutdown.is_shutting_down() {
+            match admin.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(error) = self.answer_admin(admin, stream) {
+                        eprintln!("Error answering admin request: {error}");
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(100)),
+                Err(error) => {
+                    eprintln!("Error accepting admin connection: {error}");
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+        }
+    }
+
+    fn answer_admin(&self, admin: &Admin, stream: TcpStream) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let (request, _) = Server::read_stream(&stream, &mut Vec::new(), &self.shared.head_limits)?;
+
+        let mut shut_down = false;
+        let response = if !admin.authorized(&request) {
+            Response::new(StatusCode::Unauthorized, "Unauthorized").with_header("WWW-Authenticate", "Bearer")
+        } else {
+            let path = request.path.split('?').next().unwrap_or_default();
+            match (&request.method, path) {
+                (HttpMethod::GET, "/routes") => Server::json(self.routes_json()),
+                (HttpMethod::GET, "/stats") => Server::json(self.stats_json()),
+                (HttpMethod::GET, "/log-level") => Response::new(StatusCode::Ok, log::level().name()),
+                (HttpMethod::PUT, path) if path.starts_with("/log-level/") => {
+                    match Level::parse(&path["/log-level/".len()..]) {
+                        Some(level) => {
+                            log::set_level(level);
+                            Response::new(StatusCode::Ok, level.name())
+                        }
+                        None => Response::new(StatusCode::BadRequest, "Expected error, info or debug"),
+                    }
+                }
+                (HttpMethod::POST, "/cache/flush") => {
+                    let flushed: usize = admin.caches.iter().map(ResponseCache::clear).sum();
+                    Response::new(StatusCode::Ok, &format!("Flushed {flushed} responses"))
+                }
+                (HttpMethod::GET, "/recordings") => {
+                    let recordings: Vec<String> = admin.recorders.iter()
+                        .flat_map(Recorder::exchanges)
+                        .map(|exchange| exchange.to_json())
+                        .collect();
+                    Server::json(format!("[{}]", recordings.join(",")))
+                }
+                (HttpMethod::GET, "/recordings/har") => {
+                    let exchanges: Vec<Exchange> = admin.recorders.iter().flat_map(Recorder::exchanges).collect();
+                    Server::json(recorder::har(&exchanges))
+                }
+                (HttpMethod::DELETE, "/recordings") => {
+                    let cleared: usize = admin.recorders.iter().map(Recorder::clear).sum();
+                    Response::new(StatusCode::Ok, &format!("Cleared {cleared} exchanges"))
+                }
+                (HttpMethod::POST, "/shutdown") => {
+                    shut_down = true;
+                    Response::new(StatusCode::Ok, "Shutting down")
+                }
+                _ => Response::new(StatusCode::NotFound, "Not Found"),
+            }
+        };
+        Connection::new(stream).respond(response.with_header("Connection", "close"));
+
+        // Only once answered: shutting down waits for the acceptors to stop.
+        if shut_down {
+            println!("Shutdown requested through the admin endpoint");
+            self.shutdown.shutdown();
+        }
+        Ok(())
+    }
+
+    fn json(body: String) -> Response {
+        Response::new(StatusCode::Ok, &body).with_header("Content-Type", "application/json")
+    }
+
+    fn routes_json(&self) -> String {
+        let routes = self.shared.routes.read().unwrap();
+        let endpoints: Vec<String> = routes.endpoints.iter().map(|endpoint| {
+            let kind = match (&endpoint.pattern, endpoint.prefix) {
+                (Some(_), _) => "pattern",
+                (None, true) => "prefix",
+                (None, false) => "exact",
+            };
+            format!(
+                "{{\"path\":{},\"match\":\"{kind}\",\"middleware\":{},\"from_config\":{}}}",
+                json_string(&endpoint.path),
+                endpoint.middleware.len(),
+                endpoint.from_config
+            )
+        }).collect();
+        format!(
+            "{{\"routes\":[{}],\"middleware\":{},\"fallbacks\":{},\"rewrites\":{}}}",
+            endpoints.join(","),
+            routes.middleware.len(),
+            routes.fallbacks.len(),
+            routes.rewrites.len()
+        )
+    }
+
+    fn stats_json(&self) -> String {
+        let stats = self.pool.stats();
+        let (open, idle) = self.shared.open_connections.counts();
+        let mut json = String::new();
+        let _ = write!(
+            json,
+            "{{\"pool\":{{\"size\":{},\"idle\":{},\"queued\":{},\"jobs_executed\":{},\"panics_recovered\":{},\"busy_seconds\":{:.3},\"queue_seconds\":{:.3}}},",
+            stats.size,
+            stats.idle,
+            stats.queued,
+            stats.jobs_executed,
+            stats.panics_recovered,
+            stats.busy_time.as_secs_f64(),
+            stats.queue_time.as_secs_f64()
+        );
+        let _ = write!(
+            json,
+            "\"connections\":{{\"open\":{open},\"idle\":{idle}}},\"log_level\":\"{}\",\"shutting_down\":{}}}",
+            log::level().name(),
+            self.shutdown.is_shutting_down()
+        );
+        json
+    }
+}
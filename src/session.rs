@@ -0,0 +1,283 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+use crate::{
+    clock::{self, Clock},
+    http::{Request, Response},
+    middleware::{Middleware, Next},
+    random,
+};
+
+// Server-side sessions, kept in memory and found by a random id in an
+// HttpOnly cookie. Handlers reach theirs with `request.session()`:
+//
+//     server.add_middleware(Sessions::new());
+//     server.add_handler("/login", |request| {
+//         let session = request.session().unwrap();
+//         session.set("user", "ada");
+//         session.flash_success("Welcome back!");
+//         Response::new(StatusCode::Found, "").with_header("Location", "/")
+//     });
+//
+// A session is only stored (and its cookie sent) once something is put in
+// it, and is dropped after `idle_timeout` without a request, or as soon as
+// it's emptied with `clear`. Sessions don't survive a restart.
+pub struct Sessions {
+    cookie: String,
+    principal_key: Option<String>,
+    secure: bool,
+    idle_timeout: Duration,
+    store: Mutex<HashMap<String, (Session, Instant)>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Sessions {
+    // A "session" cookie, and 30 minutes idle before a session expires.
+    pub fn new() -> Sessions {
+        Sessions {
+            cookie: "session".to_string(),
+            principal_key: None,
+            secure: false,
+            idle_timeout: Duration::from_secs(30 * 60),
+            store: Mutex::new(HashMap::new()),
+            clock: clock::system(),
+        }
+    }
+
+    pub fn cookie_name(mut self, name: &str) -> Sessions {
+        self.cookie = name.to_string();
+        self
+    }
+
+    // Marks the cookie Secure, for sites served only over HTTPS.
+    pub fn secure(mut self, secure: bool) -> Sessions {
+        self.secure = secure;
+        self
+    }
+
+    // Treats the session value under `key` (like the "user" an OAuth login
+    // stores) as who the client is, for `request.principal()` and guards.
+    pub fn principal_key(mut self, key: &str) -> Sessions {
+        self.principal_key = Some(key.to_string());
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Sessions {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Sessions {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    fn find(&self, id: &str) -> Option<Session> {
+        let mut store = self.store.lock().unwrap();
+        let now = self.clock.now();
+        match store.get_mut(id) {
+            Some((_, last_seen)) if now.duration_since(*last_seen) > self.idle_timeout => {
+                store.remove(id);
+                None
+            }
+            Some((session, last_seen)) => {
+                *last_seen = now;
+                Some(session.clone())
+            }
+            None => None,
+        }
+    }
+
+    // Moves a session to a new id, so one an attacker planted before login
+    // is useless after it.
+    fn renew(&self, old: &str, session: Session) -> String {
+        let id = random::hex(32);
+        let mut store = self.store.lock().unwrap();
+        store.remove(old);
+        store.insert(id.clone(), (session, self.clock.now()));
+        id
+    }
+
+    fn cookie(&self, value: &str, expire: bool) -> String {
+        let mut cookie = format!("{}={value}; Path=/; HttpOnly; SameSite=Lax", self.cookie);
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        if expire {
+            cookie.push_str("; Max-Age=0");
+        }
+        cookie
+    }
+}
+
+impl Default for Sessions {
+    fn default() -> Sessions {
+        Sessions::new()
+    }
+}
+
+impl Middleware for Sessions {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let existing = request.cookie(&self.cookie)
+            .and_then(|id| self.find(id).map(|session| (id.to_string(), session)));
+        let (id, session, stored) = match existing {
+            Some((id, session)) => (id, session, true),
+            None => (random::hex(32), Session::default(), false),
+        };
+        if let Some(principal) = self.principal_key.as_ref().and_then(|key| session.get(key)) {
+            request.set_principal(&principal);
+        }
+        request.session = Some(session.clone());
+        let response = next(request);
+
+        let (empty, renew) = {
+            let mut data = session.data();
+            (data.is_empty(), std::mem::take(&mut data.renew))
+        };
+        match (stored, empty) {
+            (true, false) if renew => {
+                let id = self.renew(&id, session);
+                response.with_header("Set-Cookie", &self.cookie(&id, false))
+            }
+            (false, false) => {
+                let mut store = self.store.lock().unwrap();
+                let now = self.clock.now();
+                // Sweep out the expired ones while we're here.
+                store.retain(|_, (_, last_seen)| now.duration_since(*last_seen) <= self.idle_timeout);
+                store.insert(id.clone(), (session, now));
+                drop(store);
+                response.with_header("Set-Cookie", &self.cookie(&id, false))
+            }
+            (true, true) => {
+                self.store.lock().unwrap().remove(&id);
+                response.with_header("Set-Cookie", &self.cookie("", true))
+            }
+            _ => response,
+        }
+    }
+}
+
+// One client's session. Clones share the same data.
+#[derive(Clone, Default)]
+pub struct Session {
+    data: Arc<Mutex<SessionData>>,
+}
+
+#[derive(Default)]
+struct SessionData {
+    values: HashMap<String, String>,
+    flashes: Vec<Flash>,
+    renew: bool,
+}
+
+impl SessionData {
+    fn is_empty(&self) -> bool {
+        self.values.is_empty() && self.flashes.is_empty()
+    }
+}
+
+// A one-shot message for the next page the client sees, like "Saved." after
+// a form posts and redirects.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Flash {
+    pub category: String,
+    pub message: String,
+}
+
+impl Session {
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.data().values.get(key).cloned()
+    }
+
+    pub fn set(&self, key: &str, value: &str) {
+        self.data().values.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        self.data().values.remove(key)
+    }
+
+    // Empties the session, which ends it (logging out, say).
+    pub fn clear(&self) {
+        let mut data = self.data();
+        data.values.clear();
+        data.flashes.clear();
+    }
+
+    // Gives the session a new id at the end of this request, keeping its
+    // data. Call it whenever the client logs in or gains privileges.
+    pub fn renew(&self) {
+        self.data().renew = true;
+    }
+
+    // Queues a message under `category` ("success", "error", or anything
+    // the templates know), kept until `flashes` is next called.
+    pub fn flash(&self, category: &str, message: &str) {
+        self.data().flashes.push(Flash { category: category.to_string(), message: message.to_string() });
+    }
+
+    pub fn flash_success(&self, message: &str) {
+        self.flash("success", message);
+    }
+
+    pub fn flash_error(&self, message: &str) {
+        self.flash("error", message);
+    }
+
+    // The queued messages, oldest first. Each is only ever returned once.
+    pub fn flashes(&self) -> Vec<Flash> {
+        std::mem::take(&mut self.data().flashes)
+    }
+
+    fn data(&self) -> MutexGuard<'_, SessionData> {
+        self.data.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::MockClock, http::{HttpMethod, StatusCode}};
+
+    // Sends a request with `cookie`, returning the value the handler saw and
+    // the cookie the response set, if any.
+    fn visit(sessions: &Sessions, cookie: Option<&str>) -> (Option<String>, Option<String>) {
+        let mut request = Request::new(HttpMethod::GET, "/");
+        if let Some(cookie) = cookie {
+            request.headers.push(("Cookie".to_string(), cookie.to_string()));
+        }
+        let seen = std::cell::RefCell::new(None);
+        let response = sessions.handle(&mut request, &|request| {
+            let session = request.session().unwrap();
+            *seen.borrow_mut() = session.get("user");
+            session.set("user", "ada");
+            Response::new(StatusCode::Ok, "")
+        });
+        let set_cookie = response.header("Set-Cookie")
+            .map(|cookie| cookie.split(';').next().unwrap().to_string());
+        (seen.into_inner(), set_cookie)
+    }
+
+    #[test]
+    fn sessions_expire_when_idle() {
+        let clock = MockClock::new();
+        let sessions = Sessions::new().idle_timeout(Duration::from_secs(60)).clock(clock.clone());
+
+        let (seen, cookie) = visit(&sessions, None);
+        assert_eq!(seen, None);
+        let cookie = cookie.unwrap();
+
+        // Each visit keeps it alive for another minute.
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(visit(&sessions, Some(&cookie)).0.as_deref(), Some("ada"));
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(visit(&sessions, Some(&cookie)).0.as_deref(), Some("ada"));
+
+        clock.advance(Duration::from_secs(61));
+        let (seen, renewed) = visit(&sessions, Some(&cookie));
+        assert_eq!(seen, None);
+        assert_ne!(renewed.unwrap(), cookie);
+    }
+}
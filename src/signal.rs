@@ -0,0 +1,42 @@
+use std::{
+    ffi::c_void,
+    io,
+    os::{fd::IntoRawFd, raw::c_int, unix::net::UnixStream},
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+pub const SIGHUP: u8 = 1;
+pub const SIGINT: u8 = 2;
+pub const SIGTERM: u8 = 15;
+
+// Where the handler writes; a signal handler can't do much more than that.
+static WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" {
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    fn write(fd: c_int, buffer: *const c_void, count: usize) -> isize;
+}
+
+extern "C" fn forward(signum: c_int) {
+    let fd = WRITE_FD.load(Ordering::Relaxed);
+    if fd >= 0 {
+        let byte = signum as u8;
+        unsafe { write(fd, &byte as *const u8 as *const c_void, 1) };
+    }
+}
+
+// Routes SIGHUP, SIGINT and SIGTERM into the returned stream, one byte (the
+// signal number) per signal, so they can be handled on an ordinary thread.
+pub fn listen() -> io::Result<UnixStream> {
+    let (reader, writer) = UnixStream::pair()?;
+    // The handler must never block, even if nobody is reading.
+    writer.set_nonblocking(true)?;
+    WRITE_FD.store(writer.into_raw_fd(), Ordering::SeqCst);
+
+    for signum in [SIGHUP, SIGINT, SIGTERM] {
+        if unsafe { signal(signum as c_int, forward) } == usize::MAX {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(reader)
+}
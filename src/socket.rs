@@ -0,0 +1,217 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+#[derive(Clone, Copy)]
+pub struct KeepAlive {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub retries: u32,
+}
+
+#[derive(Clone)]
+pub struct SocketOptions {
+    pub nodelay: bool,
+    pub keepalive: Option<KeepAlive>,
+    pub backlog: i32,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    pub linger: Option<Duration>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> SocketOptions {
+        SocketOptions {
+            nodelay: false,
+            keepalive: None,
+            backlog: 128,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            linger: None,
+        }
+    }
+}
+
+// The standard library can't set most of these options (or SO_REUSEPORT
+// before binding), so on Linux we build the socket ourselves and hand the
+// descriptor over to a TcpListener.
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::ffi::c_void;
+    use std::os::raw::c_int;
+
+    pub const AF_INET: c_int = 2;
+    pub const AF_INET6: c_int = 10;
+    pub const SOCK_STREAM: c_int = 1;
+    pub const SOCK_CLOEXEC: c_int = 0o2000000;
+    pub const SOL_SOCKET: c_int = 1;
+    pub const SO_REUSEADDR: c_int = 2;
+    pub const SO_SNDBUF: c_int = 7;
+    pub const SO_RCVBUF: c_int = 8;
+    pub const SO_KEEPALIVE: c_int = 9;
+    pub const SO_LINGER: c_int = 13;
+    pub const SO_REUSEPORT: c_int = 15;
+    pub const IPPROTO_TCP: c_int = 6;
+    pub const TCP_KEEPIDLE: c_int = 4;
+    pub const TCP_KEEPINTVL: c_int = 5;
+    pub const TCP_KEEPCNT: c_int = 6;
+
+    #[repr(C)]
+    pub struct SockAddrIn {
+        pub family: u16,
+        pub port: u16,
+        pub addr: [u8; 4],
+        pub zero: [u8; 8],
+    }
+
+    #[repr(C)]
+    pub struct SockAddrIn6 {
+        pub family: u16,
+        pub port: u16,
+        pub flow_info: u32,
+        pub addr: [u8; 16],
+        pub scope_id: u32,
+    }
+
+    #[repr(C)]
+    pub struct Linger {
+        pub on: c_int,
+        pub seconds: c_int,
+    }
+
+    extern "C" {
+        pub fn socket(domain: c_int, kind: c_int, protocol: c_int) -> c_int;
+        pub fn setsockopt(fd: c_int, level: c_int, name: c_int, value: *const c_void, len: u32) -> c_int;
+        pub fn bind(fd: c_int, addr: *const c_void, len: u32) -> c_int;
+        pub fn listen(fd: c_int, backlog: c_int) -> c_int;
+    }
+
+    pub fn set<T>(fd: c_int, level: c_int, name: c_int, value: &T) -> std::io::Result<()> {
+        let len = std::mem::size_of::<T>() as u32;
+        if unsafe { setsockopt(fd, level, name, value as *const T as *const c_void, len) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+fn resolve(address: &str) -> io::Result<SocketAddr> {
+    address.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("Could not resolve {address}"))
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn bind_socket(address: &str, options: &SocketOptions, reuse_port: bool) -> io::Result<TcpListener> {
+    use std::mem::size_of;
+    use std::os::fd::{FromRawFd, OwnedFd};
+    use std::os::raw::c_int;
+
+    let address = resolve(address)?;
+    let domain = match address {
+        SocketAddr::V4(_) => sys::AF_INET,
+        SocketAddr::V6(_) => sys::AF_INET6,
+    };
+
+    let fd = unsafe { sys::socket(domain, sys::SOCK_STREAM | sys::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Owning the descriptor closes it for us on every early return below.
+    let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    sys::set(fd, sys::SOL_SOCKET, sys::SO_REUSEADDR, &(1 as c_int))?;
+    if reuse_port {
+        sys::set(fd, sys::SOL_SOCKET, sys::SO_REUSEPORT, &(1 as c_int))?;
+    }
+    // Accepted sockets inherit the listener's buffer sizes, and the receive
+    // window is negotiated during the handshake, so these are set here.
+    if let Some(size) = options.send_buffer_size {
+        sys::set(fd, sys::SOL_SOCKET, sys::SO_SNDBUF, &(size as c_int))?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        sys::set(fd, sys::SOL_SOCKET, sys::SO_RCVBUF, &(size as c_int))?;
+    }
+
+    let result = match address {
+        SocketAddr::V4(v4) => {
+            let raw = sys::SockAddrIn {
+                family: sys::AF_INET as u16,
+                port: v4.port().to_be(),
+                addr: v4.ip().octets(),
+                zero: [0; 8],
+            };
+            unsafe { sys::bind(fd, &raw as *const _ as *const _, size_of::<sys::SockAddrIn>() as u32) }
+        }
+        SocketAddr::V6(v6) => {
+            let raw = sys::SockAddrIn6 {
+                family: sys::AF_INET6 as u16,
+                port: v6.port().to_be(),
+                flow_info: v6.flowinfo(),
+                addr: v6.ip().octets(),
+                scope_id: v6.scope_id(),
+            };
+            unsafe { sys::bind(fd, &raw as *const _ as *const _, size_of::<sys::SockAddrIn6>() as u32) }
+        }
+    };
+    if result < 0 || unsafe { sys::listen(fd, options.backlog) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(TcpListener::from(owned))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_socket(address: &str, _options: &SocketOptions, _reuse_port: bool) -> io::Result<TcpListener> {
+    TcpListener::bind(resolve(address)?)
+}
+
+pub fn bind(address: &str, options: &SocketOptions) -> io::Result<TcpListener> {
+    bind_socket(address, options, false)
+}
+
+// Binds `count` listeners for the same address. On Linux each one gets its
+// own SO_REUSEPORT socket so the kernel spreads connections between them;
+// elsewhere the acceptors share one socket and take turns on it.
+pub fn bind_acceptors(address: &str, options: &SocketOptions, count: usize) -> io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::with_capacity(count);
+    if cfg!(target_os = "linux") {
+        for _ in 0..count {
+            listeners.push(bind_socket(address, options, true)?);
+        }
+    } else {
+        let listener = bind_socket(address, options, false)?;
+        for _ in 1..count {
+            listeners.push(listener.try_clone()?);
+        }
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+// Applies the per-connection options to a freshly accepted stream.
+pub fn configure_stream(stream: &TcpStream, options: &SocketOptions) -> io::Result<()> {
+    stream.set_nodelay(options.nodelay)?;
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+        use std::os::raw::c_int;
+
+        let fd = stream.as_raw_fd();
+        if let Some(keepalive) = options.keepalive {
+            sys::set(fd, sys::SOL_SOCKET, sys::SO_KEEPALIVE, &(1 as c_int))?;
+            sys::set(fd, sys::IPPROTO_TCP, sys::TCP_KEEPIDLE, &(keepalive.idle.as_secs().max(1) as c_int))?;
+            sys::set(fd, sys::IPPROTO_TCP, sys::TCP_KEEPINTVL, &(keepalive.interval.as_secs().max(1) as c_int))?;
+            sys::set(fd, sys::IPPROTO_TCP, sys::TCP_KEEPCNT, &(keepalive.retries as c_int))?;
+        }
+        if let Some(linger) = options.linger {
+            let linger = sys::Linger { on: 1, seconds: linger.as_secs() as c_int };
+            sys::set(fd, sys::SOL_SOCKET, sys::SO_LINGER, &linger)?;
+        }
+    }
+
+    Ok(())
+}
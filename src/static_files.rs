@@ -0,0 +1,643 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+use crate::{
+    http::{FileBody, HttpMethod, Request, Response, StatusCode},
+    markdown,
+};
+
+// What Markdown pages are wrapped in unless `markdown_template` says
+// otherwise.
+const MARKDOWN_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<style>
+body { display: flex; gap: 2rem; max-width: 60rem; margin: 2rem auto; padding: 0 1rem; font: 16px/1.6 system-ui, sans-serif; }
+nav { min-width: 10rem; } nav ul { list-style: none; padding: 0; } nav a[aria-current] { font-weight: bold; }
+main { flex: 1; min-width: 0; } pre { overflow-x: auto; background: #f4f4f4; padding: 0.75rem; }
+</style>
+</head>
+<body>
+<nav>{nav}</nav>
+<main>
+{content}
+</main>
+</body>
+</html>
+"#;
+
+// Serves the files under a directory, mounted at some URL prefix.
+//
+// By default it won't follow symlinks that lead outside the root, and hides
+// dotfiles (`.git`, `.env`) apart from `.well-known`. `allow` and `deny` narrow
+// down further what's served with globs on the path under the root.
+pub struct StaticDir {
+    root: PathBuf,
+    spa: bool,
+    stream_over: u64,
+    symlinks_outside: bool,
+    hidden: bool,
+    allow: Vec<String>,
+    deny: Vec<String>,
+    markdown: Option<String>,
+    includes: Option<Mutex<HashMap<PathBuf, Expanded>>>,
+}
+
+// An HTML page with its includes filled in, and the modification times of
+// every file that went into it, to tell when it's out of date.
+struct Expanded {
+    html: String,
+    sources: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+// How deep includes may nest, which also stops one including itself.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+impl StaticDir {
+    pub fn new(root: &str) -> StaticDir {
+        StaticDir {
+            root: PathBuf::from(root),
+            spa: false,
+            stream_over: 1024 * 1024,
+            symlinks_outside: false,
+            hidden: false,
+            allow: vec![],
+            deny: vec![],
+            markdown: None,
+            includes: None,
+        }
+    }
+
+    // Single-page app mode: paths that don't match a file get the root
+    // index.html, so client-side routes work on reload.
+    pub fn spa(mut self, spa: bool) -> StaticDir {
+        self.spa = spa;
+        self
+    }
+
+    // Files (or ranges of them) bigger than `bytes` are copied from disk as
+    // they're sent instead of being read into memory; 1 MiB by default.
+    pub fn stream_files_over(mut self, bytes: u64) -> StaticDir {
+        self.stream_over = bytes;
+        self
+    }
+
+    // Follows symlinks wherever they point, not just within the root.
+    pub fn follow_symlinks_outside(mut self, follow: bool) -> StaticDir {
+        self.symlinks_outside = follow;
+        self
+    }
+
+    // Serves dotfiles and dot-directories too.
+    pub fn serve_hidden(mut self, serve: bool) -> StaticDir {
+        self.hidden = serve;
+        self
+    }
+
+    // Once any allow globs are given, only paths matching one are served.
+    // `*` and `?` stay within a path segment, `**` spans any number of them:
+    // `*.html`, `assets/**`, `**/*.png`.
+    pub fn allow(mut self, glob: &str) -> StaticDir {
+        self.allow.push(glob.trim_start_matches('/').to_string());
+        self
+    }
+
+    // Paths matching a deny glob are never served, even if also allowed.
+    pub fn deny(mut self, glob: &str) -> StaticDir {
+        self.deny.push(glob.trim_start_matches('/').to_string());
+        self
+    }
+
+    // Renders `.md` files to HTML pages, with a title and links to the other
+    // pages alongside. They're also found without the extension, and a
+    // directory with no index.html serves its index.md, so a folder of notes
+    // becomes a small site: /guide/setup serves guide/setup.md.
+    pub fn markdown(mut self, enabled: bool) -> StaticDir {
+        self.markdown = enabled.then(|| MARKDOWN_TEMPLATE.to_string());
+        self
+    }
+
+    // Turns Markdown on with a page template of your own, in which `{title}`,
+    // `{nav}` and `{content}` are replaced by each page's.
+    pub fn markdown_template(mut self, template: &str) -> StaticDir {
+        self.markdown = Some(template.to_string());
+        self
+    }
+
+    // Processes `<!--#include file="header.html" -->` directives in HTML
+    // files, so pages can share a header and footer without a build step.
+    // `file` is relative to the including page and `virtual` to the root;
+    // neither can climb out of it. Expanded pages are kept in memory until one
+    // of the files that went into them changes.
+    pub fn includes(mut self, enabled: bool) -> StaticDir {
+        self.includes = enabled.then(|| Mutex::new(HashMap::new()));
+        self
+    }
+
+    pub(crate) fn respond(&self, prefix: &str, request: &Request) -> Response {
+        let path = request.path.split('?').next().unwrap_or_default();
+        let relative = percent_decode(path.strip_prefix(prefix).unwrap_or(path));
+        let Some(file) = self.resolve(&relative) else {
+            return Response::new(StatusCode::Forbidden, "Forbidden");
+        };
+
+        let file = if file.is_dir() { file.join("index.html") } else { file };
+        let file = match &self.markdown {
+            Some(_) => StaticDir::markdown_source(file),
+            None => file,
+        };
+        // Hidden and filtered paths look like they aren't there at all.
+        let relative = file.strip_prefix(&self.root).unwrap_or(&file).to_string_lossy().replace('\\', "/");
+        let served = if !self.permitted(&relative) {
+            None
+        } else if !self.within_root(&file) {
+            return Response::new(StatusCode::Forbidden, "Forbidden");
+        } else if self.markdown.is_some() && file.extension().is_some_and(|extension| extension == "md") {
+            self.markdown_page(prefix, &file)
+        } else if self.includes.is_some() && is_html(&file) {
+            self.included_page(&file)
+        } else {
+            self.serve_file(&file, request)
+        };
+        match served {
+            Some(response) => response,
+            None if self.spa => {
+                let index = self.root.join("index.html");
+                match fs::read(&index) {
+                    Ok(contents) => file_response(&index, contents),
+                    Err(_) => Response::new(StatusCode::NotFound, "Not Found"),
+                }
+            }
+            None => Response::new(StatusCode::NotFound, "Not Found"),
+        }
+    }
+
+    // The Markdown file to serve for `file` when it doesn't exist itself.
+    fn markdown_source(file: PathBuf) -> PathBuf {
+        if file.exists() {
+            return file;
+        }
+        if file.file_name().is_some_and(|name| name == "index.html") {
+            return file.with_file_name("index.md");
+        }
+        let mut with_extension = file.clone().into_os_string();
+        with_extension.push(".md");
+        let with_extension = PathBuf::from(with_extension);
+        if with_extension.is_file() { with_extension } else { file }
+    }
+
+    fn markdown_page(&self, prefix: &str, file: &Path) -> Option<Response> {
+        let template = self.markdown.as_deref()?;
+        let source = fs::read_to_string(file).ok()?;
+        let stem = file.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        let title = markdown::title(&source).unwrap_or_else(|| stem.replace(['-', '_'], " "));
+        let page = template
+            .replace("{title}", &markdown::escape(&title))
+            .replace("{nav}", &self.markdown_nav(prefix, file))
+            .replace("{content}", &markdown::to_html(&source));
+        Some(Response::new(StatusCode::Ok, &page).with_header("Content-Type", "text/html; charset=utf-8"))
+    }
+
+    // Links to the Markdown pages in the same directory as `file`, by their
+    // extensionless URLs.
+    fn markdown_nav(&self, prefix: &str, file: &Path) -> String {
+        let Some(dir) = file.parent() else {
+            return String::new();
+        };
+        let url_dir = dir.strip_prefix(&self.root).unwrap_or(dir).to_string_lossy().replace('\\', "/");
+        let mut pages: Vec<String> = fs::read_dir(dir).into_iter().flatten().flatten()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter_map(|name| name.strip_suffix(".md").map(str::to_string))
+            .filter(|stem| {
+                let relative = if url_dir.is_empty() { format!("{stem}.md") } else { format!("{url_dir}/{stem}.md") };
+                self.permitted(&relative)
+            })
+            .collect();
+        pages.sort();
+
+        let base: String = [prefix, &url_dir].iter()
+            .map(|part| part.trim_matches('/'))
+            .filter(|part| !part.is_empty())
+            .map(|part| format!("/{part}"))
+            .collect();
+        let mut nav = String::from("<ul>\n");
+        for stem in pages {
+            let (href, label) = match stem.as_str() {
+                "index" => (format!("{base}/"), "Home".to_string()),
+                _ => (format!("{base}/{stem}"), stem.replace(['-', '_'], " ")),
+            };
+            let current = if file.file_stem().is_some_and(|current| current.to_string_lossy() == stem) {
+                " aria-current=\"page\""
+            } else {
+                ""
+            };
+            nav.push_str(&format!("<li><a href=\"{}\"{current}>{}</a></li>\n", markdown::escape(&href), markdown::escape(&label)));
+        }
+        nav.push_str("</ul>");
+        nav
+    }
+
+    fn included_page(&self, file: &Path) -> Option<Response> {
+        let cache = self.includes.as_ref()?;
+        let fresh = |expanded: &Expanded| {
+            expanded.sources.iter().all(|(path, modified)| modified_time(path) == *modified)
+        };
+        if let Some(expanded) = cache.lock().unwrap().get(file).filter(|expanded| fresh(expanded)) {
+            return Some(html_page(&expanded.html));
+        }
+
+        let mut sources = vec![];
+        let html = self.expand(file, 0, &mut sources)?;
+        let response = html_page(&html);
+        cache.lock().unwrap().insert(file.to_path_buf(), Expanded { html, sources });
+        Some(response)
+    }
+
+    fn expand(&self, file: &Path, depth: usize, sources: &mut Vec<(PathBuf, Option<SystemTime>)>) -> Option<String> {
+        let text = fs::read_to_string(file).ok()?;
+        sources.push((file.to_path_buf(), modified_time(file)));
+
+        let mut html = String::with_capacity(text.len());
+        let mut rest = text.as_str();
+        while let Some(start) = rest.find("<!--#include") {
+            html.push_str(&rest[..start]);
+            let Some(length) = rest[start..].find("-->") else {
+                rest = &rest[start..];
+                break;
+            };
+            let directive = &rest[start + "<!--#include".len()..start + length];
+            rest = &rest[start + length + 3..];
+
+            let included = self.include_target(file, directive)
+                .filter(|_| depth < MAX_INCLUDE_DEPTH)
+                .and_then(|target| self.expand(&target, depth + 1, sources));
+            match included {
+                Some(included) => html.push_str(&included),
+                None => {
+                    eprintln!("Error processing include in {}:{directive}", file.display());
+                    html.push_str("<!-- include failed -->");
+                }
+            }
+        }
+        html.push_str(rest);
+        Some(html)
+    }
+
+    // The file an include directive names, if it's one we may serve.
+    fn include_target(&self, page: &Path, directive: &str) -> Option<PathBuf> {
+        let attribute = |name: &str| {
+            let start = directive.find(&format!("{name}=\""))? + name.len() + 2;
+            let length = directive[start..].find('"')?;
+            Some(directive[start..start + length].to_string())
+        };
+        let relative = match (attribute("file"), attribute("virtual")) {
+            (Some(file), _) => {
+                let dir = page.parent()?.strip_prefix(&self.root).ok()?.to_string_lossy().replace('\\', "/");
+                format!("{dir}/{file}")
+            }
+            (None, Some(path)) => path,
+            (None, None) => return None,
+        };
+        let target = self.resolve(&relative)?;
+        let relative = target.strip_prefix(&self.root).ok()?.to_string_lossy().replace('\\', "/");
+        (self.permitted(&relative) && self.within_root(&target)).then_some(target)
+    }
+
+    // Answers with the file, or the part of it a Range header asks for.
+    // Anything past `stream_over` is sent straight from disk rather than
+    // read into memory first. None if it can't be read.
+    fn serve_file(&self, path: &Path, request: &Request) -> Option<Response> {
+        let metadata = fs::metadata(path).ok().filter(|metadata| metadata.is_file())?;
+        let size = metadata.len();
+        // A Range guarded by If-Range would need validators we don't send,
+        // so those get the whole file, as RFC 9110 allows.
+        let range = match request.header("Range") {
+            Some(range) if request.method == HttpMethod::GET && request.header("If-Range").is_none() => byte_range(range, size),
+            _ => ByteRange::Whole,
+        };
+
+        let (status, offset, length) = match range {
+            ByteRange::Whole => (StatusCode::Ok, 0, size),
+            ByteRange::Part(start, end) => (StatusCode::PartialContent, start, end - start + 1),
+            ByteRange::Unsatisfiable => {
+                return Some(Response::new(StatusCode::RangeNotSatisfiable, "Range Not Satisfiable")
+                    .with_header("Content-Range", &format!("bytes */{size}")));
+            }
+        };
+        let mut response = if length > self.stream_over {
+            let mut response = Response::from_bytes(status, vec![]);
+            response.file = Some(FileBody { path: path.to_path_buf(), offset, length });
+            response
+        } else {
+            let mut file = File::open(path).ok()?;
+            file.seek(SeekFrom::Start(offset)).ok()?;
+            let mut contents = Vec::with_capacity(length as usize);
+            file.take(length).read_to_end(&mut contents).ok()?;
+            Response::from_bytes(status, contents)
+        };
+        if status == StatusCode::PartialContent {
+            response.set_header("Content-Range", &format!("bytes {offset}-{}/{size}", offset + length - 1));
+        }
+        response.set_header("Content-Type", content_type(path));
+        response.set_header("Accept-Ranges", "bytes");
+        Some(response)
+    }
+
+    // Maps a URL path onto the root, refusing anything that would climb out.
+    fn resolve(&self, relative: &str) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+        for component in Path::new(relative.trim_start_matches('/')).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        Some(path)
+    }
+
+    fn permitted(&self, relative: &str) -> bool {
+        let hidden = relative.split('/').any(|part| part.starts_with('.') && part != ".well-known");
+        (self.hidden || !hidden)
+            && (self.allow.is_empty() || self.allow.iter().any(|glob| glob_matches(glob, relative)))
+            && !self.deny.iter().any(|glob| glob_matches(glob, relative))
+    }
+
+    // Whether `file`, with every symlink resolved, is still under the root.
+    // Files that don't exist pass; reading them fails anyway.
+    fn within_root(&self, file: &Path) -> bool {
+        if self.symlinks_outside {
+            return true;
+        }
+        match (fs::canonicalize(file), fs::canonicalize(&self.root)) {
+            (Ok(file), Ok(root)) => file.starts_with(root),
+            _ => true,
+        }
+    }
+}
+
+// Matches `path` against a glob where `*` and `?` don't cross a `/` and a
+// `**` segment matches any number of segments.
+fn glob_matches(glob: &str, path: &str) -> bool {
+    let globs: Vec<&str> = glob.split('/').collect();
+    let parts: Vec<&str> = path.split('/').collect();
+    segments_match(&globs, &parts)
+}
+
+fn segments_match(globs: &[&str], parts: &[&str]) -> bool {
+    match globs.split_first() {
+        None => parts.is_empty(),
+        Some((&"**", rest)) => (0..=parts.len()).any(|skip| segments_match(rest, &parts[skip..])),
+        Some((glob, rest)) => match parts.split_first() {
+            Some((part, parts)) => segment_matches(glob.as_bytes(), part.as_bytes()) && segments_match(rest, parts),
+            None => false,
+        },
+    }
+}
+
+fn segment_matches(glob: &[u8], text: &[u8]) -> bool {
+    match glob.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| segment_matches(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && segment_matches(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && segment_matches(rest, &text[1..]),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Whole,
+    // First and last byte, inclusive.
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+// Reads a `bytes=` Range header against a file of `size` bytes. Only single
+// ranges are honoured; anything else gets the whole file.
+fn byte_range(header: &str, size: u64) -> ByteRange {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Whole;
+    };
+    let Some((start, end)) = spec.trim().split_once('-').filter(|_| !spec.contains(',')) else {
+        return ByteRange::Whole;
+    };
+    // Digits only; `parse` would take "+5" too.
+    let number = |text: &str| text.bytes().all(|byte| byte.is_ascii_digit()).then(|| text.parse::<u64>().ok()).flatten();
+    let (start, end) = (start.trim(), end.trim());
+    let range = match (number(start), number(end)) {
+        // The last `end` bytes.
+        (None, Some(suffix)) if start.is_empty() => match suffix {
+            0 => return ByteRange::Unsatisfiable,
+            suffix => (size.saturating_sub(suffix), size.saturating_sub(1)),
+        },
+        (Some(start), None) if end.is_empty() => (start, size.saturating_sub(1)),
+        (Some(start), Some(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        _ => return ByteRange::Whole,
+    };
+    if range.0 >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Part(range.0, range.1)
+}
+
+fn is_html(path: &Path) -> bool {
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+    ["html", "htm", "shtml"].contains(&extension.to_ascii_lowercase().as_str())
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn html_page(html: &str) -> Response {
+    Response::new(StatusCode::Ok, html).with_header("Content-Type", "text/html; charset=utf-8")
+}
+
+fn file_response(path: &Path, contents: Vec<u8>) -> Response {
+    Response::from_bytes(StatusCode::Ok, contents).with_header("Content-Type", content_type(path))
+}
+
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" | "shtml" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "mp4" => "video/mp4",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        _ => "application/octet-stream",
+    }
+}
+
+// Escapes everything but the unreserved characters (RFC 3986), so the
+// result is safe as a path segment or a query value.
+pub fn percent_encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex = bytes.get(index + 1..index + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[index], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random;
+
+    // A root holding a few files, with a secret file beside it.
+    fn site() -> PathBuf {
+        let base = std::env::temp_dir().join(format!("webserver-static-{}", random::hex(8)));
+        for dir in ["root/.well-known", "root/.git", "root/assets/img/icons"] {
+            fs::create_dir_all(base.join(dir)).unwrap();
+        }
+        for file in ["secret", "root/index.html", "root/.env", "root/.git/config", "root/.well-known/security.txt",
+                     "root/assets/app.js", "root/assets/img/icons/a.png"] {
+            fs::write(base.join(file), file).unwrap();
+        }
+        base
+    }
+
+    fn status(dir: &StaticDir, path: &str) -> StatusCode {
+        dir.respond("/", &Request::new(HttpMethod::GET, path)).status_code
+    }
+
+    #[test]
+    fn stays_inside_the_root() {
+        let base = site();
+        let dir = StaticDir::new(base.join("root").to_str().unwrap());
+        assert_eq!(status(&dir, "/index.html"), StatusCode::Ok);
+        assert_eq!(status(&dir, "/assets/../index.html"), StatusCode::Forbidden);
+        for path in ["/../secret", "/assets/../../secret", "/%2e%2e/secret", "/%2E%2E%2fsecret", "/..%2f..%2fsecret",
+                     "/assets/%2e%2e/%2e%2e/secret"] {
+            assert_eq!(status(&dir, path), StatusCode::Forbidden, "{path}");
+        }
+        // Decoded once only: this names a file called "%2e%2e".
+        assert_eq!(status(&dir, "/%252e%252e/secret"), StatusCode::NotFound);
+        assert!(dir.resolve("/etc/passwd").unwrap().starts_with(base.join("root")));
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn hides_dotfiles_but_well_known() {
+        let base = site();
+        let root = base.join("root");
+        let dir = StaticDir::new(root.to_str().unwrap());
+        assert_eq!(status(&dir, "/.env"), StatusCode::NotFound);
+        assert_eq!(status(&dir, "/%2eenv"), StatusCode::NotFound);
+        assert_eq!(status(&dir, "/.git/config"), StatusCode::NotFound);
+        assert_eq!(status(&dir, "/.well-known/security.txt"), StatusCode::Ok);
+
+        let dir = StaticDir::new(root.to_str().unwrap()).serve_hidden(true);
+        assert_eq!(status(&dir, "/.env"), StatusCode::Ok);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn allows_and_denies_by_glob() {
+        let base = site();
+        let dir = StaticDir::new(base.join("root").to_str().unwrap()).allow("/assets/**").deny("**/*.png");
+        assert_eq!(status(&dir, "/assets/app.js"), StatusCode::Ok);
+        assert_eq!(status(&dir, "/assets/img/icons/a.png"), StatusCode::NotFound);
+        assert_eq!(status(&dir, "/index.html"), StatusCode::NotFound);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_matches("*.html", "index.html"));
+        assert!(!glob_matches("*.html", "docs/index.html"));
+        assert!(glob_matches("**/*.html", "index.html"));
+        assert!(glob_matches("**/*.html", "docs/a/b/index.html"));
+        assert!(glob_matches("assets/**", "assets/a/b.js"));
+        assert!(glob_matches("assets/**", "assets"));
+        assert!(!glob_matches("assets/**", "assetsx/a.js"));
+        assert!(glob_matches("a/**/z", "a/z"));
+        assert!(glob_matches("a/**/z", "a/b/c/z"));
+        assert!(!glob_matches("a/**/z", "a/b/c/zz"));
+        assert!(glob_matches("img?.png", "img1.png"));
+        assert!(!glob_matches("img?.png", "img.png"));
+        assert!(!glob_matches("a?b", "a/b"));
+    }
+
+    #[test]
+    fn reads_byte_ranges() {
+        use ByteRange::*;
+        let cases = [
+            ("bytes=0-9", Part(0, 9)),
+            ("bytes=10-10", Part(10, 10)),
+            ("bytes=50-500", Part(50, 99)),
+            // Open-ended, and suffixes.
+            ("bytes=90-", Part(90, 99)),
+            ("bytes=-10", Part(90, 99)),
+            ("bytes=-500", Part(0, 99)),
+            ("bytes=-0", Unsatisfiable),
+            // Starting past the end.
+            ("bytes=100-", Unsatisfiable),
+            ("bytes=100-200", Unsatisfiable),
+            // Malformed, or more than we handle.
+            ("bytes=9-1", Whole),
+            ("bytes=a-b", Whole),
+            ("bytes=-", Whole),
+            ("bytes=+5-9", Whole),
+            ("bytes=5--9", Whole),
+            ("bytes=0-1,5-6", Whole),
+            ("items=0-9", Whole),
+            ("bytes=99999999999999999999999-", Whole),
+        ];
+        for (header, range) in cases {
+            assert_eq!(byte_range(header, 100), range, "{header}");
+        }
+        assert_eq!(byte_range("bytes=0-", 0), Unsatisfiable);
+        assert_eq!(byte_range("bytes=-5", 0), Unsatisfiable);
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use crate::{
+    http::{Response, StatusCode},
+    random,
+    server::Connection,
+};
+
+// Writes a response a piece at a time, for handlers registered with
+// `Server::add_stream`. The body goes out chunked as it's written. Once the
+// client goes away every write fails and the writer counts as cancelled, so
+// the handler can stop producing data nobody will read.
+//
+// Values only known once the body has been written, like a checksum of it,
+// can follow it as trailers: declare them before starting, and set them as
+// they become known.
+//
+//     writer.declare_trailers(&["X-Checksum"])?;
+//     for block in blocks {
+//         hasher.update(&block);
+//         writer.write(&block)?;
+//     }
+//     writer.trailer("X-Checksum", &hasher.hex())?;
+pub struct ResponseWriter {
+    connection: Arc<Connection>,
+    status: Option<StatusCode>,
+    cancelled: Arc<AtomicBool>,
+    declared: Vec<String>,
+    trailers: Vec<(String, String)>,
+}
+
+impl ResponseWriter {
+    pub(crate) fn new(connection: Arc<Connection>) -> ResponseWriter {
+        ResponseWriter {
+            connection,
+            status: None,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            declared: vec![],
+            trailers: vec![],
+        }
+    }
+
+    // Announces the trailers in a Trailer header, so has to come before
+    // `start` (or the first `write`).
+    pub fn declare_trailers(&mut self, names: &[&str]) -> io::Result<()> {
+        if self.status.is_some() {
+            return Err(io::Error::other("Response already started"));
+        }
+        self.declared.extend(names.iter().map(|name| name.to_string()));
+        Ok(())
+    }
+
+    // Sets a declared trailer, sent after the last chunk. Setting one again
+    // replaces its value.
+    pub fn trailer(&mut self, name: &str, value: &str) -> io::Result<()> {
+        if !self.declared.iter().any(|declared| declared.eq_ignore_ascii_case(name)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Trailer {name} wasn't declared")));
+        }
+        self.trailers.retain(|(trailer, _)| !trailer.eq_ignore_ascii_case(name));
+        self.trailers.push((name.to_string(), value.replace(['\r', '\n'], "")));
+        Ok(())
+    }
+
+    // Sends the status line and headers. Called with 200 and no headers by
+    // the first `write` if the handler doesn't call it itself.
+    pub fn start(&mut self, status: StatusCode, headers: &[(&str, &str)]) -> io::Result<()> {
+        if self.status.is_some() {
+            return Err(io::Error::other("Response already started"));
+        }
+        self.status = Some(status);
+
+        let mut head = format!("HTTP/1.1 {status}\r\n");
+        for (name, value) in headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !self.declared.is_empty() {
+            head.push_str(&format!("Trailer: {}\r\n", self.declared.join(", ")));
+        }
+        // Streamed responses don't leave the connection in a known state for
+        // another request (the handler might stop halfway), so close it.
+        head.push_str("Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n");
+        self.send(head.as_bytes())
+    }
+
+    // Sends `data` as one chunk, straight away.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.status.is_none() {
+            self.start(StatusCode::Ok, &[])?;
+        }
+        if data.is_empty() {
+            // An empty chunk would end the body.
+            return Ok(());
+        }
+        let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(b"\r\n");
+        self.send(&chunk)
+    }
+
+    // Whether the client has disconnected (or a timeout answered for the
+    // handler), so nothing written will arrive.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    // The same flag, for threads producing data for this handler.
+    pub fn cancelled_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.cancelled)
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.is_cancelled() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let result = self.connection.with_stream(|stream| stream.write_all(bytes).and_then(|_| stream.flush()));
+        if result.is_err() {
+            self.cancelled.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+
+    // Ends the body and turns what the handler did into the Response the
+    // middleware chain sees. That response is only a record: the real one
+    // has already gone out.
+    pub(crate) fn finish(mut self, result: io::Result<()>) -> Response {
+        match (self.status, result) {
+            (Some(status), result) => {
+                if let Err(error) = &result {
+                    if !self.is_cancelled() {
+                        eprintln!("Streaming handler failed: {error}");
+                    }
+                }
+                // Ending the body after an error would make a partial
+                // response look complete, so leave it cut off instead.
+                let mut last = String::from("0\r\n");
+                for (name, value) in &self.trailers {
+                    last.push_str(&format!("{name}: {value}\r\n"));
+                }
+                last.push_str("\r\n");
+                if result.is_ok() && self.send(last.as_bytes()).is_err() {
+                    self.cancelled.store(true, Ordering::Relaxed);
+                }
+                let mut response = Response::new(status, "");
+                response.streamed = true;
+                response
+            }
+            (None, Ok(())) => Response::new(StatusCode::Ok, ""),
+            (None, Err(error)) => {
+                eprintln!("Streaming handler failed: {error}");
+                Response::new(StatusCode::InternalServerError, "Internal Server Error")
+            }
+        }
+    }
+}
+
+// Writes a multipart response over a ResponseWriter, one part at a time:
+// `multipart/x-mixed-replace` for MJPEG camera feeds, where each part
+// replaces the last on screen, or `multipart/mixed` for results sent as
+// they're ready.
+//
+//     server.add_stream("/camera", move |_, writer| {
+//         let mut parts = MultipartWriter::start(writer, "x-mixed-replace")?;
+//         while !parts.is_cancelled() {
+//             parts.part(&[("Content-Type", "image/jpeg")], &camera.frame())?;
+//         }
+//         Ok(())
+//     });
+//
+// Each part goes out as soon as it's written, with a Content-Length unless
+// its headers have one. Once the client goes away `part` fails, which ends
+// the loop above.
+pub struct MultipartWriter<'a> {
+    writer: &'a mut ResponseWriter,
+    boundary: String,
+}
+
+impl<'a> MultipartWriter<'a> {
+    // Starts a 200 response of `multipart/<subtype>` with a random boundary.
+    pub fn start(writer: &'a mut ResponseWriter, subtype: &str) -> io::Result<MultipartWriter<'a>> {
+        let boundary = format!("web_server-{}", random::hex(16));
+        let content_type = format!("multipart/{subtype}; boundary={boundary}");
+        writer.start(StatusCode::Ok, &[("Content-Type", &content_type), ("Cache-Control", "no-cache")])?;
+        Ok(MultipartWriter { writer, boundary })
+    }
+
+    pub fn part(&mut self, headers: &[(&str, &str)], body: &[u8]) -> io::Result<()> {
+        let mut part = format!("--{}\r\n", self.boundary);
+        for (name, value) in headers {
+            part.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Content-Length")) {
+            part.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        part.push_str("\r\n");
+        let mut part = part.into_bytes();
+        part.extend_from_slice(body);
+        part.extend_from_slice(b"\r\n");
+        self.writer.write(&part)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.writer.is_cancelled()
+    }
+
+    // Writes the closing boundary. Leaving it off is fine for a feed that
+    // only stops when the client does.
+    pub fn end(self) -> io::Result<()> {
+        let closing = format!("--{}--\r\n", self.boundary);
+        self.writer.write(closing.as_bytes())
+    }
+}
@@ -0,0 +1,68 @@
+// The two halves of the systemd protocol a server needs: taking over sockets
+// systemd bound for us (socket activation), and telling it how we're doing.
+// Both are plain environment variables and sockets, so there's no libsystemd
+// to link against.
+use std::{
+    env,
+    io,
+    net::TcpListener,
+    os::{
+        fd::{FromRawFd, RawFd},
+        raw::c_int,
+        unix::net::UnixDatagram,
+    },
+};
+
+// systemd hands sockets over starting at fd 3, after stdin/out/err.
+const LISTEN_FDS_START: RawFd = 3;
+const F_SETFD: c_int = 2;
+const FD_CLOEXEC: c_int = 1;
+
+extern "C" {
+    fn fcntl(fd: c_int, command: c_int, ...) -> c_int;
+}
+
+// The listening sockets systemd passed in, like `sd_listen_fds`. Returns
+// None when the process wasn't socket-activated.
+pub fn listen_fds() -> Option<Vec<TcpListener>> {
+    let pid: u32 = env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let count: RawFd = env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    // Don't let children think the sockets are meant for them.
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if pid != std::process::id() || count <= 0 {
+        return None;
+    }
+
+    let listeners = (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            unsafe { fcntl(fd, F_SETFD, FD_CLOEXEC) };
+            unsafe { TcpListener::from_raw_fd(fd) }
+        })
+        .collect();
+    Some(listeners)
+}
+
+// Sends a status update like "READY=1" to systemd, like `sd_notify`. Does
+// nothing when not running under systemd with Type=notify.
+pub fn notify(state: &str) {
+    let Ok(path) = env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(error) = send(&path, state) {
+        eprintln!("Error notifying systemd at {path}: {error}");
+    }
+}
+
+fn send(path: &str, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    // A leading @ means a socket in the abstract namespace.
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.strip_prefix('@') {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+        let address = SocketAddr::from_abstract_name(name)?;
+        return socket.send_to_addr(state.as_bytes(), &address).map(|_| ());
+    }
+    socket.send_to(state.as_bytes(), path).map(|_| ())
+}
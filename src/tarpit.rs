@@ -0,0 +1,140 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+use crate::{
+    http::{Request, Response, StatusCode},
+    metrics::Metrics,
+    middleware::{Middleware, Next},
+    static_files::percent_decode,
+};
+
+// Catches the paths vulnerability scanners try on every server they find
+// (`/wp-login.php`, `/.env`, `/.git/config`...) before they reach routing,
+// so they don't fill the log with 404s, and makes them slow to answer or
+// not answered at all:
+//
+//     let metrics = Metrics::new();
+//     server.add_middleware(Tarpit::new().path("admin.php").metrics(&metrics));
+//
+// A path is caught when any of its segments is one of the names, ignoring
+// case. Caught requests are held for the delay and then get a 404, or are
+// hung up on with `TarpitAction::Drop`. Holding a request holds a worker, so
+// only `max_held` are held at once and the rest are hung up on. With
+// `metrics`, each catch counts towards `web_server_tarpit_requests_total`,
+// labelled with the name and what was done.
+pub struct Tarpit {
+    rules: Vec<(String, Option<TarpitAction>)>,
+    action: TarpitAction,
+    max_held: usize,
+    held: AtomicUsize,
+    metrics: Option<Metrics>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TarpitAction {
+    // Wait this long, then answer 404.
+    Delay(Duration),
+    // Close the connection without a response.
+    Drop,
+}
+
+const SCANNER_PATHS: [&str; 12] = [
+    "wp-login.php", "wp-admin", "xmlrpc.php", "wp-config.php", ".env", ".git",
+    ".aws", ".ssh", "phpmyadmin", "boaform", "shell.php", "eval-stdin.php",
+];
+
+impl Tarpit {
+    // Catches the usual scanner paths, holding each request for 10 seconds.
+    pub fn new() -> Tarpit {
+        Tarpit {
+            rules: SCANNER_PATHS.iter().map(|name| (name.to_string(), None)).collect(),
+            action: TarpitAction::Delay(Duration::from_secs(10)),
+            max_held: 16,
+            held: AtomicUsize::new(0),
+            metrics: None,
+        }
+    }
+
+    // Catches this name too.
+    pub fn path(mut self, name: &str) -> Tarpit {
+        self.rules.push((name.to_ascii_lowercase(), None));
+        self
+    }
+
+    // Catches this name with its own action rather than the default one.
+    pub fn rule(mut self, name: &str, action: TarpitAction) -> Tarpit {
+        let name = name.to_ascii_lowercase();
+        self.rules.retain(|(rule, _)| *rule != name);
+        self.rules.push((name, Some(action)));
+        self
+    }
+
+    // What's done with caught requests that have no rule of their own.
+    pub fn action(mut self, action: TarpitAction) -> Tarpit {
+        self.action = action;
+        self
+    }
+
+    pub fn max_held(mut self, max_held: usize) -> Tarpit {
+        self.max_held = max_held;
+        self
+    }
+
+    pub fn metrics(mut self, metrics: &Metrics) -> Tarpit {
+        self.metrics = Some(metrics.clone());
+        self
+    }
+
+    fn caught(&self, path: &str) -> Option<(&str, TarpitAction)> {
+        let path = path.split('?').next().unwrap_or_default();
+        path.split('/').map(|segment| percent_decode(segment).to_ascii_lowercase()).find_map(|segment| {
+            self.rules.iter()
+                .find(|(name, _)| *name == segment)
+                .map(|(name, action)| (name.as_str(), action.unwrap_or(self.action)))
+        })
+    }
+
+    fn count(&self, name: &str, action: &str) {
+        if let Some(metrics) = &self.metrics {
+            metrics.increment("web_server_tarpit_requests_total", &[("path", name), ("action", action)]);
+        }
+    }
+
+    fn hang_up(&self, request: &Request, name: &str) -> Response {
+        self.count(name, "drop");
+        if let Some(connection) = &request.connection {
+            connection.hang_up();
+        }
+        // Nothing to send: the connection is gone.
+        let mut response = Response::new(StatusCode::NotFound, "");
+        response.streamed = true;
+        response
+    }
+}
+
+impl Default for Tarpit {
+    fn default() -> Tarpit {
+        Tarpit::new()
+    }
+}
+
+impl Middleware for Tarpit {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let Some((name, action)) = self.caught(&request.path) else {
+            return next(request);
+        };
+        let TarpitAction::Delay(delay) = action else {
+            return self.hang_up(request, name);
+        };
+        if self.held.fetch_add(1, Ordering::Relaxed) >= self.max_held {
+            self.held.fetch_sub(1, Ordering::Relaxed);
+            return self.hang_up(request, name);
+        }
+        self.count(name, "delay");
+        thread::sleep(delay);
+        self.held.fetch_sub(1, Ordering::Relaxed);
+        Response::new(StatusCode::NotFound, "Not Found").with_header("Connection", "close")
+    }
+}
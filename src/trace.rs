@@ -0,0 +1,94 @@
+use crate::{http::Request, random};
+
+// Where a request sits in a distributed trace, from the W3C Trace Context
+// headers (https://www.w3.org/TR/trace-context/):
+//
+//     traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01
+//
+// Handlers calling other services pass `child()`'s `headers()` along, so the
+// downstream work shows up in the same trace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    // The caller's span, which work done here is a child of.
+    pub parent_id: String,
+    pub flags: u8,
+    // Vendor-specific data, passed through untouched.
+    pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+    // The request's context, if it carries a valid traceparent.
+    pub fn from_request(request: &Request) -> Option<TraceContext> {
+        let mut context = TraceContext::parse(request.header("traceparent")?)?;
+        let states: Vec<&str> = request.headers.iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("tracestate"))
+            .map(|(_, value)| value.trim())
+            .filter(|value| !value.is_empty())
+            .collect();
+        context.tracestate = (!states.is_empty()).then(|| states.join(","));
+        Some(context)
+    }
+
+    // Starts a new trace, for requests that arrive without one.
+    pub fn root(sampled: bool) -> TraceContext {
+        TraceContext {
+            trace_id: random::hex(16),
+            parent_id: random::hex(8),
+            flags: u8::from(sampled),
+            tracestate: None,
+        }
+    }
+
+    // The context to send downstream: the same trace, with a new span for
+    // the work this server does.
+    pub fn child(&self) -> TraceContext {
+        TraceContext { parent_id: random::hex(8), ..self.clone() }
+    }
+
+    pub fn sampled(&self) -> bool {
+        self.flags & 1 == 1
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id, self.parent_id, self.flags)
+    }
+
+    // traceparent and, when there is one, tracestate, ready to add to an
+    // outgoing request.
+    pub fn headers(&self) -> Vec<(String, String)> {
+        let mut headers = vec![("traceparent".to_string(), self.traceparent())];
+        if let Some(state) = &self.tracestate {
+            headers.push(("tracestate".to_string(), state.clone()));
+        }
+        headers
+    }
+
+    fn parse(header: &str) -> Option<TraceContext> {
+        let header = header.trim();
+        let mut parts = header.splitn(5, '-');
+        let (version, trace_id, parent_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        let extra = parts.next();
+
+        let is_hex = |text: &str, length: usize| {
+            text.len() == length && text.bytes().all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+        };
+        if !is_hex(version, 2) || version == "ff" || !is_hex(trace_id, 32) || !is_hex(parent_id, 16) || !is_hex(flags, 2) {
+            return None;
+        }
+        // Version 00 has exactly four fields; later versions may add more,
+        // which we don't understand but can still read the first four of.
+        if version == "00" && extra.is_some() {
+            return None;
+        }
+        if trace_id.bytes().all(|byte| byte == b'0') || parent_id.bytes().all(|byte| byte == b'0') {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id: trace_id.to_string(),
+            parent_id: parent_id.to_string(),
+            flags: u8::from_str_radix(flags, 16).ok()?,
+            tracestate: None,
+        })
+    }
+}
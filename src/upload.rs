@@ -0,0 +1,483 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+use crate::{
+    http::{HttpMethod, Request, Response, StatusCode},
+    log::json_string,
+    random,
+    static_files::percent_decode,
+};
+
+const MAX_PART_HEAD: usize = 8 * 1024;
+
+// Limits for `Server::accept_uploads`.
+#[derive(Clone)]
+pub struct UploadOptions {
+    max_size: u64,
+    max_files: usize,
+    types: Vec<String>,
+}
+
+impl UploadOptions {
+    // Files of up to 10 MiB each, at most 10 to a request, of any type.
+    pub fn new() -> UploadOptions {
+        UploadOptions { max_size: 10 * 1024 * 1024, max_files: 10, types: vec![] }
+    }
+
+    pub fn max_size(mut self, bytes: u64) -> UploadOptions {
+        self.max_size = bytes;
+        self
+    }
+
+    pub fn max_files(mut self, files: usize) -> UploadOptions {
+        self.max_files = files.max(1);
+        self
+    }
+
+    // Only accepts files declared as one of these types, which may end in a
+    // wildcard: "image/*", "application/pdf".
+    pub fn allow_type(mut self, content_type: &str) -> UploadOptions {
+        self.types.push(content_type.to_ascii_lowercase());
+        self
+    }
+
+    pub(crate) fn total_limit(&self) -> u64 {
+        // Room for every file plus the multipart framing around them.
+        self.max_size.saturating_mul(self.max_files as u64).saturating_add(64 * 1024)
+    }
+
+    fn allows(&self, content_type: &str) -> bool {
+        let content_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        self.types.is_empty() || self.types.iter().any(|allowed| match allowed.strip_suffix('*') {
+            Some(prefix) => content_type.starts_with(prefix),
+            None => content_type == *allowed,
+        })
+    }
+}
+
+impl Default for UploadOptions {
+    fn default() -> UploadOptions {
+        UploadOptions::new()
+    }
+}
+
+// One file that was stored.
+struct Stored {
+    name: String,
+    stored: String,
+    size: u64,
+    content_type: String,
+}
+
+enum UploadError {
+    TooLarge,
+    TooMany,
+    Type(String),
+    Malformed(&'static str),
+    Io(io::Error),
+}
+
+impl From<io::Error> for UploadError {
+    fn from(error: io::Error) -> UploadError {
+        match error.kind() {
+            // What PartReader turns a broken body into.
+            io::ErrorKind::InvalidData => UploadError::Malformed("Malformed multipart body"),
+            _ => UploadError::Io(error),
+        }
+    }
+}
+
+pub(crate) struct Uploads {
+    dir: PathBuf,
+    options: UploadOptions,
+}
+
+impl Uploads {
+    pub(crate) fn new(dir: &str, options: UploadOptions) -> Uploads {
+        Uploads { dir: PathBuf::from(dir), options }
+    }
+
+    // Takes either a multipart/form-data POST, storing each file part, or a
+    // raw PUT (or POST) of a single file named by the rest of the path.
+    // Answers 201 with what was stored, as JSON.
+    pub(crate) fn respond(&self, prefix: &str, request: &Request) -> Response {
+        let content_type = request.header("Content-Type").unwrap_or("application/octet-stream").to_string();
+        let mut body: Box<dyn Read> = match request.body_reader() {
+            Some(reader) => Box::new(reader),
+            None => Box::new(io::Cursor::new(request.body_bytes().to_vec())),
+        };
+        let result = match (&request.method, multipart_boundary(&content_type)) {
+            (HttpMethod::POST, Some(boundary)) => self.multipart(&mut body, &boundary),
+            (HttpMethod::POST | HttpMethod::PUT, _) => {
+                let path = request.path.split('?').next().unwrap_or_default();
+                let name = percent_decode(path.strip_prefix(prefix).unwrap_or_default().trim_matches('/'));
+                let name = if name.is_empty() { "upload".to_string() } else { name };
+                self.store(&name, &content_type, &mut body).map(|stored| vec![stored])
+            }
+            _ => return Response::new(StatusCode::MethodNotAllowed, "Method Not Allowed").with_header("Allow", "POST, PUT"),
+        };
+
+        match result {
+            Ok(files) => {
+                let files: Vec<String> = files.iter().map(|file| {
+                    format!(
+                        "{{\"name\":{},\"stored\":{},\"size\":{},\"content_type\":{}}}",
+                        json_string(&file.name),
+                        json_string(&file.stored),
+                        file.size,
+                        json_string(&file.content_type)
+                    )
+                }).collect();
+                Response::new(StatusCode::Created, &format!("{{\"files\":[{}]}}", files.join(",")))
+                    .with_header("Content-Type", "application/json")
+            }
+            Err(UploadError::TooLarge) => Response::new(StatusCode::PayloadTooLarge, "File too large"),
+            Err(UploadError::TooMany) => Response::new(StatusCode::PayloadTooLarge, "Too many files"),
+            Err(UploadError::Type(content_type)) => {
+                Response::new(StatusCode::UnsupportedMediaType, &format!("Files of type {content_type} aren't accepted"))
+            }
+            Err(UploadError::Malformed(reason)) => Response::new(StatusCode::BadRequest, reason),
+            Err(UploadError::Io(error)) => {
+                eprintln!("Error storing upload in {}: {error}", self.dir.display());
+                Response::new(StatusCode::InternalServerError, "Internal Server Error")
+            }
+        }
+    }
+
+    fn multipart(&self, body: &mut dyn Read, boundary: &str) -> Result<Vec<Stored>, UploadError> {
+        let mut parts = Multipart::new(body, boundary);
+        let mut stored: Vec<Stored> = vec![];
+        let result = (|| {
+            while let Some(head) = parts.next_part()? {
+                let Some(name) = head.filename else {
+                    // Plain form fields aren't files; skip them.
+                    parts.copy_part(&mut io::sink(), u64::MAX)?;
+                    continue;
+                };
+                if stored.len() >= self.options.max_files {
+                    return Err(UploadError::TooMany);
+                }
+                let content_type = head.content_type.unwrap_or_else(|| "application/octet-stream".to_string());
+                stored.push(self.store(&name, &content_type, &mut PartReader(&mut parts))?);
+            }
+            Ok(())
+        })();
+        // All or nothing: a failure part way through takes the files already
+        // stored with it.
+        if let Err(error) = result {
+            for file in &stored {
+                let _ = fs::remove_file(self.dir.join(&file.stored));
+            }
+            return Err(error);
+        }
+        if stored.is_empty() {
+            return Err(UploadError::Malformed("No files in the upload"));
+        }
+        Ok(stored)
+    }
+
+    // Writes to a temporary file and renames it into place under a fresh
+    // random name, keeping only a cleaned-up extension from the client's.
+    fn store(&self, name: &str, content_type: &str, body: &mut dyn Read) -> Result<Stored, UploadError> {
+        if !self.options.allows(content_type) {
+            return Err(UploadError::Type(content_type.to_string()));
+        }
+        let name = Path::new(name).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let extension = Path::new(&name).extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+            .filter(|extension| extension.len() <= 10 && extension.chars().all(|c| c.is_ascii_alphanumeric()));
+        let stored = match extension {
+            Some(extension) => format!("{}.{extension}", random::hex(16)),
+            None => random::hex(16),
+        };
+
+        let temporary = self.dir.join(format!(".{stored}.part"));
+        let written = (|| {
+            let mut file = File::create(&temporary)?;
+            // One byte over the limit is enough to know it's too big.
+            let size = io::copy(&mut body.take(self.options.max_size.saturating_add(1)), &mut file)?;
+            if size > self.options.max_size {
+                return Err(UploadError::TooLarge);
+            }
+            file.sync_all()?;
+            fs::rename(&temporary, self.dir.join(&stored))?;
+            Ok(size)
+        })();
+        match written {
+            Ok(size) => Ok(Stored { name, stored, size, content_type: content_type.to_string() }),
+            Err(error) => {
+                let _ = fs::remove_file(&temporary);
+                Err(error)
+            }
+        }
+    }
+}
+
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    let mut parameters = content_type.split(';').map(str::trim);
+    if !parameters.next()?.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    parameters
+        .find_map(|parameter| parameter.strip_prefix("boundary="))
+        .map(|boundary| boundary.trim_matches('"').to_string())
+        .filter(|boundary| !boundary.is_empty())
+}
+
+struct PartHead {
+    filename: Option<String>,
+    content_type: Option<String>,
+}
+
+// Reads a multipart body one part at a time without holding more than a
+// chunk of it in memory.
+struct Multipart<'a> {
+    reader: &'a mut dyn Read,
+    // "\r\n--boundary", which ends every part.
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    // Whether we're inside a part's data (or the preamble before the first).
+    in_part: bool,
+    finished: bool,
+}
+
+impl<'a> Multipart<'a> {
+    fn new(reader: &'a mut dyn Read, boundary: &str) -> Multipart<'a> {
+        // The first boundary has no line break before it; pretend it does.
+        Multipart { reader, delimiter: format!("\r\n--{boundary}").into_bytes(), buffer: b"\r\n".to_vec(), in_part: true, finished: false }
+    }
+
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; 16 * 1024];
+        let read = self.reader.read(&mut chunk)?;
+        self.buffer.extend_from_slice(&chunk[..read]);
+        Ok(read > 0)
+    }
+
+    fn find(&self, needle: &[u8]) -> Option<usize> {
+        self.buffer.windows(needle.len()).position(|window| window == needle)
+    }
+
+    // Skips to the next part and reads its headers; None after the last.
+    fn next_part(&mut self) -> Result<Option<PartHead>, UploadError> {
+        if self.finished {
+            return Ok(None);
+        }
+        // Whatever is left of the current part (or the preamble) goes.
+        self.copy_part(&mut io::sink(), u64::MAX)?;
+        while self.buffer.len() < 2 {
+            if !self.fill()? {
+                return Err(UploadError::Malformed("Multipart body ended early"));
+            }
+        }
+        if self.buffer.starts_with(b"--") {
+            self.finished = true;
+            return Ok(None);
+        }
+        let head_end = loop {
+            if let Some(end) = self.find(b"\r\n\r\n") {
+                break end;
+            }
+            if self.buffer.len() > MAX_PART_HEAD {
+                return Err(UploadError::Malformed("Multipart part headers too large"));
+            }
+            if !self.fill()? {
+                return Err(UploadError::Malformed("Multipart body ended early"));
+            }
+        };
+        let head = String::from_utf8_lossy(&self.buffer[..head_end]).into_owned();
+        self.buffer.drain(..head_end + 4);
+        self.in_part = true;
+
+        let mut part = PartHead { filename: None, content_type: None };
+        let mut disposition = None;
+        for line in head.lines() {
+            let Some((name, value)) = line.split_once(':') else { continue };
+            if name.trim().eq_ignore_ascii_case("Content-Disposition") {
+                // Two could name different files, and which one counts
+                // would be anyone's guess.
+                if disposition.replace(value).is_some() {
+                    return Err(UploadError::Malformed("Multipart part has two Content-Dispositions"));
+                }
+                part.filename = value.split(';').map(str::trim)
+                    .find_map(|parameter| parameter.strip_prefix("filename="))
+                    .map(|filename| filename.trim_matches('"').to_string());
+            } else if name.trim().eq_ignore_ascii_case("Content-Type") {
+                part.content_type = Some(value.trim().to_string());
+            }
+        }
+        if disposition.is_none() {
+            return Err(UploadError::Malformed("Multipart part has no Content-Disposition"));
+        }
+        Ok(Some(part))
+    }
+
+    // Copies up to `limit` bytes of the current part's data into `output`,
+    // stopping at its delimiter, which is consumed along with the line break
+    // after it. Returns how much was copied, 0 once the part is over.
+    fn copy_part(&mut self, output: &mut dyn Write, limit: u64) -> Result<u64, UploadError> {
+        if !self.in_part {
+            return Ok(0);
+        }
+        let mut copied = 0u64;
+        loop {
+            if let Some(end) = self.find(&self.delimiter) {
+                let take = (end as u64).min(limit - copied) as usize;
+                output.write_all(&self.buffer[..take])?;
+                copied += take as u64;
+                if take < end {
+                    self.buffer.drain(..take);
+                    return Ok(copied);
+                }
+                self.buffer.drain(..end + self.delimiter.len());
+                self.in_part = false;
+                // Then either "--" for the end, or a line break.
+                if self.buffer.starts_with(b"\r\n") {
+                    self.buffer.drain(..2);
+                }
+                return Ok(copied);
+            }
+            // Keep enough back to catch a delimiter split across reads.
+            let safe = self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+            let take = (safe as u64).min(limit - copied) as usize;
+            output.write_all(&self.buffer[..take])?;
+            self.buffer.drain(..take);
+            copied += take as u64;
+            if copied == limit {
+                return Ok(copied);
+            }
+            if !self.fill()? {
+                return Err(UploadError::Malformed("Multipart body ended early"));
+            }
+        }
+    }
+}
+
+// The current part's data as a Read, for `store` to copy from.
+struct PartReader<'a, 'b>(&'a mut Multipart<'b>);
+
+impl Read for PartReader<'_, '_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut cursor = io::Cursor::new(buffer);
+        let limit = cursor.get_ref().len() as u64;
+        match self.0.copy_part(&mut cursor, limit) {
+            Ok(copied) => Ok(copied as usize),
+            Err(UploadError::Io(error)) => Err(error),
+            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed multipart body")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out `step` bytes per read, to split things across reads.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let read = self.step.min(buffer.len()).min(self.data.len());
+            buffer[..read].copy_from_slice(&self.data[..read]);
+            self.data = &self.data[read..];
+            Ok(read)
+        }
+    }
+
+    fn temp_uploads(options: UploadOptions) -> Uploads {
+        let dir = std::env::temp_dir().join(format!("webserver-uploads-{}", random::hex(8)));
+        fs::create_dir(&dir).unwrap();
+        Uploads { dir, options }
+    }
+
+    fn upload(uploads: &Uploads, body: &str, step: usize) -> Result<Vec<Stored>, UploadError> {
+        uploads.multipart(&mut Trickle { data: body.as_bytes(), step }, "XyZ")
+    }
+
+    fn files_in(uploads: &Uploads) -> Vec<String> {
+        fs::read_dir(&uploads.dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn file_part(name: &str, data: &str) -> String {
+        format!("--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{name}\"\r\n\
+                 Content-Type: text/plain\r\n\r\n{data}\r\n")
+    }
+
+    #[test]
+    fn stores_files_whole_however_the_body_is_split() {
+        let body = format!("preamble\r\n{}{}--XyZ--\r\n", file_part("a.txt", "one\r\n--XyNot"), file_part("b.TXT", "two"));
+        for step in [1, 3, 7, 16 * 1024] {
+            let uploads = temp_uploads(UploadOptions::new());
+            let stored = upload(&uploads, &body, step).ok().unwrap();
+            let contents: Vec<String> = stored.iter()
+                .map(|file| fs::read_to_string(uploads.dir.join(&file.stored)).unwrap())
+                .collect();
+            assert_eq!(contents, ["one\r\n--XyNot", "two"], "split every {step} bytes");
+            assert_eq!(stored[0].name, "a.txt");
+            assert!(stored[1].stored.ends_with(".txt"));
+            fs::remove_dir_all(&uploads.dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn skips_plain_fields() {
+        let uploads = temp_uploads(UploadOptions::new());
+        let field = "--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHello\r\n";
+        let body = format!("{field}{}{field}--XyZ--", file_part("a.txt", "one"));
+        let stored = upload(&uploads, &body, 5).ok().unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].size, 3);
+
+        let only_fields = format!("{field}--XyZ--");
+        assert!(matches!(upload(&uploads, &only_fields, 5), Err(UploadError::Malformed(_))));
+        fs::remove_dir_all(&uploads.dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_parts_over_the_size_limit() {
+        let uploads = temp_uploads(UploadOptions::new().max_size(4));
+        let fits = format!("{}--XyZ--", file_part("a.txt", "four"));
+        assert_eq!(upload(&uploads, &fits, 2).ok().unwrap()[0].size, 4);
+        fs::remove_dir_all(&uploads.dir).unwrap();
+
+        let uploads = temp_uploads(UploadOptions::new().max_size(4));
+        let body = format!("{}{}--XyZ--", file_part("a.txt", "four"), file_part("b.txt", "fives"));
+        assert!(matches!(upload(&uploads, &body, 2), Err(UploadError::TooLarge)));
+        assert!(files_in(&uploads).is_empty());
+        fs::remove_dir_all(&uploads.dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_truncated_bodies() {
+        let uploads = temp_uploads(UploadOptions::new());
+        let whole = format!("{}--XyZ--\r\n", file_part("a.txt", "one"));
+        for cut in ["--XyZ\r\nContent-Disp", "--XyZ\r\nContent-Disposition: form-data; filename=\"a\"\r\n\r\non"] {
+            assert!(matches!(upload(&uploads, cut, 4), Err(UploadError::Malformed(_))), "{cut:?}");
+        }
+        // Cut before the closing "--", and through the delimiter itself.
+        for end in [whole.len() - 4, whole.len() - 6, whole.len() - 9] {
+            assert!(matches!(upload(&uploads, &whole[..end], 4), Err(UploadError::Malformed(_))), "{:?}", &whole[..end]);
+        }
+        assert!(files_in(&uploads).is_empty());
+        fs::remove_dir_all(&uploads.dir).unwrap();
+    }
+
+    #[test]
+    fn parts_need_exactly_one_content_disposition() {
+        let uploads = temp_uploads(UploadOptions::new());
+        let missing = "--XyZ\r\nContent-Type: text/plain\r\n\r\none\r\n--XyZ--";
+        assert!(matches!(upload(&uploads, missing, 4), Err(UploadError::Malformed("Multipart part has no Content-Disposition"))));
+        let twice = "--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\
+                     Content-Disposition: form-data; name=\"b\"; filename=\"b.txt\"\r\n\r\none\r\n--XyZ--";
+        assert!(matches!(upload(&uploads, twice, 4), Err(UploadError::Malformed("Multipart part has two Content-Dispositions"))));
+        assert!(files_in(&uploads).is_empty());
+        fs::remove_dir_all(&uploads.dir).unwrap();
+    }
+}
@@ -0,0 +1,390 @@
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+};
+use crate::{
+    base64,
+    http::{http_date, HttpMethod, Request, Response, StatusCode},
+    random,
+    sha256,
+    static_files::{content_type, percent_decode, percent_encode, StaticDir},
+};
+
+// A directory shared over WebDAV (class 1: no locking), so tools like
+// `rclone`, `cadaver` or a file manager can browse and edit it. Mount it with
+// `Server::add_webdav`:
+//
+//     server.add_webdav("/files", WebDav::new("share").basic_auth("me", "secret"));
+//
+// Clients that insist on LOCK (the macOS Finder, for one) will only mount it
+// read-only.
+//
+// Uploads in progress live in the share as `.upload-*` files. They're left
+// out of listings and refused to every method, so nobody can read, move or
+// delete one half written.
+pub struct WebDav {
+    root: PathBuf,
+    files: StaticDir,
+    credentials: Option<String>,
+    max_upload: u64,
+}
+
+impl WebDav {
+    pub fn new(root: &str) -> WebDav {
+        WebDav {
+            root: PathBuf::from(root),
+            files: StaticDir::new(root).serve_hidden(true),
+            credentials: None,
+            max_upload: 1024 * 1024 * 1024,
+        }
+    }
+
+    // Asks for HTTP Basic credentials. Only use this over TLS (in front of
+    // the server): Basic sends the password as good as in the clear.
+    pub fn basic_auth(mut self, user: &str, password: &str) -> WebDav {
+        self.credentials = Some(base64::encode(format!("{user}:{password}").as_bytes()));
+        self
+    }
+
+    // The largest file PUT accepts; 1 GiB by default.
+    pub fn max_upload(mut self, bytes: u64) -> WebDav {
+        self.max_upload = bytes;
+        self
+    }
+
+    pub(crate) fn upload_limit(&self) -> u64 {
+        self.max_upload
+    }
+
+    pub(crate) fn respond(&self, prefix: &str, request: &Request) -> Response {
+        if !self.authorized(request) {
+            return Response::new(StatusCode::Unauthorized, "Unauthorized")
+                .with_header("WWW-Authenticate", "Basic realm=\"WebDAV\", charset=\"UTF-8\"");
+        }
+        let path = request.path.split('?').next().unwrap_or_default();
+        let relative = percent_decode(path.strip_prefix(prefix).unwrap_or(path));
+        let Some(target) = self.resolve(&relative) else {
+            return Response::new(StatusCode::Forbidden, "Forbidden");
+        };
+        // The share itself is where everything else lives; it can't go.
+        let changes_root = matches!(request.method, HttpMethod::DELETE | HttpMethod::MOVE | HttpMethod::COPY);
+        if changes_root && target == self.root {
+            return Response::new(StatusCode::Forbidden, "Can't delete, move or copy the share itself");
+        }
+
+        let result = match request.method {
+            HttpMethod::OPTIONS => Ok(Response::new(StatusCode::Ok, "")
+                .with_header("DAV", "1")
+                .with_header("Allow", "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, MKCOL, COPY, MOVE")),
+            HttpMethod::GET | HttpMethod::HEAD => return self.files.respond(prefix, request),
+            HttpMethod::PROPFIND => self.propfind(prefix, &relative, &target, request),
+            HttpMethod::PUT => self.put(&target, request),
+            HttpMethod::DELETE => WebDav::delete(&target),
+            HttpMethod::MKCOL => WebDav::mkcol(&target, request),
+            HttpMethod::COPY | HttpMethod::MOVE => self.copy_or_move(prefix, &target, request),
+            _ => Ok(Response::new(StatusCode::MethodNotAllowed, "Method Not Allowed")),
+        };
+        result.unwrap_or_else(|error| match error.kind() {
+            io::ErrorKind::NotFound => Response::new(StatusCode::NotFound, "Not Found"),
+            io::ErrorKind::PermissionDenied => Response::new(StatusCode::Forbidden, "Forbidden"),
+            _ => {
+                eprintln!("WebDAV {:?} {} failed: {error}", request.method, target.display());
+                Response::new(StatusCode::InternalServerError, "Internal Server Error")
+            }
+        })
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(expected) = &self.credentials else {
+            return true;
+        };
+        let given = request.header("Authorization").and_then(|value| value.strip_prefix("Basic ")).unwrap_or_default();
+        sha256::constant_time_eq(given.trim().as_bytes(), expected.as_bytes())
+    }
+
+    // Maps a path under the mount onto the root, refusing anything that
+    // climbs out of it, even through a symlink, or names an upload.
+    fn resolve(&self, relative: &str) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+        for component in Path::new(relative.trim_start_matches('/')).components() {
+            match component {
+                Component::Normal(part) if !is_upload(part) => path.push(part),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        // Whatever exists of the path has to resolve inside the root.
+        let existing = path.ancestors().find(|ancestor| ancestor.exists())?;
+        let (existing, root) = (fs::canonicalize(existing).ok()?, fs::canonicalize(&self.root).ok()?);
+        existing.starts_with(root).then_some(path)
+    }
+
+    fn propfind(&self, prefix: &str, relative: &str, target: &Path, request: &Request) -> io::Result<Response> {
+        let metadata = fs::metadata(target)?;
+        // "infinity" is allowed to be refused; answering one level is the
+        // usual compromise.
+        let depth = request.header("Depth").unwrap_or("infinity").trim();
+        let href = href_for(prefix, relative, metadata.is_dir());
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n");
+        xml.push_str(&WebDav::propstat(&href, target, &metadata));
+        if metadata.is_dir() && depth != "0" {
+            let mut entries: Vec<_> = fs::read_dir(target)?.flatten()
+                .filter(|entry| !is_upload(&entry.file_name()))
+                .collect();
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries {
+                let Ok(metadata) = entry.metadata() else { continue };
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let child = format!("{}/{name}", relative.trim_end_matches('/'));
+                xml.push_str(&WebDav::propstat(&href_for(prefix, &child, metadata.is_dir()), &entry.path(), &metadata));
+            }
+        }
+        xml.push_str("</D:multistatus>\n");
+        Ok(Response::new(StatusCode::MultiStatus, &xml).with_header("Content-Type", "application/xml; charset=utf-8"))
+    }
+
+    fn propstat(href: &str, path: &Path, metadata: &fs::Metadata) -> String {
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let mut props = format!("<D:displayname>{}</D:displayname>", xml_escape(&name));
+        if let Ok(modified) = metadata.modified() {
+            props.push_str(&format!("<D:getlastmodified>{}</D:getlastmodified>", http_date(modified)));
+        }
+        if metadata.is_dir() {
+            props.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+        } else {
+            props.push_str("<D:resourcetype/>");
+            props.push_str(&format!("<D:getcontentlength>{}</D:getcontentlength>", metadata.len()));
+            props.push_str(&format!("<D:getcontenttype>{}</D:getcontenttype>", content_type(path)));
+        }
+        format!(
+            "<D:response><D:href>{}</D:href><D:propstat><D:prop>{props}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+            xml_escape(href)
+        )
+    }
+
+    // Writes the body to a temporary file beside the target and renames it
+    // into place, so readers never see half an upload.
+    fn put(&self, target: &Path, request: &Request) -> io::Result<Response> {
+        if target.is_dir() {
+            return Ok(Response::new(StatusCode::MethodNotAllowed, "Can't PUT over a collection"));
+        }
+        let Some(parent) = target.parent().filter(|parent| parent.is_dir()) else {
+            return Ok(Response::new(StatusCode::Conflict, "Parent collection doesn't exist"));
+        };
+        let existed = target.exists();
+        let temporary = parent.join(format!("{UPLOAD_PREFIX}{}", random::hex(8)));
+        let written = (|| {
+            let mut file = File::create(&temporary)?;
+            match request.body_reader() {
+                Some(mut body) => {
+                    io::copy(&mut body, &mut file)?;
+                }
+                None => match request.body_file() {
+                    Some(body) => {
+                        io::copy(&mut body.open()?, &mut file)?;
+                    }
+                    None => file.write_all(request.body_bytes())?,
+                },
+            }
+            file.sync_all()?;
+            fs::rename(&temporary, target)
+        })();
+        if let Err(error) = written {
+            let _ = fs::remove_file(&temporary);
+            return Err(error);
+        }
+        Ok(Response::new(if existed { StatusCode::NoContent } else { StatusCode::Created }, ""))
+    }
+
+    fn delete(target: &Path) -> io::Result<Response> {
+        if fs::symlink_metadata(target)?.is_dir() {
+            fs::remove_dir_all(target)?;
+        } else {
+            fs::remove_file(target)?;
+        }
+        Ok(Response::new(StatusCode::NoContent, ""))
+    }
+
+    fn mkcol(target: &Path, request: &Request) -> io::Result<Response> {
+        if request.content_length().unwrap_or(0) > 0 {
+            return Ok(Response::new(StatusCode::UnsupportedMediaType, "MKCOL bodies aren't supported"));
+        }
+        if target.exists() {
+            return Ok(Response::new(StatusCode::MethodNotAllowed, "Already exists"));
+        }
+        if !target.parent().is_some_and(Path::is_dir) {
+            return Ok(Response::new(StatusCode::Conflict, "Parent collection doesn't exist"));
+        }
+        fs::create_dir(target)?;
+        Ok(Response::new(StatusCode::Created, ""))
+    }
+
+    fn copy_or_move(&self, prefix: &str, source: &Path, request: &Request) -> io::Result<Response> {
+        if !source.exists() {
+            return Ok(Response::new(StatusCode::NotFound, "Not Found"));
+        }
+        // The Destination is a full URL; only its path matters, and it has to
+        // be under this mount.
+        let Some(destination) = request.header("Destination") else {
+            return Ok(Response::new(StatusCode::BadRequest, "Missing Destination header"));
+        };
+        let path = match destination.split_once("://") {
+            Some((_, rest)) => rest.find('/').map_or("/", |slash| &rest[slash..]),
+            None => destination,
+        };
+        // "/files" covers "/files" and "/files/x", but not "/filesystem/x".
+        let relative = path.strip_prefix(prefix.trim_end_matches('/'))
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'));
+        let Some(relative) = relative else {
+            return Ok(Response::new(StatusCode::BadRequest, "Destination is outside this share"));
+        };
+        let Some(target) = self.resolve(&percent_decode(relative)) else {
+            return Ok(Response::new(StatusCode::Forbidden, "Forbidden"));
+        };
+        if target == self.root {
+            return Ok(Response::new(StatusCode::Forbidden, "Can't replace the share itself"));
+        }
+        if target == source || target.starts_with(source) {
+            return Ok(Response::new(StatusCode::Forbidden, "Can't copy or move something into itself"));
+        }
+        if !target.parent().is_some_and(Path::is_dir) {
+            return Ok(Response::new(StatusCode::Conflict, "Parent collection doesn't exist"));
+        }
+        let existed = target.exists();
+        if existed {
+            if request.header("Overwrite").is_some_and(|overwrite| overwrite.trim().eq_ignore_ascii_case("F")) {
+                return Ok(Response::new(StatusCode::PreconditionFailed, "Destination exists"));
+            }
+            WebDav::delete(&target)?;
+        }
+
+        if request.method == HttpMethod::MOVE {
+            fs::rename(source, &target)?;
+        } else {
+            copy_recursively(source, &target)?;
+        }
+        Ok(Response::new(if existed { StatusCode::NoContent } else { StatusCode::Created }, ""))
+    }
+}
+
+// What the temporary files PUT writes to are called.
+const UPLOAD_PREFIX: &str = ".upload-";
+
+fn is_upload(name: &OsStr) -> bool {
+    name.to_string_lossy().starts_with(UPLOAD_PREFIX)
+}
+
+fn copy_recursively(source: &Path, target: &Path) -> io::Result<()> {
+    if !source.is_dir() {
+        return fs::copy(source, target).map(|_| ());
+    }
+    fs::create_dir(target)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        if is_upload(&entry.file_name()) {
+            continue;
+        }
+        copy_recursively(&entry.path(), &target.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+// The URL of a path under the mount, percent-encoded, with collections
+// ending in a slash as clients expect.
+fn href_for(prefix: &str, relative: &str, dir: bool) -> String {
+    let mut href = prefix.trim_end_matches('/').to_string();
+    for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+        href.push('/');
+        href.push_str(&percent_encode(segment));
+    }
+    if dir || href.is_empty() {
+        href.push('/');
+    }
+    href
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn share() -> (PathBuf, WebDav) {
+        let root = std::env::temp_dir().join(format!("webserver-dav-{}", random::hex(8)));
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("docs/b.txt"), "b").unwrap();
+        let dav = WebDav::new(root.to_str().unwrap());
+        (root, dav)
+    }
+
+    fn send(dav: &WebDav, method: HttpMethod, path: &str, headers: &[(&str, &str)]) -> Response {
+        let mut request = Request::new(method, path);
+        for (name, value) in headers {
+            request.headers.push((name.to_string(), value.to_string()));
+        }
+        dav.respond("/dav", &request)
+    }
+
+    #[test]
+    fn keeps_destinations_inside_the_share() {
+        let (root, dav) = share();
+        for destination in ["http://host/elsewhere/a.txt", "/davx/a.txt", "/dav/../a.txt", "/dav/%2e%2e/escaped.txt",
+                            "http://host/dav/docs/%2E%2E/%2E%2E/escaped.txt"] {
+            let response = send(&dav, HttpMethod::COPY, "/dav/a.txt", &[("Destination", destination)]);
+            assert!(matches!(response.status_code, StatusCode::BadRequest | StatusCode::Forbidden), "{destination}");
+        }
+        assert!(!root.parent().unwrap().join("escaped.txt").exists());
+
+        let response = send(&dav, HttpMethod::MOVE, "/dav/a.txt", &[("Destination", "http://host/dav/docs/a.txt")]);
+        assert_eq!(response.status_code, StatusCode::Created);
+        assert!(root.join("docs/a.txt").exists() && !root.join("a.txt").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn overwrites_only_when_allowed() {
+        let (root, dav) = share();
+        for (method, overwrite) in [(HttpMethod::COPY, "F"), (HttpMethod::MOVE, "f")] {
+            let headers = [("Destination", "/dav/docs/b.txt"), ("Overwrite", overwrite)];
+            let response = send(&dav, method, "/dav/a.txt", &headers);
+            assert_eq!(response.status_code, StatusCode::PreconditionFailed);
+            assert_eq!(fs::read_to_string(root.join("docs/b.txt")).unwrap(), "b");
+            assert!(root.join("a.txt").exists());
+        }
+
+        let headers = [("Destination", "/dav/docs/b.txt"), ("Overwrite", "T")];
+        assert_eq!(send(&dav, HttpMethod::COPY, "/dav/a.txt", &headers).status_code, StatusCode::NoContent);
+        assert_eq!(fs::read_to_string(root.join("docs/b.txt")).unwrap(), "a");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn hides_uploads_in_progress() {
+        let (root, dav) = share();
+        fs::write(root.join(".upload-0123abcd"), "half").unwrap();
+        fs::write(root.join("docs/.upload-4567cdef"), "half").unwrap();
+
+        let listing = send(&dav, HttpMethod::PROPFIND, "/dav/", &[("Depth", "1")]);
+        let listing = String::from_utf8(listing.body).unwrap();
+        assert!(listing.contains("/dav/a.txt") && !listing.contains(".upload-"), "{listing}");
+        for method in [HttpMethod::GET, HttpMethod::PROPFIND, HttpMethod::DELETE, HttpMethod::PUT] {
+            let response = send(&dav, method.clone(), "/dav/.upload-0123abcd", &[]);
+            assert_eq!(response.status_code, StatusCode::Forbidden, "{method:?}");
+        }
+        let headers = [("Destination", "/dav/docs/.upload-0123abcd")];
+        assert_eq!(send(&dav, HttpMethod::COPY, "/dav/a.txt", &headers).status_code, StatusCode::Forbidden);
+
+        let headers = [("Destination", "/dav/copy")];
+        assert_eq!(send(&dav, HttpMethod::COPY, "/dav/docs", &headers).status_code, StatusCode::Created);
+        assert!(root.join("copy/b.txt").exists() && !root.join("copy/.upload-4567cdef").exists());
+        assert!(root.join(".upload-0123abcd").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
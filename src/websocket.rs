@@ -0,0 +1,950 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use crate::{
+    base64,
+    compress::{self, InflateError},
+    http::{HttpMethod, Request, Response, StatusCode},
+    sha1,
+};
+
+// WebSockets (RFC 6455), for handlers registered with
+// `Server::add_websocket`:
+//
+//     server.add_websocket("/echo", WebSocketOptions::new(), |_, socket| {
+//         while let Some(message) = socket.recv()? {
+//             socket.send(&message)?;
+//         }
+//         Ok(())
+//     });
+//
+// Pings and pongs are handled inside `recv`: the client is pinged after
+// `ping_interval` of quiet and given up on if it doesn't answer within
+// `pong_timeout`. Closing is a handshake either way round: `recv` answers
+// the client's close frame and returns None, and when the handler returns
+// the socket is closed with 1000, or 1011 if it failed, and the client's
+// answer waited for. Once the server starts shutting down every socket is
+// sent 1001 Going Away, so clients know to reconnect elsewhere rather than
+// seeing the connection reset.
+//
+// With `deflate`, messages are compressed (RFC 7692's permessage-deflate)
+// when the client offers it, as browsers do.
+//
+// Each socket holds a worker for as long as it's open, so size the pool
+// for them.
+pub struct WebSocket {
+    reader: TcpStream,
+    outgoing: Arc<Outgoing>,
+    options: WebSocketOptions,
+    protocol: Option<String>,
+    // Bytes read but not yet made into frames.
+    buffer: Vec<u8>,
+    // The opcode, whether it's compressed, and the payload so far of a
+    // message arriving in fragments.
+    fragments: Option<(u8, bool, Vec<u8>)>,
+    // What the client's earlier messages decompressed to, as far back as
+    // its compressor may refer; None unless it keeps its context between
+    // messages.
+    history: Option<Vec<u8>>,
+    inflate_window: usize,
+    last_heard: Instant,
+    ping_sent: Option<Instant>,
+    close_received: Option<(u16, String)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+// The close codes a server has reason to send (RFC 6455 section 7.4.1).
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_UNSUPPORTED: u16 = 1003;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+// Set on the first frame of a compressed message.
+const RSV1: u8 = 0x40;
+
+#[derive(Clone, Debug)]
+pub struct WebSocketOptions {
+    ping_interval: Option<Duration>,
+    pong_timeout: Duration,
+    close_timeout: Duration,
+    max_message_size: usize,
+    protocols: Vec<String>,
+    deflate: bool,
+    deflate_min_size: usize,
+    client_window_bits: Option<u8>,
+}
+
+// What was agreed for permessage-deflate in the handshake.
+#[derive(Clone, Copy, Debug)]
+struct Deflate {
+    // How far back our compressor may refer.
+    server_window: usize,
+    // How far back the client's may, and whether it starts afresh with
+    // each message.
+    client_window: usize,
+    client_takeover: bool,
+    min_size: usize,
+}
+
+impl WebSocketOptions {
+    // Pings after 30 seconds of quiet, allows 10 for the pong and 5 for the
+    // answer to a close, and takes messages of up to 1 MiB.
+    pub fn new() -> WebSocketOptions {
+        WebSocketOptions {
+            ping_interval: Some(Duration::from_secs(30)),
+            pong_timeout: Duration::from_secs(10),
+            close_timeout: Duration::from_secs(5),
+            max_message_size: 1024 * 1024,
+            protocols: vec![],
+            deflate: false,
+            deflate_min_size: 256,
+            client_window_bits: None,
+        }
+    }
+
+    // None stops the server pinging; the client's pings are answered
+    // regardless.
+    pub fn ping_interval(mut self, interval: Option<Duration>) -> WebSocketOptions {
+        self.ping_interval = interval.filter(|interval| !interval.is_zero());
+        self
+    }
+
+    pub fn pong_timeout(mut self, timeout: Duration) -> WebSocketOptions {
+        self.pong_timeout = timeout;
+        self
+    }
+
+    // How long to wait for the client to answer our close frame before
+    // just hanging up.
+    pub fn close_timeout(mut self, timeout: Duration) -> WebSocketOptions {
+        self.close_timeout = timeout;
+        self
+    }
+
+    // Bigger messages, whole or in fragments, close the socket with 1009.
+    pub fn max_message_size(mut self, bytes: usize) -> WebSocketOptions {
+        self.max_message_size = bytes;
+        self
+    }
+
+    // Subprotocols the handler speaks, best first. The client's first
+    // choice among them is agreed in the handshake and reported by
+    // `WebSocket::protocol`; clients offering only others are refused.
+    pub fn protocols(mut self, protocols: &[&str]) -> WebSocketOptions {
+        self.protocols = protocols.iter().map(|protocol| protocol.to_string()).collect();
+        self
+    }
+
+    // Compresses messages when the client offers permessage-deflate. Ours
+    // are each compressed on their own (server_no_context_takeover), while
+    // the client may carry its context from message to message, which
+    // costs the socket up to 32 KiB to remember.
+    pub fn deflate(mut self, enabled: bool) -> WebSocketOptions {
+        self.deflate = enabled;
+        self
+    }
+
+    // Messages shorter than this go out uncompressed, as compressing them
+    // hardly pays. 256 bytes by default.
+    pub fn deflate_min_size(mut self, bytes: usize) -> WebSocketOptions {
+        self.deflate_min_size = bytes;
+        self
+    }
+
+    // Asks clients that allow it to keep to a window of 2^bits bytes (9 to
+    // 15), so each socket remembers less of what they sent. Clients that
+    // want to keep no context at all are always let.
+    pub fn deflate_client_window_bits(mut self, bits: u8) -> WebSocketOptions {
+        self.client_window_bits = Some(bits.clamp(9, 15));
+        self
+    }
+
+    fn tick(&self) -> Duration {
+        let second = Duration::from_secs(1);
+        self.ping_interval.map_or(second, |interval| interval.min(second))
+    }
+}
+
+impl Default for WebSocketOptions {
+    fn default() -> WebSocketOptions {
+        WebSocketOptions::new()
+    }
+}
+
+// The writing half of a socket, shared with its senders and with the
+// server, which closes it on shutdown.
+pub(crate) struct Outgoing {
+    stream: Mutex<TcpStream>,
+    deflate: Option<Deflate>,
+    // When our close frame went out; nothing else may follow it.
+    close_sent: Mutex<Option<Instant>>,
+}
+
+impl Outgoing {
+    fn send(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let close_sent = self.close_sent.lock().unwrap();
+        if close_sent.is_some() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "WebSocket closed"));
+        }
+        let data = opcode == OPCODE_TEXT || opcode == OPCODE_BINARY;
+        match self.deflate.filter(|deflate| data && payload.len() >= deflate.min_size) {
+            Some(deflate) => {
+                // A message ends on a byte boundary, with what would be the
+                // start of an empty stored block; the rest of that block,
+                // 00 00 ff ff, is left off for the client to put back.
+                let mut compressed = compress::deflate_window(payload, deflate.server_window);
+                compressed.push(0);
+                self.write(opcode | RSV1, &compressed)
+            }
+            None => self.write(opcode, payload),
+        }
+    }
+
+    // Sends a close frame unless one has been sent already. Returns whether
+    // this one was.
+    fn close(&self, code: u16, reason: &str) -> io::Result<bool> {
+        let mut close_sent = self.close_sent.lock().unwrap();
+        if close_sent.is_some() {
+            return Ok(false);
+        }
+        *close_sent = Some(Instant::now());
+        // Control frames carry at most 125 bytes, two of them the code.
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        self.write(OPCODE_CLOSE, &payload).map(|_| true)
+    }
+
+    fn close_sent(&self) -> Option<Instant> {
+        *self.close_sent.lock().unwrap()
+    }
+
+    // `opcode` may have RSV1 set, for a compressed message.
+    fn write(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            length @ 0..=125 => frame.push(length as u8),
+            length @ 126..=0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        let mut stream = self.stream.lock().unwrap();
+        stream.write_all(&frame)?;
+        stream.flush()
+    }
+}
+
+// Sends on a socket from another thread than the one reading it, e.g. one
+// forwarding a Broadcast subscription.
+#[derive(Clone)]
+pub struct WebSocketSender {
+    outgoing: Arc<Outgoing>,
+}
+
+impl WebSocketSender {
+    pub fn send(&self, message: &Message) -> io::Result<()> {
+        send_message(&self.outgoing, message)
+    }
+
+    pub fn send_text(&self, text: &str) -> io::Result<()> {
+        self.outgoing.send(OPCODE_TEXT, text.as_bytes())
+    }
+
+    pub fn send_binary(&self, data: &[u8]) -> io::Result<()> {
+        self.outgoing.send(OPCODE_BINARY, data)
+    }
+
+    // Starts the close handshake; the reading side sees it finish.
+    pub fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        self.outgoing.close(code, reason).map(|_| ())
+    }
+}
+
+fn send_message(outgoing: &Outgoing, message: &Message) -> io::Result<()> {
+    match message {
+        Message::Text(text) => outgoing.send(OPCODE_TEXT, text.as_bytes()),
+        Message::Binary(data) => outgoing.send(OPCODE_BINARY, data),
+    }
+}
+
+impl WebSocket {
+    // The next message from the client, or None once the client has closed
+    // the socket or answered our close.
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        loop {
+            if self.close_received.is_some() {
+                return Ok(None);
+            }
+            let buffered = self.buffer.len();
+            if let Some(message) = self.next_frame()? {
+                return Ok(Some(message));
+            }
+            // A frame that made no message (a ping, a fragment) may have
+            // whole ones behind it already.
+            if self.close_received.is_some() || self.buffer.len() < buffered {
+                continue;
+            }
+            let filled = self.buffer.len();
+            self.buffer.resize(filled + READ_CHUNK_SIZE, 0);
+            let read = self.reader.read(&mut self.buffer[filled..]);
+            self.buffer.truncate(filled + read.as_ref().map_or(0, |read| *read));
+            match read {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "WebSocket closed without a close frame")),
+                Ok(_) => {
+                    self.last_heard = Instant::now();
+                    self.ping_sent = None;
+                }
+                Err(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => self.tick()?,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    pub fn send(&self, message: &Message) -> io::Result<()> {
+        send_message(&self.outgoing, message)
+    }
+
+    pub fn send_text(&self, text: &str) -> io::Result<()> {
+        self.outgoing.send(OPCODE_TEXT, text.as_bytes())
+    }
+
+    pub fn send_binary(&self, data: &[u8]) -> io::Result<()> {
+        self.outgoing.send(OPCODE_BINARY, data)
+    }
+
+    pub fn sender(&self) -> WebSocketSender {
+        WebSocketSender { outgoing: Arc::clone(&self.outgoing) }
+    }
+
+    // Sends a close frame and waits for the client's answer, throwing away
+    // any messages that arrive first.
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        self.outgoing.close(code, reason)?;
+        while self.recv()?.is_some() {}
+        Ok(())
+    }
+
+    // The code and reason the client closed with, once it has.
+    pub fn close_received(&self) -> Option<(u16, &str)> {
+        self.close_received.as_ref().map(|(code, reason)| (*code, reason.as_str()))
+    }
+
+    // The subprotocol agreed in the handshake, if any.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    // Called whenever a read times out: pings a quiet client, and gives up
+    // on one that stopped answering.
+    fn tick(&mut self) -> io::Result<()> {
+        if self.outgoing.close_sent().is_some_and(|sent| sent.elapsed() >= self.options.close_timeout) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "No answer to WebSocket close"));
+        }
+        if self.ping_sent.is_some_and(|sent| sent.elapsed() >= self.options.pong_timeout) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "No answer to WebSocket ping"));
+        }
+        if let Some(interval) = self.options.ping_interval {
+            if self.ping_sent.is_none() && self.last_heard.elapsed() >= interval && self.outgoing.close_sent().is_none() {
+                self.outgoing.send(OPCODE_PING, b"")?;
+                self.ping_sent = Some(Instant::now());
+            }
+        }
+        Ok(())
+    }
+
+    // Closes the socket over something the client got wrong, and returns
+    // the error for `recv` to fail with.
+    fn fail(&self, code: u16, reason: &str) -> io::Error {
+        let _ = self.outgoing.close(code, reason);
+        io::Error::new(io::ErrorKind::InvalidData, format!("WebSocket closed with {code}: {reason}"))
+    }
+
+    // Takes one frame off the buffer, if a whole one is there, and deals
+    // with it. Returns a message when one is complete.
+    fn next_frame(&mut self) -> io::Result<Option<Message>> {
+        let Some((header, length)) = self.frame_length()? else {
+            return Ok(None);
+        };
+        if self.buffer.len() < header + length {
+            return Ok(None);
+        }
+        let (first, mask) = (self.buffer[0], [self.buffer[header - 4], self.buffer[header - 3], self.buffer[header - 2], self.buffer[header - 1]]);
+        let mut payload: Vec<u8> = self.buffer.drain(..header + length).skip(header).collect();
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+        let (fin, compressed, opcode) = (first & 0x80 != 0, first & RSV1 != 0, first & 0x0f);
+        if compressed && (self.outgoing.deflate.is_none() || !matches!(opcode, OPCODE_TEXT | OPCODE_BINARY)) {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Reserved bits set"));
+        }
+
+        match opcode {
+            OPCODE_TEXT | OPCODE_BINARY if self.fragments.is_some() => {
+                Err(self.fail(CLOSE_PROTOCOL_ERROR, "Expected a continuation frame"))
+            }
+            OPCODE_TEXT | OPCODE_BINARY if fin => self.message(opcode, compressed, payload).map(Some),
+            OPCODE_TEXT | OPCODE_BINARY => {
+                self.fragments = Some((opcode, compressed, payload));
+                Ok(None)
+            }
+            OPCODE_CONTINUATION => {
+                let Some((opcode, compressed, mut message)) = self.fragments.take() else {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Continuation with nothing to continue"));
+                };
+                if message.len() + payload.len() > self.options.max_message_size {
+                    return Err(self.fail(CLOSE_TOO_BIG, "Message too big"));
+                }
+                message.extend_from_slice(&payload);
+                if fin {
+                    return self.message(opcode, compressed, message).map(Some);
+                }
+                self.fragments = Some((opcode, compressed, message));
+                Ok(None)
+            }
+            OPCODE_PING => {
+                // Once we've sent a close, nothing else may follow it.
+                match self.outgoing.send(OPCODE_PONG, &payload) {
+                    Err(error) if error.kind() != io::ErrorKind::NotConnected => Err(error),
+                    _ => Ok(None),
+                }
+            }
+            OPCODE_PONG => {
+                self.ping_sent = None;
+                Ok(None)
+            }
+            OPCODE_CLOSE => {
+                let (code, reason) = match payload.len() {
+                    0 => (CLOSE_NORMAL, String::new()),
+                    1 => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Close frame cut short")),
+                    _ => match String::from_utf8(payload[2..].to_vec()) {
+                        Ok(reason) => (u16::from_be_bytes([payload[0], payload[1]]), reason),
+                        Err(_) => return Err(self.fail(CLOSE_INVALID_DATA, "Close reason isn't UTF-8")),
+                    },
+                };
+                if !valid_close_code(code) {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Invalid close code"));
+                }
+                // Answered with the same code, unless this is the answer.
+                self.outgoing.close(code, "")?;
+                self.close_received = Some((code, reason));
+                Ok(None)
+            }
+            _ => Err(self.fail(CLOSE_PROTOCOL_ERROR, "Unknown opcode")),
+        }
+    }
+
+    // The header and payload lengths of the frame at the front of the
+    // buffer, once enough of it has arrived to tell.
+    fn frame_length(&self) -> io::Result<Option<(usize, usize)>> {
+        let buffer = &self.buffer;
+        if buffer.len() < 2 {
+            return Ok(None);
+        }
+        let (first, second) = (buffer[0], buffer[1]);
+        // RSV1 is checked once the opcode is known to allow it.
+        if first & 0x30 != 0 {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Reserved bits set"));
+        }
+        if second & 0x80 == 0 {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Client frames must be masked"));
+        }
+        let control = first & 0x08 != 0;
+        let (extended, length) = match second & 0x7f {
+            126 if buffer.len() < 4 => return Ok(None),
+            126 => (2, u16::from_be_bytes([buffer[2], buffer[3]]) as u64),
+            127 if buffer.len() < 10 => return Ok(None),
+            127 => (8, u64::from_be_bytes(buffer[2..10].try_into().unwrap())),
+            length => (0, length as u64),
+        };
+        if control && (length > 125 || first & 0x80 == 0) {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Control frames must be short and whole"));
+        }
+        if length > self.options.max_message_size as u64 {
+            return Err(self.fail(CLOSE_TOO_BIG, "Message too big"));
+        }
+        Ok(Some((2 + extended + 4, length as usize)))
+    }
+
+    fn message(&mut self, opcode: u8, compressed: bool, payload: Vec<u8>) -> io::Result<Message> {
+        if payload.len() > self.options.max_message_size {
+            return Err(self.fail(CLOSE_TOO_BIG, "Message too big"));
+        }
+        let payload = if compressed { self.inflate(payload)? } else { payload };
+        if opcode == OPCODE_BINARY {
+            return Ok(Message::Binary(payload));
+        }
+        String::from_utf8(payload).map(Message::Text).map_err(|_| self.fail(CLOSE_INVALID_DATA, "Text message isn't UTF-8"))
+    }
+
+    fn inflate(&mut self, mut payload: Vec<u8>) -> io::Result<Vec<u8>> {
+        payload.extend_from_slice(&[0x00, 0x00, 0xff, 0xff]);
+        let history = self.history.as_deref().unwrap_or_default();
+        let inflated = match compress::inflate_continuing(&payload, history, self.options.max_message_size) {
+            Ok(inflated) => inflated,
+            Err(InflateError::TooLarge) => return Err(self.fail(CLOSE_TOO_BIG, "Message too big")),
+            Err(_) => return Err(self.fail(CLOSE_INVALID_DATA, "Invalid compressed message")),
+        };
+        if let Some(history) = &mut self.history {
+            history.extend_from_slice(&inflated);
+            let excess = history.len().saturating_sub(self.inflate_window);
+            history.drain(..excess);
+        }
+        Ok(inflated)
+    }
+}
+
+// Whether a client may close with `code` (RFC 6455 section 7.4). 1005, 1006
+// and 1015 only stand in for a code that never came, the rest of 1000-2999
+// is unassigned, and nothing is defined outside 1000-4999.
+fn valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+// Every open socket, so a shutdown can say goodbye to them.
+#[derive(Clone, Default)]
+pub(crate) struct OpenSockets {
+    state: Arc<Mutex<OpenState>>,
+}
+
+#[derive(Default)]
+struct OpenState {
+    next_id: u64,
+    sockets: HashMap<u64, Arc<Outgoing>>,
+    going_away: bool,
+}
+
+impl OpenSockets {
+    // None once the server is shutting down.
+    fn register(&self, outgoing: &Arc<Outgoing>) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        if state.going_away {
+            return None;
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.sockets.insert(id, Arc::clone(outgoing));
+        Some(id)
+    }
+
+    fn unregister(&self, id: u64) {
+        self.state.lock().unwrap().sockets.remove(&id);
+    }
+
+    // Sends every open socket 1001 and refuses new ones. Returns how many
+    // were open.
+    pub(crate) fn going_away(&self) -> usize {
+        let sockets: Vec<Arc<Outgoing>> = {
+            let mut state = self.state.lock().unwrap();
+            state.going_away = true;
+            state.sockets.values().cloned().collect()
+        };
+        for outgoing in &sockets {
+            let _ = outgoing.close(CLOSE_GOING_AWAY, "Server shutting down");
+        }
+        sockets.len()
+    }
+}
+
+// Upgrades the request to a WebSocket and runs `handler` on it, for
+// `Server::add_websocket`. What comes back is only a record of what
+// happened; the handshake has been written already.
+pub(crate) fn serve<F>(request: &Request, options: &WebSocketOptions, sockets: &OpenSockets, handler: &F) -> Response
+where
+    F: Fn(&Request, &mut WebSocket) -> io::Result<()>,
+{
+    let Handshake { key, protocol, deflate } = match handshake(request, options) {
+        Ok(handshake) => handshake,
+        Err(response) => return response,
+    };
+    let Some(connection) = &request.connection else {
+        return Response::new(StatusCode::InternalServerError, "Internal Server Error");
+    };
+    let streams = connection.with_stream(|stream| Ok((stream.try_clone()?, stream.try_clone()?)));
+    let (reader, writer) = match streams {
+        Ok(streams) => streams,
+        Err(error) => {
+            eprintln!("Error cloning stream for WebSocket: {error}");
+            return Response::new(StatusCode::InternalServerError, "Internal Server Error");
+        }
+    };
+    let outgoing = Arc::new(Outgoing {
+        stream: Mutex::new(writer),
+        deflate: deflate.as_ref().map(|(deflate, _)| *deflate),
+        close_sent: Mutex::new(None),
+    });
+    let Some(id) = sockets.register(&outgoing) else {
+        return Response::new(StatusCode::ServiceUnavailable, "Server shutting down").with_header("Connection", "close");
+    };
+
+    let accept = base64::encode(&sha1::digest(format!("{key}{ACCEPT_GUID}").as_bytes()));
+    let mut head = format!("HTTP/1.1 {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n", StatusCode::SwitchingProtocols);
+    if let Some(protocol) = &protocol {
+        head.push_str(&format!("Sec-WebSocket-Protocol: {protocol}\r\n"));
+    }
+    if let Some((_, extensions)) = &deflate {
+        head.push_str(&format!("Sec-WebSocket-Extensions: {extensions}\r\n"));
+    }
+    head.push_str("\r\n");
+
+    let result = outgoing.stream.lock().unwrap().write_all(head.as_bytes())
+        .and_then(|_| reader.set_read_timeout(Some(options.tick())));
+    let result = result.and_then(|_| {
+        let mut socket = WebSocket {
+            reader,
+            outgoing: Arc::clone(&outgoing),
+            options: options.clone(),
+            protocol,
+            buffer: vec![],
+            fragments: None,
+            history: deflate.as_ref().filter(|(deflate, _)| deflate.client_takeover).map(|_| vec![]),
+            inflate_window: deflate.as_ref().map_or(0, |(deflate, _)| deflate.client_window),
+            last_heard: Instant::now(),
+            ping_sent: None,
+            close_received: None,
+        };
+        let result = handler(request, &mut socket);
+        // Say goodbye properly, if the socket is still there to say it on.
+        if socket.close_received.is_none() {
+            let code = if result.is_ok() { CLOSE_NORMAL } else { CLOSE_INTERNAL_ERROR };
+            let _ = socket.close(code, "");
+        }
+        result
+    });
+    sockets.unregister(id);
+    if let Err(error) = &result {
+        if !matches!(error.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::NotConnected | io::ErrorKind::InvalidData) {
+            eprintln!("WebSocket handler failed: {error}");
+        }
+    }
+    let mut response = Response::new(StatusCode::SwitchingProtocols, "");
+    response.streamed = true;
+    response
+}
+
+// What a client's upgrade request was agreed to.
+struct Handshake {
+    key: String,
+    protocol: Option<String>,
+    // With the Sec-WebSocket-Extensions value agreeing to it.
+    deflate: Option<(Deflate, String)>,
+}
+
+// Checks the upgrade request, returning what was agreed, or the response
+// refusing it.
+fn handshake(request: &Request, options: &WebSocketOptions) -> Result<Handshake, Response> {
+    let has_token = |name: &str, token: &str| {
+        request.header(name).is_some_and(|value| value.split(',').any(|item| item.trim().eq_ignore_ascii_case(token)))
+    };
+    if request.method != HttpMethod::GET || !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Err(Response::new(StatusCode::UpgradeRequired, "WebSocket upgrade required")
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade"));
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        return Err(Response::new(StatusCode::UpgradeRequired, "Unsupported WebSocket version")
+            .with_header("Sec-WebSocket-Version", "13"));
+    }
+    let key = request.header("Sec-WebSocket-Key").unwrap_or_default().trim().to_string();
+    if base64::decode(&key).is_none_or(|key| key.len() != 16) {
+        return Err(Response::new(StatusCode::BadRequest, "Invalid Sec-WebSocket-Key"));
+    }
+
+    let deflate = match request.header("Sec-WebSocket-Extensions") {
+        Some(offers) if options.deflate => offers.split(',').find_map(|offer| negotiate_deflate(offer, options)),
+        _ => None,
+    };
+    if options.protocols.is_empty() {
+        return Ok(Handshake { key, protocol: None, deflate });
+    }
+    let offered: Vec<&str> = request.header("Sec-WebSocket-Protocol").unwrap_or_default().split(',').map(str::trim).collect();
+    match offered.iter().find(|protocol| options.protocols.iter().any(|supported| supported == *protocol)) {
+        Some(protocol) => Ok(Handshake { key, protocol: Some(protocol.to_string()), deflate }),
+        None => Err(Response::new(StatusCode::BadRequest, "No supported WebSocket subprotocol")),
+    }
+}
+
+// Agrees to one permessage-deflate offer, e.g. "permessage-deflate;
+// client_max_window_bits", unless it has parameters we can't go along with
+// (RFC 7692 section 7.1).
+fn negotiate_deflate(offer: &str, options: &WebSocketOptions) -> Option<(Deflate, String)> {
+    let mut parameters = offer.split(';').map(str::trim);
+    if !parameters.next()?.eq_ignore_ascii_case("permessage-deflate") {
+        return None;
+    }
+    let window_bits = |value: Option<&str>| value.map(|value| value.trim_matches('"').parse::<u8>().ok().filter(|bits| (8..=15).contains(bits)));
+
+    let mut deflate = Deflate {
+        server_window: 1 << 15,
+        client_window: 1 << 15,
+        client_takeover: true,
+        min_size: options.deflate_min_size,
+    };
+    // We never carry context between our messages, so say so whether asked
+    // or not.
+    let mut agreed = String::from("permessage-deflate; server_no_context_takeover");
+    let mut seen = vec![];
+    for parameter in parameters.filter(|parameter| !parameter.is_empty()) {
+        let (name, value) = match parameter.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (parameter, None),
+        };
+        let name = name.to_ascii_lowercase();
+        if seen.contains(&name) {
+            return None;
+        }
+        match (name.as_str(), value) {
+            ("server_no_context_takeover", None) => {}
+            ("client_no_context_takeover", None) => {
+                deflate.client_takeover = false;
+                agreed.push_str("; client_no_context_takeover");
+            }
+            ("server_max_window_bits", Some(_)) => {
+                let bits = window_bits(value)??;
+                deflate.server_window = 1 << bits;
+                agreed.push_str(&format!("; server_max_window_bits={bits}"));
+            }
+            // The client lets us pick its window, up to what it says.
+            ("client_max_window_bits", _) => {
+                let offered = window_bits(value).unwrap_or(Some(15))?;
+                if let Some(bits) = options.client_window_bits {
+                    let bits = bits.min(offered);
+                    deflate.client_window = 1 << bits;
+                    agreed.push_str(&format!("; client_max_window_bits={bits}"));
+                }
+            }
+            _ => return None,
+        }
+        seen.push(name);
+    }
+    Some((deflate, agreed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    // A socket as the server sees it, and the client's end of the
+    // connection.
+    fn connect(options: WebSocketOptions) -> (WebSocket, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let outgoing = Arc::new(Outgoing { stream: Mutex::new(server.try_clone().unwrap()), deflate: None, close_sent: Mutex::new(None) });
+        let socket = WebSocket {
+            reader: server,
+            outgoing,
+            options,
+            protocol: None,
+            buffer: vec![],
+            fragments: None,
+            history: None,
+            inflate_window: 0,
+            last_heard: Instant::now(),
+            ping_sent: None,
+            close_received: None,
+        };
+        (socket, client)
+    }
+
+    // A frame as a client would send it: `first` is FIN, RSV and opcode.
+    fn frame(first: u8, payload: &[u8], masked: bool) -> Vec<u8> {
+        let mask_bit = if masked { 0x80 } else { 0 };
+        let mut frame = vec![first];
+        match payload.len() {
+            length @ 0..=125 => frame.push(mask_bit | length as u8),
+            length @ 126..=0xffff => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        if masked {
+            frame.extend_from_slice(&mask);
+        }
+        frame.extend(payload.iter().enumerate().map(|(index, byte)| if masked { byte ^ mask[index % 4] } else { *byte }));
+        frame
+    }
+
+    fn close_frame(code: u16, reason: &str) -> Vec<u8> {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        frame(0x80 | OPCODE_CLOSE, &payload, true)
+    }
+
+    // The next frame the server sent, as its opcode and payload.
+    fn read_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        client.read_exact(&mut head).unwrap();
+        assert!(head[1] <= 125, "only short frames expected");
+        let mut payload = vec![0; head[1] as usize];
+        client.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0f, payload)
+    }
+
+    fn close_code(client: &mut TcpStream) -> u16 {
+        let (opcode, payload) = read_frame(client);
+        assert_eq!(opcode, OPCODE_CLOSE);
+        u16::from_be_bytes([payload[0], payload[1]])
+    }
+
+    // Sends `bytes`, which `recv` should fail on, and returns the code the
+    // server closed with.
+    fn refused(options: WebSocketOptions, bytes: &[u8]) -> u16 {
+        let (mut socket, mut client) = connect(options);
+        client.write_all(bytes).unwrap();
+        let error = socket.recv().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        close_code(&mut client)
+    }
+
+    #[test]
+    fn reads_fragments_around_control_frames() {
+        let (mut socket, mut client) = connect(WebSocketOptions::new());
+        let mut bytes = frame(OPCODE_TEXT, b"hel", true);
+        bytes.extend(frame(0x80 | OPCODE_PING, b"?", true));
+        bytes.extend(frame(0x80 | OPCODE_CONTINUATION, b"lo", true));
+        client.write_all(&bytes).unwrap();
+        assert_eq!(socket.recv().unwrap(), Some(Message::Text("hello".to_string())));
+        assert_eq!(read_frame(&mut client), (OPCODE_PONG, b"?".to_vec()));
+    }
+
+    #[test]
+    fn refuses_unmasked_frames() {
+        assert_eq!(refused(WebSocketOptions::new(), &frame(0x80 | OPCODE_TEXT, b"hi", false)), CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn refuses_oversized_messages() {
+        // Refused on the length alone, before any payload arrives.
+        let mut huge = vec![0x80 | OPCODE_BINARY, 0x80 | 127];
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        huge.extend_from_slice(&[0; 4]);
+        assert_eq!(refused(WebSocketOptions::new(), &huge), CLOSE_TOO_BIG);
+
+        let small = || WebSocketOptions::new().max_message_size(100);
+        assert_eq!(refused(small(), &frame(0x80 | OPCODE_BINARY, &[0; 200], true)), CLOSE_TOO_BIG);
+        let mut fragments = frame(OPCODE_BINARY, &[0; 60], true);
+        fragments.extend(frame(0x80 | OPCODE_CONTINUATION, &[0; 60], true));
+        assert_eq!(refused(small(), &fragments), CLOSE_TOO_BIG);
+    }
+
+    #[test]
+    fn refuses_fragmented_or_long_control_frames() {
+        for opcode in [OPCODE_PING, OPCODE_PONG, OPCODE_CLOSE] {
+            assert_eq!(refused(WebSocketOptions::new(), &frame(opcode, b"", true)), CLOSE_PROTOCOL_ERROR);
+            assert_eq!(refused(WebSocketOptions::new(), &frame(0x80 | opcode, &[0; 126], true)), CLOSE_PROTOCOL_ERROR);
+        }
+    }
+
+    #[test]
+    fn refuses_invalid_close_codes() {
+        for code in [0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000, u16::MAX] {
+            assert_eq!(refused(WebSocketOptions::new(), &close_frame(code, "")), CLOSE_PROTOCOL_ERROR, "{code}");
+        }
+        assert_eq!(refused(WebSocketOptions::new(), &frame(0x80 | OPCODE_CLOSE, &[3], true)), CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn answers_valid_close_codes() {
+        for code in [CLOSE_NORMAL, CLOSE_GOING_AWAY, CLOSE_TOO_BIG, 1014, 3000, 4999] {
+            let (mut socket, mut client) = connect(WebSocketOptions::new());
+            client.write_all(&close_frame(code, "bye")).unwrap();
+            assert_eq!(socket.recv().unwrap(), None);
+            assert_eq!(socket.close_received(), Some((code, "bye")));
+            assert_eq!(close_code(&mut client), code);
+        }
+    }
+
+    // A socket that agreed to permessage-deflate.
+    fn deflating() -> (WebSocket, TcpStream) {
+        let (mut socket, client) = connect(WebSocketOptions::new().deflate(true));
+        let stream = socket.outgoing.stream.lock().unwrap().try_clone().unwrap();
+        let deflate = Deflate { server_window: 1 << 15, client_window: 1 << 15, client_takeover: true, min_size: 0 };
+        socket.outgoing = Arc::new(Outgoing { stream: Mutex::new(stream), deflate: Some(deflate), close_sent: Mutex::new(None) });
+        socket.history = Some(vec![]);
+        socket.inflate_window = 1 << 15;
+        (socket, client)
+    }
+
+    fn compressed(text: &str) -> Vec<u8> {
+        let mut payload = compress::deflate_window(text.as_bytes(), 1 << 15);
+        payload.push(0);
+        frame(0x80 | RSV1 | OPCODE_TEXT, &payload, true)
+    }
+
+    #[test]
+    fn inflates_and_deflates_messages() {
+        let (mut socket, mut client) = deflating();
+        let mut bytes = compressed("hello, hello, hello");
+        bytes.extend(compressed("again"));
+        bytes.extend(frame(0x80 | OPCODE_TEXT, b"plain", true));
+        client.write_all(&bytes).unwrap();
+        for text in ["hello, hello, hello", "again", "plain"] {
+            assert_eq!(socket.recv().unwrap(), Some(Message::Text(text.to_string())));
+        }
+
+        socket.send_text("hello, hello, hello").unwrap();
+        let mut head = [0; 2];
+        client.read_exact(&mut head).unwrap();
+        assert_eq!(head[0], 0x80 | RSV1 | OPCODE_TEXT);
+        let mut payload = vec![0; head[1] as usize];
+        client.read_exact(&mut payload).unwrap();
+        payload.extend_from_slice(&[0x00, 0x00, 0xff, 0xff]);
+        assert_eq!(compress::inflate_continuing(&payload, &[], 1024).unwrap(), b"hello, hello, hello");
+    }
+
+    #[test]
+    fn refuses_compression_where_it_wasnt_agreed() {
+        assert_eq!(refused(WebSocketOptions::new(), &compressed("hi")), CLOSE_PROTOCOL_ERROR);
+
+        let (mut socket, mut client) = deflating();
+        client.write_all(&frame(0x80 | RSV1 | OPCODE_PING, b"", true)).unwrap();
+        assert_eq!(socket.recv().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(close_code(&mut client), CLOSE_PROTOCOL_ERROR);
+
+        let (mut socket, mut client) = deflating();
+        client.write_all(&frame(0x80 | RSV1 | OPCODE_TEXT, b"not deflate", true)).unwrap();
+        assert_eq!(socket.recv().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(close_code(&mut client), CLOSE_INVALID_DATA);
+    }
+}